@@ -0,0 +1,22 @@
+use std::process::Command;
+
+fn main() {
+    // Builds without a git checkout, e.g. through Nix, can provide the hash
+    // themselves
+    let git_hash = std::env::var("OPENMELEE_GIT_HASH")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=OPENMELEE_GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-env-changed=OPENMELEE_GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -35,6 +35,7 @@
               pkgs.libiconv
             ];
           dontUseCmakeConfigure = true;
+          OPENMELEE_GIT_HASH = self.shortRev or "dirty";
         };
 
         cargoArtifacts = craneLib.buildDepsOnly _crateBuildAttrs;
@@ -0,0 +1,10 @@
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor_uid VARCHAR REFERENCES users(uid),
+    action VARCHAR NOT NULL,
+    subject_uid VARCHAR REFERENCES users(uid),
+    details VARCHAR,
+    created_at INTEGER NOT NULL
+)
@@ -0,0 +1,3 @@
+DROP TABLE match_results;
+DROP TABLE match_players;
+DROP TABLE matches
@@ -0,0 +1,23 @@
+CREATE TABLE matches (
+    match_id VARCHAR PRIMARY KEY NOT NULL,
+    mode VARCHAR NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE match_players (
+    match_id VARCHAR NOT NULL REFERENCES matches(match_id),
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    port INTEGER NOT NULL,
+    PRIMARY KEY (match_id, uid)
+);
+
+CREATE TABLE match_results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    match_id VARCHAR NOT NULL REFERENCES matches(match_id),
+    reporter_uid VARCHAR NOT NULL REFERENCES users(uid),
+    winner_uid VARCHAR NOT NULL REFERENCES users(uid),
+    idempotency_key VARCHAR,
+    created_at INTEGER NOT NULL,
+    UNIQUE (match_id, reporter_uid),
+    UNIQUE (reporter_uid, idempotency_key)
+)
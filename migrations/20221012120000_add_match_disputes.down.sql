@@ -0,0 +1,5 @@
+ALTER TABLE match_results DROP COLUMN replay_hash;
+ALTER TABLE match_results DROP COLUMN client_version;
+
+ALTER TABLE matches DROP COLUMN winner_uid;
+ALTER TABLE matches DROP COLUMN status
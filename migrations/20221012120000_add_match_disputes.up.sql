@@ -0,0 +1,5 @@
+ALTER TABLE matches ADD COLUMN status VARCHAR NOT NULL DEFAULT 'pending';
+ALTER TABLE matches ADD COLUMN winner_uid VARCHAR;
+
+ALTER TABLE match_results ADD COLUMN client_version VARCHAR;
+ALTER TABLE match_results ADD COLUMN replay_hash VARCHAR
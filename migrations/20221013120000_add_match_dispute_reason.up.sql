@@ -0,0 +1 @@
+ALTER TABLE matches ADD COLUMN dispute_reason VARCHAR
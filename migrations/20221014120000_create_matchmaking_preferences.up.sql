@@ -0,0 +1,5 @@
+CREATE TABLE matchmaking_preferences (
+    uid VARCHAR PRIMARY KEY NOT NULL REFERENCES users(uid),
+    max_ping_ms INTEGER,
+    strict BOOLEAN NOT NULL DEFAULT FALSE
+)
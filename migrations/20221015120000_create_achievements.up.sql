@@ -0,0 +1,17 @@
+CREATE TABLE achievements (
+    id VARCHAR PRIMARY KEY NOT NULL,
+    name VARCHAR NOT NULL,
+    description VARCHAR NOT NULL
+);
+
+CREATE TABLE user_achievements (
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    achievement_id VARCHAR NOT NULL REFERENCES achievements(id),
+    awarded_at INTEGER NOT NULL,
+    PRIMARY KEY (uid, achievement_id)
+);
+
+INSERT INTO achievements (id, name, description) VALUES
+    ('first_win', 'First win', 'Won a match'),
+    ('games_100', 'Regular', 'Played 100 matches'),
+    ('tournament_winner', 'Champion', 'Won a tournament')
@@ -0,0 +1,6 @@
+CREATE TABLE instance_logo (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    content_type VARCHAR NOT NULL,
+    data BLOB NOT NULL,
+    updated_at INTEGER NOT NULL
+)
@@ -0,0 +1,7 @@
+CREATE TABLE pages (
+    slug VARCHAR PRIMARY KEY NOT NULL,
+    title VARCHAR NOT NULL,
+    content TEXT NOT NULL,
+    show_in_navbar BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at INTEGER NOT NULL
+)
@@ -0,0 +1,6 @@
+CREATE TABLE announcements (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    title VARCHAR NOT NULL,
+    content TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+)
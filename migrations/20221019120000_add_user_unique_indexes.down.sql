@@ -0,0 +1,3 @@
+DROP INDEX users_connect_code_canonical;
+
+DROP INDEX users_username_normalized
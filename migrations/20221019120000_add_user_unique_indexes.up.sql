@@ -0,0 +1,3 @@
+CREATE UNIQUE INDEX users_username_normalized ON users (lower(username));
+
+CREATE UNIQUE INDEX users_connect_code_canonical ON users (upper(connect_code))
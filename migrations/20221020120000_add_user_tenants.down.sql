@@ -0,0 +1,25 @@
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TABLE users_old AS SELECT * FROM users;
+
+DROP TABLE users;
+
+CREATE TABLE users (
+    uid VARCHAR PRIMARY KEY NOT NULL,
+    username VARCHAR UNIQUE NOT NULL,
+    password VARCHAR NOT NULL,
+    play_key VARCHAR NOT NULL,
+    display_name VARCHAR NOT NULL,
+    connect_code VARCHAR UNIQUE NOT NULL,
+    latest_version VARCHAR,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+INSERT INTO users (uid, username, password, play_key, display_name, connect_code, latest_version, is_admin)
+SELECT uid, username, password, play_key, display_name, connect_code, latest_version, is_admin FROM users_old;
+
+DROP TABLE users_old;
+
+CREATE UNIQUE INDEX users_username_normalized ON users (lower(username));
+
+CREATE UNIQUE INDEX users_connect_code_canonical ON users (upper(connect_code))
@@ -0,0 +1,29 @@
+-- SQLite can't drop the column constraints making usernames and connect
+-- codes unique across the whole server, so the table is rebuilt. Foreign key
+-- checks are deferred until the rows are copied back.
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TABLE users_old AS SELECT * FROM users;
+
+DROP TABLE users;
+
+CREATE TABLE users (
+    uid VARCHAR PRIMARY KEY NOT NULL,
+    username VARCHAR NOT NULL,
+    password VARCHAR NOT NULL,
+    play_key VARCHAR NOT NULL,
+    display_name VARCHAR NOT NULL,
+    connect_code VARCHAR NOT NULL,
+    latest_version VARCHAR,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    tenant_id VARCHAR NOT NULL DEFAULT ''
+);
+
+INSERT INTO users (uid, username, password, play_key, display_name, connect_code, latest_version, is_admin)
+SELECT uid, username, password, play_key, display_name, connect_code, latest_version, is_admin FROM users_old;
+
+DROP TABLE users_old;
+
+CREATE UNIQUE INDEX users_username_normalized ON users (tenant_id, lower(username));
+
+CREATE UNIQUE INDEX users_connect_code_canonical ON users (tenant_id, upper(connect_code))
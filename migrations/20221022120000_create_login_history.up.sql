@@ -0,0 +1,9 @@
+CREATE TABLE login_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    ip_address VARCHAR NOT NULL,
+    user_agent VARCHAR,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX login_history_uid ON login_history (uid, created_at);
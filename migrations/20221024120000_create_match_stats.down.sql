@@ -0,0 +1,3 @@
+DROP INDEX matches_created_at;
+
+DROP TABLE match_stats_daily
@@ -0,0 +1,11 @@
+-- Matches per day, mode and tenant, rebuilt periodically so the stats page
+-- doesn't have to scan the matches table
+CREATE TABLE match_stats_daily (
+    tenant_id VARCHAR NOT NULL,
+    day VARCHAR NOT NULL,
+    mode VARCHAR NOT NULL,
+    matches INTEGER NOT NULL,
+    PRIMARY KEY (tenant_id, day, mode)
+);
+
+CREATE INDEX matches_created_at ON matches (created_at)
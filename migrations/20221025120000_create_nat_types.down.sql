@@ -0,0 +1 @@
+DROP TABLE nat_types
@@ -0,0 +1,5 @@
+CREATE TABLE nat_types (
+    uid VARCHAR PRIMARY KEY NOT NULL REFERENCES users(uid),
+    nat_type VARCHAR NOT NULL,
+    updated_at INTEGER NOT NULL
+)
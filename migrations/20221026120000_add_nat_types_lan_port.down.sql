@@ -0,0 +1 @@
+ALTER TABLE nat_types DROP COLUMN lan_port
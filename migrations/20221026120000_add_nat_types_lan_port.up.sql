@@ -0,0 +1 @@
+ALTER TABLE nat_types ADD COLUMN lan_port INTEGER
@@ -0,0 +1,5 @@
+DROP TABLE user_daily_quests;
+
+DROP TABLE user_streaks;
+
+ALTER TABLE matches DROP COLUMN stage
@@ -0,0 +1,19 @@
+ALTER TABLE matches ADD COLUMN stage INTEGER;
+
+CREATE TABLE user_streaks (
+    uid VARCHAR PRIMARY KEY NOT NULL REFERENCES users(uid),
+    play_days INTEGER NOT NULL,
+    longest_play_days INTEGER NOT NULL,
+    last_played_on VARCHAR NOT NULL,
+    wins INTEGER NOT NULL,
+    longest_wins INTEGER NOT NULL
+);
+
+CREATE TABLE user_daily_quests (
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    day VARCHAR NOT NULL,
+    quest_id VARCHAR NOT NULL,
+    progress INTEGER NOT NULL,
+    completed_at INTEGER,
+    PRIMARY KEY (uid, day, quest_id)
+)
@@ -0,0 +1,3 @@
+ALTER TABLE matches DROP COLUMN set_game;
+
+ALTER TABLE matches DROP COLUMN set_id
@@ -0,0 +1,5 @@
+-- Direct matches between the same players form sets, identified by the
+-- match ID of their first game
+ALTER TABLE matches ADD COLUMN set_id VARCHAR;
+
+ALTER TABLE matches ADD COLUMN set_game INTEGER
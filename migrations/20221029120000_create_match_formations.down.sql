@@ -0,0 +1,5 @@
+DROP INDEX match_formations_tenant_id_created_at;
+
+DROP TABLE match_formation_players;
+
+DROP TABLE match_formations
@@ -0,0 +1,21 @@
+-- Written when matchmaking forms a match, before any result is reported, to
+-- analyze queue health and fairness
+CREATE TABLE match_formations (
+    match_id VARCHAR PRIMARY KEY NOT NULL REFERENCES matches(match_id),
+    tenant_id VARCHAR NOT NULL,
+    mode VARCHAR NOT NULL,
+    algorithm VARCHAR NOT NULL,
+    algorithm_version INTEGER NOT NULL,
+    -- Comma separated in-game IDs
+    stages VARCHAR NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE match_formation_players (
+    match_id VARCHAR NOT NULL REFERENCES match_formations(match_id),
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    waited_seconds INTEGER NOT NULL,
+    PRIMARY KEY (match_id, uid)
+);
+
+CREATE INDEX match_formations_tenant_id_created_at ON match_formations (tenant_id, created_at)
@@ -0,0 +1,12 @@
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    name VARCHAR NOT NULL,
+    scopes VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    revoked_at INTEGER
+);
+
+CREATE INDEX api_tokens_uid ON api_tokens (uid);
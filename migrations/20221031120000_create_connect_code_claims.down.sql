@@ -0,0 +1 @@
+DROP TABLE connect_code_claims
\ No newline at end of file
@@ -0,0 +1,14 @@
+CREATE TABLE connect_code_claims (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    connect_code VARCHAR NOT NULL,
+    official_uid VARCHAR NOT NULL,
+    official_display_name VARCHAR NOT NULL,
+    status VARCHAR NOT NULL,
+    created_at INTEGER NOT NULL,
+    reviewer_uid VARCHAR REFERENCES users(uid),
+    reviewed_at INTEGER
+);
+
+CREATE INDEX connect_code_claims_uid ON connect_code_claims (uid, created_at);
+CREATE INDEX connect_code_claims_status ON connect_code_claims (status);
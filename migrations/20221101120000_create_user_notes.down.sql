@@ -0,0 +1 @@
+DROP TABLE user_notes
\ No newline at end of file
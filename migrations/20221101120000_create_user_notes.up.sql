@@ -0,0 +1,9 @@
+CREATE TABLE user_notes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    author_uid VARCHAR NOT NULL REFERENCES users(uid),
+    content TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX user_notes_uid ON user_notes (uid, created_at);
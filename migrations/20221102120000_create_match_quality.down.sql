@@ -0,0 +1,5 @@
+DROP TABLE match_ratings;
+
+DROP TABLE match_quality;
+
+ALTER TABLE match_formation_players DROP COLUMN rtt_ms
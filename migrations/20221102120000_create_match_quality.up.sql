@@ -0,0 +1,21 @@
+-- Round trip time between each player and the matchmaking server, unknown
+-- for matches formed before it was recorded
+ALTER TABLE match_formation_players ADD COLUMN rtt_ms INTEGER;
+
+-- Scored once players had time to report the result of the match
+CREATE TABLE match_quality (
+    match_id VARCHAR PRIMARY KEY NOT NULL REFERENCES match_formations(match_id),
+    score INTEGER NOT NULL,
+    ping_ms INTEGER,
+    reported BOOLEAN NOT NULL,
+    scored_at INTEGER NOT NULL
+);
+
+-- Answers to the survey shown on the profile page after a match
+CREATE TABLE match_ratings (
+    match_id VARCHAR NOT NULL REFERENCES matches(match_id),
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    rating INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (match_id, uid)
+);
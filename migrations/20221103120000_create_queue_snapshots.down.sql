@@ -0,0 +1,3 @@
+DROP INDEX queue_snapshots_tenant_id_taken_at;
+
+DROP TABLE queue_snapshots
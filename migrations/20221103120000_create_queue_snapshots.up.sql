@@ -0,0 +1,14 @@
+-- Taken periodically by the matchmaking server for every queue, including
+-- empty ones, to plan capacity and maintenance windows
+CREATE TABLE queue_snapshots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    tenant_id VARCHAR NOT NULL,
+    mode VARCHAR NOT NULL,
+    taken_at INTEGER NOT NULL,
+    -- Time since the previous snapshot, which matches_formed covers
+    period_seconds INTEGER NOT NULL,
+    waiting INTEGER NOT NULL,
+    matches_formed INTEGER NOT NULL
+);
+
+CREATE INDEX queue_snapshots_tenant_id_taken_at ON queue_snapshots (tenant_id, taken_at)
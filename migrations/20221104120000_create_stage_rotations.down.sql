@@ -0,0 +1,3 @@
+DROP INDEX stage_rotations_mode_starts_at;
+
+DROP TABLE stage_rotations
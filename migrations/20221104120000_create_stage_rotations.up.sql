@@ -0,0 +1,13 @@
+-- Seasonal stage lists replacing the default stages of a mode, stages being
+-- their ids separated by commas
+CREATE TABLE stage_rotations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR NOT NULL,
+    mode VARCHAR NOT NULL,
+    stages VARCHAR NOT NULL,
+    starts_at INTEGER NOT NULL,
+    ends_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX stage_rotations_mode_starts_at ON stage_rotations (mode, starts_at)
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN is_shadow_queued BOOLEAN NOT NULL DEFAULT FALSE;
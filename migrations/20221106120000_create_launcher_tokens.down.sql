@@ -0,0 +1 @@
+DROP TABLE launcher_tokens;
@@ -0,0 +1,8 @@
+CREATE TABLE launcher_tokens (
+    token_hash VARCHAR PRIMARY KEY NOT NULL,
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX launcher_tokens_expires_at ON launcher_tokens (expires_at);
@@ -0,0 +1,9 @@
+CREATE TABLE recovery_attempts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ip_address VARCHAR NOT NULL,
+    uid VARCHAR,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX recovery_attempts_ip_address ON recovery_attempts (ip_address, created_at);
+CREATE INDEX recovery_attempts_uid ON recovery_attempts (uid, created_at);
@@ -0,0 +1,6 @@
+CREATE TABLE ratings (
+    uid VARCHAR PRIMARY KEY NOT NULL REFERENCES users(uid),
+    rating REAL NOT NULL,
+    games INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
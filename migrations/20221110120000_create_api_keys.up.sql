@@ -0,0 +1,12 @@
+CREATE TABLE api_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR NOT NULL,
+    scopes VARCHAR NOT NULL,
+    key_hash VARCHAR NOT NULL UNIQUE,
+    requests_per_minute INTEGER NOT NULL,
+    request_count INTEGER NOT NULL DEFAULT 0,
+    throttled_count INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    revoked_at INTEGER
+);
@@ -0,0 +1,8 @@
+CREATE TABLE player_summaries (
+    result_id INTEGER NOT NULL REFERENCES match_results(id),
+    uid VARCHAR NOT NULL REFERENCES users(uid),
+    stocks INTEGER NOT NULL,
+    percent INTEGER NOT NULL,
+    lras BOOLEAN NOT NULL,
+    PRIMARY KEY (result_id, uid)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE queue_pauses (
+    mode VARCHAR PRIMARY KEY NOT NULL,
+    reason VARCHAR NOT NULL,
+    paused_by VARCHAR REFERENCES users(uid),
+    paused_at INTEGER NOT NULL
+);
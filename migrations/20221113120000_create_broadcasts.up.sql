@@ -0,0 +1,8 @@
+CREATE TABLE broadcasts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message VARCHAR NOT NULL,
+    sent_by VARCHAR REFERENCES users(uid),
+    created_at INTEGER NOT NULL,
+    delivered_at INTEGER,
+    delivered_count INTEGER
+);
@@ -0,0 +1,10 @@
+-- Bumped on every change to a user, so clients polling a user's profile can
+-- be answered from the version alone. Recursive triggers are off, and the
+-- condition keeps the bump itself from counting as another change.
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER users_bump_version AFTER UPDATE ON users
+WHEN NEW.version = OLD.version
+BEGIN
+    UPDATE users SET version = OLD.version + 1 WHERE uid = OLD.uid;
+END
@@ -0,0 +1,199 @@
+use std::io::prelude::Read;
+
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{FromRow, SqliteExecutor, SqlitePool};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    crypto,
+    events::{self, Event},
+    ids::Uid,
+    matches::MatchResult,
+    Config,
+};
+
+pub const FIRST_WIN: &str = "first_win";
+pub const GAMES_100: &str = "games_100";
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-OpenMelee-Signature";
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Achievement {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub awarded_at: i64,
+}
+
+impl Achievement {
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Vec<Achievement>, sqlx::Error> {
+        sqlx::query_as::<_, Achievement>("select achievements.id, achievements.name, achievements.description, user_achievements.awarded_at from achievements join user_achievements on achievements.id = user_achievements.achievement_id where user_achievements.uid = $1 order by user_achievements.awarded_at")
+            .bind(uid)
+            .fetch_all(executor)
+            .await
+    }
+
+    // Returns whether the achievement is new to the user
+    pub async fn award<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        achievement_id: &str,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("insert or ignore into user_achievements (uid, achievement_id, awarded_at) values ($1, $2, $3)")
+            .bind(uid)
+            .bind(achievement_id)
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() > 0)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Award {
+    pub uid: Uid,
+    pub achievement_id: String,
+}
+
+pub async fn award_for_event(pool: &SqlitePool, event: &Event) -> Result<Vec<Award>, sqlx::Error> {
+    let mut awards = vec![];
+
+    match event {
+        Event::MatchConfirmed {
+            winner_uids,
+            player_uids,
+            ..
+        } => {
+            for uid in winner_uids {
+                if Achievement::award(pool, uid.clone(), FIRST_WIN).await? {
+                    awards.push(Award {
+                        uid: uid.clone(),
+                        achievement_id: FIRST_WIN.to_string(),
+                    });
+                }
+            }
+
+            for uid in player_uids {
+                let games = MatchResult::count_wins(pool, uid.clone()).await?
+                    + MatchResult::count_losses(pool, uid.clone()).await?;
+
+                if games >= 100 && Achievement::award(pool, uid.clone(), GAMES_100).await? {
+                    awards.push(Award {
+                        uid: uid.clone(),
+                        achievement_id: GAMES_100.to_string(),
+                    });
+                }
+            }
+        }
+        Event::BroadcastCreated { .. } => {}
+    }
+
+    Ok(awards)
+}
+
+fn read_webhook_secret(path: &str) -> String {
+    let mut buffer = String::new();
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .unwrap_or_else(|_| panic!("Unable to read {}", path));
+
+    buffer.trim().to_string()
+}
+
+pub async fn start_awarding(config: Config, pool: SqlitePool) {
+    let webhook = match (
+        config.achievements_webhook_url,
+        config.achievements_webhook_secret_path,
+    ) {
+        (Some(url), Some(secret_path)) => Some((url, read_webhook_secret(&secret_path))),
+        (Some(_), None) => {
+            println!("Achievements webhook URL configured without a secret, not sending awards");
+            None
+        }
+        _ => None,
+    };
+    let client = reqwest::Client::new();
+    let mut events = events::subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                println!("Achievements missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let awards = match award_for_event(&pool, &event).await {
+            Ok(awards) => awards,
+            Err(err) => {
+                println!("Failed to award achievements for {:?}: {}", event, err);
+                continue;
+            }
+        };
+
+        if let Some((url, secret)) = &webhook {
+            for award in awards {
+                let body = serde_json::to_vec(&award).unwrap();
+                let result = client
+                    .post(url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(
+                        WEBHOOK_SIGNATURE_HEADER,
+                        crypto::sign(secret.as_bytes(), &body),
+                    )
+                    .body(body)
+                    .send()
+                    .await;
+
+                if let Err(err) = result {
+                    println!("Failed to send achievement webhook: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::achievements::*;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    #[sqlx::test]
+    async fn awards_first_win_once(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        for match_id in ["mode.unranked-1", "mode.unranked-2"] {
+            MatchBuilder::new(match_id)
+                .player(&user)
+                .confirmed(&user)
+                .insert(&pool)
+                .await;
+
+            let awards = award_for_event(
+                &pool,
+                &Event::MatchConfirmed {
+                    match_id: match_id.parse().unwrap(),
+                    winner_uids: vec![user.uid.clone()],
+                    player_uids: vec![user.uid.clone()],
+                },
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(awards.len(), usize::from(match_id == "mode.unranked-1"));
+        }
+
+        let achievements = Achievement::get_for_user(&pool, user.uid).await.unwrap();
+        assert_eq!(achievements.len(), 1);
+        assert_eq!(achievements[0].id, FIRST_WIN);
+    }
+}
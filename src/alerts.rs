@@ -0,0 +1,289 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{formations::QueueWait, startup, Config};
+
+const QUEUE_WAIT_PERCENTILE: usize = 95;
+
+static RESPONSES: AtomicU64 = AtomicU64::new(0);
+static SERVER_ERRORS: AtomicU64 = AtomicU64::new(0);
+static FAILED_LOGINS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_response(is_server_error: bool) {
+    RESPONSES.fetch_add(1, Ordering::Relaxed);
+    if is_server_error {
+        SERVER_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_failed_login() {
+    FAILED_LOGINS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Internal measurement watched by an alert rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// Percentage of web server responses which were server errors since the last evaluation
+    ErrorRatePercent,
+    /// 95th percentile of how long players matched since the last evaluation waited in a queue, in seconds
+    QueueWaitP95Seconds,
+    /// Size of the database, in bytes
+    DatabaseSizeBytes,
+    /// Failed logins to the web UI since the last evaluation
+    FailedLogins,
+}
+
+impl fmt::Display for AlertMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            AlertMetric::ErrorRatePercent => "error_rate_percent",
+            AlertMetric::QueueWaitP95Seconds => "queue_wait_p95_seconds",
+            AlertMetric::DatabaseSizeBytes => "database_size_bytes",
+            AlertMetric::FailedLogins => "failed_logins",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+/// Fires while a metric is above a threshold, such as {"metric": "failed_logins", "above": 50}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AlertRule {
+    pub metric: AlertMetric,
+    pub above: u64,
+}
+
+// Metrics over the last interval, None when there was nothing to measure,
+// such as no matches formed
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Metrics {
+    error_rate_percent: Option<u64>,
+    queue_wait_p95_seconds: Option<u64>,
+    database_size_bytes: Option<u64>,
+    failed_logins: Option<u64>,
+}
+
+impl Metrics {
+    async fn gather(pool: &SqlitePool, since: i64) -> Metrics {
+        let responses = RESPONSES.swap(0, Ordering::Relaxed);
+        let server_errors = SERVER_ERRORS.swap(0, Ordering::Relaxed);
+
+        let waits = QueueWait::get_player_waits_since(pool, since)
+            .await
+            .unwrap_or_else(|err| {
+                println!("Failed to get queue waits for alerts: {}", err);
+                vec![]
+            });
+        let database_size_bytes = startup::get_database_size_bytes(pool)
+            .await
+            .map_err(|err| println!("Failed to get the database size for alerts: {}", err))
+            .ok();
+
+        Metrics {
+            error_rate_percent: (responses > 0).then(|| server_errors * 100 / responses),
+            queue_wait_p95_seconds: get_percentile(&waits, QUEUE_WAIT_PERCENTILE)
+                .map(|wait| wait.max(0) as u64),
+            database_size_bytes: database_size_bytes.map(|size| size as u64),
+            failed_logins: Some(FAILED_LOGINS.swap(0, Ordering::Relaxed)),
+        }
+    }
+
+    fn get(&self, metric: AlertMetric) -> Option<u64> {
+        match metric {
+            AlertMetric::ErrorRatePercent => self.error_rate_percent,
+            AlertMetric::QueueWaitP95Seconds => self.queue_wait_p95_seconds,
+            AlertMetric::DatabaseSizeBytes => self.database_size_bytes,
+            AlertMetric::FailedLogins => self.failed_logins,
+        }
+    }
+}
+
+// Nearest-rank percentile of values sorted in ascending order
+fn get_percentile(sorted: &[i64], percentile: usize) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * percentile).div_ceil(100).max(1);
+
+    Some(sorted[rank - 1])
+}
+
+// Sent when a rule starts or stops firing. Discord webhooks post the
+// content as a message and ignore the rest.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertNotification {
+    pub content: String,
+    pub server_name: String,
+    pub metric: AlertMetric,
+    pub above: u64,
+    pub value: Option<u64>,
+    pub firing: bool,
+    pub created_at: i64,
+}
+
+// Rules only notify when they change state, not at every evaluation. A
+// metric with nothing to measure stops its rules from firing.
+fn evaluate(
+    rules: &[AlertRule],
+    metrics: &Metrics,
+    firing: &mut [bool],
+    server_name: &str,
+    now: i64,
+) -> Vec<AlertNotification> {
+    let mut notifications = vec![];
+
+    for (rule, was_firing) in rules.iter().zip(firing.iter_mut()) {
+        let value = metrics.get(rule.metric);
+        let is_firing = value.is_some_and(|value| value > rule.above);
+        if is_firing == *was_firing {
+            continue;
+        }
+        *was_firing = is_firing;
+
+        let content = match (is_firing, value) {
+            (true, Some(value)) => format!(
+                "[{}] {} is {}, above {}",
+                server_name, rule.metric, value, rule.above
+            ),
+            (_, Some(value)) => format!(
+                "[{}] {} is back to {}, at most {}",
+                server_name, rule.metric, value, rule.above
+            ),
+            (_, None) => format!(
+                "[{}] {} has nothing to measure, no longer above {}",
+                server_name, rule.metric, rule.above
+            ),
+        };
+        notifications.push(AlertNotification {
+            content,
+            server_name: server_name.to_string(),
+            metric: rule.metric,
+            above: rule.above,
+            value,
+            firing: is_firing,
+            created_at: now,
+        });
+    }
+
+    notifications
+}
+
+pub async fn start_alerting(config: Config, pool: SqlitePool) {
+    if config.alert_rules.is_empty() {
+        return;
+    }
+    let interval_seconds = config.alert_interval_seconds.max(1);
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+    let mut firing = vec![false; config.alert_rules.len()];
+
+    // The first tick completes immediately, skip it so that rates cover a
+    // full interval
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now().timestamp();
+        let metrics = Metrics::gather(&pool, now - interval_seconds as i64).await;
+        let notifications = evaluate(
+            &config.alert_rules,
+            &metrics,
+            &mut firing,
+            &config.server_name,
+            now,
+        );
+
+        for notification in notifications {
+            println!("Alert: {}", notification.content);
+
+            if let Some(url) = &config.alert_webhook_url {
+                if let Err(err) = client.post(url.clone()).json(&notification).send().await {
+                    println!("Failed to send alert webhook: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::alerts::*;
+
+    #[test]
+    fn test_get_percentile() {
+        assert_eq!(get_percentile(&[], 95), None);
+        assert_eq!(get_percentile(&[7], 95), Some(7));
+        assert_eq!(
+            get_percentile(&(1..=20).collect::<Vec<i64>>(), 95),
+            Some(19)
+        );
+        assert_eq!(
+            get_percentile(&(1..=100).collect::<Vec<i64>>(), 95),
+            Some(95)
+        );
+    }
+
+    #[test]
+    fn test_rules_notify_when_they_change_state() {
+        let rules = [
+            AlertRule {
+                metric: AlertMetric::FailedLogins,
+                above: 10,
+            },
+            AlertRule {
+                metric: AlertMetric::ErrorRatePercent,
+                above: 5,
+            },
+        ];
+        let mut firing = [false, false];
+        let metrics = |failed_logins, error_rate_percent| Metrics {
+            failed_logins: Some(failed_logins),
+            error_rate_percent,
+            ..Metrics::default()
+        };
+
+        let notifications = evaluate(&rules, &metrics(11, None), &mut firing, "Test", 100);
+        assert_eq!(
+            notifications,
+            vec![AlertNotification {
+                content: "[Test] failed_logins is 11, above 10".to_string(),
+                server_name: "Test".to_string(),
+                metric: AlertMetric::FailedLogins,
+                above: 10,
+                value: Some(11),
+                firing: true,
+                created_at: 100,
+            }]
+        );
+        assert_eq!(firing, [true, false]);
+
+        assert_eq!(
+            evaluate(&rules, &metrics(50, Some(5)), &mut firing, "Test", 160),
+            vec![]
+        );
+
+        let notifications = evaluate(&rules, &metrics(10, Some(6)), &mut firing, "Test", 220);
+        assert_eq!(
+            notifications
+                .iter()
+                .map(|notification| notification.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec![
+                "[Test] failed_logins is back to 10, at most 10",
+                "[Test] error_rate_percent is 6, above 5",
+            ]
+        );
+        assert_eq!(firing, [false, true]);
+
+        evaluate(&rules, &metrics(0, None), &mut firing, "Test", 280);
+        assert_eq!(firing, [false, false]);
+    }
+}
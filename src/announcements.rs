@@ -0,0 +1,102 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+use validator::Validate;
+
+use crate::pages::render_markdown;
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Announcement {
+    pub id: i64,
+    pub title: String,
+    pub content: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct AnnouncementForm {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be at least 1 and at most 100 characters long"
+    ))]
+    pub title: String,
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub content: String,
+}
+
+impl Announcement {
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        form: &AnnouncementForm,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query("insert into announcements (title, content, created_at) values ($1, $2, $3)")
+            .bind(form.title.trim())
+            .bind(&form.content)
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|result| result.last_insert_rowid())
+    }
+
+    pub async fn get_recent<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        limit: i64,
+    ) -> Result<Vec<Announcement>, sqlx::Error> {
+        sqlx::query_as::<_, Announcement>(
+            "select * from announcements order by created_at desc, id desc limit $1",
+        )
+        .bind(limit)
+        .fetch_all(executor)
+        .await
+    }
+
+    pub async fn delete<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from announcements where id = $1")
+            .bind(id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub fn render_content(&self) -> String {
+        render_markdown(&self.content)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::announcements::*;
+
+    #[sqlx::test]
+    async fn recent_announcements_are_newest_first(pool: Pool<Sqlite>) {
+        for title in ["First", "Second"] {
+            Announcement::create(
+                &pool,
+                &AnnouncementForm {
+                    title: title.to_string(),
+                    content: "Content".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let announcements = Announcement::get_recent(&pool, 1).await.unwrap();
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(announcements[0].title, "Second");
+
+        Announcement::delete(&pool, announcements[0].id)
+            .await
+            .unwrap();
+        let announcements = Announcement::get_recent(&pool, 10).await.unwrap();
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(announcements[0].title, "First");
+    }
+}
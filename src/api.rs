@@ -0,0 +1,234 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 50;
+pub const MAX_PAGE_LIMIT: i64 = 200;
+
+// Query parameters accepted by every list endpoint, next to its own filters
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PageQuery {
+    #[serde(default)]
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    // Cursors are the hex encoded sort key of the last item of the previous
+    // page, opaque to clients so the key can change without breaking them
+    pub fn after(&self) -> Result<Option<String>, ApiError> {
+        match &self.cursor {
+            None => Ok(None),
+            Some(cursor) => hex::decode(cursor)
+                .ok()
+                .and_then(|key| String::from_utf8(key).ok())
+                .map(Some)
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid cursor")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub limit: i64,
+    pub next_cursor: Option<String>,
+    pub total: i64,
+}
+
+impl<T> Paginated<T> {
+    // Expects up to limit + 1 items, the extra one only telling whether
+    // there is another page
+    pub fn new(mut items: Vec<T>, limit: i64, total: i64, key: impl Fn(&T) -> String) -> Self {
+        let has_more = items.len() as i64 > limit;
+        items.truncate(limit as usize);
+
+        let next_cursor = match items.last() {
+            Some(last) if has_more => Some(hex::encode(key(last))),
+            _ => None,
+        };
+
+        Paginated {
+            items,
+            limit,
+            next_cursor,
+            total,
+        }
+    }
+
+    pub fn map<U>(self, f: impl Fn(T) -> U) -> Paginated<U> {
+        Paginated {
+            items: self.items.into_iter().map(f).collect(),
+            limit: self.limit,
+            next_cursor: self.next_cursor,
+            total: self.total,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    pub limit: i64,
+    pub next_cursor: Option<String>,
+    pub total: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub message: String,
+}
+
+// Shape of every JSON API list response, errors included
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub data: Option<T>,
+    pub meta: Option<Meta>,
+    pub errors: Vec<ErrorMessage>,
+}
+
+impl<T> From<Paginated<T>> for Envelope<Vec<T>> {
+    fn from(page: Paginated<T>) -> Envelope<Vec<T>> {
+        Envelope {
+            data: Some(page.items),
+            meta: Some(Meta {
+                limit: page.limit,
+                next_cursor: page.next_cursor,
+                total: page.total,
+            }),
+            errors: vec![],
+        }
+    }
+}
+
+// Short lists are returned whole, with the same metadata as pages
+impl<T> From<Vec<T>> for Envelope<Vec<T>> {
+    fn from(items: Vec<T>) -> Envelope<Vec<T>> {
+        let total = items.len() as i64;
+        Paginated::new(items, total, total, |_| String::new()).into()
+    }
+}
+
+impl<T: Serialize> IntoResponse for Envelope<T> {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+// Unix timestamps as ISO-8601 in UTC, such as 2022-11-08T12:00:00Z
+pub fn format_timestamp(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+pub trait CreatedAt {
+    fn created_at(&self) -> i64;
+}
+
+// Objects are stored with Unix timestamps, the API sends them along with
+// the time they were created at as ISO-8601 for clients that expect dates
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WithUtcTime<T> {
+    #[serde(flatten)]
+    pub inner: T,
+    pub created_at_utc: String,
+}
+
+impl<T: CreatedAt> From<T> for WithUtcTime<T> {
+    fn from(inner: T) -> WithUtcTime<T> {
+        WithUtcTime {
+            created_at_utc: format_timestamp(inner.created_at()),
+            inner,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: &str) -> ApiError {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Envelope::<()> {
+            data: None,
+            meta: None,
+            errors: vec![ErrorMessage {
+                message: self.message,
+            }],
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::*;
+
+    #[test]
+    fn test_limit_is_clamped() {
+        let query = |limit| PageQuery {
+            limit,
+            cursor: None,
+        };
+
+        assert_eq!(query(None).limit(), DEFAULT_PAGE_LIMIT);
+        assert_eq!(query(Some(0)).limit(), 1);
+        assert_eq!(query(Some(10_000)).limit(), MAX_PAGE_LIMIT);
+    }
+
+    #[test]
+    fn test_next_cursor_points_after_last_item() {
+        let page = Paginated::new(vec!["a", "b", "c"], 2, 3, |item| item.to_string());
+        assert_eq!(page.items, vec!["a", "b"]);
+
+        let query = PageQuery {
+            limit: Some(2),
+            cursor: page.next_cursor,
+        };
+        assert_eq!(query.after(), Ok(Some("b".to_string())));
+
+        let last_page = Paginated::new(vec!["c"], 2, 3, |item| item.to_string());
+        assert_eq!(last_page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_invalid_cursor_is_rejected() {
+        let query = PageQuery {
+            limit: None,
+            cursor: Some("not hex".to_string()),
+        };
+
+        assert_eq!(query.after().unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_timestamps_are_formatted_in_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1667908800), "2022-11-08T12:00:00Z");
+    }
+}
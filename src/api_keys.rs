@@ -0,0 +1,383 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, Row, SqliteExecutor};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::api_tokens::hash_token;
+
+// Sent by integrations instead of the Authorization header of user tokens
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const MAX_REQUESTS_PER_MINUTE: i64 = 10_000;
+// Tells keys apart from user tokens, for secret scanners among others
+const API_KEY_PREFIX: &str = "omk_";
+const RATE_LIMIT_WINDOW_SECONDS: i64 = 60;
+
+// What an operator's integration, such as a stats site or a Discord bot, may
+// read from the public JSON API. Keys never act on behalf of a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiKeyScope {
+    // Players, looked up by UID or connect code, and their badges
+    ReadUsers,
+    ReadMatches,
+    ReadStats,
+}
+
+impl ApiKeyScope {
+    pub const ALL: [ApiKeyScope; 3] = [
+        ApiKeyScope::ReadUsers,
+        ApiKeyScope::ReadMatches,
+        ApiKeyScope::ReadStats,
+    ];
+
+    // The scope covering a read of the JSON API, None for the endpoints keys
+    // can't be used on, such as those reading a user's own data
+    pub fn for_path(path: &str) -> Option<ApiKeyScope> {
+        let path = path.strip_prefix("/api/v1/")?;
+        let (resource, _) = path.split_once('/').unwrap_or((path, ""));
+
+        match resource {
+            "users" | "user" | "connect-code" => Some(ApiKeyScope::ReadUsers),
+            "matches" => Some(ApiKeyScope::ReadMatches),
+            "stats" => Some(ApiKeyScope::ReadStats),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiKeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            ApiKeyScope::ReadUsers => "read-users",
+            ApiKeyScope::ReadMatches => "read-matches",
+            ApiKeyScope::ReadStats => "read-stats",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-users" => Ok(ApiKeyScope::ReadUsers),
+            "read-matches" => Ok(ApiKeyScope::ReadMatches),
+            "read-stats" => Ok(ApiKeyScope::ReadStats),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub requests_per_minute: i64,
+    // Requests let through and refused for exceeding the rate limit
+    pub request_count: i64,
+    pub throttled_count: i64,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+// Submitted from the admin page, unchecked scopes are omitted
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct ApiKeyForm {
+    #[validate(length(
+        min = 1,
+        max = 50,
+        message = "Must be at least 1 and at most 50 characters long"
+    ))]
+    pub name: String,
+    #[validate(custom(
+        function = "validate_requests_per_minute",
+        message = "Must be a number between 1 and 10000"
+    ))]
+    pub requests_per_minute: String,
+    #[serde(default, rename = "read-users")]
+    pub read_users: Option<String>,
+    #[serde(default, rename = "read-matches")]
+    pub read_matches: Option<String>,
+    #[serde(default, rename = "read-stats")]
+    pub read_stats: Option<String>,
+}
+
+fn parse_requests_per_minute(requests_per_minute: &str) -> Option<i64> {
+    requests_per_minute
+        .trim()
+        .parse()
+        .ok()
+        .filter(|requests_per_minute| (1..=MAX_REQUESTS_PER_MINUTE).contains(requests_per_minute))
+}
+
+fn validate_requests_per_minute(requests_per_minute: &str) -> Result<(), ValidationError> {
+    match parse_requests_per_minute(requests_per_minute) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("requests_per_minute")),
+    }
+}
+
+impl ApiKeyForm {
+    pub fn get_scopes(&self) -> Vec<ApiKeyScope> {
+        [
+            (ApiKeyScope::ReadUsers, &self.read_users),
+            (ApiKeyScope::ReadMatches, &self.read_matches),
+            (ApiKeyScope::ReadStats, &self.read_stats),
+        ]
+        .into_iter()
+        .filter(|(_, checked)| checked.is_some())
+        .map(|(scope, _)| scope)
+        .collect()
+    }
+
+    // Also checks that the key allows reading something
+    pub fn check(&self) -> Result<(), ValidationErrors> {
+        let mut errors = match self.validate() {
+            Ok(()) => ValidationErrors::new(),
+            Err(errors) => errors,
+        };
+
+        if self.get_scopes().is_empty() {
+            let mut error = ValidationError::new("scopes");
+            error.message = Some(Cow::Borrowed("Must allow reading something"));
+            errors.add("scopes", error);
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+impl ApiKey {
+    fn from_row(row: SqliteRow) -> ApiKey {
+        ApiKey {
+            id: row.get("id"),
+            name: row.get("name"),
+            scopes: row
+                .get::<String, &str>("scopes")
+                .split(',')
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            requests_per_minute: row.get("requests_per_minute"),
+            request_count: row.get("request_count"),
+            throttled_count: row.get("throttled_count"),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+        }
+    }
+
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    // The form must have been checked. Returns the key itself, which is
+    // shown to the admin once and can't be recovered afterwards.
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        form: &ApiKeyForm,
+    ) -> Result<String, sqlx::Error> {
+        let key = format!(
+            "{}{}",
+            API_KEY_PREFIX,
+            hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+        );
+
+        sqlx::query("insert into api_keys (name, scopes, key_hash, requests_per_minute, created_at) values ($1, $2, $3, $4, $5)")
+            .bind(form.name.trim())
+            .bind(
+                form.get_scopes()
+                    .iter()
+                    .map(|scope| scope.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+            .bind(hash_token(&key))
+            .bind(parse_requests_per_minute(&form.requests_per_minute))
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|_| key)
+    }
+
+    // Keys that weren't revoked, newest first
+    pub async fn get_all<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+    ) -> Result<Vec<ApiKey>, sqlx::Error> {
+        sqlx::query(
+            "select * from api_keys where revoked_at is null order by created_at desc, id desc",
+        )
+        .fetch_all(executor)
+        .await
+        .map(|rows| rows.into_iter().map(ApiKey::from_row).collect())
+    }
+
+    // False if there is no such key
+    pub async fn revoke<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("update api_keys set revoked_at = $1 where id = $2 and revoked_at is null")
+            .bind(Utc::now().timestamp())
+            .bind(id)
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    pub async fn authenticate<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        key: &str,
+    ) -> Result<Option<ApiKey>, sqlx::Error> {
+        sqlx::query("select * from api_keys where key_hash = $1 and revoked_at is null")
+            .bind(hash_token(key))
+            .fetch_optional(executor)
+            .await
+            .map(|row| row.map(ApiKey::from_row))
+    }
+
+    pub async fn record_use<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+        throttled: bool,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let column = match throttled {
+            true => "throttled_count",
+            false => "request_count",
+        };
+        sqlx::query(&format!(
+            "update api_keys set {0} = {0} + 1, last_used_at = $1 where id = $2",
+            column
+        ))
+        .bind(now)
+        .bind(id)
+        .execute(executor)
+        .await
+        .map(|_| ())
+    }
+}
+
+// Requests of each key in the current window, kept in memory so that
+// refusing a request doesn't take a query
+static RATE_LIMIT_WINDOWS: Lazy<Mutex<HashMap<i64, (i64, i64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Counts a request of the key against its limit, in fixed one-minute
+// windows. Returns the seconds until the next window if it's exceeded.
+pub fn check_rate_limit(id: i64, requests_per_minute: i64, now: i64) -> Result<(), i64> {
+    let window_start = now - now.rem_euclid(RATE_LIMIT_WINDOW_SECONDS);
+    let mut windows = RATE_LIMIT_WINDOWS.lock().unwrap();
+    let (start, count) = windows.entry(id).or_insert((window_start, 0));
+    if *start != window_start {
+        *start = window_start;
+        *count = 0;
+    }
+
+    if *count >= requests_per_minute {
+        return Err(window_start + RATE_LIMIT_WINDOW_SECONDS - now);
+    }
+    *count += 1;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::api_keys::*;
+
+    fn form(scopes: &[&str], requests_per_minute: &str) -> ApiKeyForm {
+        let scope = |name: &str| scopes.contains(&name).then(|| "on".to_string());
+        ApiKeyForm {
+            name: "Discord bot".to_string(),
+            requests_per_minute: requests_per_minute.to_string(),
+            read_users: scope("read-users"),
+            read_matches: scope("read-matches"),
+            read_stats: scope("read-stats"),
+        }
+    }
+
+    #[test]
+    fn test_for_path() {
+        for (path, scope) in [
+            ("/api/v1/users", Some(ApiKeyScope::ReadUsers)),
+            ("/api/v1/user/1234", Some(ApiKeyScope::ReadUsers)),
+            (
+                "/api/v1/connect-code/test-001",
+                Some(ApiKeyScope::ReadUsers),
+            ),
+            ("/api/v1/matches", Some(ApiKeyScope::ReadMatches)),
+            ("/api/v1/stats/queues", Some(ApiKeyScope::ReadStats)),
+            ("/api/v1/me", None),
+            ("/api/v1/usersx", None),
+            ("/stats", None),
+        ] {
+            assert_eq!(ApiKeyScope::for_path(path), scope, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_form_is_checked() {
+        assert!(form(&["read-stats"], "60").check().is_ok());
+
+        let errors = form(&[], "0").check().unwrap_err();
+        let fields = errors.field_errors();
+        assert!(fields.contains_key("scopes"));
+        assert!(fields.contains_key("requests_per_minute"));
+    }
+
+    #[test]
+    fn test_rate_limit_resets_every_minute() {
+        let id = -1;
+        assert_eq!(check_rate_limit(id, 2, 120), Ok(()));
+        assert_eq!(check_rate_limit(id, 2, 150), Ok(()));
+        assert_eq!(check_rate_limit(id, 2, 170), Err(10));
+        assert_eq!(check_rate_limit(id, 2, 180), Ok(()));
+    }
+
+    #[sqlx::test]
+    async fn keys_are_stored_hashed_and_can_be_revoked(pool: Pool<Sqlite>) {
+        let key = ApiKey::create(&pool, &form(&["read-matches"], "60"))
+            .await
+            .unwrap();
+        assert!(key.starts_with(API_KEY_PREFIX));
+
+        let api_key = ApiKey::authenticate(&pool, &key).await.unwrap().unwrap();
+        assert_eq!(api_key.scopes, vec![ApiKeyScope::ReadMatches]);
+        assert_eq!(api_key.requests_per_minute, 60);
+
+        ApiKey::record_use(&pool, api_key.id, false, 100)
+            .await
+            .unwrap();
+        ApiKey::record_use(&pool, api_key.id, true, 101)
+            .await
+            .unwrap();
+        let api_key = &ApiKey::get_all(&pool).await.unwrap()[0];
+        assert_eq!(
+            (
+                api_key.request_count,
+                api_key.throttled_count,
+                api_key.last_used_at
+            ),
+            (1, 1, Some(101))
+        );
+
+        assert!(ApiKey::revoke(&pool, api_key.id).await.unwrap());
+        assert_eq!(ApiKey::authenticate(&pool, &key).await.unwrap(), None);
+        assert_eq!(ApiKey::get_all(&pool).await.unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqliteRow, Row, SqliteExecutor};
+
+use crate::ids::Uid;
+
+pub const MAX_API_TOKENS_PER_USER: usize = 10;
+pub const MAX_API_TOKEN_NAME_CHARS: usize = 50;
+// Makes tokens easy to recognize, for secret scanners among others
+const API_TOKEN_PREFIX: &str = "omt_";
+
+// What a token lets third-party tools read on behalf of its owner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiScope {
+    // The user, their preferences and their connection health
+    ReadProfile,
+    // The matches the user played
+    ReadHistory,
+    // The user's record, shown by the stream overlay the token is part of
+    // the address of
+    Overlay,
+    // Public information about the server, shown by widgets embedded on
+    // other sites, the token being part of their address
+    Widget,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 4] = [
+        ApiScope::ReadProfile,
+        ApiScope::ReadHistory,
+        ApiScope::Overlay,
+        ApiScope::Widget,
+    ];
+}
+
+impl fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            ApiScope::ReadProfile => "read-profile",
+            ApiScope::ReadHistory => "read-history",
+            ApiScope::Overlay => "overlay",
+            ApiScope::Widget => "widget",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl FromStr for ApiScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-profile" => Ok(ApiScope::ReadProfile),
+            "read-history" => Ok(ApiScope::ReadHistory),
+            "overlay" => Ok(ApiScope::Overlay),
+            "widget" => Ok(ApiScope::Widget),
+            _ => Err(()),
+        }
+    }
+}
+
+// Only the SHA-256 digest of a token is stored, tokens are random enough
+// that a slow hash wouldn't make them any harder to guess
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiToken {
+    pub id: i64,
+    pub uid: Uid,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+impl ApiToken {
+    fn from_row(row: SqliteRow) -> ApiToken {
+        ApiToken {
+            id: row.get("id"),
+            uid: row.get("uid"),
+            name: row.get("name"),
+            scopes: row
+                .get::<String, &str>("scopes")
+                .split(',')
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+        }
+    }
+
+    pub fn has_scope(&self, scope: ApiScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    // Returns the token itself, which is shown to the user once and can't
+    // be recovered afterwards
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        name: String,
+        scopes: &[ApiScope],
+    ) -> Result<String, sqlx::Error> {
+        let token = format!(
+            "{}{}",
+            API_TOKEN_PREFIX,
+            hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+        );
+
+        sqlx::query("insert into api_tokens (uid, name, scopes, token_hash, created_at) values ($1, $2, $3, $4, $5)")
+            .bind(uid)
+            .bind(name)
+            .bind(
+                scopes
+                    .iter()
+                    .map(|scope| scope.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+            .bind(hash_token(&token))
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|_| token)
+    }
+
+    // Tokens that weren't revoked, newest first
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Vec<ApiToken>, sqlx::Error> {
+        sqlx::query("select * from api_tokens where uid = $1 and revoked_at is null order by created_at desc, id desc")
+            .bind(uid)
+            .fetch_all(executor)
+            .await
+            .map(|rows| rows.into_iter().map(ApiToken::from_row).collect())
+    }
+
+    // False if the user has no such token
+    pub async fn revoke<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("update api_tokens set revoked_at = $1 where id = $2 and uid = $3 and revoked_at is null")
+            .bind(Utc::now().timestamp())
+            .bind(id)
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    // Tokens of banned users, or of users from another tenant, are treated
+    // as unknown. The statement is run to completion, SQLite may not apply
+    // the update if it's reset after the first returned row.
+    pub async fn authenticate<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        token: &str,
+        tenant_id: String,
+    ) -> Result<Option<ApiToken>, sqlx::Error> {
+        sqlx::query("update api_tokens set last_used_at = $1 where token_hash = $2 and revoked_at is null and uid in (select uid from users where tenant_id = $3 and is_banned = false) returning *")
+            .bind(Utc::now().timestamp())
+            .bind(hash_token(token))
+            .bind(tenant_id)
+            .fetch_all(executor)
+            .await
+            .map(|rows| rows.into_iter().next().map(ApiToken::from_row))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::api_tokens::*;
+    use crate::models::User;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::UserBuilder;
+
+    #[sqlx::test]
+    async fn tokens_are_stored_hashed_and_can_be_revoked(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Stream overlay".to_string(),
+            &[ApiScope::ReadProfile],
+        )
+        .await
+        .unwrap();
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+
+        let stored = sqlx::query_scalar::<_, String>("select token_hash from api_tokens")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, hash_token(&token));
+        assert_ne!(stored, token);
+
+        let authenticated = ApiToken::authenticate(&pool, &token, DEFAULT_TENANT.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(authenticated.name, "Stream overlay");
+        assert!(authenticated.has_scope(ApiScope::ReadProfile));
+        assert!(!authenticated.has_scope(ApiScope::ReadHistory));
+        assert!(authenticated.last_used_at.is_some());
+        assert_eq!(
+            ApiToken::get_for_user(&pool, user.uid.clone())
+                .await
+                .unwrap(),
+            vec![authenticated.clone()]
+        );
+
+        assert_eq!(
+            ApiToken::authenticate(&pool, &token, "other".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            ApiToken::authenticate(&pool, "omt_guess", DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Only the owner can revoke a token
+        assert!(
+            !ApiToken::revoke(&pool, "someone-else".parse().unwrap(), authenticated.id)
+                .await
+                .unwrap()
+        );
+        assert!(ApiToken::revoke(&pool, user.uid.clone(), authenticated.id)
+            .await
+            .unwrap());
+        assert_eq!(
+            ApiToken::authenticate(&pool, &token, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            ApiToken::get_for_user(&pool, user.uid).await.unwrap(),
+            vec![]
+        );
+    }
+
+    #[sqlx::test]
+    async fn tokens_of_banned_users_are_rejected(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Stats site".to_string(),
+            &ApiScope::ALL,
+        )
+        .await
+        .unwrap();
+
+        User::set_banned(&pool, user.uid, true).await.unwrap();
+
+        assert_eq!(
+            ApiToken::authenticate(&pool, &token, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
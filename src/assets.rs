@@ -0,0 +1,92 @@
+use std::borrow::Cow;
+use std::path::{Component, Path};
+
+use crate::{Asset, CONFIG};
+
+// Files in the configured asset directory take precedence over the bundled
+// ones, so operators only have to keep the files they customized
+pub fn get_asset(path: &str) -> Option<Cow<'static, [u8]>> {
+    get_asset_from(CONFIG.assets_path.as_deref(), path)
+}
+
+fn get_asset_from(assets_path: Option<&str>, path: &str) -> Option<Cow<'static, [u8]>> {
+    let is_relative = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !is_relative {
+        return None;
+    }
+
+    if let Some(assets_path) = assets_path {
+        if let Ok(data) = std::fs::read(Path::new(assets_path).join(path)) {
+            return Some(Cow::Owned(data));
+        }
+    }
+
+    Asset::get(path).map(|asset| asset.data)
+}
+
+// Writes every bundled asset to the directory and returns the paths of the
+// ones written. Existing files are kept unless overwritten, so exporting
+// again after an upgrade only adds the new ones.
+pub fn export(directory: &Path, overwrite: bool) -> std::io::Result<Vec<String>> {
+    let mut written = vec![];
+
+    for path in Asset::iter() {
+        let destination = directory.join(path.as_ref());
+        if destination.exists() && !overwrite {
+            continue;
+        }
+
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&destination, Asset::get(&path).unwrap().data)?;
+        written.push(path.to_string());
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assets::*;
+
+    #[test]
+    fn exported_assets_replace_bundled_ones() {
+        let directory =
+            std::env::temp_dir().join(format!("openmelee-assets-{}", rand::random::<u64>()));
+        let assets_path = directory.to_str();
+
+        let written = export(&directory, false).unwrap();
+        assert!(written.contains(&"static/main.css".to_string()));
+        assert!(written.contains(&"templates/index.html.tera".to_string()));
+
+        std::fs::write(directory.join("static/main.css"), "body {}").unwrap();
+        std::fs::remove_file(directory.join("static/logo.svg")).unwrap();
+        assert_eq!(export(&directory, false).unwrap(), vec!["static/logo.svg"]);
+
+        assert_eq!(
+            get_asset_from(assets_path, "static/main.css")
+                .unwrap()
+                .as_ref(),
+            b"body {}"
+        );
+        assert_eq!(
+            get_asset_from(assets_path, "static/logo.svg"),
+            Asset::get("static/logo.svg").map(|asset| asset.data)
+        );
+        assert_eq!(
+            get_asset_from(None, "static/main.css"),
+            Asset::get("static/main.css").map(|asset| asset.data)
+        );
+        assert_eq!(get_asset_from(assets_path, "static/missing.css"), None);
+        assert_eq!(
+            get_asset_from(assets_path, "static/../static/main.css"),
+            None
+        );
+        assert_eq!(get_asset_from(assets_path, "/etc/hostname"), None);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
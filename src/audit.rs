@@ -0,0 +1,152 @@
+use std::{fmt, net::IpAddr};
+
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{FromRow, SqliteExecutor};
+
+use crate::ids::Uid;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AuditAction {
+    Impersonate,
+    ResolveDispute,
+    UpdateLogo,
+    RemoveLogo,
+    SavePage,
+    DeletePage,
+    PublishAnnouncement,
+    DeleteAnnouncement,
+    CreateStageRotation,
+    DeleteStageRotation,
+    SetDisplayName,
+    Ban,
+    Unban,
+    ApproveConnectCodeClaim,
+    RejectConnectCodeClaim,
+    SetConnectCode,
+    AddUserNote,
+    AddToShadowQueue,
+    RemoveFromShadowQueue,
+    RunSqlQuery,
+    RecoverAccount,
+    CreateApiKey,
+    RevokeApiKey,
+    PauseQueue,
+    ResumeQueue,
+    SendBroadcast,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            AuditAction::Impersonate => "impersonate",
+            AuditAction::ResolveDispute => "resolve_dispute",
+            AuditAction::UpdateLogo => "update_logo",
+            AuditAction::RemoveLogo => "remove_logo",
+            AuditAction::SavePage => "save_page",
+            AuditAction::DeletePage => "delete_page",
+            AuditAction::PublishAnnouncement => "publish_announcement",
+            AuditAction::DeleteAnnouncement => "delete_announcement",
+            AuditAction::CreateStageRotation => "create_stage_rotation",
+            AuditAction::DeleteStageRotation => "delete_stage_rotation",
+            AuditAction::SetDisplayName => "set_display_name",
+            AuditAction::Ban => "ban",
+            AuditAction::Unban => "unban",
+            AuditAction::ApproveConnectCodeClaim => "approve_connect_code_claim",
+            AuditAction::RejectConnectCodeClaim => "reject_connect_code_claim",
+            AuditAction::SetConnectCode => "set_connect_code",
+            AuditAction::AddUserNote => "add_user_note",
+            AuditAction::AddToShadowQueue => "add_to_shadow_queue",
+            AuditAction::RemoveFromShadowQueue => "remove_from_shadow_queue",
+            AuditAction::RunSqlQuery => "run_sql_query",
+            AuditAction::RecoverAccount => "recover_account",
+            AuditAction::CreateApiKey => "create_api_key",
+            AuditAction::RevokeApiKey => "revoke_api_key",
+            AuditAction::PauseQueue => "pause_queue",
+            AuditAction::ResumeQueue => "resume_queue",
+            AuditAction::SendBroadcast => "send_broadcast",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    // None when the action was performed by the operator outside of the web UI
+    pub actor_uid: Option<Uid>,
+    pub action: String,
+    pub subject_uid: Option<Uid>,
+    pub details: Option<String>,
+    pub created_at: i64,
+    // Where the action was performed from, None for the operator
+    pub ip_address: Option<String>,
+}
+
+impl AuditLogEntry {
+    pub async fn record<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        actor_uid: Option<Uid>,
+        ip_address: Option<IpAddr>,
+        action: AuditAction,
+        subject_uid: Option<Uid>,
+        details: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into audit_log (actor_uid, action, subject_uid, details, created_at, ip_address) values ($1, $2, $3, $4, $5, $6)")
+            .bind(actor_uid)
+            .bind(action.to_string())
+            .bind(subject_uid)
+            .bind(details)
+            .bind(Utc::now().timestamp())
+            .bind(ip_address.map(|ip| ip.to_string()))
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_recent<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        limit: i64,
+    ) -> Result<Vec<AuditLogEntry>, sqlx::Error> {
+        sqlx::query_as::<_, AuditLogEntry>("select * from audit_log order by id desc limit $1")
+            .bind(limit)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::audit::*;
+    use crate::test_support::UserBuilder;
+
+    #[sqlx::test]
+    async fn can_record_and_get_recent_entries(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        AuditLogEntry::record(
+            &pool,
+            Some(user.uid.clone()),
+            Some(IpAddr::from([203, 0, 113, 7])),
+            AuditAction::Impersonate,
+            Some(user.uid.clone()),
+            None,
+        )
+        .await
+        .expect("Could not record audit log entry");
+
+        AuditLogEntry::record(&pool, None, None, AuditAction::Impersonate, None, None)
+            .await
+            .expect("Could not record audit log entry");
+
+        let entries = AuditLogEntry::get_recent(&pool, 10).await.unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].actor_uid, None);
+        assert_eq!(entries[1].actor_uid, Some(user.uid));
+        assert_eq!(entries[1].action, "impersonate");
+        assert_eq!(entries[1].ip_address, Some("203.0.113.7".to_string()));
+    }
+}
@@ -1,12 +1,13 @@
 // based on:
 // https://github.com/tokio-rs/axum/blob/0.5.x/examples/jwt/src/main.rs
 
+use std::fmt;
 use std::io::prelude::Read;
 
 use async_trait::async_trait;
 use axum::{
     extract::{FromRequest, RequestParts},
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{Html, IntoResponse, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
@@ -18,12 +19,27 @@ use serde::{Deserialize, Serialize};
 use sqlx::SqliteExecutor;
 use tera::Context;
 
-use crate::models::User;
+use crate::{
+    api::ApiError,
+    api_tokens::{ApiScope, ApiToken},
+    ids::Uid,
+    models::User,
+    tenants::CurrentTenant,
+};
 
 pub const JWT_COOKIE_NAME: &str = "token";
 pub const JWT_COOKIE_DURATION_HOURS: i64 = 1;
+pub const IMPERSONATOR_COOKIE_NAME: &str = "impersonator_token";
+pub const IMPERSONATION_DURATION_MINUTES: i64 = 15;
 
 static JWT_KEYS: Lazy<Keys> = Lazy::new(|| {
+    // Tests don't configure a secret, their tokens only have to last as long
+    // as the process. The server refuses to start without one.
+    #[cfg(any(test, feature = "test-support"))]
+    if crate::CONFIG.jwt_secret_path.is_none() {
+        return Keys::new(&rand::random::<[u8; 32]>());
+    }
+
     let jwt_secret_file_path = crate::CONFIG.jwt_secret_path.as_ref().unwrap();
     let mut buffer = String::new();
     let mut file = std::fs::File::open(jwt_secret_file_path.clone())
@@ -35,12 +51,15 @@ static JWT_KEYS: Lazy<Keys> = Lazy::new(|| {
     return Keys::new(buffer.trim().as_bytes());
 });
 
+// Returns the uid of the user along with their token
 pub async fn create_token<'a, T: SqliteExecutor<'a>>(
     executor: T,
+    tenant_id: String,
     payload: &AuthPayload,
-) -> Result<String, AuthError> {
+) -> Result<(Uid, String), AuthError> {
     match User::get_user_from_credentials(
         executor,
+        tenant_id.clone(),
         payload.username.clone(),
         payload.password.clone(),
     )
@@ -48,20 +67,46 @@ pub async fn create_token<'a, T: SqliteExecutor<'a>>(
     {
         Some(user) => {
             let claims = Claims {
-                uid: user.uid,
+                uid: user.uid.clone(),
                 exp: usize::try_from(
                     (Utc::now() + Duration::hours(JWT_COOKIE_DURATION_HOURS)).timestamp(),
                 )
                 .unwrap(),
+                impersonator_uid: None,
+                tenant_id,
             };
 
-            encode(&Header::default(), &claims, &JWT_KEYS.encoding)
-                .map_err(|_| AuthError::TokenCreation)
+            encode_claims(&claims).map(|token| (user.uid, token))
         }
         None => Err(AuthError::WrongCredentials),
     }
 }
 
+// Creates a short-lived token allowing an admin to view pages as the given
+// user. Handlers must refuse to perform any changes with such a token, see
+// Claims::is_impersonation.
+pub fn create_impersonation_token(
+    impersonator_uid: Uid,
+    uid: Uid,
+    tenant_id: String,
+) -> Result<String, AuthError> {
+    let claims = Claims {
+        uid,
+        exp: usize::try_from(
+            (Utc::now() + Duration::minutes(IMPERSONATION_DURATION_MINUTES)).timestamp(),
+        )
+        .unwrap(),
+        impersonator_uid: Some(impersonator_uid),
+        tenant_id,
+    };
+
+    encode_claims(&claims)
+}
+
+pub(crate) fn encode_claims(claims: &Claims) -> Result<String, AuthError> {
+    encode(&Header::default(), claims, &JWT_KEYS.encoding).map_err(|_| AuthError::TokenCreation)
+}
+
 struct Keys {
     encoding: EncodingKey,
     decoding: DecodingKey,
@@ -78,8 +123,20 @@ impl Keys {
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
-    pub uid: String,
+    pub uid: Uid,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator_uid: Option<Uid>,
+    // Tokens are only accepted on the hostname of the tenant they were
+    // created for
+    #[serde(default)]
+    pub tenant_id: String,
+}
+
+impl Claims {
+    pub fn is_impersonation(&self) -> bool {
+        self.impersonator_uid.is_some()
+    }
 }
 
 #[async_trait]
@@ -90,6 +147,10 @@ where
     type Rejection = AuthError;
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let tenant = CurrentTenant::from_request(req)
+            .await
+            .map_err(|_| AuthError::InvalidToken)?;
+
         PrivateCookieJar::<cookie::Key>::from_request(req)
             .await
             .map_err(|_| AuthError::InvalidToken)
@@ -109,6 +170,13 @@ where
                 return Ok(claim);
             })
             .unwrap_or(Err(AuthError::InvalidToken))
+            .and_then(|claims| {
+                if claims.tenant_id != tenant.id() {
+                    return Err(AuthError::InvalidToken);
+                }
+
+                Ok(claims)
+            })
     }
 }
 
@@ -121,12 +189,90 @@ impl TryFrom<&str> for Claims {
     }
 }
 
+// Who a JSON API request is made for: the user logged in on the site, or
+// the owner of the bearer token a third-party tool sent
+pub enum ApiCredentials {
+    Session(Claims),
+    Token { token: String, tenant_id: String },
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for ApiCredentials
+where
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+
+        match token {
+            Some(token) => {
+                let tenant = CurrentTenant::from_request(req)
+                    .await
+                    .map_err(|_| ApiError::new(StatusCode::UNAUTHORIZED, "Invalid API token"))?;
+                Ok(ApiCredentials::Token {
+                    token,
+                    tenant_id: tenant.id(),
+                })
+            }
+            None => Claims::from_request(req)
+                .await
+                .map(ApiCredentials::Session)
+                .map_err(|_| {
+                    ApiError::new(StatusCode::UNAUTHORIZED, "Log in or send an API token")
+                }),
+        }
+    }
+}
+
+impl ApiCredentials {
+    // Returns the UID of the user the request is made for. Sessions may read
+    // everything, tokens only what their scopes allow.
+    pub async fn authorize<'a, T: SqliteExecutor<'a>>(
+        self,
+        executor: T,
+        scope: ApiScope,
+    ) -> Result<Uid, ApiError> {
+        let (token, tenant_id) = match self {
+            ApiCredentials::Session(claims) => return Ok(claims.uid),
+            ApiCredentials::Token { token, tenant_id } => (token, tenant_id),
+        };
+
+        match ApiToken::authenticate(executor, &token, tenant_id)
+            .await
+            .unwrap()
+        {
+            Some(token) if token.has_scope(scope) => Ok(token.uid),
+            Some(_) => Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                &format!("API token lacks the {} scope", scope),
+            )),
+            None => Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid API token")),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct AuthPayload {
     pub username: String,
     pub password: SecretString,
 }
 
+// Leaves the password out, so logging a payload can't leak it
+impl fmt::Debug for AuthPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthPayload")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct PublicAuthPayload {
     pub username: String,
@@ -140,23 +286,29 @@ impl From<&AuthPayload> for PublicAuthPayload {
     }
 }
 
+#[derive(Debug)]
 pub enum AuthError {
     WrongCredentials,
     TokenCreation,
     InvalidToken,
+    Forbidden,
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        let status_code = match self {
-            AuthError::WrongCredentials => StatusCode::UNAUTHORIZED,
-            AuthError::TokenCreation => StatusCode::INTERNAL_SERVER_ERROR,
-            AuthError::InvalidToken => StatusCode::BAD_REQUEST,
+        let (status_code, template) = match self {
+            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "unauthorized.html.tera"),
+            AuthError::TokenCreation => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "unauthorized.html.tera")
+            }
+            AuthError::InvalidToken => (StatusCode::BAD_REQUEST, "unauthorized.html.tera"),
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, "forbidden.html.tera"),
         };
 
-        let content = crate::TEMPLATES
-            .render("unauthorized.html.tera", &Context::new())
-            .unwrap();
+        let mut context = Context::new();
+        context.insert("logged_in", &matches!(self, AuthError::Forbidden));
+        context.insert("base_path", &crate::CONFIG.webserver_base_path);
+        let content = crate::TEMPLATES.render(template, &context).unwrap();
 
         (status_code, Html(content)).into_response()
     }
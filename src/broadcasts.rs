@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+use validator::Validate;
+
+use crate::ids::Uid;
+
+// Broadcasts the matchmaking server wasn't running for are dropped once
+// they're this old, a restart warning is misleading after the restart
+pub const BROADCAST_MAX_AGE_SECONDS: i64 = 60;
+
+// A message admins sent to every peer connected to the matchmaking server,
+// such as a restart warning. The matchmaking server is told about it through
+// an event and records how many peers it was sent to.
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Broadcast {
+    pub id: i64,
+    pub message: String,
+    pub sent_by: Option<Uid>,
+    pub created_at: i64,
+    // None until the matchmaking server sent it
+    pub delivered_at: Option<i64>,
+    pub delivered_count: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct BroadcastForm {
+    #[validate(length(
+        min = 1,
+        max = 200,
+        message = "Must be at least 1 and at most 200 characters long"
+    ))]
+    pub message: String,
+}
+
+impl Broadcast {
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        form: &BroadcastForm,
+        sent_by: Option<Uid>,
+        now: i64,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query("insert into broadcasts (message, sent_by, created_at) values ($1, $2, $3)")
+            .bind(form.message.trim())
+            .bind(sent_by)
+            .bind(now)
+            .execute(executor)
+            .await
+            .map(|result| result.last_insert_rowid())
+    }
+
+    // Oldest first, so they're delivered in the order they were sent
+    pub async fn get_undelivered<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        now: i64,
+    ) -> Result<Vec<Broadcast>, sqlx::Error> {
+        sqlx::query_as::<_, Broadcast>(
+            "select * from broadcasts where delivered_at is null and created_at >= $1 order by id",
+        )
+        .bind(now - BROADCAST_MAX_AGE_SECONDS)
+        .fetch_all(executor)
+        .await
+    }
+
+    pub async fn record_delivery<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+        delivered_count: i64,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update broadcasts set delivered_at = $1, delivered_count = $2 where id = $3")
+            .bind(now)
+            .bind(delivered_count)
+            .bind(id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_recent<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        limit: i64,
+    ) -> Result<Vec<Broadcast>, sqlx::Error> {
+        sqlx::query_as::<_, Broadcast>("select * from broadcasts order by id desc limit $1")
+            .bind(limit)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::broadcasts::*;
+
+    fn form(message: &str) -> BroadcastForm {
+        BroadcastForm {
+            message: message.to_string(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn broadcasts_are_delivered_once_unless_stale(pool: Pool<Sqlite>) {
+        let now = 1_000;
+        Broadcast::create(
+            &pool,
+            &form("Stale"),
+            None,
+            now - BROADCAST_MAX_AGE_SECONDS - 1,
+        )
+        .await
+        .unwrap();
+        let first = Broadcast::create(&pool, &form(" Restarting soon "), None, now - 5)
+            .await
+            .unwrap();
+        let second = Broadcast::create(&pool, &form("Restarting now"), None, now)
+            .await
+            .unwrap();
+
+        let undelivered = Broadcast::get_undelivered(&pool, now).await.unwrap();
+        assert_eq!(
+            undelivered
+                .iter()
+                .map(|broadcast| broadcast.id)
+                .collect::<Vec<_>>(),
+            vec![first, second]
+        );
+        assert_eq!(undelivered[0].message, "Restarting soon");
+
+        Broadcast::record_delivery(&pool, first, 12, now)
+            .await
+            .unwrap();
+        let undelivered = Broadcast::get_undelivered(&pool, now).await.unwrap();
+        assert_eq!(undelivered.len(), 1);
+        assert_eq!(undelivered[0].id, second);
+
+        let recent = Broadcast::get_recent(&pool, 2).await.unwrap();
+        assert_eq!(recent[1].delivered_count, Some(12));
+        assert_eq!(recent[1].delivered_at, Some(now));
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::LATEST_SLIPPI_CLIENT_VERSION;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("OPENMELEE_GIT_HASH");
+
+// Forced when the server starts, see main
+pub static STARTED_AT: Lazy<DateTime<Utc>> = Lazy::new(Utc::now);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub started_at: i64,
+    pub uptime_seconds: i64,
+    pub uptime: String,
+    pub latest_client_version: String,
+}
+
+impl BuildInfo {
+    pub fn current() -> BuildInfo {
+        let uptime_seconds = (Utc::now() - *STARTED_AT).num_seconds();
+
+        BuildInfo {
+            version: VERSION.to_string(),
+            git_hash: GIT_HASH.to_string(),
+            started_at: STARTED_AT.timestamp(),
+            uptime_seconds,
+            uptime: format_uptime(uptime_seconds),
+            latest_client_version: LATEST_SLIPPI_CLIENT_VERSION.to_string(),
+        }
+    }
+}
+
+fn format_uptime(seconds: i64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::build_info::*;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(59), "0m");
+        assert_eq!(format_uptime(2 * 3600 + 5 * 60), "2h 5m");
+        assert_eq!(format_uptime(3 * 86400 + 4 * 3600 + 59), "3d 4h");
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::{FromRow, SqliteExecutor};
+
+use crate::game::OnlinePlayMode;
+
+pub const SNAPSHOT_INTERVAL_SECONDS: i64 = 5 * 60;
+// A couple of snapshots may be missed before queues are shown as empty
+pub const CURRENT_SNAPSHOT_SECONDS: i64 = 2 * SNAPSHOT_INTERVAL_SECONDS;
+// Long enough for a few weeks to even out in the weekly pattern
+pub const SNAPSHOT_RETENTION_DAYS: i64 = 8 * 7;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Pattern {
+    // Per hour of the day
+    Daily,
+    // Per day of the week, from Sunday
+    Weekly,
+}
+
+impl Pattern {
+    // strftime format of the slot a snapshot falls in
+    fn get_slot_format(&self) -> &'static str {
+        match self {
+            Pattern::Daily => "%H",
+            Pattern::Weekly => "%w",
+        }
+    }
+
+    fn get_slot_seconds(&self) -> i64 {
+        match self {
+            Pattern::Daily => 60 * 60,
+            Pattern::Weekly => 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueSnapshot {
+    pub id: i64,
+    pub tenant_id: String,
+    pub mode: String,
+    pub taken_at: i64,
+    // Time since the previous snapshot, which matches_formed covers
+    pub period_seconds: i64,
+    pub waiting: i64,
+    pub matches_formed: i64,
+}
+
+impl QueueSnapshot {
+    // Matches formed during the period are counted from match formations
+    pub async fn save<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        mode: OnlinePlayMode,
+        taken_at: i64,
+        period_seconds: i64,
+        waiting: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into queue_snapshots (tenant_id, mode, taken_at, period_seconds, waiting, matches_formed) values ($1, $2, $3, $4, $5, (select count(match_id) from match_formations where tenant_id = $1 and mode = $2 and created_at >= $3 - $4 and created_at < $3))")
+            .bind(tenant_id)
+            .bind(mode.to_string())
+            .bind(taken_at)
+            .bind(period_seconds)
+            .bind(waiting)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // The most recent snapshot of each mode, if it was taken since the given
+    // time, so a stopped matchmaking server doesn't show stale queues
+    pub async fn get_latest<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        since: i64,
+    ) -> Result<Vec<QueueSnapshot>, sqlx::Error> {
+        sqlx::query_as::<_, QueueSnapshot>("select * from queue_snapshots where tenant_id = $1 and taken_at >= $2 and taken_at = (select max(latest.taken_at) from queue_snapshots as latest where latest.tenant_id = queue_snapshots.tenant_id and latest.mode = queue_snapshots.mode) order by mode")
+            .bind(tenant_id)
+            .bind(since)
+            .fetch_all(executor)
+            .await
+    }
+
+    // Snapshots of every queue are taken together, so the highest ID since
+    // the given time changes whenever the result of get_latest does
+    pub async fn get_latest_id<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        since: i64,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar::<_, Option<i64>>(
+            "select max(id) from queue_snapshots where tenant_id = $1 and taken_at >= $2",
+        )
+        .bind(tenant_id)
+        .bind(since)
+        .fetch_one(executor)
+        .await
+    }
+
+    pub async fn delete_before<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        before: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from queue_snapshots where taken_at < $1")
+            .bind(before)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+}
+
+// Activity of a queue averaged over the same hour of every day, or the same
+// day of every week, in UTC
+#[derive(Debug, PartialEq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapacitySlot {
+    pub mode: String,
+    pub slot: i64,
+    pub mean_waiting: f64,
+    pub peak_waiting: i64,
+    // Per hour or per day, following the pattern
+    pub matches_formed: f64,
+}
+
+impl CapacitySlot {
+    pub async fn get_pattern<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        since: i64,
+        pattern: Pattern,
+    ) -> Result<Vec<CapacitySlot>, sqlx::Error> {
+        sqlx::query_as::<_, CapacitySlot>("select mode, cast(strftime($1, taken_at, 'unixepoch') as integer) as slot, round(avg(waiting), 1) as mean_waiting, max(waiting) as peak_waiting, round(1.0 * $2 * sum(matches_formed) / sum(period_seconds), 1) as matches_formed from queue_snapshots where tenant_id = $3 and taken_at >= $4 group by mode, slot order by mode, slot")
+            .bind(pattern.get_slot_format())
+            .bind(pattern.get_slot_seconds())
+            .bind(tenant_id)
+            .bind(since)
+            .fetch_all(executor)
+            .await
+    }
+
+    // The slot of each mode where the fewest matches are formed, or the
+    // fewest players wait on a tie, best suited for maintenance
+    pub fn get_quietest(slots: &[CapacitySlot]) -> Vec<&CapacitySlot> {
+        let mut quietest: BTreeMap<&str, &CapacitySlot> = BTreeMap::new();
+        for slot in slots {
+            let is_quieter = match quietest.get(slot.mode.as_str()) {
+                Some(current) => {
+                    (slot.matches_formed, slot.mean_waiting)
+                        < (current.matches_formed, current.mean_waiting)
+                }
+                None => true,
+            };
+            if is_quieter {
+                quietest.insert(&slot.mode, slot);
+            }
+        }
+
+        quietest.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::capacity::*;
+    use crate::formations::MatchFormation;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::MatchBuilder;
+
+    // Thursday 2022-11-03 at 14:00 UTC
+    const THURSDAY_AFTERNOON: i64 = 1667484000;
+
+    #[sqlx::test]
+    async fn snapshots_are_averaged_per_hour_and_weekday(pool: Pool<Sqlite>) {
+        MatchBuilder::new("mode.unranked-1").insert(&pool).await;
+        MatchFormation {
+            match_id: "mode.unranked-1".parse().unwrap(),
+            tenant_id: DEFAULT_TENANT.to_string(),
+            mode: "unranked".to_string(),
+            algorithm: "first_fit".to_string(),
+            algorithm_version: 1,
+            stages: vec![],
+            players: vec![],
+            created_at: THURSDAY_AFTERNOON + 60,
+        }
+        .save(&pool)
+        .await
+        .unwrap();
+
+        for (taken_at, waiting) in [
+            (THURSDAY_AFTERNOON + SNAPSHOT_INTERVAL_SECONDS, 4),
+            (THURSDAY_AFTERNOON + 2 * SNAPSHOT_INTERVAL_SECONDS, 1),
+        ] {
+            QueueSnapshot::save(
+                &pool,
+                DEFAULT_TENANT.to_string(),
+                OnlinePlayMode::Unranked,
+                taken_at,
+                SNAPSHOT_INTERVAL_SECONDS,
+                waiting,
+            )
+            .await
+            .unwrap();
+        }
+        QueueSnapshot::save(
+            &pool,
+            "other".to_string(),
+            OnlinePlayMode::Unranked,
+            THURSDAY_AFTERNOON,
+            SNAPSHOT_INTERVAL_SECONDS,
+            10,
+        )
+        .await
+        .unwrap();
+
+        let daily = CapacitySlot::get_pattern(
+            &pool,
+            DEFAULT_TENANT.to_string(),
+            THURSDAY_AFTERNOON,
+            Pattern::Daily,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            daily,
+            vec![CapacitySlot {
+                mode: "unranked".to_string(),
+                slot: 14,
+                mean_waiting: 2.5,
+                peak_waiting: 4,
+                // One match in 10 minutes
+                matches_formed: 6.0,
+            }]
+        );
+
+        let weekly = CapacitySlot::get_pattern(
+            &pool,
+            DEFAULT_TENANT.to_string(),
+            THURSDAY_AFTERNOON,
+            Pattern::Weekly,
+        )
+        .await
+        .unwrap();
+        assert_eq!((weekly[0].slot, weekly[0].matches_formed), (4, 144.0));
+
+        QueueSnapshot::delete_before(&pool, THURSDAY_AFTERNOON + 2 * SNAPSHOT_INTERVAL_SECONDS)
+            .await
+            .unwrap();
+        let daily = CapacitySlot::get_pattern(&pool, DEFAULT_TENANT.to_string(), 0, Pattern::Daily)
+            .await
+            .unwrap();
+        assert_eq!((daily[0].mean_waiting, daily[0].matches_formed), (1.0, 0.0));
+    }
+
+    #[sqlx::test]
+    async fn only_recent_latest_snapshots_are_returned(pool: Pool<Sqlite>) {
+        for (mode, taken_at, waiting) in [
+            (OnlinePlayMode::Unranked, THURSDAY_AFTERNOON, 4),
+            (
+                OnlinePlayMode::Unranked,
+                THURSDAY_AFTERNOON + SNAPSHOT_INTERVAL_SECONDS,
+                2,
+            ),
+            (OnlinePlayMode::Direct, THURSDAY_AFTERNOON - 60 * 60, 1),
+        ] {
+            QueueSnapshot::save(
+                &pool,
+                DEFAULT_TENANT.to_string(),
+                mode,
+                taken_at,
+                SNAPSHOT_INTERVAL_SECONDS,
+                waiting,
+            )
+            .await
+            .unwrap();
+        }
+
+        let latest =
+            QueueSnapshot::get_latest(&pool, DEFAULT_TENANT.to_string(), THURSDAY_AFTERNOON)
+                .await
+                .unwrap();
+        assert_eq!(
+            latest
+                .iter()
+                .map(|snapshot| (snapshot.mode.as_str(), snapshot.waiting))
+                .collect::<Vec<(&str, i64)>>(),
+            vec![("unranked", 2)]
+        );
+    }
+
+    #[test]
+    fn test_get_quietest() {
+        let slot = |mode: &str, slot, mean_waiting, matches_formed| CapacitySlot {
+            mode: mode.to_string(),
+            slot,
+            mean_waiting,
+            peak_waiting: 0,
+            matches_formed,
+        };
+        let slots = [
+            slot("direct", 3, 0.0, 1.0),
+            slot("unranked", 2, 3.0, 0.0),
+            slot("unranked", 3, 1.0, 0.0),
+            slot("unranked", 4, 0.0, 2.0),
+        ];
+
+        assert_eq!(
+            CapacitySlot::get_quietest(&slots)
+                .into_iter()
+                .map(|slot| (slot.mode.as_str(), slot.slot))
+                .collect::<Vec<(&str, i64)>>(),
+            vec![("direct", 3), ("unranked", 3)]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+
+use crate::ids::{ConnectCode, Uid};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ClaimStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl fmt::Display for ClaimStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            ClaimStatus::Pending => "pending",
+            ClaimStatus::Approved => "approved",
+            ClaimStatus::Rejected => "rejected",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+// The fields of an official Slippi user.json the claim is based on. The
+// play key is never read, so it can't end up stored.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OfficialUser {
+    pub uid: Uid,
+    pub connect_code: ConnectCode,
+    pub display_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OfficialUserJson {
+    uid: String,
+    connect_code: String,
+    display_name: String,
+}
+
+impl OfficialUser {
+    pub fn parse(user_json: &str) -> Result<OfficialUser, String> {
+        let user = serde_json::from_str::<OfficialUserJson>(user_json.trim())
+            .map_err(|_| "This doesn't look like a Slippi user.json file.".to_string())?;
+
+        match (user.uid.parse(), user.connect_code.parse()) {
+            (Ok(uid), Ok(connect_code)) => Ok(OfficialUser {
+                uid,
+                connect_code,
+                display_name: user.display_name,
+            }),
+            _ => Err("The user.json file doesn't contain a valid connect code.".to_string()),
+        }
+    }
+}
+
+// A user's request to be given the connect code they have on the official
+// Slippi server, reviewed by an admin
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectCodeClaim {
+    pub id: i64,
+    pub uid: Uid,
+    pub connect_code: ConnectCode,
+    pub official_uid: Uid,
+    pub official_display_name: String,
+    pub status: String,
+    pub created_at: i64,
+    pub reviewer_uid: Option<Uid>,
+    pub reviewed_at: Option<i64>,
+}
+
+impl ConnectCodeClaim {
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        official_user: &OfficialUser,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into connect_code_claims (uid, connect_code, official_uid, official_display_name, status, created_at) values ($1, $2, $3, $4, $5, $6)")
+            .bind(uid)
+            .bind(official_user.connect_code.clone())
+            .bind(official_user.uid.clone())
+            .bind(official_user.display_name.clone())
+            .bind(ClaimStatus::Pending.to_string())
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+    ) -> Result<ConnectCodeClaim, sqlx::Error> {
+        sqlx::query_as::<_, ConnectCodeClaim>("select * from connect_code_claims where id = $1")
+            .bind(id)
+            .fetch_one(executor)
+            .await
+    }
+
+    pub async fn get_latest_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Option<ConnectCodeClaim>, sqlx::Error> {
+        sqlx::query_as::<_, ConnectCodeClaim>(
+            "select * from connect_code_claims where uid = $1 order by created_at desc, id desc limit 1",
+        )
+        .bind(uid)
+        .fetch_optional(executor)
+        .await
+    }
+
+    // Oldest first, claims of the users of other tenants are left to their
+    // own admins
+    pub async fn get_pending<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+    ) -> Result<Vec<ConnectCodeClaim>, sqlx::Error> {
+        sqlx::query_as::<_, ConnectCodeClaim>("select connect_code_claims.* from connect_code_claims join users on users.uid = connect_code_claims.uid where users.tenant_id = $1 and connect_code_claims.status = $2 order by connect_code_claims.created_at, connect_code_claims.id")
+            .bind(tenant_id)
+            .bind(ClaimStatus::Pending.to_string())
+            .fetch_all(executor)
+            .await
+    }
+
+    // False if the claim was already reviewed
+    pub async fn review<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+        status: ClaimStatus,
+        reviewer_uid: Uid,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("update connect_code_claims set status = $1, reviewer_uid = $2, reviewed_at = $3 where id = $4 and status = $5")
+            .bind(status.to_string())
+            .bind(reviewer_uid)
+            .bind(Utc::now().timestamp())
+            .bind(id)
+            .bind(ClaimStatus::Pending.to_string())
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::claims::*;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::UserBuilder;
+
+    const USER_JSON: &str = r#"{"uid":"official-uid","playKey":"secret","connectCode":"FOX#123","displayName":"Fox","latestVersion":"3.0.2"}"#;
+
+    #[test]
+    fn test_parse_official_user_json() {
+        assert_eq!(
+            OfficialUser::parse(USER_JSON),
+            Ok(OfficialUser {
+                uid: "official-uid".parse().unwrap(),
+                connect_code: "FOX#123".parse().unwrap(),
+                display_name: "Fox".to_string(),
+            })
+        );
+        assert!(OfficialUser::parse("not json").is_err());
+        assert!(OfficialUser::parse(
+            r#"{"uid":"official-uid","connectCode":"FOX","displayName":"Fox"}"#
+        )
+        .is_err());
+    }
+
+    #[sqlx::test]
+    async fn claims_are_reviewed_once(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let admin = UserBuilder::new()
+            .connect_code("ADMN#001")
+            .admin()
+            .insert(&pool)
+            .await;
+        let official_user = OfficialUser::parse(USER_JSON).unwrap();
+
+        ConnectCodeClaim::create(&pool, user.uid.clone(), &official_user)
+            .await
+            .unwrap();
+
+        let pending = ConnectCodeClaim::get_pending(&pool, DEFAULT_TENANT.to_string())
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            (pending[0].connect_code.as_str(), pending[0].status.as_str()),
+            ("FOX#123", "pending")
+        );
+        assert_eq!(
+            ConnectCodeClaim::get_pending(&pool, "other".to_string())
+                .await
+                .unwrap(),
+            vec![]
+        );
+
+        assert!(ConnectCodeClaim::review(
+            &pool,
+            pending[0].id,
+            ClaimStatus::Approved,
+            admin.uid.clone()
+        )
+        .await
+        .unwrap());
+        assert!(!ConnectCodeClaim::review(
+            &pool,
+            pending[0].id,
+            ClaimStatus::Rejected,
+            admin.uid.clone()
+        )
+        .await
+        .unwrap());
+
+        let claim = ConnectCodeClaim::get_latest_for_user(&pool, user.uid)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claim.status, "approved");
+        assert_eq!(claim.reviewer_uid, Some(admin.uid));
+    }
+}
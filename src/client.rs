@@ -0,0 +1,170 @@
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Deserialize};
+use url::Url;
+
+use crate::{
+    api::{Envelope, PageQuery},
+    build_info::BuildInfo,
+    formations::QueueWait,
+    ids::Uid,
+    matches::Match,
+    models::{MatchmakingPreferences, PublicUser},
+    quality::QualitySummary,
+    stats::DailyMatchCount,
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    // The server couldn't be reached, or answered something unexpected
+    Request(reqwest::Error),
+    // The server refused the request, with the message of its response
+    Api { status: u16, message: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Request(err) => write!(f, "{}", err),
+            ClientError::Api { status, message } => write!(f, "{} ({})", message, status),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Request(err)
+    }
+}
+
+// Unknown users are answered with an object holding only the latest client
+// version, like the official server does for launchers
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UserLookup {
+    Found(PublicUser),
+    NotFound {},
+}
+
+// Typed client for the JSON API of an OpenMelee server, sharing its response
+// types with the server so the two can't drift apart. Endpoints about the
+// user themselves need an API token with the matching scope.
+#[derive(Debug, Clone)]
+pub struct Client {
+    base_url: Url,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    // Servers can be hosted under a path, which must end with a slash for
+    // endpoints to be joined to it
+    pub fn new(mut base_url: Url) -> Client {
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        Client {
+            base_url,
+            token: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_token(mut self, token: &str) -> Client {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &PageQuery,
+    ) -> Result<T, ClientError> {
+        let mut request = self
+            .http
+            .get(self.base_url.join(path).unwrap())
+            .query(query);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = match response.json::<Envelope<()>>().await {
+                Ok(envelope) if !envelope.errors.is_empty() => envelope.errors[0].message.clone(),
+                _ => status.to_string(),
+            };
+            return Err(ClientError::Api {
+                status: status.as_u16(),
+                message,
+            });
+        }
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn get_version(&self) -> Result<BuildInfo, ClientError> {
+        self.get("api/v1/version", &PageQuery::default()).await
+    }
+
+    pub async fn get_user(&self, uid: &Uid) -> Result<Option<PublicUser>, ClientError> {
+        match self
+            .get(&format!("api/v1/user/{}", uid), &PageQuery::default())
+            .await?
+        {
+            UserLookup::Found(user) => Ok(Some(user)),
+            UserLookup::NotFound {} => Ok(None),
+        }
+    }
+
+    pub async fn list_users(
+        &self,
+        page: &PageQuery,
+    ) -> Result<Envelope<Vec<PublicUser>>, ClientError> {
+        self.get("api/v1/users", page).await
+    }
+
+    pub async fn list_matches(
+        &self,
+        page: &PageQuery,
+    ) -> Result<Envelope<Vec<Match>>, ClientError> {
+        self.get("api/v1/matches", page).await
+    }
+
+    pub async fn get_stats(&self) -> Result<Envelope<Vec<DailyMatchCount>>, ClientError> {
+        self.get("api/v1/stats", &PageQuery::default()).await
+    }
+
+    // Recent time spent waiting in each queue
+    pub async fn get_queue_stats(&self) -> Result<Envelope<Vec<QueueWait>>, ClientError> {
+        self.get("api/v1/stats/queues", &PageQuery::default()).await
+    }
+
+    // Recent quality of matches in each queue
+    pub async fn get_quality_stats(&self) -> Result<Envelope<Vec<QualitySummary>>, ClientError> {
+        self.get("api/v1/stats/quality", &PageQuery::default())
+            .await
+    }
+
+    // Requires the read-profile scope
+    pub async fn get_own_user(&self) -> Result<PublicUser, ClientError> {
+        self.get("api/v1/me", &PageQuery::default()).await
+    }
+
+    // Requires the read-profile scope
+    pub async fn get_preferences(&self) -> Result<MatchmakingPreferences, ClientError> {
+        self.get("api/v1/preferences", &PageQuery::default()).await
+    }
+
+    // Requires the read-history scope
+    pub async fn list_own_matches(
+        &self,
+        page: &PageQuery,
+    ) -> Result<Envelope<Vec<Match>>, ClientError> {
+        self.get("api/v1/me/matches", page).await
+    }
+}
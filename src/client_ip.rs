@@ -0,0 +1,184 @@
+use std::net::{IpAddr, SocketAddr};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequest, RequestParts},
+    http::{header, HeaderMap, StatusCode},
+    Extension,
+};
+
+use crate::Config;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// Address of the client a request comes from, looking through the reverse
+// proxies listed in webserver_trusted_proxies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<B> FromRequest<B> for ClientIp
+where
+    B: Send,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(config) = Extension::<Config>::from_request(req)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request(req)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(ClientIp(resolve_client_ip(
+            peer.ip(),
+            req.headers(),
+            &config.webserver_trusted_proxies,
+        )))
+    }
+}
+
+// Each proxy appends the address it received the request from, so the
+// client is the rightmost address not belonging to a trusted proxy. Headers
+// are ignored entirely when the peer itself isn't trusted, as anyone can
+// send them.
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    let mut hops = get_forwarded_hops(headers);
+    if hops.is_empty() {
+        hops = get_x_forwarded_for_hops(headers);
+    }
+
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        match parse_hop(hop) {
+            Some(ip) => {
+                client = ip;
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            // Obfuscated or unknown identifiers can't be looked through
+            None => break,
+        }
+    }
+
+    client
+}
+
+// for= parameters of the standard Forwarded header (RFC 7239)
+fn get_forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect()
+}
+
+fn get_x_forwarded_for_hops(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .collect()
+}
+
+// Hops may carry a port, in which case IPv6 addresses are bracketed
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    hop.strip_prefix('[')
+        .and_then(|hop| hop.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use crate::client_ip::*;
+
+    const PROXY: &str = "10.0.0.1";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_headers_from_untrusted_peers_are_ignored() {
+        let headers = headers("x-forwarded-for", "1.2.3.4");
+
+        assert_eq!(
+            resolve_client_ip(ip("5.6.7.8"), &headers, &[ip(PROXY)]),
+            ip("5.6.7.8")
+        );
+        assert_eq!(resolve_client_ip(ip(PROXY), &headers, &[]), ip(PROXY));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_is_followed_through_trusted_proxies() {
+        let trusted = [ip(PROXY), ip("10.0.0.2")];
+
+        assert_eq!(
+            resolve_client_ip(ip(PROXY), &headers("x-forwarded-for", "1.2.3.4"), &trusted),
+            ip("1.2.3.4")
+        );
+        // Only the rightmost untrusted hop is believed, the client could
+        // have sent the ones before it
+        assert_eq!(
+            resolve_client_ip(
+                ip(PROXY),
+                &headers("x-forwarded-for", "9.9.9.9, 1.2.3.4, 10.0.0.2"),
+                &trusted
+            ),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            resolve_client_ip(ip(PROXY), &headers("x-forwarded-for", "garbage"), &trusted),
+            ip(PROXY)
+        );
+    }
+
+    #[test]
+    fn test_forwarded_is_preferred() {
+        let mut headers = headers("x-forwarded-for", "9.9.9.9");
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static("for=1.2.3.4;proto=https, for=\"[2001:db8::1]:4711\""),
+        );
+
+        assert_eq!(
+            resolve_client_ip(ip(PROXY), &headers, &[ip(PROXY)]),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve_client_ip(ip(PROXY), &headers, &[ip(PROXY), ip("2001:db8::1")]),
+            ip("1.2.3.4")
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use serde::Serialize;
+
+use crate::nat::{NatReport, NatType};
+
+// Matches created within this window are considered when looking for
+// connection failures
+pub const FAILURE_WINDOW_SECONDS: i64 = 7 * 24 * 60 * 60;
+// Players get this long to report a match before it counts as failed
+pub const REPORT_GRACE_SECONDS: i64 = 30 * 60;
+// Fewer failures than this are more likely players leaving early
+const MIN_FAILED_MATCHES: i64 = 2;
+
+// A user's recent connection problems, and what they can do about them
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionHealth {
+    pub nat_type: Option<NatType>,
+    pub lan_port: Option<u16>,
+    pub checked_at: Option<i64>,
+    pub recent_matches: i64,
+    pub failed_matches: i64,
+    pub advice: Vec<String>,
+}
+
+impl ConnectionHealth {
+    pub fn new(
+        nat_report: Option<NatReport>,
+        recent_matches: i64,
+        failed_matches: i64,
+    ) -> ConnectionHealth {
+        let (nat_type, lan_port, checked_at) = match &nat_report {
+            Some(report) => (
+                Some(report.nat_type),
+                report.lan_port,
+                Some(report.updated_at),
+            ),
+            None => (None, None, None),
+        };
+
+        ConnectionHealth {
+            nat_type,
+            lan_port,
+            checked_at,
+            recent_matches,
+            failed_matches,
+            advice: get_advice(nat_type, lan_port, failed_matches),
+        }
+    }
+}
+
+fn get_port_forwarding_advice(lan_port: Option<u16>) -> String {
+    match lan_port {
+        Some(lan_port) => format!(
+            "Forward UDP port {} on your router to this computer, and set it as the netplay port in Dolphin's Slippi settings so it doesn't change. Enabling UPnP on your router may also work.",
+            lan_port
+        ),
+        None => "Set a fixed netplay port in Dolphin's Slippi settings and forward it (UDP) on your router to this computer. Enabling UPnP on your router may also work.".to_string(),
+    }
+}
+
+fn get_advice(
+    nat_type: Option<NatType>,
+    lan_port: Option<u16>,
+    failed_matches: i64,
+) -> Vec<String> {
+    let mut advice = vec![];
+    let failing = failed_matches >= MIN_FAILED_MATCHES;
+
+    if failing {
+        advice.push(format!(
+            "{} of your recent matches were never reported, which usually means you and your opponent couldn't connect.",
+            failed_matches
+        ));
+    }
+
+    match nat_type {
+        None => advice.push(
+            "Search for a match once so the server can check how your connection is seen."
+                .to_string(),
+        ),
+        Some(NatType::Unknown) => advice.push(
+            "Your client didn't report its local address, so your connection couldn't be checked. Updating the client may help."
+                .to_string(),
+        ),
+        Some(NatType::Open) => {
+            if failing {
+                advice.push(
+                    "Your computer is directly reachable, so a firewall is the most likely culprit. Make sure it allows Dolphin to receive connections."
+                        .to_string(),
+                );
+            }
+        }
+        Some(NatType::Cone) | Some(NatType::PortPreserving) => {
+            if failing {
+                advice.push(get_port_forwarding_advice(lan_port));
+            }
+        }
+        Some(NatType::PortTranslating) => {
+            advice.push(
+                "Your router changes the port of your connections, so some opponents may not be able to reach you."
+                    .to_string(),
+            );
+            advice.push(get_port_forwarding_advice(lan_port));
+        }
+        Some(NatType::Symmetric) => {
+            advice.push(
+                "Your router uses a different port for every opponent, so players behind similar routers can't connect to you."
+                    .to_string(),
+            );
+            advice.push(get_port_forwarding_advice(lan_port));
+            advice.push(
+                "If your computer is behind two routers, such as your provider's and your own, put one of them in bridge mode."
+                    .to_string(),
+            );
+        }
+    }
+
+    advice
+}
+
+#[cfg(test)]
+mod test {
+    use crate::connection_health::*;
+
+    #[test]
+    fn test_healthy_connections_get_no_advice() {
+        let report = NatReport {
+            nat_type: NatType::PortPreserving,
+            lan_port: Some(51000),
+            updated_at: 0,
+        };
+
+        assert_eq!(
+            ConnectionHealth::new(Some(report), 10, 1).advice,
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_failing_connections_are_told_what_to_forward() {
+        let report = NatReport {
+            nat_type: NatType::PortPreserving,
+            lan_port: Some(51000),
+            updated_at: 0,
+        };
+        let health = ConnectionHealth::new(Some(report), 10, 4);
+
+        assert_eq!(health.advice.len(), 2);
+        assert!(health.advice[0].starts_with("4 of your recent matches"));
+        assert!(health.advice[1].starts_with("Forward UDP port 51000"));
+    }
+
+    #[test]
+    fn test_symmetric_nat_is_always_reported() {
+        let report = NatReport {
+            nat_type: NatType::Symmetric,
+            lan_port: None,
+            updated_at: 0,
+        };
+        let health = ConnectionHealth::new(Some(report), 0, 0);
+
+        assert_eq!(health.nat_type, Some(NatType::Symmetric));
+        assert!(health.advice[0].contains("different port for every opponent"));
+        assert!(health.advice[1].starts_with("Set a fixed netplay port"));
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    fmt,
+    os::raw::{c_int, c_void},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libsqlite3_sys::sqlite3_progress_handler;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, Column, Row, Sqlite, SqlitePool, TypeInfo, ValueRef};
+use validator::Validate;
+
+pub const MAX_ROWS: usize = 200;
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+// How many virtual machine instructions SQLite runs between checks of the
+// deadline
+const PROGRESS_INSTRUCTIONS: c_int = 1000;
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct ConsoleForm {
+    #[validate(length(
+        min = 1,
+        max = 2000,
+        message = "Must be at least 1 and at most 2000 characters long"
+    ))]
+    pub query: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConsoleError {
+    // Anything besides a single SELECT statement
+    NotSelect,
+    Timeout,
+    Sql(String),
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsoleError::NotSelect => write!(f, "Only a single SELECT statement can be run"),
+            ConsoleError::Timeout => {
+                write!(
+                    f,
+                    "The query took longer than {} seconds",
+                    TIMEOUT.as_secs()
+                )
+            }
+            ConsoleError::Sql(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ConsoleError {
+    fn from(err: sqlx::Error) -> Self {
+        ConsoleError::Sql(err.to_string())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    // Values are formatted as text, NULL included
+    pub rows: Vec<Vec<String>>,
+    // Whether rows past MAX_ROWS were left out
+    pub truncated: bool,
+}
+
+// SQLite runs every statement of a query string, so a single one is allowed,
+// and it must read rather than write. WITH can start a DELETE as well, which
+// wrapping the statement in a subquery rules out.
+fn check_statement(query: &str) -> Result<&str, ConsoleError> {
+    let statement = query.trim().trim_end_matches(';').trim_end();
+    if statement.contains(';') {
+        return Err(ConsoleError::NotSelect);
+    }
+
+    let keyword = statement
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    match keyword.as_str() {
+        "select" | "with" => Ok(statement),
+        _ => Err(ConsoleError::NotSelect),
+    }
+}
+
+fn format_value(row: &SqliteRow, index: usize) -> String {
+    let value = match row.try_get_raw(index) {
+        Ok(value) => value,
+        Err(_) => return String::new(),
+    };
+    if value.is_null() {
+        return "NULL".to_string();
+    }
+
+    let type_name = value.type_info().name().to_string();
+    let decoded = match type_name.as_str() {
+        "INTEGER" => <i64 as sqlx::Decode<Sqlite>>::decode(value).map(|value| value.to_string()),
+        "REAL" => <f64 as sqlx::Decode<Sqlite>>::decode(value).map(|value| value.to_string()),
+        "BLOB" => <Vec<u8> as sqlx::Decode<Sqlite>>::decode(value)
+            .map(|value| format!("x'{}'", hex::encode(value))),
+        _ => <String as sqlx::Decode<Sqlite>>::decode(value),
+    };
+
+    decoded.unwrap_or_default()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Called by SQLite while a statement runs, which it aborts with SQLITE_INTERRUPT
+// once this returns non-zero. The deadline is passed in place of a pointer, so
+// nothing has to outlive the query.
+unsafe extern "C" fn abort_after_deadline(deadline: *mut c_void) -> c_int {
+    (now_millis() >= deadline as u64) as c_int
+}
+
+pub async fn run(pool: &SqlitePool, query: &str) -> Result<QueryResult, ConsoleError> {
+    run_with_timeout(pool, query, TIMEOUT).await
+}
+
+// Runs on a connection of its own, made read-only and closed afterwards, so
+// neither the setting nor the progress handler can affect other requests.
+// Dropping a future doesn't stop SQLite, so the query is aborted by the
+// progress handler instead, which also releases its lock on the database.
+async fn run_with_timeout(
+    pool: &SqlitePool,
+    query: &str,
+    timeout: Duration,
+) -> Result<QueryResult, ConsoleError> {
+    let statement = check_statement(query)?;
+    let mut connection = pool.acquire().await?.detach();
+    sqlx::query("pragma query_only = true")
+        .execute(&mut connection)
+        .await?;
+
+    let deadline = now_millis() + timeout.as_millis() as u64;
+    {
+        let mut handle = connection.lock_handle().await?;
+        unsafe {
+            sqlite3_progress_handler(
+                handle.as_raw_handle().as_ptr(),
+                PROGRESS_INSTRUCTIONS,
+                Some(abort_after_deadline),
+                deadline as *mut c_void,
+            );
+        }
+    }
+
+    // The newline ends any trailing comment
+    let limited = format!("select * from ({}\n) limit {}", statement, MAX_ROWS + 1);
+    let mut rows = sqlx::query(&limited)
+        .fetch_all(&mut connection)
+        .await
+        .map_err(|err| {
+            if now_millis() >= deadline {
+                ConsoleError::Timeout
+            } else {
+                ConsoleError::from(err)
+            }
+        })?;
+
+    let columns = match rows.first() {
+        Some(row) => row
+            .columns()
+            .iter()
+            .map(|column| column.name().to_string())
+            .collect(),
+        None => vec![],
+    };
+    let truncated = rows.len() > MAX_ROWS;
+    rows.truncate(MAX_ROWS);
+
+    Ok(QueryResult {
+        columns,
+        rows: rows
+            .iter()
+            .map(|row| {
+                (0..row.len())
+                    .map(|index| format_value(row, index))
+                    .collect()
+            })
+            .collect(),
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::console::*;
+    use crate::test_support::UserBuilder;
+
+    #[test]
+    fn test_check_statement() {
+        assert_eq!(check_statement(" select 1;\n"), Ok("select 1"));
+        assert_eq!(
+            check_statement("WITH recent AS (select 1) select * from recent"),
+            Ok("WITH recent AS (select 1) select * from recent")
+        );
+        assert_eq!(
+            check_statement("delete from users"),
+            Err(ConsoleError::NotSelect)
+        );
+        assert_eq!(
+            check_statement("select 1; delete from users"),
+            Err(ConsoleError::NotSelect)
+        );
+        assert_eq!(check_statement(""), Err(ConsoleError::NotSelect));
+    }
+
+    #[sqlx::test]
+    async fn queries_read_without_writing(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        let result = run(
+            &pool,
+            "select uid, is_admin, null as missing, 1.5 as ratio, x'ff' as data from users",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result,
+            QueryResult {
+                columns: vec!["uid", "is_admin", "missing", "ratio", "data"]
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+                rows: vec![vec![
+                    user.uid.to_string(),
+                    "0".to_string(),
+                    "NULL".to_string(),
+                    "1.5".to_string(),
+                    "x'ff'".to_string(),
+                ]],
+                truncated: false,
+            }
+        );
+
+        let result = run(
+            &pool,
+            "with recursive numbers(n) as (select 1 union all select n + 1 from numbers) select n from numbers",
+        )
+        .await
+        .unwrap();
+        assert_eq!((result.rows.len(), result.truncated), (MAX_ROWS, true));
+
+        assert!(matches!(
+            run(
+                &pool,
+                "with doomed as (select uid from users) delete from users"
+            )
+            .await,
+            Err(ConsoleError::Sql(_))
+        ));
+        assert!(matches!(
+            run(&pool, "select * from missing").await,
+            Err(ConsoleError::Sql(_))
+        ));
+        assert_eq!(
+            run_with_timeout(
+                &pool,
+                "with recursive numbers(n) as (select 1 union all select n + 1 from numbers) \
+                 select count(*) from users, numbers",
+                Duration::from_millis(200),
+            )
+            .await,
+            Err(ConsoleError::Timeout)
+        );
+        assert_eq!(
+            sqlx::query_scalar::<_, i64>("select count(uid) from users")
+                .fetch_one(&pool)
+                .await
+                .unwrap(),
+            1
+        );
+        // Connections handed back to the pool can still write
+        sqlx::query("update users set is_admin = true")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// HMAC-SHA256, hex encoded, so that receivers of webhooks and files signed
+// by this server can tell they weren't forged or edited
+pub fn sign(key: &[u8], body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(body);
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Compares in constant time, so that how long it takes doesn't tell how much
+// of a forged signature is right
+pub fn verify(key: &[u8], body: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::crypto::*;
+
+    #[test]
+    fn test_sign_matches_rfc_4231() {
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            sign(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_verify_only_accepts_the_signature() {
+        let signature = sign(b"key", b"body");
+
+        assert!(verify(b"key", b"body", &signature));
+        assert!(!verify(b"key", b"edited", &signature));
+        assert!(!verify(b"other", b"body", &signature));
+        assert!(!verify(b"key", b"body", &signature[..62]));
+        assert!(!verify(b"key", b"body", "not hex"));
+    }
+}
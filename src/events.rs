@@ -0,0 +1,32 @@
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::ids::{MatchId, Uid};
+
+// Subscribers lagging further behind than this miss events
+const EVENT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    MatchConfirmed {
+        match_id: MatchId,
+        // Both players of the winning team in teams matches
+        winner_uids: Vec<Uid>,
+        player_uids: Vec<Uid>,
+    },
+    // Picked up by the matchmaking server, which sends it to its peers
+    BroadcastCreated {
+        id: i64,
+    },
+}
+
+static EVENTS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(EVENT_CAPACITY).0);
+
+// Events published while nobody is subscribed are dropped
+pub fn publish(event: Event) {
+    let _ = EVENTS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor, SqlitePool};
+
+use crate::game::Stage;
+use crate::ids::{MatchId, Uid};
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormedPlayer {
+    pub uid: Uid,
+    pub waited_seconds: i64,
+    // Round trip time to the matchmaking server
+    pub rtt_ms: i64,
+}
+
+// How matchmaking formed a match, recorded whether or not its result is
+// ever reported
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchFormation {
+    pub match_id: MatchId,
+    pub tenant_id: String,
+    pub mode: String,
+    // The pairing algorithm, or direct for players who searched for each
+    // other, and the version of its implementation
+    pub algorithm: String,
+    pub algorithm_version: i64,
+    pub stages: Vec<Stage>,
+    pub players: Vec<FormedPlayer>,
+    pub created_at: i64,
+}
+
+impl MatchFormation {
+    pub async fn save(&self, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("insert into match_formations (match_id, tenant_id, mode, algorithm, algorithm_version, stages, created_at) values ($1, $2, $3, $4, $5, $6, $7)")
+            .bind(self.match_id.clone())
+            .bind(self.tenant_id.clone())
+            .bind(self.mode.clone())
+            .bind(self.algorithm.clone())
+            .bind(self.algorithm_version)
+            .bind(
+                self.stages
+                    .iter()
+                    .map(|stage| (*stage as u8).to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+            .bind(self.created_at)
+            .execute(&mut tx)
+            .await?;
+
+        for player in &self.players {
+            sqlx::query("insert into match_formation_players (match_id, uid, waited_seconds, rtt_ms) values ($1, $2, $3, $4)")
+                .bind(self.match_id.clone())
+                .bind(player.uid.clone())
+                .bind(player.waited_seconds)
+                .bind(player.rtt_ms)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+}
+
+// Time players of a tenant spent in a queue before being matched
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueWait {
+    pub mode: String,
+    pub algorithm: String,
+    pub matches: i64,
+    pub mean_wait_seconds: i64,
+    pub longest_wait_seconds: i64,
+    // Mean difference between the longest and shortest wait within a match,
+    // high when players who just joined are matched ahead of others
+    pub mean_wait_gap_seconds: i64,
+}
+
+impl QueueWait {
+    pub async fn get_since<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        since: i64,
+    ) -> Result<Vec<QueueWait>, sqlx::Error> {
+        sqlx::query_as::<_, QueueWait>("select mode, algorithm, count(match_id) as matches, cast(round(avg(mean_wait)) as integer) as mean_wait_seconds, max(longest_wait) as longest_wait_seconds, cast(round(avg(longest_wait - shortest_wait)) as integer) as mean_wait_gap_seconds from (select match_formations.match_id, match_formations.mode, match_formations.algorithm, avg(match_formation_players.waited_seconds) as mean_wait, max(match_formation_players.waited_seconds) as longest_wait, min(match_formation_players.waited_seconds) as shortest_wait from match_formations join match_formation_players on match_formation_players.match_id = match_formations.match_id where match_formations.tenant_id = $1 and match_formations.created_at >= $2 group by match_formations.match_id) group by mode, algorithm order by mode, algorithm")
+            .bind(tenant_id)
+            .bind(since)
+            .fetch_all(executor)
+            .await
+    }
+
+    // Of every player matched since, in any tenant, shortest first
+    pub async fn get_player_waits_since<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        since: i64,
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>("select match_formation_players.waited_seconds from match_formation_players join match_formations on match_formations.match_id = match_formation_players.match_id where match_formations.created_at >= $1 order by match_formation_players.waited_seconds")
+            .bind(since)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use sqlx::{Pool, Sqlite};
+
+    use crate::formations::*;
+    use crate::models::User;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    async fn form(pool: &SqlitePool, match_id: &str, players: &[(&User, i64)]) {
+        MatchBuilder::new(match_id)
+            .players(
+                &players
+                    .iter()
+                    .map(|(user, _)| (*user).clone())
+                    .collect::<Vec<User>>(),
+            )
+            .insert(pool)
+            .await;
+
+        MatchFormation {
+            match_id: match_id.parse().unwrap(),
+            tenant_id: DEFAULT_TENANT.to_string(),
+            mode: "unranked".to_string(),
+            algorithm: "first_fit".to_string(),
+            algorithm_version: 1,
+            stages: vec![Stage::Battlefield, Stage::DreamLand],
+            players: players
+                .iter()
+                .map(|(user, waited_seconds)| FormedPlayer {
+                    uid: user.uid.clone(),
+                    waited_seconds: *waited_seconds,
+                    rtt_ms: 20,
+                })
+                .collect(),
+            created_at: Utc::now().timestamp(),
+        }
+        .save(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn queue_waits_are_summarized_per_mode_and_algorithm(pool: Pool<Sqlite>) {
+        let user_a = UserBuilder::new().insert(&pool).await;
+        let user_b = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+
+        form(&pool, "mode.unranked-1", &[(&user_a, 10), (&user_b, 30)]).await;
+        form(&pool, "mode.unranked-2", &[(&user_a, 60), (&user_b, 60)]).await;
+
+        let stages = sqlx::query_scalar::<_, String>(
+            "select stages from match_formations where match_id = 'mode.unranked-1'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(stages, "31,28");
+
+        assert_eq!(
+            QueueWait::get_since(&pool, DEFAULT_TENANT.to_string(), 0)
+                .await
+                .unwrap(),
+            vec![QueueWait {
+                mode: "unranked".to_string(),
+                algorithm: "first_fit".to_string(),
+                matches: 2,
+                mean_wait_seconds: 40,
+                longest_wait_seconds: 60,
+                mean_wait_gap_seconds: 10,
+            }]
+        );
+        assert_eq!(
+            QueueWait::get_since(&pool, "other".to_string(), 0)
+                .await
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            QueueWait::get_player_waits_since(&pool, 0).await.unwrap(),
+            vec![10, 30, 60, 60]
+        );
+    }
+}
@@ -1,7 +1,11 @@
 use std::fmt;
+use std::str::FromStr;
 
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use crate::melee::{StageData, STAGES};
+use crate::rotations::StageRotation;
+
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum ControllerPort {
@@ -23,6 +27,41 @@ impl ControllerPort {
             _ => vec![ControllerPort::One, ControllerPort::Two],
         }
     }
+
+    // Teammates sit on alternate ports, 1 and 3 against 2 and 4. Players may
+    // be ordered by latency to pick the host, which keeps the two closest to
+    // the server from always being on the same team.
+    pub fn get_team(&self, mode: OnlinePlayMode) -> Option<Team> {
+        match (mode, self) {
+            (OnlinePlayMode::Teams, ControllerPort::One | ControllerPort::Three) => Some(Team::Red),
+            (OnlinePlayMode::Teams, ControllerPort::Two | ControllerPort::Four) => Some(Team::Blue),
+            _ => None,
+        }
+    }
+}
+
+// Ports are stored as their number in match_players
+impl TryFrom<u8> for ControllerPort {
+    type Error = ();
+
+    fn try_from(port: u8) -> Result<Self, Self::Error> {
+        match port {
+            1 => Ok(ControllerPort::One),
+            2 => Ok(ControllerPort::Two),
+            3 => Ok(ControllerPort::Three),
+            4 => Ok(ControllerPort::Four),
+            _ => Err(()),
+        }
+    }
+}
+
+// In-game team colors, sent as the team ID Slippi clients expect. Melee also
+// has a green team, which two against two doesn't need.
+#[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Deserialize_repr, Serialize_repr)]
+#[repr(u8)]
+pub enum Team {
+    Red = 0,
+    Blue = 1,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Deserialize_repr, Serialize_repr)]
@@ -46,6 +85,20 @@ impl fmt::Display for OnlinePlayMode {
     }
 }
 
+impl FromStr for OnlinePlayMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ranked" => Ok(OnlinePlayMode::Ranked),
+            "unranked" => Ok(OnlinePlayMode::Unranked),
+            "direct" => Ok(OnlinePlayMode::Direct),
+            "teams" => Ok(OnlinePlayMode::Teams),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum Stage {
@@ -58,7 +111,31 @@ pub enum Stage {
 }
 
 impl Stage {
-    pub fn get_allowed_stages(mode: OnlinePlayMode) -> Vec<Stage> {
+    pub fn from_id(id: u8) -> Option<Stage> {
+        STAGES
+            .iter()
+            .find(|data| data.id == id)
+            .map(|data| data.stage)
+    }
+
+    pub fn get_data(&self) -> &'static StageData {
+        STAGES.iter().find(|data| data.stage == *self).unwrap()
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        self.get_data().name
+    }
+
+    // The rotation active for the mode replaces the default stages, given
+    // the currently active rotations
+    pub fn get_allowed_stages(mode: OnlinePlayMode, rotations: &[StageRotation]) -> Vec<Stage> {
+        if let Some(rotation) = rotations
+            .iter()
+            .find(|rotation| rotation.mode == mode.to_string())
+        {
+            return rotation.stages.clone();
+        }
+
         let mut allowed_stages = vec![
             Stage::PokemonStadium,
             Stage::YoshisStory,
@@ -75,3 +152,21 @@ impl Stage {
         }
     }
 }
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_data().slug)
+    }
+}
+
+impl FromStr for Stage {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        STAGES
+            .iter()
+            .find(|data| data.slug == s)
+            .map(|data| data.stage)
+            .ok_or(())
+    }
+}
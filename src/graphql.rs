@@ -0,0 +1,335 @@
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Object, OutputType, Request, Response, Result,
+    Schema, SimpleObject,
+};
+use once_cell::sync::Lazy;
+use sqlx::SqlitePool;
+
+use crate::{
+    achievements::Achievement,
+    api::{PageQuery, Paginated, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT},
+    ids::Uid,
+    matches::{get_winner_uids, LeaderboardEntry, Match, MatchFilter, MatchResult},
+    models::{MatchmakingPreferences, PublicUser, User},
+    tenants::Tenant,
+};
+
+// Deep enough for a match, its players and their achievements
+pub const MAX_DEPTH: usize = 6;
+
+pub type GraphQLSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: Lazy<GraphQLSchema> = Lazy::new(|| {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+});
+
+// Set by the webserver for each request, queries only ever see the users
+// and matches of its tenant
+pub struct RequestContext {
+    pub pool: SqlitePool,
+    pub tenant_id: String,
+    // Whoever the request was authorized for, the only user whose private
+    // fields are resolved
+    pub viewer_uid: Option<Uid>,
+}
+
+pub async fn execute(request: Request, context: RequestContext) -> Response {
+    SCHEMA.execute(request.data(context)).await
+}
+
+// Pages have the same limits and cursors as those of the JSON API
+#[derive(SimpleObject)]
+#[graphql(concrete(name = "UserPage", params(UserObject)))]
+#[graphql(concrete(name = "MatchPage", params(MatchObject)))]
+pub struct Page<T: OutputType> {
+    items: Vec<T>,
+    limit: i64,
+    next_cursor: Option<String>,
+    total: i64,
+}
+
+impl<T: OutputType> From<Paginated<T>> for Page<T> {
+    fn from(page: Paginated<T>) -> Page<T> {
+        Page {
+            items: page.items,
+            limit: page.limit,
+            next_cursor: page.next_cursor,
+            total: page.total,
+        }
+    }
+}
+
+// Built from what the JSON API shows of a user, so credentials can't be
+// queried
+pub struct UserObject(PublicUser);
+
+#[Object(name = "User")]
+impl UserObject {
+    async fn uid(&self) -> &str {
+        &self.0.uid
+    }
+
+    async fn display_name(&self) -> &str {
+        &self.0.display_name
+    }
+
+    async fn connect_code(&self) -> &str {
+        &self.0.connect_code
+    }
+
+    async fn latest_version(&self) -> &str {
+        &self.0.latest_version
+    }
+
+    async fn wins(&self, ctx: &Context<'_>) -> Result<i64> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        Ok(MatchResult::count_wins(&context.pool, self.0.uid.clone()).await?)
+    }
+
+    async fn losses(&self, ctx: &Context<'_>) -> Result<i64> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        Ok(MatchResult::count_losses(&context.pool, self.0.uid.clone()).await?)
+    }
+
+    async fn achievements(&self, ctx: &Context<'_>) -> Result<Vec<AchievementObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let achievements = Achievement::get_for_user(&context.pool, self.0.uid.clone()).await?;
+        Ok(achievements
+            .into_iter()
+            .map(AchievementObject::from)
+            .collect())
+    }
+
+    // Private, like GET /api/v1/preferences
+    async fn preferences(&self, ctx: &Context<'_>) -> Result<PreferencesObject> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        if context.viewer_uid.as_ref() != Some(&self.0.uid) {
+            return Err("Only visible to the user themselves".into());
+        }
+
+        let preferences = MatchmakingPreferences::get(&context.pool, self.0.uid.clone()).await?;
+        Ok(PreferencesObject {
+            max_ping_ms: preferences.max_ping_ms,
+            strict: preferences.strict,
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Preferences")]
+pub struct PreferencesObject {
+    max_ping_ms: Option<u32>,
+    strict: bool,
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Achievement")]
+pub struct AchievementObject {
+    id: String,
+    name: String,
+    description: String,
+    awarded_at: i64,
+}
+
+impl From<Achievement> for AchievementObject {
+    fn from(achievement: Achievement) -> AchievementObject {
+        AchievementObject {
+            id: achievement.id,
+            name: achievement.name,
+            description: achievement.description,
+            awarded_at: achievement.awarded_at,
+        }
+    }
+}
+
+pub struct MatchObject(Match);
+
+#[Object(name = "Match")]
+impl MatchObject {
+    async fn match_id(&self) -> &str {
+        &self.0.match_id
+    }
+
+    async fn mode(&self) -> &str {
+        &self.0.mode
+    }
+
+    async fn status(&self) -> &str {
+        &self.0.status
+    }
+
+    async fn created_at(&self) -> i64 {
+        self.0.created_at
+    }
+
+    async fn stage(&self) -> Option<i64> {
+        self.0.stage
+    }
+
+    // Both players of the winning team in teams matches
+    async fn winner_uids(&self, ctx: &Context<'_>) -> Result<Vec<String>> {
+        let winner_uid = match &self.0.winner_uid {
+            Some(winner_uid) => winner_uid,
+            None => return Ok(vec![]),
+        };
+
+        let context = ctx.data_unchecked::<RequestContext>();
+        let players = Match::get_player_teams(&context.pool, &self.0).await?;
+        Ok(get_winner_uids(&players, winner_uid)
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+
+    async fn players(&self, ctx: &Context<'_>) -> Result<Vec<UserObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let mut players = vec![];
+        for uid in Match::get_player_uids(&context.pool, self.0.match_id.clone()).await? {
+            let user = User::get(&context.pool, uid).await?;
+            players.push(UserObject(PublicUser::from(&user)));
+        }
+
+        Ok(players)
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "LeaderboardEntry")]
+pub struct LeaderboardEntryObject {
+    display_name: String,
+    connect_code: String,
+    wins: i64,
+}
+
+impl From<LeaderboardEntry> for LeaderboardEntryObject {
+    fn from(entry: LeaderboardEntry) -> LeaderboardEntryObject {
+        LeaderboardEntryObject {
+            display_name: entry.display_name,
+            connect_code: entry.connect_code.to_string(),
+            wins: entry.wins,
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // Users of other tenants are treated as unknown
+    async fn user(&self, ctx: &Context<'_>, uid: String) -> Result<Option<UserObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let uid = match uid.parse::<Uid>() {
+            Ok(uid) => uid,
+            Err(_) => return Ok(None),
+        };
+        match Tenant::get_for_user(&context.pool, uid.clone()).await {
+            Ok(tenant_id) if tenant_id == context.tenant_id => {}
+            _ => return Ok(None),
+        }
+
+        let user = User::get(&context.pool, uid).await?;
+        Ok(Some(UserObject(PublicUser::from(&user))))
+    }
+
+    async fn user_by_connect_code(
+        &self,
+        ctx: &Context<'_>,
+        connect_code: String,
+    ) -> Result<Option<UserObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let connect_code = match connect_code.parse() {
+            Ok(connect_code) => connect_code,
+            Err(_) => return Ok(None),
+        };
+
+        Ok(
+            User::get_by_connect_code(&context.pool, context.tenant_id.clone(), connect_code)
+                .await
+                .ok()
+                .map(|user| UserObject(PublicUser::from(&user))),
+        )
+    }
+
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        limit: Option<i64>,
+        cursor: Option<String>,
+    ) -> Result<Page<UserObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let query = PageQuery { limit, cursor };
+        let after = query.after().map_err(|error| error.message)?;
+
+        let users = User::get_page(
+            &context.pool,
+            context.tenant_id.clone(),
+            after,
+            query.limit() + 1,
+        )
+        .await?;
+        let total = User::count_in_tenant(&context.pool, context.tenant_id.clone()).await?;
+
+        Ok(Paginated::new(users, query.limit(), total, |user| {
+            user.connect_code.to_string()
+        })
+        .map(|user| UserObject(PublicUser::from(&user)))
+        .into())
+    }
+
+    async fn matches(
+        &self,
+        ctx: &Context<'_>,
+        limit: Option<i64>,
+        cursor: Option<String>,
+        uid: Option<String>,
+        status: Option<String>,
+    ) -> Result<Page<MatchObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let query = PageQuery { limit, cursor };
+        let after = match query.after().map_err(|error| error.message)? {
+            Some(key) => Some(Match::parse_cursor_key(&key).ok_or("Invalid cursor")?),
+            None => None,
+        };
+        let filter = MatchFilter {
+            uid: match uid {
+                Some(uid) => Some(uid.parse::<Uid>()?),
+                None => None,
+            },
+            status,
+        };
+
+        let matches = Match::get_page(
+            &context.pool,
+            context.tenant_id.clone(),
+            &filter,
+            after,
+            query.limit() + 1,
+        )
+        .await?;
+        let total = Match::count(&context.pool, context.tenant_id.clone(), &filter).await?;
+
+        Ok(
+            Paginated::new(matches, query.limit(), total, Match::get_cursor_key)
+                .map(MatchObject)
+                .into(),
+        )
+    }
+
+    async fn leaderboard(
+        &self,
+        ctx: &Context<'_>,
+        limit: Option<i64>,
+    ) -> Result<Vec<LeaderboardEntryObject>> {
+        let context = ctx.data_unchecked::<RequestContext>();
+        let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+
+        let entries =
+            LeaderboardEntry::get_top(&context.pool, context.tenant_id.clone(), limit).await?;
+        Ok(entries
+            .into_iter()
+            .map(LeaderboardEntryObject::from)
+            .collect())
+    }
+}
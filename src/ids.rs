@@ -0,0 +1,269 @@
+use std::{fmt, ops::Deref, str::FromStr};
+
+use bson::{oid::ObjectId, Uuid};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef},
+    Sqlite,
+};
+
+use crate::{game::OnlinePlayMode, models::User};
+
+// Longer IDs than any generated here or by Slippi are refused, so they can't
+// be used to store arbitrary data
+const MAX_ID_LENGTH: usize = 64;
+const MATCH_ID_PREFIX: &str = "mode.";
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InvalidId {
+    pub kind: &'static str,
+    pub value: String,
+}
+
+impl fmt::Display for InvalidId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} is not a valid {}", self.value, self.kind)
+    }
+}
+
+impl std::error::Error for InvalidId {}
+
+// Identifiers are validated when parsed from outside input, such as requests,
+// matchmaking packets and command line arguments. Those read from the
+// database were validated before being stored, so they are taken as is.
+macro_rules! id_type {
+    ($(#[$meta:meta])* $name:ident, $kind:literal, $is_valid:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Deserialize, Serialize)]
+        #[serde(try_from = "String", into = "String")]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = InvalidId;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                let is_valid: fn(&str) -> bool = $is_valid;
+                if is_valid(value) {
+                    Ok($name(value.to_string()))
+                } else {
+                    Err(InvalidId {
+                        kind: $kind,
+                        value: value.to_string(),
+                    })
+                }
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = InvalidId;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                value.parse()
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> String {
+                id.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl sqlx::Type<Sqlite> for $name {
+            fn type_info() -> SqliteTypeInfo {
+                <String as sqlx::Type<Sqlite>>::type_info()
+            }
+
+            fn compatible(ty: &SqliteTypeInfo) -> bool {
+                <String as sqlx::Type<Sqlite>>::compatible(ty)
+            }
+        }
+
+        impl<'q> sqlx::Encode<'q, Sqlite> for $name {
+            fn encode_by_ref(&self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> IsNull {
+                <String as sqlx::Encode<'q, Sqlite>>::encode_by_ref(&self.0, buf)
+            }
+        }
+
+        impl<'r> sqlx::Decode<'r, Sqlite> for $name {
+            fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+                <String as sqlx::Decode<'r, Sqlite>>::decode(value).map($name)
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_ID_LENGTH
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+id_type!(
+    // Users' UUIDs, or the IDs official Slippi accounts have
+    Uid, "user ID", is_token
+);
+
+id_type!(
+    // Secret clients authenticate with when searching for a match
+    PlayKey, "play key", is_token
+);
+
+id_type!(
+    // The mode and creation time of a match, which clients show
+    MatchId,
+    "match ID",
+    |value| {
+        value.len() <= MAX_ID_LENGTH
+            && value
+                .strip_prefix(MATCH_ID_PREFIX)
+                .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_graphic()))
+    }
+);
+
+id_type!(
+    // Always in upper case, see User::is_valid_connect_code
+    ConnectCode,
+    "connect code",
+    User::is_valid_connect_code
+);
+
+impl Uid {
+    pub fn generate() -> Uid {
+        Uid(Uuid::new().to_string())
+    }
+}
+
+impl PlayKey {
+    pub fn generate() -> PlayKey {
+        PlayKey(ObjectId::new().to_hex())
+    }
+}
+
+impl MatchId {
+    pub fn generate(mode: OnlinePlayMode) -> MatchId {
+        MatchId(format!(
+            "{}{}-{}",
+            MATCH_ID_PREFIX,
+            mode,
+            Utc::now().to_rfc3339()
+        ))
+    }
+}
+
+impl ConnectCode {
+    // Only for connect codes validated with the rest of a form, whose
+    // errors are reported field by field, or built from a valid prefix
+    pub(crate) fn new_unchecked(value: String) -> ConnectCode {
+        ConnectCode(value)
+    }
+
+    // Connect codes are stored in upper case, but typed in any, and put in
+    // URLs with a dash instead of the #, which would start a fragment
+    pub fn canonicalize(value: &str) -> Result<ConnectCode, InvalidId> {
+        let value = value.trim().to_uppercase();
+        if value.contains('#') {
+            return value.parse();
+        }
+
+        match value.rsplit_once('-') {
+            Some((prefix, discriminant)) => format!("{}#{}", prefix, discriminant).parse(),
+            None => value.parse(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ids::*;
+
+    #[test]
+    fn test_ids_are_validated_when_parsed() {
+        assert!(Uid::generate().parse::<Uid>().is_ok());
+        assert!("AbCdEfGhIj0123456789abcdefgh".parse::<Uid>().is_ok());
+        assert_eq!(
+            "".parse::<Uid>(),
+            Err(InvalidId {
+                kind: "user ID",
+                value: "".to_string(),
+            })
+        );
+        assert!("../users".parse::<Uid>().is_err());
+        assert!("a".repeat(65).parse::<PlayKey>().is_err());
+        assert!(PlayKey::generate().parse::<PlayKey>().is_ok());
+
+        let match_id = MatchId::generate(OnlinePlayMode::Unranked);
+        assert!(match_id.starts_with("mode.unranked-"));
+        assert!(match_id.parse::<MatchId>().is_ok());
+        assert!("unranked-1".parse::<MatchId>().is_err());
+        assert!("mode.".parse::<MatchId>().is_err());
+        assert!("mode.unranked 1".parse::<MatchId>().is_err());
+
+        assert_eq!("TEST#001".parse::<ConnectCode>().unwrap(), "TEST#001");
+        assert!("test#001".parse::<ConnectCode>().is_err());
+    }
+
+    #[test]
+    fn test_connect_codes_are_canonicalized() {
+        for connect_code in ["TEST#001", " test#001 ", "test-001", "TEST-001"] {
+            assert_eq!(ConnectCode::canonicalize(connect_code).unwrap(), "TEST#001");
+        }
+        assert!(ConnectCode::canonicalize("test001").is_err());
+        assert!(ConnectCode::canonicalize("TE-ST#001").is_err());
+    }
+
+    #[test]
+    fn test_ids_are_validated_when_deserialized() {
+        assert_eq!(
+            serde_json::from_str::<ConnectCode>(r#""TEST#001""#).unwrap(),
+            "TEST#001"
+        );
+        assert!(serde_json::from_str::<ConnectCode>(r#""TEST""#).is_err());
+        assert_eq!(
+            serde_json::to_string(&"1234".parse::<Uid>().unwrap()).unwrap(),
+            r#""1234""#
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use chrono::Utc;
+use qrcode::{render::svg, QrCode};
+use rand::Rng;
+use serde::Serialize;
+use sqlx::SqliteExecutor;
+use url::Url;
+
+use crate::api_tokens::hash_token;
+use crate::ids::Uid;
+
+// Long enough to scan the code and start the launcher, a token can only be
+// redeemed once anyway
+pub const LAUNCHER_TOKEN_TTL_SECONDS: i64 = 10 * 60;
+const LAUNCHER_TOKEN_PREFIX: &str = "oml_";
+const DEEP_LINK_BASE: &str = "openmelee://configure";
+
+// What the profile page shows after a link is generated, the token in it
+// can't be recovered afterwards
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LauncherLink {
+    pub link: String,
+    // Inline SVG encoding the link, for launchers on another device
+    pub qr_code: String,
+    pub expires_at: i64,
+}
+
+impl LauncherLink {
+    // Launchers open the link, then exchange the token for the user's
+    // user.json at api/v1/launcher/bootstrap on the server
+    pub fn new(public_url: &str, token: &str, expires_at: i64) -> LauncherLink {
+        let mut link = Url::parse(DEEP_LINK_BASE).unwrap();
+        link.query_pairs_mut()
+            .append_pair("server", public_url)
+            .append_pair("token", token);
+        let link = link.to_string();
+
+        let qr_code = QrCode::new(link.as_bytes())
+            .unwrap()
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+        // The XML declaration isn't allowed within HTML
+        let qr_code = match qr_code.find("<svg") {
+            Some(start) => qr_code[start..].to_string(),
+            None => qr_code,
+        };
+
+        LauncherLink {
+            link,
+            qr_code,
+            expires_at,
+        }
+    }
+}
+
+// Expired tokens are removed whenever a new one is created
+pub async fn create_token<'a, T: SqliteExecutor<'a>>(
+    executor: T,
+    uid: Uid,
+) -> Result<(String, i64), sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let expires_at = now + LAUNCHER_TOKEN_TTL_SECONDS;
+    let token = format!(
+        "{}{}",
+        LAUNCHER_TOKEN_PREFIX,
+        hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+    );
+
+    sqlx::query("delete from launcher_tokens where expires_at <= $1; insert into launcher_tokens (token_hash, uid, created_at, expires_at) values ($2, $3, $1, $4)")
+        .bind(now)
+        .bind(hash_token(&token))
+        .bind(uid)
+        .bind(expires_at)
+        .execute(executor)
+        .await
+        .map(|_| (token, expires_at))
+}
+
+// Returns the uid of the token's owner and deletes the token, tokens which
+// expired, or of banned users or users from another tenant, are treated as
+// unknown. The statement is run to completion, see ApiToken::authenticate.
+pub async fn redeem_token<'a, T: SqliteExecutor<'a>>(
+    executor: T,
+    token: &str,
+    tenant_id: String,
+) -> Result<Option<Uid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uid>("delete from launcher_tokens where token_hash = $1 and expires_at > $2 and uid in (select uid from users where tenant_id = $3 and is_banned = false) returning uid")
+        .bind(hash_token(token))
+        .bind(Utc::now().timestamp())
+        .bind(tenant_id)
+        .fetch_all(executor)
+        .await
+        .map(|uids| uids.into_iter().next())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::launcher::*;
+    use crate::models::User;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::UserBuilder;
+
+    #[test]
+    fn test_launcher_link() {
+        let link = LauncherLink::new("https://openmelee.example.com/", "oml_abc", 1);
+
+        assert_eq!(
+            link.link,
+            "openmelee://configure?server=https%3A%2F%2Fopenmelee.example.com%2F&token=oml_abc"
+        );
+        assert!(link.qr_code.starts_with("<svg "));
+        assert!(link.qr_code.ends_with("</svg>"));
+    }
+
+    #[sqlx::test]
+    async fn tokens_are_redeemed_once(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let (token, expires_at) = create_token(&pool, user.uid.clone()).await.unwrap();
+        assert!(token.starts_with(LAUNCHER_TOKEN_PREFIX));
+        assert!(expires_at > Utc::now().timestamp());
+
+        assert_eq!(
+            redeem_token(&pool, &token, "other".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            redeem_token(&pool, &token, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            Some(user.uid.clone())
+        );
+        assert_eq!(
+            redeem_token(&pool, &token, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Expired tokens are removed by the next one created
+        let (expired, _) = create_token(&pool, user.uid.clone()).await.unwrap();
+        sqlx::query("update launcher_tokens set expires_at = 0")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (token, _) = create_token(&pool, user.uid.clone()).await.unwrap();
+        assert_eq!(
+            redeem_token(&pool, &expired, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            sqlx::query_scalar::<_, i64>("select count(token_hash) from launcher_tokens")
+                .fetch_one(&pool)
+                .await
+                .unwrap(),
+            1
+        );
+
+        User::set_banned(&pool, user.uid.clone(), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            redeem_token(&pool, &token, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
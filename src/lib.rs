@@ -1,48 +1,322 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::prelude::Read;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
-use figment::{providers::Env, providers::Serialized, Figment};
-use once_cell::sync::Lazy;
+use figment::{
+    providers::{Env, Format, Serialized, Toml, Yaml},
+    value::Dict,
+    Figment,
+};
+use once_cell::sync::{Lazy, OnceCell};
 use rust_embed::RustEmbed;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::SqlitePool;
 use tera::Tera;
 use url::Url;
 
+pub mod achievements;
+pub mod alerts;
+pub mod announcements;
+pub mod api;
+pub mod api_keys;
+pub mod api_tokens;
+pub mod assets;
+pub mod audit;
 pub mod auth;
+pub mod broadcasts;
+pub mod build_info;
+pub mod capacity;
+pub mod claims;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod client_ip;
+pub mod connection_health;
+#[cfg(feature = "sql-console")]
+pub mod console;
+pub mod crypto;
+pub mod events;
+pub mod formations;
 pub mod game;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod ids;
+pub mod launcher;
+pub mod logins;
+pub mod matches;
+pub mod melee;
+pub mod metrics;
 pub mod models;
+pub mod nat;
+pub mod notes;
+pub mod pages;
+pub mod quality;
+pub mod quests;
+pub mod queue_pauses;
+pub mod ranking;
+pub mod recovery;
+pub mod rotations;
+pub mod schedule;
+pub mod schema;
+pub mod sets;
+pub mod startup;
+pub mod stats;
+pub mod telemetry;
+pub mod tenants;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod theme;
+pub mod user_csv;
+pub mod watchdog;
 
 pub const LATEST_SLIPPI_CLIENT_VERSION: &str = "2.5.1";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// ENet peer IDs are 12 bits wide
+const MATCHMAKING_MAX_PEERS_LIMIT: u64 = 4095;
+const MATCHMAKING_MAX_CHANNELS_LIMIT: u64 = 255;
+// Longest wait between two attempts to connect to the database
+const DATABASE_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Clients show the message in a single line
+const MATCHMAKING_MESSAGE_MAX_CHARS: usize = 200;
+
+/// How the player hosting a match is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HostSelection {
+    /// Any of the players
+    Random,
+    /// The player with the lowest round-trip time to the server
+    LowestLatency,
+}
+
+/// How waiting players are paired in the unranked queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PairingAlgorithm {
+    /// Each player, longest waiting first, with the first acceptable opponent
+    FirstFit,
+    /// The acceptable pairs with the lowest estimated ping first
+    ClosestPing,
+}
+
+impl fmt::Display for PairingAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            PairingAlgorithm::FirstFit => "first_fit",
+            PairingAlgorithm::ClosestPing => "closest_ping",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
+    /// Name of the server shown in the web UI and feeds
+    pub server_name: String,
+    /// Address the web server listens on
     pub webserver_address: IpAddr,
+    /// Port the web server listens on
     pub webserver_port: u16,
+    /// Maximum size of request bodies accepted by the web server, in bytes
+    pub webserver_max_body_bytes: usize,
+    /// Seconds after which the web server gives up on a request
+    pub webserver_request_timeout_seconds: u64,
+    /// Maximum number of requests the web server handles at once, others are refused with 503
+    pub webserver_max_in_flight_requests: usize,
+    /// Addresses of reverse proxies whose X-Forwarded-For and Forwarded headers are trusted
+    pub webserver_trusted_proxies: Vec<IpAddr>,
+    /// Content-Security-Policy header sent with every response, not sent if empty
+    pub webserver_content_security_policy: String,
+    /// X-Frame-Options header sent with every response, not sent if empty
+    pub webserver_frame_options: String,
+    /// Referrer-Policy header sent with every response, not sent if empty
+    pub webserver_referrer_policy: String,
+    /// Seconds browsers should only use HTTPS for, sent when public_url uses https, 0 to disable
+    pub webserver_hsts_max_age_seconds: u64,
+    /// Path the site is served under behind a reverse proxy, such as /slippi, empty to serve it at the root
+    pub webserver_base_path: String,
+    /// Address the ENet matchmaking server listens on
     pub matchmaking_server_address: Ipv4Addr,
+    /// Port the ENet matchmaking server listens on
     pub matchmaking_port: u16,
+    /// Maximum number of simultaneously connected matchmaking peers
     pub matchmaking_max_peers: u64,
+    /// Peer slots kept for spectators, such as broadcast tools, which other connected peers can't take
+    pub matchmaking_spectator_slots: u64,
+    /// Channels each matchmaking peer may open, as many as ENet allows if unset
+    pub matchmaking_channel_limit: Option<u64>,
+    /// Bytes per second the matchmaking server receives from all peers, which ENet throttles them to, unlimited if unset
+    pub matchmaking_incoming_bandwidth_bytes: Option<u32>,
+    /// Bytes per second the matchmaking server sends to all peers, unlimited if unset
+    pub matchmaking_outgoing_bandwidth_bytes: Option<u32>,
+    /// How the player hosting a match is chosen, random or lowest_latency
+    pub matchmaking_host_selection: HostSelection,
+    /// Match players sharing a public IP in public queues, connecting them over their LAN addresses
+    pub matchmaking_lan_mode: bool,
+    /// Seconds between two queue status messages sent to a waiting peer
+    pub matchmaking_queue_status_interval_seconds: u64,
+    /// Tickets a peer may create per minute before being disconnected
+    pub matchmaking_max_tickets_per_minute: u64,
+    /// Largest matchmaking packet accepted, in bytes, peers sending larger ones are disconnected
+    pub matchmaking_max_packet_bytes: usize,
+    /// Deepest nesting of JSON objects and arrays accepted in matchmaking packets
+    pub matchmaking_max_json_depth: usize,
+    /// Longest JSON string accepted in matchmaking packets, in bytes
+    pub matchmaking_max_json_string_bytes: usize,
+    /// Seconds a matchmaking peer may stay connected without a ticket, or unreachable, before being disconnected
+    pub matchmaking_stale_peer_timeout_seconds: u64,
+    /// Seconds after a match is created during which its players can reconnect and be sent it again
+    pub matchmaking_reconnect_window_seconds: u64,
+    /// UDP port on which clients can probe the server from their matchmaking socket, to detect symmetric NATs
+    pub matchmaking_nat_probe_port: Option<u16>,
+    /// How players are paired in the unranked queue, first_fit or closest_ping
+    pub matchmaking_pairing_algorithm: PairingAlgorithm,
+    /// Pairing algorithm run next to the real one, only logging the matches it would have formed
+    pub matchmaking_shadow_pairing_algorithm: Option<PairingAlgorithm>,
+    /// Hours during which queues are open, per mode, queues without a schedule are always open
+    pub matchmaking_schedules: Vec<schedule::QueueSchedule>,
+    /// Short message shown in clients when players search and when they are matched, such as "Ranked resets Sunday"
+    pub matchmaking_message: Option<String>,
+    /// Seconds the matchmaking server may go without servicing its peers before being reported as stalled
+    pub matchmaking_watchdog_threshold_seconds: u64,
+    /// Endpoint notified when the matchmaking server stalls and when it recovers, disabled if unset
+    pub matchmaking_watchdog_webhook_url: Option<Url>,
+    /// Games in a Direct set between two players, which ends once one of them won most of them
+    pub matchmaking_direct_best_of: u64,
+    /// Ask players on their profile page to rate their last match, shown in the statistics next to its quality score
+    pub match_survey: bool,
+    /// Path of the SQLite database, created if missing
     pub database_url: String,
+    /// Maximum number of connections in the database pool
     pub database_max_connections: u32,
+    /// Path to a file containing the database encryption key, requires the sqlcipher feature
+    pub database_key_path: Option<String>,
+    /// Path of a read-only replica of the database, used for statistics and history lookups
+    pub database_read_url: Option<String>,
+    /// Times connecting to the database is retried at startup before giving up, such as while a network mount isn't ready
+    pub database_connect_retries: u32,
+    /// Milliseconds before retrying to connect to the database, doubled after each attempt up to 30 seconds
+    pub database_connect_retry_delay_ms: u64,
+    /// Retry connecting to the database until it's available, regardless of database_connect_retries
+    pub database_wait: bool,
+    /// URL the server is publicly reachable at, used in user.json files
     pub public_url: Option<Url>,
+    /// Path to a file containing the JWT secret (required)
     pub jwt_secret_path: Option<String>,
+    /// Path to a file containing the cookie secret, generated if missing
     pub cookie_secret_path: Option<String>,
+    /// Path to a file containing the secret user.json files are signed with, so hand-edited files can be told apart, unsigned if unset
+    pub user_json_secret_path: Option<String>,
+    /// Serve rank lookups in the official Slippi format at /graphql, for launchers expecting it
+    pub slippi_rank_api_compat: bool,
+    /// Path to a file served as /robots.txt instead of the default one
+    pub robots_txt_path: Option<String>,
+    /// Directory of templates and static files, such as written by export-assets, used instead of the bundled ones
+    pub assets_path: Option<String>,
+    /// Theme of the web UI, follows the seasonal events calendar if unset
+    pub theme: Option<theme::Theme>,
+    /// Compress pages and other responses outside of /api with gzip for clients supporting it
+    pub compression_html_gzip: bool,
+    /// Compress pages and other responses outside of /api with Brotli for clients supporting it
+    pub compression_html_brotli: bool,
+    /// Compress JSON API responses with gzip for clients supporting it
+    pub compression_json_gzip: bool,
+    /// Compress JSON API responses with Brotli for clients supporting it
+    pub compression_json_brotli: bool,
+    /// Opt-in endpoint receiving anonymous aggregate usage statistics, disabled if unset
+    pub telemetry_url: Option<Url>,
+    /// Hours between two telemetry reports
+    pub telemetry_interval_hours: u64,
+    /// Endpoint notified of every achievement awarded, disabled if unset
+    pub achievements_webhook_url: Option<Url>,
+    /// Path to a file containing the secret used to sign achievement webhooks
+    pub achievements_webhook_secret_path: Option<String>,
+    /// Conditions on internal metrics which are logged and sent to alert_webhook_url when they start and stop holding
+    pub alert_rules: Vec<alerts::AlertRule>,
+    /// Seconds between two evaluations of the alert rules, rates are measured over this interval
+    pub alert_interval_seconds: u64,
+    /// Endpoint notified when an alert rule starts or stops firing, such as a Discord webhook, disabled if unset
+    pub alert_webhook_url: Option<Url>,
+    /// Serve histograms of matchmaking durations at /metrics, in the Prometheus text format
+    pub prometheus_metrics: bool,
+    /// Other communities hosted by this server, each with its own users
+    pub tenants: Vec<tenants::Tenant>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            server_name: "OpenMelee".to_string(),
             webserver_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
             webserver_port: 5000,
+            webserver_max_body_bytes: 64 * 1024,
+            webserver_request_timeout_seconds: 30,
+            webserver_max_in_flight_requests: 512,
+            webserver_trusted_proxies: vec![],
+            webserver_content_security_policy: "default-src 'self'; img-src 'self' data:; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'".to_string(),
+            webserver_frame_options: "DENY".to_string(),
+            webserver_referrer_policy: "same-origin".to_string(),
+            webserver_hsts_max_age_seconds: 365 * 24 * 60 * 60,
+            webserver_base_path: "".to_string(),
             matchmaking_server_address: Ipv4Addr::LOCALHOST,
             matchmaking_port: 43113,
             matchmaking_max_peers: 1024,
+            matchmaking_spectator_slots: 0,
+            matchmaking_channel_limit: None,
+            matchmaking_incoming_bandwidth_bytes: None,
+            matchmaking_outgoing_bandwidth_bytes: None,
+            matchmaking_host_selection: HostSelection::LowestLatency,
+            matchmaking_lan_mode: false,
+            matchmaking_queue_status_interval_seconds: 5,
+            matchmaking_max_tickets_per_minute: 20,
+            matchmaking_max_packet_bytes: 4096,
+            matchmaking_max_json_depth: 4,
+            matchmaking_max_json_string_bytes: 256,
+            matchmaking_stale_peer_timeout_seconds: 30,
+            matchmaking_reconnect_window_seconds: 120,
+            matchmaking_nat_probe_port: None,
+            matchmaking_pairing_algorithm: PairingAlgorithm::FirstFit,
+            matchmaking_shadow_pairing_algorithm: None,
+            matchmaking_schedules: vec![],
+            matchmaking_message: None,
+            matchmaking_watchdog_threshold_seconds: 30,
+            matchmaking_watchdog_webhook_url: None,
+            matchmaking_direct_best_of: 5,
+            match_survey: false,
             database_url: "openmelee.sqlite".to_string(),
             database_max_connections: 10,
+            database_key_path: None,
+            database_read_url: None,
+            database_connect_retries: 5,
+            database_connect_retry_delay_ms: 500,
+            database_wait: false,
             public_url: None,
             jwt_secret_path: None,
             cookie_secret_path: Some("openmelee-cookie.key".to_string()),
+            user_json_secret_path: None,
+            slippi_rank_api_compat: false,
+            robots_txt_path: None,
+            assets_path: None,
+            theme: None,
+            compression_html_gzip: true,
+            compression_html_brotli: true,
+            compression_json_gzip: true,
+            compression_json_brotli: true,
+            telemetry_url: None,
+            telemetry_interval_hours: 24,
+            achievements_webhook_url: None,
+            achievements_webhook_secret_path: None,
+            alert_rules: vec![],
+            alert_interval_seconds: 60,
+            alert_webhook_url: None,
+            prometheus_metrics: false,
+            tenants: vec![],
         }
     }
 }
@@ -66,26 +340,394 @@ impl Config {
             .unwrap_or("localhost".to_string())
     }
 
-    pub fn format_user_discovery_url(self) -> String {
-        let url = self
-            .clone()
+    // public_url already includes the base path, if there is one
+    pub fn format_public_url(self) -> String {
+        self.clone()
             .public_url
             .and_then(|public_url| Some(public_url.to_string()))
-            .unwrap_or(Config::format_webserver_address(self));
+            .unwrap_or(format!(
+                "http://{}:{}{}/",
+                self.webserver_address, self.webserver_port, self.webserver_base_path
+            ))
+    }
 
-        format!("{}user", url)
+    // Cookies are only sent to the site, not to what else is hosted on the
+    // same domain
+    pub fn get_cookie_path(&self) -> String {
+        match self.webserver_base_path.is_empty() {
+            true => "/".to_string(),
+            false => self.webserver_base_path.clone(),
+        }
+    }
+
+    pub fn format_user_discovery_url(self) -> String {
+        format!("{}user", self.format_public_url())
+    }
+
+    // Ports are ignored, so tenants can be tried out on a local server
+    pub fn get_tenant_for_host(&self, host: &str) -> Option<&tenants::Tenant> {
+        let hostname = host.split(':').next().unwrap_or(host);
+
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.hostname.eq_ignore_ascii_case(hostname))
+    }
+
+    pub fn get_tenant(&self, id: &str) -> Option<&tenants::Tenant> {
+        self.tenants.iter().find(|tenant| tenant.id == id)
+    }
+
+    // Tenants with schedules of their own don't inherit the server's
+    pub fn get_schedules(&self, tenant_id: &str) -> &[schedule::QueueSchedule] {
+        match self.get_tenant(tenant_id) {
+            Some(tenants::Tenant {
+                schedules: Some(schedules),
+                ..
+            }) => schedules,
+            _ => &self.matchmaking_schedules,
+        }
+    }
+
+    // Tenants with a message of their own don't inherit the server's
+    pub fn get_matchmaking_message(&self, tenant_id: &str) -> Option<&str> {
+        match self.get_tenant(tenant_id) {
+            Some(tenants::Tenant {
+                message: Some(message),
+                ..
+            }) => Some(message),
+            _ => self.matchmaking_message.as_deref(),
+        }
+    }
+
+    pub fn get_schedule(
+        &self,
+        tenant_id: &str,
+        mode: game::OnlinePlayMode,
+    ) -> Option<&schedule::QueueSchedule> {
+        self.get_schedules(tenant_id)
+            .iter()
+            .find(|schedule| schedule.mode == mode.to_string())
+    }
+
+    pub fn is_known_tenant(&self, id: &str) -> bool {
+        id == tenants::DEFAULT_TENANT || self.get_tenant(id).is_some()
     }
 
     pub fn can_set_secure_cookie(self) -> bool {
         self.public_url.is_some() && self.public_url.unwrap().scheme() == "https"
     }
+
+    // The defaults, overridden by the file if there is one, overridden in
+    // turn by OPENMELEE_ environment variables. Options the file sets but
+    // that don't exist are reported, as they are most likely typos.
+    pub fn load(file: Option<&Path>) -> Result<Config, Vec<String>> {
+        Config::figment(file)?
+            .merge(Env::prefixed("OPENMELEE_"))
+            .extract()
+            .map_err(list_errors)
+    }
+
+    // The defaults overridden by the file only, to tell which options the
+    // environment changes
+    pub fn load_file(file: Option<&Path>) -> Result<Config, Vec<String>> {
+        Config::figment(file)?.extract().map_err(list_errors)
+    }
+
+    fn figment(file: Option<&Path>) -> Result<Figment, Vec<String>> {
+        let mut figment = Figment::from(Serialized::defaults(Config::default()));
+
+        if let Some(file) = file {
+            if !file.is_file() {
+                return Err(vec![format!("{} doesn't exist", file.display())]);
+            }
+            let file_figment = match file.extension().and_then(|extension| extension.to_str()) {
+                Some("toml") => Figment::from(Toml::file(file)),
+                Some("yaml" | "yml") => Figment::from(Yaml::file(file)),
+                _ => {
+                    return Err(vec![format!(
+                        "{} must be a .toml, .yaml or .yml file",
+                        file.display()
+                    )])
+                }
+            };
+
+            let known_options = serde_json::to_value(Config::default()).unwrap();
+            let options: Dict = file_figment.extract().map_err(list_errors)?;
+            let unknown_options: Vec<String> = options
+                .keys()
+                .filter(|option| known_options.get(option).is_none())
+                .map(|option| format!("{} has no option named {}", file.display(), option))
+                .collect();
+            if !unknown_options.is_empty() {
+                return Err(unknown_options);
+            }
+
+            figment = figment.merge(file_figment);
+        }
+
+        Ok(figment)
+    }
+
+    // Lists every problem at once, so they can all be fixed before the next
+    // start instead of one panic at a time
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+
+        if self.webserver_port == self.matchmaking_port {
+            problems.push(format!(
+                "webserver_port and matchmaking_port are both {}",
+                self.webserver_port
+            ));
+        }
+        if self.matchmaking_nat_probe_port == Some(self.matchmaking_port) {
+            problems.push(format!(
+                "matchmaking_nat_probe_port and matchmaking_port are both {}",
+                self.matchmaking_port
+            ));
+        }
+        if !(1..=MATCHMAKING_MAX_PEERS_LIMIT).contains(&self.matchmaking_max_peers) {
+            problems.push(format!(
+                "matchmaking_max_peers must be between 1 and {}",
+                MATCHMAKING_MAX_PEERS_LIMIT
+            ));
+        }
+        if self.matchmaking_spectator_slots >= self.matchmaking_max_peers {
+            problems.push(
+                "matchmaking_spectator_slots must be less than matchmaking_max_peers".to_string(),
+            );
+        }
+        if let Some(channel_limit) = self.matchmaking_channel_limit {
+            if !(1..=MATCHMAKING_MAX_CHANNELS_LIMIT).contains(&channel_limit) {
+                problems.push(format!(
+                    "matchmaking_channel_limit must be between 1 and {}",
+                    MATCHMAKING_MAX_CHANNELS_LIMIT
+                ));
+            }
+        }
+        for (option, bandwidth) in [
+            (
+                "matchmaking_incoming_bandwidth_bytes",
+                self.matchmaking_incoming_bandwidth_bytes,
+            ),
+            (
+                "matchmaking_outgoing_bandwidth_bytes",
+                self.matchmaking_outgoing_bandwidth_bytes,
+            ),
+        ] {
+            // ENet takes 0 as unlimited
+            if bandwidth == Some(0) {
+                problems.push(format!(
+                    "{} must be greater than 0, or unset for no limit",
+                    option
+                ));
+            }
+        }
+        for (option, value) in [
+            (
+                "webserver_max_in_flight_requests",
+                self.webserver_max_in_flight_requests as u64,
+            ),
+            (
+                "database_max_connections",
+                self.database_max_connections as u64,
+            ),
+            (
+                "database_connect_retry_delay_ms",
+                self.database_connect_retry_delay_ms,
+            ),
+            ("telemetry_interval_hours", self.telemetry_interval_hours),
+            ("alert_interval_seconds", self.alert_interval_seconds),
+            (
+                "matchmaking_direct_best_of",
+                self.matchmaking_direct_best_of,
+            ),
+            (
+                "matchmaking_max_tickets_per_minute",
+                self.matchmaking_max_tickets_per_minute,
+            ),
+            (
+                "matchmaking_max_packet_bytes",
+                self.matchmaking_max_packet_bytes as u64,
+            ),
+            (
+                "matchmaking_max_json_depth",
+                self.matchmaking_max_json_depth as u64,
+            ),
+            (
+                "matchmaking_max_json_string_bytes",
+                self.matchmaking_max_json_string_bytes as u64,
+            ),
+            (
+                "matchmaking_stale_peer_timeout_seconds",
+                self.matchmaking_stale_peer_timeout_seconds,
+            ),
+            (
+                "matchmaking_reconnect_window_seconds",
+                self.matchmaking_reconnect_window_seconds,
+            ),
+            (
+                "matchmaking_watchdog_threshold_seconds",
+                self.matchmaking_watchdog_threshold_seconds,
+            ),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be greater than 0", option));
+            }
+        }
+
+        let base_path = &self.webserver_base_path;
+        if !base_path.is_empty()
+            && (!base_path.starts_with('/')
+                || base_path.ends_with('/')
+                || base_path.contains(['*', ':', '?', '#']))
+        {
+            problems.push(format!(
+                "webserver_base_path must start with a / and not end with one, such as /slippi, but is {}",
+                base_path
+            ));
+        }
+
+        if let Err(err) = SqliteConnectOptions::from_str(&self.database_url) {
+            problems.push(format!("database_url is invalid: {}", err));
+        }
+        if let Some(database_read_url) = &self.database_read_url {
+            if let Err(err) = SqliteConnectOptions::from_str(database_read_url) {
+                problems.push(format!("database_read_url is invalid: {}", err));
+            }
+        }
+        if self.database_key_path.is_some() && !cfg!(feature = "sqlcipher") {
+            problems.push(
+                "database_key_path is set, but the server was built without the sqlcipher feature"
+                    .to_string(),
+            );
+        }
+
+        if self.jwt_secret_path.is_none() {
+            problems.push("jwt_secret_path is required".to_string());
+        }
+        for (option, path) in [
+            ("jwt_secret_path", &self.jwt_secret_path),
+            ("database_key_path", &self.database_key_path),
+            ("robots_txt_path", &self.robots_txt_path),
+            ("user_json_secret_path", &self.user_json_secret_path),
+            (
+                "achievements_webhook_secret_path",
+                &self.achievements_webhook_secret_path,
+            ),
+        ] {
+            if let Some(path) = path {
+                if !std::path::Path::new(path).is_file() {
+                    problems.push(format!(
+                        "{} points to {}, which is not a file",
+                        option, path
+                    ));
+                }
+            }
+        }
+        if let Some(assets_path) = &self.assets_path {
+            if !std::path::Path::new(assets_path).is_dir() {
+                problems.push(format!(
+                    "assets_path points to {}, which is not a directory",
+                    assets_path
+                ));
+            }
+        }
+        // Generated on first start, so only its directory has to exist
+        if let Some(cookie_secret_path) = &self.cookie_secret_path {
+            let path = std::path::Path::new(cookie_secret_path);
+            let directory_exists = match path.parent() {
+                Some(parent) => parent.as_os_str().is_empty() || parent.is_dir(),
+                None => true,
+            };
+            if !path.is_file() && !directory_exists {
+                problems.push(format!(
+                    "cookie_secret_path points to {}, whose directory doesn't exist",
+                    cookie_secret_path
+                ));
+            }
+        }
+
+        let mut schedule_lists = vec![(
+            "matchmaking_schedules".to_string(),
+            &self.matchmaking_schedules,
+        )];
+        for tenant in &self.tenants {
+            if let Some(schedules) = &tenant.schedules {
+                schedule_lists.push((format!("schedules of tenant {}", tenant.id), schedules));
+            }
+        }
+        for (option, schedules) in schedule_lists {
+            for (index, schedule) in schedules.iter().enumerate() {
+                if let Err(err) = schedule.check() {
+                    problems.push(format!("{} has an invalid schedule: {}", option, err));
+                }
+                if schedules[..index]
+                    .iter()
+                    .any(|other| other.mode == schedule.mode)
+                {
+                    problems.push(format!(
+                        "{} has more than one schedule for {}",
+                        option, schedule.mode
+                    ));
+                }
+            }
+        }
+
+        let mut messages = vec![("matchmaking_message".to_string(), &self.matchmaking_message)];
+        for tenant in &self.tenants {
+            messages.push((format!("message of tenant {}", tenant.id), &tenant.message));
+        }
+        for (option, message) in messages {
+            if let Some(message) = message {
+                if message.chars().count() > MATCHMAKING_MESSAGE_MAX_CHARS {
+                    problems.push(format!(
+                        "{} is longer than {} characters",
+                        option, MATCHMAKING_MESSAGE_MAX_CHARS
+                    ));
+                }
+            }
+        }
+
+        for (index, tenant) in self.tenants.iter().enumerate() {
+            if tenant.id == tenants::DEFAULT_TENANT {
+                problems.push(format!("tenant {} has no id", index));
+            }
+            if self.tenants[..index]
+                .iter()
+                .any(|other| other.id == tenant.id)
+            {
+                problems.push(format!("tenant id {} is used more than once", tenant.id));
+            }
+            if self.tenants[..index]
+                .iter()
+                .any(|other| other.hostname.eq_ignore_ascii_case(&tenant.hostname))
+            {
+                problems.push(format!(
+                    "tenant hostname {} is used more than once",
+                    tenant.hostname
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
 }
 
+fn list_errors(err: figment::Error) -> Vec<String> {
+    err.into_iter().map(|err| err.to_string()).collect()
+}
+
+// Set from the --config option, before the configuration is first read
+pub static CONFIG_FILE: OnceCell<PathBuf> = OnceCell::new();
+
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
-    Figment::from(Serialized::defaults(Config::default()))
-        .merge(Env::prefixed("OPENMELEE_"))
-        .extract()
-        .unwrap()
+    Config::load(CONFIG_FILE.get().map(PathBuf::as_path)).unwrap_or_else(|problems| {
+        panic!("Invalid configuration: {}", problems.join(", "));
+    })
 });
 
 #[derive(RustEmbed)]
@@ -98,8 +740,8 @@ pub static TEMPLATES: Lazy<Tera> = Lazy::new(|| {
         .filter(|asset_path| asset_path.ends_with(".tera"))
         .map(move |asset_path| {
             let _asset_path = asset_path.clone();
-            let asset = Asset::get(&_asset_path).unwrap();
-            let contents = std::str::from_utf8(asset.data.as_ref()).unwrap();
+            let asset = assets::get_asset(&_asset_path).unwrap();
+            let contents = std::str::from_utf8(asset.as_ref()).unwrap();
 
             (
                 std::path::Path::new(&asset_path.to_string())
@@ -117,19 +759,110 @@ pub static TEMPLATES: Lazy<Tera> = Lazy::new(|| {
     tera.add_raw_templates(templates)
         .expect("Failed to parse templates");
 
+    tera.register_function("build_info", |_: &HashMap<String, tera::Value>| {
+        Ok(tera::to_value(build_info::BuildInfo::current()).unwrap())
+    });
+
+    tera.register_filter(
+        "markdown",
+        |value: &tera::Value, _: &HashMap<String, tera::Value>| {
+            let markdown = tera::try_get_value!("markdown", "value", String, value);
+            Ok(tera::to_value(pages::render_markdown(&markdown)).unwrap())
+        },
+    );
+
+    tera.register_function("navbar_links", |_: &HashMap<String, tera::Value>| {
+        Ok(tera::to_value(pages::get_navbar_links()).unwrap())
+    });
+
+    tera.register_function("theme", |_: &HashMap<String, tera::Value>| {
+        Ok(tera::to_value(theme::ThemeContext::current(CONFIG.theme)).unwrap())
+    });
+
     tera
 });
 
+fn read_database_key(database_key_path: &str) -> String {
+    let mut buffer = String::new();
+    let mut file = std::fs::File::open(database_key_path)
+        .unwrap_or_else(|_| panic!("Unable to open {}", database_key_path));
+
+    file.read_to_string(&mut buffer)
+        .unwrap_or_else(|_| panic!("Unable to read {}", database_key_path));
+
+    buffer.trim().to_string()
+}
+
+// Pool used by lookups which can tolerate lagging behind recent writes. It is
+// the primary pool unless a replica is configured.
+#[derive(Clone)]
+pub struct ReadPool(pub SqlitePool);
+
 pub async fn init_pool(config: Config) -> SqlitePool {
     let connection_options = SqliteConnectOptions::from_str(&config.database_url.clone())
         .expect("Failed to connect to database")
         .create_if_missing(true);
 
-    SqlitePoolOptions::new()
-        .max_connections(config.database_max_connections)
-        .connect_with(connection_options)
-        .await
-        .expect("Failed to initialize database pool")
+    connect(config, connection_options).await
+}
+
+pub async fn init_read_pool(config: Config, pool: &SqlitePool) -> ReadPool {
+    let database_read_url = match &config.database_read_url {
+        Some(database_read_url) => database_read_url.clone(),
+        None => return ReadPool(pool.clone()),
+    };
+
+    let connection_options = SqliteConnectOptions::from_str(&database_read_url)
+        .expect("Failed to connect to read replica")
+        .read_only(true);
+
+    ReadPool(connect(config, connection_options).await)
+}
+
+async fn connect(config: Config, mut connection_options: SqliteConnectOptions) -> SqlitePool {
+    if let Some(database_key_path) = config.database_key_path {
+        if !cfg!(feature = "sqlcipher") {
+            panic!(
+                "Database key configured, but OpenMelee was built without the sqlcipher feature"
+            );
+        }
+
+        let key = read_database_key(&database_key_path);
+        connection_options =
+            connection_options.pragma("key", format!("'{}'", key.replace('\'', "''")));
+    }
+
+    let initial_delay = Duration::from_millis(config.database_connect_retry_delay_ms);
+    let mut attempt = 0;
+    loop {
+        let err = match SqlitePoolOptions::new()
+            .max_connections(config.database_max_connections)
+            .connect_with(connection_options.clone())
+            .await
+        {
+            Ok(pool) => return pool,
+            Err(err) => err,
+        };
+
+        if !config.database_wait && attempt >= config.database_connect_retries {
+            panic!("Failed to initialize database pool: {}", err);
+        }
+        let delay = get_retry_delay(initial_delay, attempt);
+        attempt += 1;
+        println!(
+            "Database unavailable on attempt {}, retrying in {:.1} seconds: {}",
+            attempt,
+            delay.as_secs_f64(),
+            err
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn get_retry_delay(initial_delay: Duration, attempt: u32) -> Duration {
+    initial_delay
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(DATABASE_MAX_RETRY_DELAY)
 }
 
 pub async fn run_migrations(pool: &SqlitePool) {
@@ -143,7 +876,7 @@ pub async fn run_migrations(pool: &SqlitePool) {
 mod test {
     use url::Url;
 
-    use crate::Config;
+    use crate::{game::OnlinePlayMode, schedule::QueueSchedule, tenants::Tenant, Config};
 
     #[test]
     fn test_format_user_discovery_url_without_public_url() {
@@ -171,10 +904,288 @@ mod test {
         assert_eq!(config.format_matchmaking_host(), "localhost");
     }
 
+    #[test]
+    fn test_config_schema_describes_all_options() {
+        let schema = serde_json::to_value(schemars::schema_for!(Config)).unwrap();
+        let config = serde_json::to_value(Config::default()).unwrap();
+
+        let properties = schema.get("properties").unwrap().as_object().unwrap();
+
+        for (key, _) in config.as_object().unwrap() {
+            assert!(properties.contains_key(key), "{} missing from schema", key);
+            assert!(properties.get(key).unwrap().get("description").is_some());
+        }
+    }
+
     #[test]
     fn test_format_matchmaking_host_with_public_url() {
         let mut config = Config::default();
         config.public_url = Some(Url::try_from("https://example.org").unwrap());
         assert_eq!(config.format_matchmaking_host(), "example.org");
     }
+
+    #[tokio::test]
+    async fn test_read_pool_is_read_only() {
+        use crate::{init_pool, init_read_pool, run_migrations};
+
+        let directory = std::env::temp_dir().join(format!("openmelee-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let database_path = directory.join("openmelee.sqlite");
+
+        let config = Config {
+            database_url: database_path.to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let pool = init_pool(config.clone()).await;
+        run_migrations(&pool).await;
+
+        let crate::ReadPool(read_pool) = init_read_pool(
+            Config {
+                database_read_url: Some(config.database_url.clone()),
+                ..config
+            },
+            &pool,
+        )
+        .await;
+        assert!(sqlx::query("select count(uid) from users")
+            .fetch_one(&read_pool)
+            .await
+            .is_ok());
+        assert!(sqlx::query("delete from users")
+            .execute(&read_pool)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_config() {
+        let config = Config {
+            jwt_secret_path: Some("Cargo.toml".to_string()),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_tenant_schedules_replace_the_server_ones() {
+        let schedule = |mode: &str| QueueSchedule {
+            mode: mode.to_string(),
+            timezone: "UTC".to_string(),
+            windows: vec!["* 18:00-23:00".to_string()],
+        };
+        let tenant = |schedules| Tenant {
+            id: "pal".to_string(),
+            hostname: "pal.example.org".to_string(),
+            name: "PAL Melee".to_string(),
+            stages: None,
+            schedules,
+            message: None,
+        };
+        let config = Config {
+            matchmaking_schedules: vec![schedule("ranked")],
+            tenants: vec![tenant(Some(vec![schedule("unranked")]))],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.get_schedule("", OnlinePlayMode::Ranked),
+            Some(&schedule("ranked"))
+        );
+        assert_eq!(config.get_schedule("", OnlinePlayMode::Unranked), None);
+        assert_eq!(config.get_schedule("pal", OnlinePlayMode::Ranked), None);
+        assert_eq!(
+            config.get_schedule("pal", OnlinePlayMode::Unranked),
+            Some(&schedule("unranked"))
+        );
+    }
+
+    #[test]
+    fn test_tenant_message_replaces_the_server_one() {
+        let tenant = |id: &str, message: Option<&str>| Tenant {
+            id: id.to_string(),
+            hostname: format!("{}.example.org", id),
+            name: id.to_string(),
+            stages: None,
+            schedules: None,
+            message: message.map(str::to_string),
+        };
+        let config = Config {
+            matchmaking_message: Some("Ranked resets Sunday".to_string()),
+            tenants: vec![
+                tenant("pal", Some("Weekly on Friday")),
+                tenant("ntsc", None),
+            ],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.get_matchmaking_message(""),
+            Some("Ranked resets Sunday")
+        );
+        assert_eq!(
+            config.get_matchmaking_message("pal"),
+            Some("Weekly on Friday")
+        );
+        assert_eq!(
+            config.get_matchmaking_message("ntsc"),
+            Some("Ranked resets Sunday")
+        );
+    }
+
+    #[test]
+    fn test_validate_lists_all_problems() {
+        let tenant = Tenant {
+            id: "pal".to_string(),
+            hostname: "pal.example.org".to_string(),
+            name: "PAL Melee".to_string(),
+            stages: None,
+            schedules: None,
+            message: None,
+        };
+        let config = Config {
+            matchmaking_port: Config::default().webserver_port,
+            matchmaking_max_peers: 10_000,
+            matchmaking_spectator_slots: 10_000,
+            matchmaking_channel_limit: Some(256),
+            matchmaking_outgoing_bandwidth_bytes: Some(0),
+            webserver_base_path: "slippi/".to_string(),
+            database_max_connections: 0,
+            database_read_url: Some("sqlite://replica.sqlite?mode=bogus".to_string()),
+            robots_txt_path: Some("missing/robots.txt".to_string()),
+            cookie_secret_path: Some("missing/cookie.key".to_string()),
+            matchmaking_schedules: vec![QueueSchedule {
+                mode: "ranked".to_string(),
+                timezone: "Mars/Olympus".to_string(),
+                windows: vec![],
+            }],
+            matchmaking_message: Some("Ranked resets Sunday! ".repeat(10)),
+            tenants: vec![tenant.clone(), tenant],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                "webserver_port and matchmaking_port are both 5000".to_string(),
+                "matchmaking_max_peers must be between 1 and 4095".to_string(),
+                "matchmaking_spectator_slots must be less than matchmaking_max_peers".to_string(),
+                "matchmaking_channel_limit must be between 1 and 255".to_string(),
+                "matchmaking_outgoing_bandwidth_bytes must be greater than 0, or unset for no limit"
+                    .to_string(),
+                "database_max_connections must be greater than 0".to_string(),
+                "webserver_base_path must start with a / and not end with one, such as /slippi, but is slippi/"
+                    .to_string(),
+                "database_read_url is invalid: error with configuration: unknown value \"bogus\" for `mode`"
+                    .to_string(),
+                "jwt_secret_path is required".to_string(),
+                "robots_txt_path points to missing/robots.txt, which is not a file".to_string(),
+                "cookie_secret_path points to missing/cookie.key, whose directory doesn't exist"
+                    .to_string(),
+                "matchmaking_schedules has an invalid schedule: unknown time zone \"Mars/Olympus\""
+                    .to_string(),
+                "matchmaking_message is longer than 200 characters".to_string(),
+                "tenant id pal is used more than once".to_string(),
+                "tenant hostname pal.example.org is used more than once".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_config_files_override_defaults() {
+        let directory = std::env::temp_dir().join(format!("openmelee-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = directory.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+
+        let toml = write(
+            "openmelee.toml",
+            "webserver_port = 8080\nserver_name = \"PAL\"",
+        );
+        let config = Config::load(Some(&toml)).unwrap();
+        assert_eq!(config.webserver_port, 8080);
+        assert_eq!(config.server_name, "PAL");
+        assert_eq!(config.matchmaking_port, Config::default().matchmaking_port);
+
+        let yaml = write("openmelee.yml", "webserver_port: 8081\n");
+        assert_eq!(Config::load(Some(&yaml)).unwrap().webserver_port, 8081);
+
+        let typo = write("typo.toml", "webserver_prot = 8080\n");
+        let problem = format!("{} has no option named webserver_prot", typo.display());
+        assert_eq!(Config::load(Some(&typo)).unwrap_err(), vec![problem]);
+
+        let invalid = write("invalid.toml", "webserver_port = \"http\"\n");
+        assert!(Config::load(Some(&invalid)).unwrap_err()[0].contains("webserver_port"));
+
+        let json = write("openmelee.json", "{}");
+        assert!(Config::load(Some(&json)).is_err());
+        assert!(Config::load(Some(&directory.join("missing.toml"))).is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_a_limit() {
+        use std::time::Duration;
+
+        use crate::get_retry_delay;
+
+        let initial_delay = Duration::from_millis(500);
+        assert_eq!(get_retry_delay(initial_delay, 0), initial_delay);
+        assert_eq!(get_retry_delay(initial_delay, 3), Duration::from_secs(4));
+        assert_eq!(get_retry_delay(initial_delay, 6), Duration::from_secs(30));
+        assert_eq!(get_retry_delay(initial_delay, 40), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Failed to initialize database pool")]
+    async fn test_connecting_gives_up_after_the_retries() {
+        crate::init_pool(Config {
+            database_url: "missing/directory/openmelee.sqlite".to_string(),
+            database_connect_retries: 2,
+            database_connect_retry_delay_ms: 1,
+            ..Config::default()
+        })
+        .await;
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[tokio::test]
+    async fn test_encrypted_database_cannot_be_opened_without_key() {
+        use std::io::Write;
+
+        use crate::{init_pool, run_migrations};
+
+        let directory = std::env::temp_dir().join(format!("openmelee-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let database_path = directory.join("openmelee.sqlite");
+        let key_path = directory.join("database.key");
+        writeln!(std::fs::File::create(&key_path).unwrap(), "hunter2").unwrap();
+
+        let config = Config {
+            database_url: database_path.to_string_lossy().to_string(),
+            database_key_path: Some(key_path.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+        let pool = init_pool(config.clone()).await;
+        run_migrations(&pool).await;
+        pool.close().await;
+
+        let pool_without_key = init_pool(Config {
+            database_key_path: None,
+            ..config
+        })
+        .await;
+        assert!(sqlx::query("select count(uid) from users")
+            .fetch_one(&pool_without_key)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -0,0 +1,104 @@
+use std::net::IpAddr;
+
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{FromRow, SqliteExecutor};
+
+use crate::ids::Uid;
+
+// User agents are only shown to help users recognize their devices
+const MAX_USER_AGENT_CHARS: usize = 256;
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Login {
+    pub id: i64,
+    pub uid: Uid,
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+    pub created_at: i64,
+}
+
+impl Login {
+    pub async fn record<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        ip_address: IpAddr,
+        user_agent: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        let user_agent = user_agent.map(|user_agent| {
+            user_agent
+                .chars()
+                .take(MAX_USER_AGENT_CHARS)
+                .collect::<String>()
+        });
+
+        sqlx::query("insert into login_history (uid, ip_address, user_agent, created_at) values ($1, $2, $3, $4)")
+            .bind(uid)
+            .bind(ip_address.to_string())
+            .bind(user_agent)
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_recent<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        limit: i64,
+    ) -> Result<Vec<Login>, sqlx::Error> {
+        sqlx::query_as::<_, Login>(
+            "select * from login_history where uid = $1 order by created_at desc, id desc limit $2",
+        )
+        .bind(uid)
+        .bind(limit)
+        .fetch_all(executor)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::logins::*;
+    use crate::test_support::UserBuilder;
+
+    #[sqlx::test]
+    async fn can_record_and_get_recent_logins(pool: Pool<Sqlite>) {
+        let mut uids = vec![];
+        for connect_code in ["TEST#001", "TEST#002"] {
+            let user = UserBuilder::new()
+                .connect_code(connect_code)
+                .insert(&pool)
+                .await;
+            uids.push(user.uid);
+        }
+
+        Login::record(
+            &pool,
+            uids[0].clone(),
+            IpAddr::from([203, 0, 113, 7]),
+            Some("a".repeat(1000)),
+        )
+        .await
+        .unwrap();
+        Login::record(&pool, uids[0].clone(), IpAddr::from([203, 0, 113, 8]), None)
+            .await
+            .unwrap();
+        Login::record(&pool, uids[1].clone(), IpAddr::from([203, 0, 113, 9]), None)
+            .await
+            .unwrap();
+
+        let logins = Login::get_recent(&pool, uids[0].clone(), 10).await.unwrap();
+
+        assert_eq!(logins.len(), 2);
+        assert_eq!(logins[0].ip_address, "203.0.113.8");
+        assert_eq!(logins[1].ip_address, "203.0.113.7");
+        assert_eq!(
+            logins[1].user_agent.as_ref().unwrap().len(),
+            MAX_USER_AGENT_CHARS
+        );
+    }
+}
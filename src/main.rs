@@ -1,50 +1,293 @@
+use std::future::Future;
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
 mod matchmaking;
+mod operator;
+mod service;
 mod webserver;
 
-use openmelee::{init_pool, run_migrations};
+use openmelee::{
+    ids::{ConnectCode, InvalidId},
+    init_pool, init_read_pool, run_migrations, Config,
+};
 
 #[derive(Parser)]
 #[clap()]
 struct Cli {
     #[clap(subcommand)]
     command: Option<Commands>,
+    /// Keep retrying to connect to the database until it's available, as with database_wait
+    #[clap(long, global = true)]
+    wait_for_db: bool,
+    /// Read options from a TOML or YAML file, which OPENMELEE_ environment variables override
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print a JSON Schema describing all configuration options
+    ConfigSchema,
+    /// Register the server as a system service using the current configuration
+    InstallService,
+    /// Remove the system service registered with install-service
+    UninstallService,
+    /// Run the server as the Windows service registered with install-service
+    #[clap(hide = true)]
+    RunService,
+    /// Compare the database schema to the one created by the migrations
+    VerifySchema,
+    /// Write the bundled templates and static files to a directory, to customize them with assets_path
+    ExportAssets {
+        directory: String,
+        /// Replace files already in the directory
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// Inspect and edit user accounts without the web UI
+    User {
+        /// Tenant the user is registered in, the default one if unset
+        #[clap(long, global = true, default_value = "")]
+        tenant: String,
+        #[clap(subcommand)]
+        command: UserCommands,
+    },
 }
 
 #[derive(Subcommand)]
-enum Commands {}
+enum UserCommands {
+    /// Print the account registered with a connect code
+    Show {
+        #[clap(value_parser = parse_connect_code)]
+        connect_code: ConnectCode,
+    },
+    /// Change the name shown in game for a user
+    SetDisplayName {
+        #[clap(value_parser = parse_connect_code)]
+        connect_code: ConnectCode,
+        display_name: String,
+    },
+    /// Prevent a user from logging in and matchmaking
+    Ban {
+        #[clap(value_parser = parse_connect_code)]
+        connect_code: ConnectCode,
+    },
+    /// Lift the ban of a user
+    Unban {
+        #[clap(value_parser = parse_connect_code)]
+        connect_code: ConnectCode,
+    },
+    /// Write the users of the tenant as CSV, to standard output if no file is given
+    Export {
+        #[clap(long)]
+        output: Option<String>,
+    },
+    /// Update display names and bans from a CSV file, with the columns connect_code, display_name and banned
+    Import {
+        file: String,
+        /// Only report the changes and invalid rows, without applying anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+fn parse_connect_code(connect_code: &str) -> Result<ConnectCode, InvalidId> {
+    ConnectCode::canonicalize(connect_code)
+}
+
+fn exit_with_problems(problems: Vec<String>) -> ! {
+    println!("Invalid configuration:");
+    for problem in problems {
+        println!("  - {}", problem);
+    }
+    std::process::exit(1);
+}
+
+// Runs until both servers exit, or until shutdown resolves
+async fn run_server(config: Config, shutdown: impl Future<Output = ()>) {
+    once_cell::sync::Lazy::force(&openmelee::build_info::STARTED_AT);
+
+    if let Err(problems) = config.validate() {
+        exit_with_problems(problems);
+    }
+
+    let pool = init_pool(config.clone()).await;
+
+    run_migrations(&pool).await;
+
+    let read_pool = init_read_pool(config.clone(), &pool).await;
+
+    openmelee::startup::log_startup_info(&config, &pool).await;
+
+    tokio::spawn(openmelee::telemetry::start_reporting(
+        config.clone(),
+        read_pool.0.clone(),
+    ));
+
+    tokio::spawn(openmelee::stats::start_aggregating(pool.clone()));
+
+    tokio::spawn(openmelee::quality::start_scoring(pool.clone()));
+
+    tokio::spawn(openmelee::nat::start_probe_listener(config.clone()));
+
+    tokio::spawn(openmelee::achievements::start_awarding(
+        config.clone(),
+        pool.clone(),
+    ));
+
+    tokio::spawn(openmelee::quests::start_tracking(pool.clone()));
+
+    tokio::spawn(openmelee::watchdog::start_watching(config.clone()));
+
+    tokio::spawn(openmelee::alerts::start_alerting(
+        config.clone(),
+        pool.clone(),
+    ));
+
+    let read_pool_handle = read_pool.0.clone();
+    let webserver_thread = tokio::spawn(webserver::start_server(
+        config.clone(),
+        pool.clone(),
+        read_pool,
+    ));
+
+    let enet_server_thread = tokio::task::spawn_blocking({
+        let pool = pool.clone();
+        move || matchmaking::start_server(config.clone(), pool)
+    });
+
+    let servers = async {
+        if webserver_thread.await.is_err() {
+            println!("webserver thread exited abnormally")
+        }
+        if enet_server_thread.await.is_err() {
+            println!("ENet server thread exited abnormally")
+        }
+    };
+    tokio::select! {
+        _ = servers => {}
+        // The ENet server can't be interrupted, it ends with the process,
+        // but the database is left in a consistent state
+        _ = shutdown => {
+            println!("Shutting down");
+            read_pool_handle.close().await;
+            pool.close().await;
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    if let Some(file) = &cli.config {
+        openmelee::CONFIG_FILE.set(file.clone()).unwrap();
+    }
+    // Checked before the configuration is first read, which would panic
+    if let Err(problems) = Config::load(cli.config.as_deref()) {
+        exit_with_problems(problems);
+    }
+    let mut config = openmelee::CONFIG.clone();
+    if cli.wait_for_db {
+        config.database_wait = true;
+    }
 
     match &cli.command {
-        None => {
-            let config = openmelee::CONFIG.clone();
-
-            if config.jwt_secret_path.is_none() {
-                panic!("JWT secret path not configured, exiting");
+        None => run_server(config, std::future::pending()).await,
+        Some(Commands::ConfigSchema) => {
+            let schema = schemars::schema_for!(Config);
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        }
+        Some(Commands::InstallService) => {
+            if let Err(err) = service::install(config.clone(), cli.config.as_deref()) {
+                println!("Failed to install service: {}", err);
+                std::process::exit(1);
             }
-
+        }
+        Some(Commands::RunService) => {
+            let runtime = tokio::runtime::Handle::current();
+            let result = tokio::task::block_in_place(|| {
+                service::run_service(move |stop| {
+                    runtime.block_on(run_server(config, async {
+                        let _ = stop.await;
+                    }))
+                })
+            });
+            if let Err(err) = result {
+                println!("Failed to run service: {}", err);
+                std::process::exit(1);
+            }
+            // Dropping the runtime would wait for the ENet server, which never returns
+            std::process::exit(0);
+        }
+        Some(Commands::UninstallService) => {
+            if let Err(err) = service::uninstall() {
+                println!("Failed to uninstall service: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::VerifySchema) => {
             let pool = init_pool(config.clone()).await;
 
-            run_migrations(&pool).await;
-
-            let webserver_thread =
-                tokio::spawn(webserver::start_server(config.clone(), pool.clone()));
+            match openmelee::schema::verify_schema(&pool).await {
+                Ok(drift) if drift.is_empty() => println!("Database schema is up to date"),
+                Ok(drift) => {
+                    for difference in drift {
+                        println!("{}", difference);
+                    }
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    println!("Failed to verify database schema: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::ExportAssets {
+            directory,
+            overwrite,
+        }) => match openmelee::assets::export(std::path::Path::new(directory), *overwrite) {
+            Ok(written) => {
+                for path in &written {
+                    println!("Wrote {}", path);
+                }
+                println!("Exported {} assets to {}", written.len(), directory);
+            }
+            Err(err) => {
+                println!("Failed to export assets: {}", err);
+                std::process::exit(1);
+            }
+        },
+        Some(Commands::User { tenant, command }) => {
+            let pool = init_pool(config.clone()).await;
 
-            let enet_server_thread = tokio::task::spawn_blocking(move || {
-                matchmaking::start_server(config.clone(), pool);
-            });
+            let result = match command {
+                UserCommands::Show { connect_code } => {
+                    operator::show(&pool, tenant, connect_code).await
+                }
+                UserCommands::SetDisplayName {
+                    connect_code,
+                    display_name,
+                } => operator::set_display_name(&pool, tenant, connect_code, display_name).await,
+                UserCommands::Ban { connect_code } => {
+                    operator::set_banned(&pool, tenant, connect_code, true).await
+                }
+                UserCommands::Unban { connect_code } => {
+                    operator::set_banned(&pool, tenant, connect_code, false).await
+                }
+                UserCommands::Export { output } => {
+                    operator::export(&pool, tenant, output.as_deref()).await
+                }
+                UserCommands::Import { file, dry_run } => {
+                    operator::import(&pool, tenant, file, *dry_run).await
+                }
+            };
 
-            if webserver_thread.await.is_err() {
-                println!("webserver thread exited abnormally")
-            }
-            if enet_server_thread.await.is_err() {
-                println!("ENet server thread exited abnormally")
+            if let Err(err) = result {
+                println!("{}", err);
+                std::process::exit(1);
             }
         }
-        Some(_) => (),
     }
 }
@@ -0,0 +1,951 @@
+use std::fmt;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Row, SqliteConnection, SqliteExecutor, SqlitePool};
+
+use crate::api::CreatedAt;
+use crate::game::{ControllerPort, OnlinePlayMode, Stage, Team};
+use crate::ids::{ConnectCode, MatchId, Uid};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MatchStatus {
+    Pending,
+    Confirmed,
+    // Needs an admin to resolve it, see DisputeReason
+    Disputed,
+    Resolved,
+}
+
+impl fmt::Display for MatchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            MatchStatus::Pending => "pending",
+            MatchStatus::Confirmed => "confirmed",
+            MatchStatus::Disputed => "disputed",
+            MatchStatus::Resolved => "resolved",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DisputeReason {
+    WinnerMismatch,
+    // The replays uploaded by the players aren't of the same game
+    ReplayMismatch,
+}
+
+impl fmt::Display for DisputeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            DisputeReason::WinnerMismatch => "winner_mismatch",
+            DisputeReason::ReplayMismatch => "replay_mismatch",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+// The winner and, in teams matches, their teammate, who won with them
+pub fn get_winner_uids(players: &[(Uid, Option<Team>)], winner_uid: &Uid) -> Vec<Uid> {
+    let winning_team = players
+        .iter()
+        .find(|(uid, _)| uid == winner_uid)
+        .and_then(|(_, team)| *team);
+
+    players
+        .iter()
+        .filter(|(uid, team)| uid == winner_uid || (team.is_some() && *team == winning_team))
+        .map(|(uid, _)| uid.clone())
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Verdict {
+    // Waiting for every player to report
+    Pending,
+    // Any player of the winning team, see get_winner_uids
+    Confirmed(Uid),
+    Disputed(DisputeReason),
+}
+
+impl Verdict {
+    // Replay hashes are only compared when every player uploaded one, a
+    // mismatch means the reports aren't about the same game. Teammates may
+    // each name themselves as the winner, and still agree.
+    pub fn from_results(players: &[(Uid, Option<Team>)], results: &[MatchResult]) -> Verdict {
+        if results.len() < players.len() {
+            return Verdict::Pending;
+        }
+
+        let replay_hashes: Option<Vec<&String>> = results
+            .iter()
+            .map(|result| result.replay_hash.as_ref())
+            .collect();
+        if let Some(replay_hashes) = replay_hashes {
+            if replay_hashes.iter().any(|hash| hash != &replay_hashes[0]) {
+                return Verdict::Disputed(DisputeReason::ReplayMismatch);
+            }
+        }
+
+        let winner_uids = get_winner_uids(players, &results[0].winner_uid);
+        if results
+            .iter()
+            .all(|result| winner_uids.contains(&result.winner_uid))
+        {
+            Verdict::Confirmed(results[0].winner_uid.clone())
+        } else {
+            Verdict::Disputed(DisputeReason::WinnerMismatch)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Match {
+    pub match_id: MatchId,
+    pub mode: String,
+    pub created_at: i64,
+    pub status: String,
+    // Only set once the match is confirmed or resolved
+    pub winner_uid: Option<Uid>,
+    pub dispute_reason: Option<String>,
+    // In-game ID, from the first report that included it
+    pub stage: Option<i64>,
+    // Only set for Direct matches, see SetGame
+    pub set_id: Option<String>,
+    pub set_game: Option<i64>,
+}
+
+impl CreatedAt for Match {
+    fn created_at(&self) -> i64 {
+        self.created_at
+    }
+}
+
+// Filters accepted by the match list endpoint
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct MatchFilter {
+    #[serde(default)]
+    pub uid: Option<Uid>,
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+impl Match {
+    // Matches are listed newest first, and the ID breaks ties between
+    // matches created in the same second
+    pub fn get_cursor_key(&self) -> String {
+        format!("{}:{}", self.created_at, self.match_id)
+    }
+
+    pub fn parse_cursor_key(key: &str) -> Option<(i64, String)> {
+        let (created_at, match_id) = key.split_once(':')?;
+        Some((created_at.parse().ok()?, match_id.to_string()))
+    }
+
+    // Lists the matches played by users of the tenant, starting after the
+    // given cursor key
+    pub async fn get_page<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        filter: &MatchFilter,
+        after: Option<(i64, String)>,
+        limit: i64,
+    ) -> Result<Vec<Match>, sqlx::Error> {
+        let (after_created_at, after_match_id) = after.unzip();
+
+        sqlx::query_as::<_, Match>("select * from matches where exists (select 1 from match_players join users on users.uid = match_players.uid where match_players.match_id = matches.match_id and users.tenant_id = $1) and ($2 is null or exists (select 1 from match_players where match_players.match_id = matches.match_id and match_players.uid = $2)) and ($3 is null or status = $3) and ($4 is null or (created_at, match_id) < ($4, $5)) order by created_at desc, match_id desc limit $6")
+            .bind(tenant_id)
+            .bind(filter.uid.clone())
+            .bind(filter.status.clone())
+            .bind(after_created_at)
+            .bind(after_match_id)
+            .bind(limit)
+            .fetch_all(executor)
+            .await
+    }
+
+    pub async fn count<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        filter: &MatchFilter,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query("select count(match_id) from matches where exists (select 1 from match_players join users on users.uid = match_players.uid where match_players.match_id = matches.match_id and users.tenant_id = $1) and ($2 is null or exists (select 1 from match_players where match_players.match_id = matches.match_id and match_players.uid = $2)) and ($3 is null or status = $3)")
+            .bind(tenant_id)
+            .bind(filter.uid.clone())
+            .bind(filter.status.clone())
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<i64, usize>(0))
+    }
+
+    pub async fn create(
+        pool: &SqlitePool,
+        match_id: MatchId,
+        mode: OnlinePlayMode,
+        players: Vec<(Uid, ControllerPort)>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("insert into matches (match_id, mode, created_at) values ($1, $2, $3)")
+            .bind(match_id.clone())
+            .bind(mode.to_string())
+            .bind(Utc::now().timestamp())
+            .execute(&mut tx)
+            .await?;
+
+        for (uid, port) in players {
+            sqlx::query("insert into match_players (match_id, uid, port) values ($1, $2, $3)")
+                .bind(match_id.clone())
+                .bind(uid)
+                .bind(port as u8)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+    ) -> Result<Match, sqlx::Error> {
+        sqlx::query_as::<_, Match>("select * from matches where match_id = $1")
+            .bind(match_id)
+            .fetch_one(executor)
+            .await
+    }
+
+    // Matches the user was paired in between the given times, and how many
+    // of those nobody reported a result for, most likely because the
+    // players couldn't connect to each other
+    pub async fn count_unreported_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        from: i64,
+        to: i64,
+    ) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query("select count(matches.match_id), coalesce(sum(not exists (select 1 from match_results where match_results.match_id = matches.match_id)), 0) from matches join match_players on match_players.match_id = matches.match_id where match_players.uid = $1 and matches.created_at >= $2 and matches.created_at < $3")
+            .bind(uid)
+            .bind(from)
+            .bind(to)
+            .fetch_one(executor)
+            .await
+            .map(|row| (row.get::<i64, usize>(0), row.get::<i64, usize>(1)))
+    }
+
+    // Reports of the same game agree on the stage, so the first one is kept
+    pub async fn set_stage<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+        stage: Stage,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update matches set stage = coalesce(stage, $1) where match_id = $2")
+            .bind(stage as u8)
+            .bind(match_id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // Players are only paired within one tenant, so any of them tells which
+    // tenant the match belongs to
+    pub async fn get_by_status<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        status: MatchStatus,
+    ) -> Result<Vec<Match>, sqlx::Error> {
+        sqlx::query_as::<_, Match>("select * from matches where status = $1 and exists (select 1 from match_players join users on users.uid = match_players.uid where match_players.match_id = matches.match_id and users.tenant_id = $2) order by created_at")
+            .bind(status.to_string())
+            .bind(tenant_id)
+            .fetch_all(executor)
+            .await
+    }
+
+    pub async fn set_status<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+        status: MatchStatus,
+        winner_uid: Option<Uid>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update matches set status = $1, winner_uid = $2 where match_id = $3")
+            .bind(status.to_string())
+            .bind(winner_uid)
+            .bind(match_id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn apply_verdict<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+        verdict: Verdict,
+    ) -> Result<(), sqlx::Error> {
+        let (status, winner_uid, dispute_reason) = match verdict {
+            Verdict::Pending => (MatchStatus::Pending, None, None),
+            Verdict::Confirmed(winner_uid) => (MatchStatus::Confirmed, Some(winner_uid), None),
+            Verdict::Disputed(reason) => (MatchStatus::Disputed, None, Some(reason.to_string())),
+        };
+
+        sqlx::query("update matches set status = $1, winner_uid = $2, dispute_reason = $3 where match_id = $4")
+            .bind(status.to_string())
+            .bind(winner_uid)
+            .bind(dispute_reason)
+            .bind(match_id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // The last match the user played that counts towards their record
+    pub async fn get_last_counted_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Option<Match>, sqlx::Error> {
+        sqlx::query_as::<_, Match>("select matches.* from matches join match_players on matches.match_id = match_players.match_id where match_players.uid = $1 and matches.status in ($2, $3) order by matches.created_at desc, matches.match_id desc limit 1")
+            .bind(uid)
+            .bind(MatchStatus::Confirmed.to_string())
+            .bind(MatchStatus::Resolved.to_string())
+            .fetch_optional(executor)
+            .await
+    }
+
+    pub async fn get_player_uids<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+    ) -> Result<Vec<Uid>, sqlx::Error> {
+        sqlx::query("select uid from match_players where match_id = $1 order by port")
+            .bind(match_id)
+            .fetch_all(executor)
+            .await
+            .map(|rows| rows.iter().map(|row| row.get::<Uid, usize>(0)).collect())
+    }
+
+    // Players of one against one matches have no team
+    pub async fn get_player_teams<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        played_match: &Match,
+    ) -> Result<Vec<(Uid, Option<Team>)>, sqlx::Error> {
+        let mode = played_match.mode.parse::<OnlinePlayMode>().ok();
+
+        sqlx::query("select uid, port from match_players where match_id = $1 order by port")
+            .bind(played_match.match_id.clone())
+            .fetch_all(executor)
+            .await
+            .map(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        let port = ControllerPort::try_from(row.get::<u8, usize>(1)).ok();
+                        let team = port.zip(mode).and_then(|(port, mode)| port.get_team(mode));
+                        (row.get::<Uid, usize>(0), team)
+                    })
+                    .collect()
+            })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchResult {
+    #[serde(skip)]
+    pub id: i64,
+    pub match_id: MatchId,
+    pub reporter_uid: Uid,
+    pub winner_uid: Uid,
+    // Chosen by the client, so a retried report can be recognized and
+    // answered with the result stored the first time
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
+    pub created_at: i64,
+    // Kept as evidence in case of a dispute
+    pub client_version: Option<String>,
+    pub replay_hash: Option<String>,
+}
+
+impl CreatedAt for MatchResult {
+    fn created_at(&self) -> i64 {
+        self.created_at
+    }
+}
+
+impl MatchResult {
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+        reporter_uid: Uid,
+        winner_uid: Uid,
+        idempotency_key: Option<String>,
+        client_version: Option<String>,
+        replay_hash: Option<String>,
+    ) -> Result<MatchResult, sqlx::Error> {
+        let mut result = MatchResult {
+            id: 0,
+            match_id,
+            reporter_uid,
+            winner_uid,
+            idempotency_key,
+            created_at: Utc::now().timestamp(),
+            client_version,
+            replay_hash,
+        };
+
+        let query_result = sqlx::query("insert into match_results (match_id, reporter_uid, winner_uid, idempotency_key, created_at, client_version, replay_hash) values ($1, $2, $3, $4, $5, $6, $7)")
+            .bind(result.match_id.clone())
+            .bind(result.reporter_uid.clone())
+            .bind(result.winner_uid.clone())
+            .bind(result.idempotency_key.clone())
+            .bind(result.created_at)
+            .bind(result.client_version.clone())
+            .bind(result.replay_hash.clone())
+            .execute(executor)
+            .await?;
+
+        result.id = query_result.last_insert_rowid();
+        Ok(result)
+    }
+
+    pub async fn get_all_for_match<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+    ) -> Result<Vec<MatchResult>, sqlx::Error> {
+        sqlx::query_as::<_, MatchResult>(
+            "select * from match_results where match_id = $1 order by id",
+        )
+        .bind(match_id)
+        .fetch_all(executor)
+        .await
+    }
+
+    pub async fn get_by_reporter<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+        reporter_uid: Uid,
+    ) -> Result<MatchResult, sqlx::Error> {
+        sqlx::query_as::<_, MatchResult>(
+            "select * from match_results where match_id = $1 and reporter_uid = $2",
+        )
+        .bind(match_id)
+        .bind(reporter_uid)
+        .fetch_one(executor)
+        .await
+    }
+
+    pub async fn get_by_idempotency_key<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        reporter_uid: Uid,
+        idempotency_key: String,
+    ) -> Result<MatchResult, sqlx::Error> {
+        sqlx::query_as::<_, MatchResult>(
+            "select * from match_results where reporter_uid = $1 and idempotency_key = $2",
+        )
+        .bind(reporter_uid)
+        .bind(idempotency_key)
+        .fetch_one(executor)
+        .await
+    }
+
+    // Only matches everyone agreed on, or that were resolved by an admin,
+    // are counted. Teammates of the winner won too, they sit on ports of the
+    // same parity, see ControllerPort::get_team.
+    pub async fn count_wins<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query("select count(matches.match_id) from matches join match_players on matches.match_id = match_players.match_id join match_players winner on winner.match_id = matches.match_id and winner.uid = matches.winner_uid where match_players.uid = $1 and (winner.uid = $1 or (matches.mode = $4 and winner.port % 2 = match_players.port % 2)) and matches.status in ($2, $3)")
+            .bind(uid)
+            .bind(MatchStatus::Confirmed.to_string())
+            .bind(MatchStatus::Resolved.to_string())
+            .bind(OnlinePlayMode::Teams.to_string())
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<i64, usize>(0))
+    }
+
+    pub async fn count_losses<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query("select count(matches.match_id) from matches join match_players on matches.match_id = match_players.match_id join match_players winner on winner.match_id = matches.match_id and winner.uid = matches.winner_uid where match_players.uid = $1 and not (winner.uid = $1 or (matches.mode = $4 and winner.port % 2 = match_players.port % 2)) and matches.status in ($2, $3)")
+            .bind(uid)
+            .bind(MatchStatus::Confirmed.to_string())
+            .bind(MatchStatus::Resolved.to_string())
+            .bind(OnlinePlayMode::Teams.to_string())
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<i64, usize>(0))
+    }
+}
+
+// How a player's last game ended, as seen by the client of one of the
+// players. Ranked reports may include one for each player, so admins can
+// tell a quit out from a legitimate win when the reports are disputed.
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerSummary {
+    #[serde(skip)]
+    pub result_id: i64,
+    pub uid: Uid,
+    pub stocks: u8,
+    pub percent: u16,
+    // Whether the player left the game with L+R+A+Start
+    #[serde(default)]
+    pub lras: bool,
+}
+
+impl PlayerSummary {
+    pub async fn create_all(
+        conn: &mut SqliteConnection,
+        result_id: i64,
+        summaries: &[PlayerSummary],
+    ) -> Result<(), sqlx::Error> {
+        for summary in summaries {
+            sqlx::query("insert into player_summaries (result_id, uid, stocks, percent, lras) values ($1, $2, $3, $4, $5)")
+                .bind(result_id)
+                .bind(summary.uid.clone())
+                .bind(summary.stocks)
+                .bind(summary.percent)
+                .bind(summary.lras)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Those of every report of the match, in the order they were reported
+    pub async fn get_all_for_match<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+    ) -> Result<Vec<PlayerSummary>, sqlx::Error> {
+        sqlx::query_as::<_, PlayerSummary>("select player_summaries.* from player_summaries join match_results on match_results.id = player_summaries.result_id where match_results.match_id = $1 order by player_summaries.result_id, player_summaries.uid")
+            .bind(match_id)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardEntry {
+    pub display_name: String,
+    pub connect_code: ConnectCode,
+    pub wins: i64,
+}
+
+impl LeaderboardEntry {
+    // Counted like the wins of a single user, banned users are left out
+    pub async fn get_top<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        limit: i64,
+    ) -> Result<Vec<LeaderboardEntry>, sqlx::Error> {
+        sqlx::query_as::<_, LeaderboardEntry>("select users.display_name, users.connect_code, count(matches.match_id) as wins from matches join match_players winner on winner.match_id = matches.match_id and winner.uid = matches.winner_uid join match_players on match_players.match_id = matches.match_id and (match_players.uid = winner.uid or (matches.mode = $5 and match_players.port % 2 = winner.port % 2)) join users on users.uid = match_players.uid where users.tenant_id = $1 and not users.is_banned and matches.status in ($2, $3) group by users.uid order by wins desc, users.display_name limit $4")
+            .bind(tenant_id)
+            .bind(MatchStatus::Confirmed.to_string())
+            .bind(MatchStatus::Resolved.to_string())
+            .bind(limit)
+            .bind(OnlinePlayMode::Teams.to_string())
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::matches::*;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    #[sqlx::test]
+    async fn can_create_match_and_get_players(pool: Pool<Sqlite>) {
+        let user_a = UserBuilder::new().insert(&pool).await;
+        let user_b = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+
+        Match::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            OnlinePlayMode::Unranked,
+            vec![
+                (user_b.uid.clone(), ControllerPort::Two),
+                (user_a.uid.clone(), ControllerPort::One),
+            ],
+        )
+        .await
+        .expect("Could not create match");
+
+        let created_match = Match::get(&pool, "mode.unranked-1".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(created_match.mode, "unranked");
+        assert_eq!(created_match.status, "pending");
+        assert_eq!(
+            Match::get_player_uids(&pool, "mode.unranked-1".parse().unwrap())
+                .await
+                .unwrap(),
+            vec![user_a.uid, user_b.uid]
+        );
+    }
+
+    #[sqlx::test]
+    async fn cannot_reuse_idempotency_key(pool: Pool<Sqlite>) {
+        let user_a = UserBuilder::new().insert(&pool).await;
+        let user_b = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+
+        for match_id in ["mode.unranked-1", "mode.unranked-2"] {
+            MatchBuilder::new(match_id)
+                .players(&[user_a.clone(), user_b.clone()])
+                .insert(&pool)
+                .await;
+        }
+
+        let result = MatchResult::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            user_a.uid.clone(),
+            user_a.uid.clone(),
+            Some("key".to_string()),
+            None,
+            None,
+        )
+        .await
+        .expect("Could not create match result");
+
+        assert_eq!(
+            MatchResult::get_by_idempotency_key(&pool, user_a.uid.clone(), "key".to_string())
+                .await
+                .unwrap(),
+            result
+        );
+        assert!(MatchResult::create(
+            &pool,
+            "mode.unranked-2".parse().unwrap(),
+            user_a.uid.clone(),
+            user_a.uid.clone(),
+            Some("key".to_string()),
+            None,
+            None,
+        )
+        .await
+        .is_err());
+        assert!(MatchResult::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            user_a.uid.clone(),
+            user_a.uid.clone(),
+            None,
+            None,
+            None,
+        )
+        .await
+        .is_err());
+    }
+
+    fn result_with_winner(
+        reporter_uid: &str,
+        winner_uid: &str,
+        replay_hash: Option<&str>,
+    ) -> MatchResult {
+        MatchResult {
+            id: 0,
+            match_id: "mode.unranked-1".parse().unwrap(),
+            reporter_uid: reporter_uid.parse().unwrap(),
+            winner_uid: winner_uid.parse().unwrap(),
+            idempotency_key: None,
+            created_at: 0,
+            client_version: None,
+            replay_hash: replay_hash.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_verdict_from_results() {
+        let players = vec![("a".parse().unwrap(), None), ("b".parse().unwrap(), None)];
+
+        assert_eq!(
+            Verdict::from_results(&players, &[result_with_winner("a", "a", None)]),
+            Verdict::Pending
+        );
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "b", None),
+                    result_with_winner("b", "b", None)
+                ]
+            ),
+            Verdict::Confirmed("b".parse().unwrap())
+        );
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "a", None),
+                    result_with_winner("b", "b", None)
+                ]
+            ),
+            Verdict::Disputed(DisputeReason::WinnerMismatch)
+        );
+    }
+
+    #[test]
+    fn test_verdict_from_results_credits_teammates() {
+        let players = vec![
+            ("a".parse().unwrap(), Some(Team::Red)),
+            ("b".parse().unwrap(), Some(Team::Blue)),
+            ("c".parse().unwrap(), Some(Team::Red)),
+            ("d".parse().unwrap(), Some(Team::Blue)),
+        ];
+
+        assert_eq!(
+            get_winner_uids(&players, &"c".parse().unwrap()),
+            vec!["a".parse::<Uid>().unwrap(), "c".parse().unwrap()]
+        );
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "a", None),
+                    result_with_winner("b", "c", None),
+                    result_with_winner("c", "c", None),
+                    result_with_winner("d", "a", None)
+                ]
+            ),
+            Verdict::Confirmed("a".parse().unwrap())
+        );
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "a", None),
+                    result_with_winner("b", "b", None),
+                    result_with_winner("c", "c", None),
+                    result_with_winner("d", "d", None)
+                ]
+            ),
+            Verdict::Disputed(DisputeReason::WinnerMismatch)
+        );
+    }
+
+    #[test]
+    fn test_verdict_from_results_compares_replay_hashes() {
+        let players = vec![("a".parse().unwrap(), None), ("b".parse().unwrap(), None)];
+
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "b", Some("1234")),
+                    result_with_winner("b", "b", Some("1234"))
+                ]
+            ),
+            Verdict::Confirmed("b".parse().unwrap())
+        );
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "b", Some("1234")),
+                    result_with_winner("b", "b", Some("5678"))
+                ]
+            ),
+            Verdict::Disputed(DisputeReason::ReplayMismatch)
+        );
+        assert_eq!(
+            Verdict::from_results(
+                &players,
+                &[
+                    result_with_winner("a", "b", Some("1234")),
+                    result_with_winner("b", "b", None)
+                ]
+            ),
+            Verdict::Confirmed("b".parse().unwrap())
+        );
+    }
+
+    #[sqlx::test]
+    async fn count_wins_and_losses_exclude_unconfirmed_matches(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        for (match_id, status) in [
+            ("mode.unranked-1", MatchStatus::Confirmed),
+            ("mode.unranked-2", MatchStatus::Disputed),
+            ("mode.unranked-3", MatchStatus::Resolved),
+        ] {
+            MatchBuilder::new(match_id)
+                .player(&user)
+                .status(status, Some(&user))
+                .insert(&pool)
+                .await;
+        }
+
+        assert_eq!(
+            MatchResult::count_wins(&pool, user.uid.clone())
+                .await
+                .unwrap(),
+            2
+        );
+
+        let opponent = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        MatchBuilder::new("mode.unranked-4")
+            .players(&[user.clone(), opponent.clone()])
+            .confirmed(&opponent)
+            .insert(&pool)
+            .await;
+
+        assert_eq!(MatchResult::count_losses(&pool, user.uid).await.unwrap(), 1);
+    }
+
+    #[sqlx::test]
+    async fn count_wins_and_losses_credit_teammates(pool: Pool<Sqlite>) {
+        let mut players = vec![];
+        for connect_code in ["TEST#001", "TEST#002", "TEST#003", "TEST#004"] {
+            players.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+
+        // Ports 1 and 3 against 2 and 4
+        let teams_match = MatchBuilder::new("mode.teams-1")
+            .mode(OnlinePlayMode::Teams)
+            .players(&players)
+            .confirmed(&players[2])
+            .insert(&pool)
+            .await;
+        assert_eq!(
+            Match::get_player_teams(&pool, &teams_match).await.unwrap(),
+            vec![
+                (players[0].uid.clone(), Some(Team::Red)),
+                (players[1].uid.clone(), Some(Team::Blue)),
+                (players[2].uid.clone(), Some(Team::Red)),
+                (players[3].uid.clone(), Some(Team::Blue)),
+            ]
+        );
+
+        for (player, wins, losses) in [
+            (&players[0], 1, 0),
+            (&players[1], 0, 1),
+            (&players[2], 1, 0),
+            (&players[3], 0, 1),
+        ] {
+            assert_eq!(
+                MatchResult::count_wins(&pool, player.uid.clone())
+                    .await
+                    .unwrap(),
+                wins
+            );
+            assert_eq!(
+                MatchResult::count_losses(&pool, player.uid.clone())
+                    .await
+                    .unwrap(),
+                losses
+            );
+        }
+
+        let mut leaderboard = LeaderboardEntry::get_top(&pool, DEFAULT_TENANT.to_string(), 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.connect_code.to_string(), entry.wins))
+            .collect::<Vec<(String, i64)>>();
+        leaderboard.sort();
+        assert_eq!(
+            leaderboard,
+            vec![("TEST#001".to_string(), 1), ("TEST#003".to_string(), 1)]
+        );
+    }
+
+    #[sqlx::test]
+    async fn matches_are_paged_newest_first(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        // Created within the same second, so only the ID orders them
+        for match_id in ["mode.unranked-1", "mode.unranked-2", "mode.unranked-3"] {
+            MatchBuilder::new(match_id)
+                .player(&user)
+                .insert(&pool)
+                .await;
+        }
+        sqlx::query("update matches set created_at = 1000")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let filter = MatchFilter::default();
+        let first_page = Match::get_page(&pool, DEFAULT_TENANT.to_string(), &filter, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(first_page[0].match_id, "mode.unranked-3");
+
+        let after = Match::parse_cursor_key(&first_page[1].get_cursor_key());
+        let second_page = Match::get_page(&pool, DEFAULT_TENANT.to_string(), &filter, after, 2)
+            .await
+            .unwrap();
+        assert_eq!(second_page.len(), 1);
+        assert_eq!(second_page[0].match_id, "mode.unranked-1");
+
+        let confirmed = MatchFilter {
+            status: Some(MatchStatus::Confirmed.to_string()),
+            ..MatchFilter::default()
+        };
+        assert_eq!(
+            Match::count(&pool, DEFAULT_TENANT.to_string(), &confirmed)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[sqlx::test]
+    async fn count_unreported_for_user_counts_matches_without_results(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        for match_id in ["mode.unranked-1", "mode.unranked-2"] {
+            MatchBuilder::new(match_id)
+                .player(&user)
+                .insert(&pool)
+                .await;
+        }
+        MatchResult::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            user.uid.clone(),
+            user.uid.clone(),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let now = Utc::now().timestamp();
+        assert_eq!(
+            Match::count_unreported_for_user(&pool, user.uid.clone(), 0, now + 1)
+                .await
+                .unwrap(),
+            (2, 1)
+        );
+        assert_eq!(
+            Match::count_unreported_for_user(&pool, user.uid, now + 1, now + 2)
+                .await
+                .unwrap(),
+            (0, 0)
+        );
+    }
+}
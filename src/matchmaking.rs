@@ -1,25 +1,83 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use encoding_rs::SHIFT_JIS;
 use enet::*;
 use itertools::Itertools;
 use rand::{seq::SliceRandom, thread_rng};
 use serde::{de, Deserialize, Serialize};
 use sqlx::SqlitePool;
+use tokio::{
+    runtime::Handle,
+    sync::broadcast::{error::TryRecvError, Receiver},
+};
 use unicode_normalization::UnicodeNormalization;
 
-use openmelee::{game::*, models, Config, LATEST_SLIPPI_CLIENT_VERSION};
+use openmelee::{
+    broadcasts::Broadcast,
+    capacity::{QueueSnapshot, SNAPSHOT_INTERVAL_SECONDS, SNAPSHOT_RETENTION_DAYS},
+    events,
+    formations::{FormedPlayer, MatchFormation},
+    game::*,
+    ids::{ConnectCode, MatchId, PlayKey, Uid},
+    matches::Match,
+    metrics::{PACKET_PARSE_SECONDS, SERVICE_LOOP_SECONDS, TICKET_MATCH_SECONDS},
+    models,
+    nat::{self, NatReport, NatType},
+    queue_pauses::QueuePause,
+    ranking::{get_rating_band, Rating},
+    rotations::StageRotation,
+    sets::SetGame,
+    telemetry,
+    tenants::{Tenant, DEFAULT_TENANT},
+    watchdog::MATCHMAKING_HEARTBEAT,
+    Config, HostSelection, PairingAlgorithm, LATEST_SLIPPI_CLIENT_VERSION,
+};
 
 const ENET_CHANNEL_ID: u8 = 0;
+// Matches formed within this many seconds are used to estimate wait times
+const MATCH_RATE_WINDOW_SECONDS: i64 = 10 * 60;
+// Relaxed ping limits grow by this much for every interval spent waiting
+const PING_RELAX_STEP_MS: u32 = 25;
+const PING_RELAX_INTERVAL_SECONDS: i64 = 15;
+// Round-trip times within the same bucket are considered equally good when
+// choosing a host, so small fluctuations don't decide it
+const HOST_RTT_BUCKET_MS: u128 = 10;
+// Bumped whenever pairing changes, so that match formations recorded before
+// can be told apart
+const PAIRING_VERSION: i64 = 1;
+// Tickets a peer resends unchanged within this many seconds are ignored,
+// instead of putting it back at the end of its queue
+const DUPLICATE_TICKET_WINDOW_SECONDS: i64 = 5;
+const TICKET_RATE_WINDOW_SECONDS: i64 = 60;
+// Queues which are snapshotted even while nobody searches in them
+const SNAPSHOT_MODES: [OnlinePlayMode; 4] = [
+    OnlinePlayMode::Ranked,
+    OnlinePlayMode::Unranked,
+    OnlinePlayMode::Direct,
+    OnlinePlayMode::Teams,
+];
+// Players in a Teams match, two against two
+const TEAMS_GROUP_SIZE: usize = 4;
+// Rotations starting or ending, and queues being paused or resumed, take
+// effect within this many seconds. New tickets are checked right away.
+const ROTATION_REFRESH_SECONDS: i64 = 60;
+const QUEUE_PAUSED_ERROR_CODE: &str = "queue-paused";
+// Players searching again this soon after being matched can't have played
+// their match, even the shortest game takes longer
+const ABANDONED_MATCH_SECONDS: i64 = 45;
+// Clients try connecting to their opponents for about this long before
+// giving up and searching again
+const OPPONENT_CONNECT_SECONDS: i64 = 15;
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct User {
-    uid: String,
-    play_key: String,
+    uid: Uid,
+    play_key: PlayKey,
     display_name: String,
-    connect_code: String,
+    connect_code: ConnectCode,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -50,17 +108,25 @@ struct Player {
     ip_address: String,
     ip_address_lan: String,
     port: ControllerPort,
-    uid: String,
+    // Only set in Teams matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    team_id: Option<Team>,
+    uid: Uid,
     display_name: String,
-    connect_code: String,
+    connect_code: ConnectCode,
+    // Not read by Slippi clients, shown to help troubleshoot connections
+    nat_type: NatType,
 }
 
 impl Player {
     fn new(
         ticket: CreateTicket,
         address: Address,
+        nat_type: NatType,
         is_local_player: bool,
         port: ControllerPort,
+        team_id: Option<Team>,
+        use_lan_address: bool,
     ) -> Player {
         let CreateTicket {
             user,
@@ -72,10 +138,16 @@ impl Player {
             uid: user.uid,
             display_name: user.display_name,
             connect_code: user.connect_code,
-            ip_address: format!("{}:{}", address.ip(), address.port()),
+            ip_address: if use_lan_address {
+                ip_address_lan.clone()
+            } else {
+                format!("{}:{}", address.ip(), address.port())
+            },
             ip_address_lan,
             is_local_player,
             port,
+            team_id,
+            nat_type,
         }
     }
 }
@@ -87,330 +159,2991 @@ struct CreateTicket {
     ip_address_lan: String,
     search: Search,
     user: User,
+    // Community the player searches in, the default one if omitted
+    #[serde(default)]
+    tenant: String,
+    // Match the player was put in before losing their connection, which is
+    // sent again within the reconnect window instead of searching
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    match_id: Option<MatchId>,
+}
+
+// Sent instead of a ticket by spectators, such as broadcast tools, which
+// stay connected to be told about the matches formed in their community
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum SpectatorMessage {
+    #[serde(rename = "spectate", rename_all = "camelCase")]
+    Spectate {
+        uid: Uid,
+        play_key: PlayKey,
+        #[serde(default)]
+        tenant: String,
+    },
+}
+
+// Only what the community can already see of each player
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpectatedPlayer {
+    display_name: String,
+    connect_code: ConnectCode,
+    port: ControllerPort,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    team_id: Option<Team>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 enum MatchmakingMessage {
     #[serde(rename = "create-ticket-resp", rename_all = "camelCase")]
-    CreateTicketResponse {},
+    CreateTicketResponse {
+        // Shown by the client instead of searching
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        // Lets clients tell errors they can handle apart, only set for
+        // paused queues so far
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_code: Option<String>,
+        // Operator's message, shown by the client while searching
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
     #[serde(rename = "get-ticket-resp", rename_all = "camelCase")]
     GetTicketResponse {
         latest_version: String,
-        match_id: String,
+        match_id: MatchId,
         is_host: bool,
         is_assigned: bool,
         players: Vec<Player>,
+        // Stages allowed in this game, the previous games of a Direct set
+        // may rule some out
         stages: Vec<Stage>,
+        // Number of the game within its Direct set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        set_game: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename = "queue-status", rename_all = "camelCase")]
+    QueueStatus {
+        position: usize,
+        // None until enough matches were formed to estimate it
+        estimated_wait_seconds: Option<i64>,
     },
+    #[serde(rename = "spectate-resp", rename_all = "camelCase")]
+    SpectateResponse {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    #[serde(rename = "match-formed", rename_all = "camelCase")]
+    MatchFormed {
+        match_id: MatchId,
+        mode: OnlinePlayMode,
+        players: Vec<SpectatedPlayer>,
+    },
+    // Admins' messages to everyone connected, such as restart warnings
+    #[serde(rename = "broadcast", rename_all = "camelCase")]
+    Broadcast { message: String },
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 struct PeerData {
     ticket: CreateTicket,
     joined_at: i64,
+    last_queue_status_at: i64,
+    preferences: models::MatchmakingPreferences,
+    nat_type: NatType,
+    // Only paired with other shadow queued peers, see User::set_shadow_queued
+    shadow_queued: bool,
+    // Only loaded for Ranked tickets, whose players are paired by rating
+    rating: Option<f64>,
+    // Opponents of the match the player was just unable to play, which they
+    // aren't paired with again. Players with any are searched for first.
+    abandoned_opponents: Vec<Uid>,
+}
+
+impl PeerData {
+    // Players who couldn't play their last match come first, then those
+    // who waited longest
+    fn get_queue_order(&self) -> (bool, i64) {
+        (self.abandoned_opponents.is_empty(), self.joined_at)
+    }
+}
+
+// Recently formed matches per mode, the rate at which they were formed is
+// used to estimate how long waiting peers still have to wait.
+#[derive(Debug, Default)]
+struct MatchRate {
+    formed_at: HashMap<OnlinePlayMode, VecDeque<i64>>,
+}
+
+impl MatchRate {
+    fn record(&mut self, mode: OnlinePlayMode, now: i64) {
+        self.formed_at.entry(mode).or_default().push_back(now);
+    }
+
+    fn estimate_wait_seconds(
+        &mut self,
+        mode: OnlinePlayMode,
+        position: usize,
+        now: i64,
+    ) -> Option<i64> {
+        let formed_at = self.formed_at.entry(mode).or_default();
+        while let Some(&time) = formed_at.front() {
+            if time >= now - MATCH_RATE_WINDOW_SECONDS {
+                break;
+            }
+            formed_at.pop_front();
+        }
+
+        if formed_at.is_empty() {
+            return None;
+        }
+
+        let players_per_match = ControllerPort::get_ports(mode).len();
+        let matches_needed = position.div_ceil(players_per_match);
+
+        Some(matches_needed as i64 * MATCH_RATE_WINDOW_SECONDS / formed_at.len() as i64)
+    }
+}
+
+// Where searching peers are queued, by community and mode, kept up to date
+// as tickets are created and peers leave. Each tick goes through the queued
+// peers' host slots, instead of reading and grouping every peer's ticket.
+#[derive(Debug, Default)]
+struct QueueIndex {
+    // Queues are numbered in the order they were first used, there are only
+    // as many as communities times modes
+    queues: Vec<(String, OnlinePlayMode)>,
+    queued: HashMap<SocketAddr, usize>,
+    // Community of each spectator
+    spectators: HashMap<SocketAddr, String>,
+    // Host slot of each peer holding one, whether searching, spectating,
+    // matched or yet to send anything
+    slots: HashMap<SocketAddr, usize>,
+    // Connected peers whose slot wasn't looked up yet
+    unassigned: Vec<SocketAddr>,
+}
+
+impl QueueIndex {
+    fn insert(&mut self, address: SocketAddr, tenant: String, mode: OnlinePlayMode) {
+        let queue =
+            match self.queues.iter().position(|(queue_tenant, queue_mode)| {
+                queue_tenant == &tenant && queue_mode == &mode
+            }) {
+                Some(queue) => queue,
+                None => {
+                    self.queues.push((tenant, mode));
+                    self.queues.len() - 1
+                }
+            };
+        self.queued.insert(address, queue);
+    }
+
+    fn insert_spectator(&mut self, address: SocketAddr, tenant: String) {
+        self.spectators.insert(address, tenant);
+    }
+
+    fn remove(&mut self, address: SocketAddr) {
+        self.queued.remove(&address);
+        self.spectators.remove(&address);
+    }
+
+    fn connect(&mut self, address: SocketAddr) {
+        self.slots.remove(&address);
+        self.unassigned.push(address);
+    }
+
+    fn disconnect(&mut self, address: SocketAddr) {
+        self.remove(address);
+        self.slots.remove(&address);
+        self.unassigned.retain(|&unassigned| unassigned != address);
+    }
+
+    // ENet gives a new peer the first free slot of the host without telling
+    // which, so it's looked up once after the peer connected
+    fn assign_slots(&mut self, host: &mut Host<PeerData>) {
+        if self.unassigned.is_empty() {
+            return;
+        }
+
+        for (slot, peer) in host.peers().enumerate() {
+            if peer.state() == PeerState::Disconnected {
+                continue;
+            }
+            let address = get_peer_address(&peer);
+            if let Some(i) = self
+                .unassigned
+                .iter()
+                .position(|&unassigned| unassigned == address)
+            {
+                self.unassigned.swap_remove(i);
+                self.slots.insert(address, slot);
+                if self.unassigned.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Queued peers are taken from the host in the order of their slots, so
+    // its peers are only stepped over, without being read, up to the last of
+    // them. ENet hands out the first free slot, which keeps that close to
+    // the number of connected peers rather than the host's size. Slots taken
+    // over by another peer since are skipped rather than trusted.
+    fn get_peers_by_queue<'a>(&self, host: &'a mut Host<PeerData>) -> Vec<Vec<Peer<'a, PeerData>>> {
+        let mut peers_by_queue: Vec<Vec<Peer<PeerData>>> =
+            self.queues.iter().map(|_| vec![]).collect();
+        let mut queued = self
+            .queued
+            .iter()
+            .filter_map(|(address, &queue)| Some((*self.slots.get(address)?, *address, queue)))
+            .collect_vec();
+        queued.sort_unstable();
+
+        let mut peers = host.peers();
+        let mut next_slot = 0;
+        for (slot, address, queue) in queued {
+            let peer = match slot
+                .checked_sub(next_slot)
+                .and_then(|skipped| peers.nth(skipped))
+            {
+                Some(peer) => peer,
+                None => continue,
+            };
+            next_slot = slot + 1;
+            if peer.state() == PeerState::Connected
+                && peer.data().is_some()
+                && get_peer_address(&peer) == address
+            {
+                peers_by_queue[queue].push(peer);
+            }
+        }
+
+        peers_by_queue
+    }
+
+    fn is_spectator(&self, address: SocketAddr) -> bool {
+        self.spectators.contains_key(&address)
+    }
+
+    // Players can't hold the host slots kept for spectators, and spectators
+    // can't take more than those. Every connected peer that isn't an admitted
+    // spectator counts as a player, including the one asking.
+    fn has_free_slot(&self, spectator: bool, config: &Config) -> bool {
+        let spectator_slots = config.matchmaking_spectator_slots;
+        if spectator {
+            (self.spectators.len() as u64) < spectator_slots
+        } else {
+            let connected = self.slots.len() + self.unassigned.len();
+            let players = connected.saturating_sub(self.spectators.len());
+            players as u64 <= config.matchmaking_max_peers - spectator_slots
+        }
+    }
+
+    fn count_waiting(&self, tenant: &str, mode: OnlinePlayMode) -> usize {
+        match self
+            .queues
+            .iter()
+            .position(|(queue_tenant, queue_mode)| queue_tenant == tenant && queue_mode == &mode)
+        {
+            Some(queue) => self
+                .queued
+                .values()
+                .filter(|&&queued| queued == queue)
+                .count(),
+            None => 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum TicketVerdict {
+    Accepted,
+    Duplicate,
+    RateExceeded,
+}
+
+#[derive(Debug, Default)]
+struct TicketHistory {
+    submitted_at: VecDeque<i64>,
+    last_accepted_at: i64,
+    last_accepted: Vec<u8>,
+}
+
+// Tickets each connected peer sent recently. Duplicates count towards the
+// rate too, so resending the same ticket in a loop still gets a peer
+// disconnected.
+#[derive(Debug, Default)]
+struct TicketLimiter {
+    history: HashMap<SocketAddr, TicketHistory>,
+}
+
+impl TicketLimiter {
+    fn check(
+        &mut self,
+        address: SocketAddr,
+        ticket: &[u8],
+        now: i64,
+        max_per_minute: u64,
+    ) -> TicketVerdict {
+        let history = self.history.entry(address).or_default();
+        while let Some(&time) = history.submitted_at.front() {
+            if time > now - TICKET_RATE_WINDOW_SECONDS {
+                break;
+            }
+            history.submitted_at.pop_front();
+        }
+        history.submitted_at.push_back(now);
+
+        if history.submitted_at.len() as u64 > max_per_minute {
+            TicketVerdict::RateExceeded
+        } else if history.last_accepted == ticket
+            && now - history.last_accepted_at < DUPLICATE_TICKET_WINDOW_SECONDS
+        {
+            TicketVerdict::Duplicate
+        } else {
+            history.last_accepted = ticket.to_vec();
+            history.last_accepted_at = now;
+            TicketVerdict::Accepted
+        }
+    }
+
+    fn remove(&mut self, address: SocketAddr) {
+        self.history.remove(&address);
+    }
+}
+
+// Sent as the data of the disconnection, other disconnections send 0
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum DisconnectReason {
+    // Connected without searching, or still connected after being matched
+    NoTicket = 1,
+    // Not answering, or not finishing connecting or disconnecting
+    Unreachable = 2,
+}
+
+// When each peer holding a slot stopped searching or answering. Peers get
+// disconnected once stale for the timeout, and have as long again to
+// acknowledge it before their slot is taken back.
+#[derive(Debug, Default)]
+struct PeerReaper {
+    stale_since: HashMap<SocketAddr, i64>,
+}
+
+impl PeerReaper {
+    fn check(
+        &mut self,
+        address: SocketAddr,
+        state: PeerState,
+        has_ticket: bool,
+        now: i64,
+        timeout_seconds: i64,
+    ) -> Option<DisconnectReason> {
+        let reason = match (state, has_ticket) {
+            (PeerState::Disconnected, _) | (PeerState::Connected, true) => None,
+            (PeerState::Connected, false) => Some(DisconnectReason::NoTicket),
+            _ => Some(DisconnectReason::Unreachable),
+        };
+        if reason.is_none() {
+            self.stale_since.remove(&address);
+            return None;
+        }
+
+        let stale_since = self.stale_since.entry(address).or_insert(now);
+        if now - *stale_since < timeout_seconds {
+            return None;
+        }
+        *stale_since = now;
+        reason
+    }
+
+    fn remove(&mut self, address: SocketAddr) {
+        self.stale_since.remove(&address);
+    }
+}
+
+// Responses of recently formed matches, by match and player. Players whose
+// connection drops shortly after being matched can ask for theirs again with
+// the match ID, rather than being matched with someone else.
+#[derive(Debug, Default)]
+struct ReconnectWindow {
+    responses: HashMap<(MatchId, Uid), (MatchmakingMessage, i64)>,
+}
+
+impl ReconnectWindow {
+    fn record(&mut self, uid: Uid, message: &MatchmakingMessage, now: i64) {
+        if let MatchmakingMessage::GetTicketResponse { match_id, .. } = message {
+            self.responses
+                .insert((match_id.clone(), uid), (message.clone(), now));
+        }
+    }
+
+    fn get(
+        &self,
+        match_id: &MatchId,
+        uid: &Uid,
+        now: i64,
+        window_seconds: i64,
+    ) -> Option<MatchmakingMessage> {
+        match self.responses.get(&(match_id.clone(), uid.clone())) {
+            Some((message, created_at)) if now - created_at < window_seconds => {
+                Some(message.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn expire(&mut self, now: i64, window_seconds: i64) {
+        self.responses
+            .retain(|_, (_, created_at)| now - *created_at < window_seconds);
+    }
+}
+
+// Opponents of each player's last match, while it's recent, and who of them
+// couldn't play it through no fault of their own. The first player of a match
+// to search again before their client gave up connecting left it, by dodging
+// or failing to connect, and those searching again after them were left
+// waiting. A first player only back once their client gave up waited for
+// opponents who never connected. So did the others back about as late, but
+// not those much later, who went offline instead of connecting.
+#[derive(Debug, Default)]
+struct AbandonedMatches {
+    formed: HashMap<Uid, (MatchId, Vec<Uid>, i64)>,
+    // Matches someone already searched again after, with when they formed
+    returned: HashMap<MatchId, MatchReturn>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MatchReturn {
+    formed_at: i64,
+    first_returned_at: i64,
+    // Whether the first player back left before their opponents could connect
+    left_early: bool,
+}
+
+impl AbandonedMatches {
+    fn record(&mut self, uid: Uid, message: &MatchmakingMessage, now: i64) {
+        if let MatchmakingMessage::GetTicketResponse {
+            match_id, players, ..
+        } = message
+        {
+            let opponents = players
+                .iter()
+                .map(|player| player.uid.clone())
+                .filter(|player_uid| player_uid != &uid)
+                .collect();
+            self.formed.insert(uid, (match_id.clone(), opponents, now));
+        }
+    }
+
+    // The match a player searching again was left waiting in, with their
+    // opponents in it, only given once
+    fn take(&mut self, uid: &Uid, now: i64) -> Option<(MatchId, Vec<Uid>)> {
+        let (match_id, opponents, formed_at) = match self.formed.remove(uid) {
+            Some(formed) if now - formed.2 < ABANDONED_MATCH_SECONDS => formed,
+            _ => return None,
+        };
+
+        let left_waiting = match self.returned.get(&match_id) {
+            Some(first) if first.left_early => true,
+            Some(first) => now - first.first_returned_at < OPPONENT_CONNECT_SECONDS,
+            None => {
+                let left_early = now - formed_at < OPPONENT_CONNECT_SECONDS;
+                self.returned.insert(
+                    match_id.clone(),
+                    MatchReturn {
+                        formed_at,
+                        first_returned_at: now,
+                        left_early,
+                    },
+                );
+                !left_early
+            }
+        };
+
+        left_waiting.then(|| (match_id, opponents))
+    }
+
+    fn expire(&mut self, now: i64) {
+        self.formed
+            .retain(|_, (_, _, formed_at)| now - *formed_at < ABANDONED_MATCH_SECONDS);
+        self.returned
+            .retain(|_, first| now - first.formed_at < ABANDONED_MATCH_SECONDS);
+    }
+}
+
+// What the matchmaking loop keeps track of across events and ticks
+#[derive(Debug, Default)]
+struct LoopState {
+    queue_index: QueueIndex,
+    ticket_limiter: TicketLimiter,
+    peer_reaper: PeerReaper,
+    reconnect_window: ReconnectWindow,
+    abandoned_matches: AbandonedMatches,
+}
+
+// Checked before packets are parsed, so that peers can't make the server
+// allocate much for them. Scans the JSON without validating it, parsing
+// rejects whatever else is wrong.
+fn check_packet(data: &[u8], config: &Config) -> Result<(), String> {
+    if data.len() > config.matchmaking_max_packet_bytes {
+        return Err(format!(
+            "packet of {} bytes is larger than {}",
+            data.len(),
+            config.matchmaking_max_packet_bytes
+        ));
+    }
+
+    let mut depth: usize = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut string_bytes = 0;
+    for &byte in data {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+                continue;
+            }
+            string_bytes += 1;
+            if string_bytes > config.matchmaking_max_json_string_bytes {
+                return Err(format!(
+                    "string longer than {} bytes",
+                    config.matchmaking_max_json_string_bytes
+                ));
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => {
+                in_string = true;
+                string_bytes = 0;
+            }
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > config.matchmaking_max_json_depth {
+                    return Err(format!(
+                        "nested deeper than {} levels",
+                        config.matchmaking_max_json_depth
+                    ));
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+// Every community's queues are recorded, so that quiet hours show up as
+// empty rather than missing
+async fn save_queue_snapshots(
+    pool: &SqlitePool,
+    config: &Config,
+    queue_index: &QueueIndex,
+    now: i64,
+    period_seconds: i64,
+) {
+    let tenants = std::iter::once(DEFAULT_TENANT)
+        .chain(config.tenants.iter().map(|tenant| tenant.id.as_str()))
+        .collect_vec();
+
+    for tenant in tenants {
+        for mode in SNAPSHOT_MODES {
+            let waiting = queue_index.count_waiting(tenant, mode) as i64;
+            if let Err(err) =
+                QueueSnapshot::save(pool, tenant.to_string(), mode, now, period_seconds, waiting)
+                    .await
+            {
+                println!("Failed to save the {} queue snapshot: {}", mode, err);
+            }
+        }
+    }
+
+    let retention_seconds = SNAPSHOT_RETENTION_DAYS * 24 * 60 * 60;
+    if let Err(err) = QueueSnapshot::delete_before(pool, now - retention_seconds).await {
+        println!("Failed to delete old queue snapshots: {}", err);
+    }
+}
+
+// The previous rotations are kept when they can't be loaded, rather than
+// falling back to the default stages mid-season
+async fn refresh_rotations(pool: &SqlitePool, rotations: &mut Vec<StageRotation>, now: i64) {
+    match StageRotation::get_active(pool, now).await {
+        Ok(active) => *rotations = active,
+        Err(err) => println!("Failed to load stage rotations: {}", err),
+    }
+}
+
+// Players already searching in a paused queue keep their place, but aren't
+// paired until it's resumed
+async fn refresh_paused_modes(pool: &SqlitePool, paused_modes: &mut Vec<OnlinePlayMode>) {
+    match QueuePause::get_all(pool).await {
+        Ok(pauses) => {
+            *paused_modes = pauses
+                .iter()
+                .filter_map(|pause| pause.mode.parse().ok())
+                .collect()
+        }
+        Err(err) => println!("Failed to load paused queues: {}", err),
+    }
+}
+
+// Peers without a ticket are asked to disconnect, unreachable ones are
+// dropped right away, which ENet doesn't report as a disconnection
+fn reap_stale_peers(
+    host: &mut Host<PeerData>,
+    config: &Config,
+    peer_reaper: &mut PeerReaper,
+    queue_index: &mut QueueIndex,
+    ticket_limiter: &mut TicketLimiter,
+    now: i64,
+) {
+    let timeout_seconds = config.matchmaking_stale_peer_timeout_seconds as i64;
+
+    for mut peer in host.peers() {
+        let address = get_peer_address(&peer);
+        let reason = match peer_reaper.check(
+            address,
+            peer.state(),
+            peer.data().is_some() || queue_index.is_spectator(address),
+            now,
+            timeout_seconds,
+        ) {
+            Some(reason) => reason,
+            None => continue,
+        };
+
+        println!("Peer {} is stale, disconnecting: {:?}", address, reason);
+        telemetry::record_peer_reaped();
+        match reason {
+            DisconnectReason::NoTicket => peer.disconnect_later(reason as u32),
+            DisconnectReason::Unreachable => {
+                queue_index.disconnect(address);
+                ticket_limiter.remove(address);
+                peer_reaper.remove(address);
+                // Only disconnection events free the data of a peer, the
+                // next one taking its slot would otherwise get its ticket
+                peer.set_data(None);
+                peer.disconnect_now(reason as u32);
+            }
+        }
+    }
+}
+
+fn get_channel_limit(config: &Config) -> ChannelLimit {
+    match config.matchmaking_channel_limit {
+        Some(limit) => ChannelLimit::Limited(limit),
+        None => ChannelLimit::Maximum,
+    }
+}
+
+fn get_bandwidth_limit(bytes_per_second: Option<u32>) -> BandwidthLimit {
+    match bytes_per_second {
+        Some(bytes_per_second) => BandwidthLimit::Limited(bytes_per_second),
+        None => BandwidthLimit::Unlimited,
+    }
+}
+
+// ENet 0.3 has no peer IDs, peers are told apart by their address
+fn get_peer_address(peer: &Peer<PeerData>) -> SocketAddr {
+    SocketAddr::from((*peer.address().ip(), peer.address().port()))
 }
 
 pub fn start_server(config: Config, pool: SqlitePool) {
+    // ENet isn't async, so the server runs on a blocking thread and only
+    // enters the runtime for database access
+    let runtime = Handle::current();
+    let mut match_rate = MatchRate::default();
+    let mut state = LoopState::default();
+    let reconnect_window_seconds = config.matchmaking_reconnect_window_seconds as i64;
+    let queue_status_interval = config.matchmaking_queue_status_interval_seconds as i64;
     let enet = Enet::new().expect("Could not initialize ENet");
     let listen_address = Address::new(config.matchmaking_server_address, config.matchmaking_port);
     let mut host = enet
         .create_host::<PeerData>(
             Some(&listen_address),
             config.matchmaking_max_peers,
-            ChannelLimit::Maximum,
-            BandwidthLimit::Unlimited,
-            BandwidthLimit::Unlimited,
+            get_channel_limit(&config),
+            get_bandwidth_limit(config.matchmaking_incoming_bandwidth_bytes),
+            get_bandwidth_limit(config.matchmaking_outgoing_bandwidth_bytes),
         )
         .expect("Could not create ENet host");
 
     println!(
         "Matchmaking server listening on {}",
-        config.format_matchmaking_server_address(),
+        config.clone().format_matchmaking_server_address(),
     );
 
+    let mut last_snapshot_at = Utc::now().timestamp();
+    let mut last_reaped_at = last_snapshot_at;
+    let mut rotations = vec![];
+    runtime.block_on(refresh_rotations(&pool, &mut rotations, last_snapshot_at));
+    let mut paused_modes = vec![];
+    runtime.block_on(refresh_paused_modes(&pool, &mut paused_modes));
+    let mut last_rotation_refresh_at = last_snapshot_at;
+    let mut app_events = events::subscribe();
     loop {
-        host.service(1000)
-            .expect("ENet service failed")
-            .map(|event| handle_enet_event(event, pool.clone()));
+        // Measured from when the wait for an event ends, until the next one
+        let _loop_timer = match host.service(1000).expect("ENet service failed") {
+            Some(event) => {
+                let loop_timer = SERVICE_LOOP_SECONDS.start_timer();
+                runtime.block_on(handle_enet_event(event, &config, pool.clone(), &mut state));
+                loop_timer
+            }
+            None => SERVICE_LOOP_SECONDS.start_timer(),
+        };
+        state.queue_index.assign_slots(&mut host);
 
-        let connected_peers = host
-            .peers()
-            .filter(|peer| peer.state() == PeerState::Connected)
-            .filter(|peer| peer.data().is_some());
+        let now = Utc::now().timestamp();
+        MATCHMAKING_HEARTBEAT.tick(now);
+        if now > last_reaped_at {
+            reap_stale_peers(
+                &mut host,
+                &config,
+                &mut state.peer_reaper,
+                &mut state.queue_index,
+                &mut state.ticket_limiter,
+                now,
+            );
+            state.reconnect_window.expire(now, reconnect_window_seconds);
+            state.abandoned_matches.expire(now);
+            last_reaped_at = now;
+        }
+        if has_new_broadcasts(&mut app_events) {
+            send_broadcasts(&mut host, &runtime, &pool, now);
+        }
+        if now - last_snapshot_at >= SNAPSHOT_INTERVAL_SECONDS {
+            runtime.block_on(save_queue_snapshots(
+                &pool,
+                &config,
+                &state.queue_index,
+                now,
+                now - last_snapshot_at,
+            ));
+            last_snapshot_at = now;
+        }
+        if now - last_rotation_refresh_at >= ROTATION_REFRESH_SECONDS {
+            runtime.block_on(refresh_rotations(&pool, &mut rotations, now));
+            runtime.block_on(refresh_paused_modes(&pool, &mut paused_modes));
+            last_rotation_refresh_at = now;
+        }
+
+        if state.queue_index.is_empty() {
+            continue;
+        }
 
-        let peers_by_game_mode =
-            connected_peers.group_by(|peer| peer.data().unwrap().ticket.search.mode);
+        // Players are only matched with others of the same community
+        let peers_by_queue = state.queue_index.get_peers_by_queue(&mut host);
 
-        peers_by_game_mode.into_iter().for_each(|(mode, peers)| {
-            handle_matchmaking(mode, peers.collect_vec());
-        });
+        // Responses sent to matched peers, with their address and uid
+        let mut responses = vec![];
+        // Matches to tell spectators about, with their community
+        let mut formed = vec![];
+        for (queue, peers) in peers_by_queue.into_iter().enumerate() {
+            if peers.is_empty() {
+                continue;
+            }
+            let (tenant, mode) = &state.queue_index.queues[queue];
+            if paused_modes.contains(mode) {
+                continue;
+            }
+            let queue_responses = handle_matchmaking(
+                *mode,
+                Tenant::get_stages(config.get_tenant(tenant), *mode, &rotations),
+                peers.clone(),
+                &runtime,
+                &pool,
+                &mut match_rate,
+                &config,
+            );
+            formed.extend(
+                queue_responses
+                    .iter()
+                    .filter_map(|(_, _, response)| get_match_formed(*mode, response))
+                    .map(|message| (tenant.clone(), message)),
+            );
+            responses.extend(queue_responses);
+            send_queue_status(*mode, peers, &mut match_rate, queue_status_interval);
+        }
+        if !formed.is_empty() && !state.queue_index.spectators.is_empty() {
+            notify_spectators(&mut host, &state.queue_index, &formed);
+        }
+        for (address, uid, response) in responses {
+            state.queue_index.remove(address);
+            state.abandoned_matches.record(uid.clone(), &response, now);
+            state.reconnect_window.record(uid, &response, now);
+        }
     }
 }
 
-async fn handle_enet_event(mut event: Event<'_, PeerData>, pool: SqlitePool) {
+async fn handle_enet_event(
+    mut event: Event<'_, PeerData>,
+    config: &Config,
+    pool: SqlitePool,
+    state: &mut LoopState,
+) {
+    let LoopState {
+        queue_index,
+        ticket_limiter,
+        peer_reaper,
+        reconnect_window,
+        abandoned_matches,
+    } = state;
+
     match event {
-        Event::Connect(_) => println!("New connection!"),
-        Event::Disconnect(..) => println!("Disconnect!"),
+        Event::Connect(ref peer) => {
+            println!("New connection!");
+            queue_index.connect(get_peer_address(peer));
+        }
+        Event::Disconnect(ref peer, _) => {
+            println!("Disconnect!");
+            queue_index.disconnect(get_peer_address(peer));
+            ticket_limiter.remove(get_peer_address(peer));
+            peer_reaper.remove(get_peer_address(peer));
+        }
         Event::Receive {
             ref packet,
             ref mut sender,
             ..
         } => {
-            let packet_data = std::str::from_utf8(packet.data()).unwrap();
-            let message: CreateTicket = serde_json::from_str(packet_data).unwrap();
+            if let Err(problem) = check_packet(packet.data(), config) {
+                println!(
+                    "Peer {} sent an invalid packet, disconnecting: {}",
+                    get_peer_address(sender),
+                    problem
+                );
+                telemetry::record_ticket_dropped();
+                queue_index.remove(get_peer_address(sender));
+                sender.disconnect_later(0);
+                return;
+            }
+
+            let now = Utc::now().timestamp();
+            match ticket_limiter.check(
+                get_peer_address(sender),
+                packet.data(),
+                now,
+                config.matchmaking_max_tickets_per_minute,
+            ) {
+                TicketVerdict::Accepted => (),
+                TicketVerdict::Duplicate => {
+                    telemetry::record_ticket_dropped();
+                    return;
+                }
+                TicketVerdict::RateExceeded => {
+                    println!(
+                        "Peer {} sent too many tickets, disconnecting",
+                        get_peer_address(sender)
+                    );
+                    telemetry::record_ticket_dropped();
+                    queue_index.remove(get_peer_address(sender));
+                    sender.disconnect_later(0);
+                    return;
+                }
+            }
+
+            // A new ticket replaces the previous one, if it's accepted
+            queue_index.remove(get_peer_address(sender));
+
+            let parse_timer = PACKET_PARSE_SECONDS.start_timer();
+            let packet_data = String::from_utf8_lossy(packet.data());
+            if let Ok(message) = serde_json::from_str::<SpectatorMessage>(&packet_data) {
+                drop(parse_timer);
+                handle_spectate(message, sender, config, &pool, queue_index).await;
+                return;
+            }
+            let ticket = serde_json::from_str::<CreateTicket>(&packet_data);
+            drop(parse_timer);
+            let message = match ticket {
+                Ok(message) => message,
+                Err(err) => {
+                    println!(
+                        "Peer {} sent an invalid ticket, disconnecting: {}",
+                        get_peer_address(sender),
+                        err
+                    );
+                    telemetry::record_ticket_dropped();
+                    sender.disconnect_later(0);
+                    return;
+                }
+            };
 
             println!("{:?}", packet_data);
 
+            let preferences = models::MatchmakingPreferences::get(&pool, message.user.uid.clone())
+                .await
+                .unwrap_or_default();
+            let shadow_queued =
+                models::User::is_shadow_queued(&pool, message.user.uid.clone()).await;
+            let rating = match message.search.mode {
+                OnlinePlayMode::Ranked => Rating::get(&pool, message.user.uid.clone())
+                    .await
+                    .ok()
+                    .map(|rating| rating.rating),
+                _ => None,
+            };
+            let observed_address = get_peer_address(sender);
+            let lan_address = message.ip_address_lan.parse().ok();
+            let nat_type = NatType::classify(
+                lan_address,
+                observed_address,
+                nat::take_probe(&message.user.uid, observed_address.ip(), now),
+            );
             sender.set_data(Some(PeerData {
                 ticket: message.clone(),
-                joined_at: Utc::now().timestamp(),
+                joined_at: now,
+                last_queue_status_at: now,
+                preferences,
+                nat_type,
+                shadow_queued,
+                rating,
+                abandoned_opponents: vec![],
             }));
 
-            if !models::User::check_play_key(&pool, message.user.uid, message.user.play_key).await {
+            if !models::User::check_play_key(&pool, message.user.uid.clone(), message.user.play_key)
+                .await
+            {
                 println!(
                     "User {:?} failed play_key validation",
                     message.user.connect_code
                 );
                 sender.disconnect_later(0);
+            } else if !config.is_known_tenant(&message.tenant)
+                || Tenant::get_for_user(&pool, message.user.uid.clone())
+                    .await
+                    .ok()
+                    != Some(message.tenant.clone())
+            {
+                println!(
+                    "User {:?} is not registered in tenant {:?}",
+                    message.user.connect_code, message.tenant
+                );
+                sender.disconnect_later(0);
             } else {
-                match message.search.mode {
-                    OnlinePlayMode::Direct | OnlinePlayMode::Unranked => {
-                        sender
-                            .send_packet(
-                                Packet::new(
-                                    &serde_json::to_string(
-                                        &MatchmakingMessage::CreateTicketResponse {},
-                                    )
-                                    .unwrap()
-                                    .into_bytes(),
-                                    PacketMode::ReliableSequenced,
-                                )
-                                .unwrap(),
-                                ENET_CHANNEL_ID,
-                            )
-                            .unwrap();
+                let nat_report = NatReport::new(nat_type, lan_address);
+                if let Err(err) = nat_report.save(&pool, message.user.uid.clone()).await {
+                    println!(
+                        "Failed to save NAT type of {:?}: {}",
+                        message.user.connect_code, err
+                    );
+                }
+
+                if let Some(match_id) = &message.match_id {
+                    match reconnect_window.get(
+                        match_id,
+                        &message.user.uid,
+                        now,
+                        config.matchmaking_reconnect_window_seconds as i64,
+                    ) {
+                        Some(response) => {
+                            println!(
+                                "User {:?} reconnected to match {}",
+                                message.user.connect_code, match_id
+                            );
+                            send_message(sender, &response);
+                            // Done searching, like peers which were just matched
+                            sender.set_data(None);
+                            return;
+                        }
+                        None => println!(
+                            "User {:?} can't rejoin match {}, searching again",
+                            message.user.connect_code, match_id
+                        ),
                     }
-                    _ => {
-                        println!("Play mode {:?} not implemented", message.search.mode);
-                        sender.disconnect_later(0);
+                }
+
+                if let Some(error) =
+                    get_closed_queue_error(config, &message.tenant, message.search.mode, Utc::now())
+                {
+                    println!("User {:?}: {}", message.user.connect_code, error);
+                    send_message(
+                        sender,
+                        &MatchmakingMessage::CreateTicketResponse {
+                            error: Some(error),
+                            error_code: None,
+                            message: None,
+                        },
+                    );
+                    sender.disconnect_later(0);
+                    return;
+                }
+
+                let pause = QueuePause::get(&pool, message.search.mode)
+                    .await
+                    .unwrap_or_else(|err| {
+                        println!("Failed to check whether the queue is paused: {}", err);
+                        None
+                    });
+                if let Some(pause) = pause {
+                    println!(
+                        "User {:?} can't search, the {} queue is paused",
+                        message.user.connect_code, pause.mode
+                    );
+                    send_message(
+                        sender,
+                        &MatchmakingMessage::CreateTicketResponse {
+                            error: Some(pause.get_message()),
+                            error_code: Some(QUEUE_PAUSED_ERROR_CODE.to_string()),
+                            message: None,
+                        },
+                    );
+                    sender.disconnect_later(0);
+                    return;
+                }
+
+                if !queue_index.has_free_slot(false, config) {
+                    println!(
+                        "User {:?} can't search, every slot is taken",
+                        message.user.connect_code
+                    );
+                    telemetry::record_ticket_rejected();
+                    send_message(
+                        sender,
+                        &MatchmakingMessage::CreateTicketResponse {
+                            error: Some("The server is full, please try again later".to_string()),
+                            error_code: None,
+                            message: None,
+                        },
+                    );
+                    sender.disconnect_later(0);
+                    return;
+                }
+
+                // Players search for a specific opponent in Direct mode, so
+                // they can only be helped in the queues
+                if matches!(
+                    message.search.mode,
+                    OnlinePlayMode::Ranked | OnlinePlayMode::Unranked | OnlinePlayMode::Teams
+                ) {
+                    if let Some((match_id, opponents)) =
+                        abandoned_matches.take(&message.user.uid, now)
+                    {
+                        println!(
+                            "User {:?} couldn't play match {}, searching first",
+                            message.user.connect_code, match_id
+                        );
+                        if let Some(data) = sender.data_mut() {
+                            data.abandoned_opponents = opponents;
+                        }
                     }
                 }
+
+                queue_index.insert(
+                    observed_address,
+                    message.tenant.clone(),
+                    message.search.mode,
+                );
+                send_message(
+                    sender,
+                    &MatchmakingMessage::CreateTicketResponse {
+                        error: None,
+                        error_code: None,
+                        message: config
+                            .get_matchmaking_message(&message.tenant)
+                            .map(str::to_string),
+                    },
+                );
             }
         }
     }
 }
 
-fn handle_matchmaking(mode: OnlinePlayMode, peers: Vec<Peer<PeerData>>) {
-    let mut rng = thread_rng();
-    let mut matched_peers: Vec<Vec<Peer<PeerData>>> = vec![];
+// Spectators are checked like players searching, then hold one of the slots
+// kept for them until they disconnect
+async fn handle_spectate(
+    message: SpectatorMessage,
+    sender: &mut Peer<'_, PeerData>,
+    config: &Config,
+    pool: &SqlitePool,
+    queue_index: &mut QueueIndex,
+) {
+    let SpectatorMessage::Spectate {
+        uid,
+        play_key,
+        tenant,
+    } = message;
+    // Spectators don't search
+    sender.set_data(None);
 
-    if mode == OnlinePlayMode::Direct {
-        peers
-            .iter()
-            .group_by(|peer| {
-                let CreateTicket { user, search, .. } = &peer.data().unwrap().ticket;
-                vec![
-                    user.connect_code.clone(),
-                    search.connect_code.clone().unwrap(),
-                ]
-                .into_iter()
-                .collect::<HashSet<_>>()
-            })
-            .into_iter()
-            .for_each(|(_, peer_group)| {
-                let peer_vec = peer_group.collect_vec();
-                if peer_vec.len() > 1 {
-                    matched_peers.push(peer_vec.clone().into_iter().cloned().collect_vec());
-                }
-            });
+    let error = if !models::User::check_play_key(pool, uid.clone(), play_key).await {
+        Some("Invalid play key")
+    } else if !config.is_known_tenant(&tenant)
+        || Tenant::get_for_user(pool, uid.clone()).await.ok() != Some(tenant.clone())
+    {
+        Some("Not registered in this community")
+    } else if !queue_index.has_free_slot(true, config) {
+        telemetry::record_spectator_rejected();
+        Some("Every spectator slot is taken, please try again later")
+    } else {
+        None
+    };
+
+    match error {
+        Some(error) => {
+            println!("Spectator {} was turned away: {}", uid, error);
+            send_message(
+                sender,
+                &MatchmakingMessage::SpectateResponse {
+                    error: Some(error.to_string()),
+                },
+            );
+            sender.disconnect_later(0);
+        }
+        None => {
+            println!("Spectator {} joined tenant {:?}", uid, tenant);
+            telemetry::record_spectator_admitted();
+            queue_index.insert_spectator(get_peer_address(sender), tenant);
+            send_message(
+                sender,
+                &MatchmakingMessage::SpectateResponse { error: None },
+            );
+        }
+    }
+}
+
+// Every player of a match gets a response, only the host's is turned into a
+// notification so each match is only announced once
+fn get_match_formed(
+    mode: OnlinePlayMode,
+    response: &MatchmakingMessage,
+) -> Option<MatchmakingMessage> {
+    match response {
+        MatchmakingMessage::GetTicketResponse {
+            match_id,
+            is_host: true,
+            players,
+            ..
+        } => Some(MatchmakingMessage::MatchFormed {
+            match_id: match_id.clone(),
+            mode,
+            players: players
+                .iter()
+                .map(|player| SpectatedPlayer {
+                    display_name: player.display_name.clone(),
+                    connect_code: player.connect_code.clone(),
+                    port: player.port,
+                    team_id: player.team_id,
+                })
+                .collect(),
+        }),
+        _ => None,
+    }
+}
+
+fn notify_spectators(
+    host: &mut Host<PeerData>,
+    queue_index: &QueueIndex,
+    formed: &[(String, MatchmakingMessage)],
+) {
+    for mut peer in host.peers() {
+        let tenant = match queue_index.spectators.get(&get_peer_address(&peer)) {
+            Some(tenant) => tenant,
+            None => continue,
+        };
+        for (_, message) in formed.iter().filter(|(formed_in, _)| formed_in == tenant) {
+            send_message(&mut peer, message);
+        }
+    }
+}
+
+// Also true when events were missed, the broadcasts are looked up anyway
+fn has_new_broadcasts(app_events: &mut Receiver<events::Event>) -> bool {
+    let mut found = false;
+    loop {
+        match app_events.try_recv() {
+            Ok(events::Event::BroadcastCreated { .. }) | Err(TryRecvError::Lagged(_)) => {
+                found = true
+            }
+            Ok(_) => {}
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return found,
+        }
+    }
+}
+
+// Sent to every connected peer, whether searching, matched or spectating
+fn send_broadcasts(host: &mut Host<PeerData>, runtime: &Handle, pool: &SqlitePool, now: i64) {
+    let broadcasts = match runtime.block_on(Broadcast::get_undelivered(pool, now)) {
+        Ok(broadcasts) => broadcasts,
+        Err(err) => {
+            println!("Failed to load broadcasts: {}", err);
+            return;
+        }
+    };
+
+    for broadcast in broadcasts {
+        let message = MatchmakingMessage::Broadcast {
+            message: broadcast.message,
+        };
+        let mut delivered_count = 0;
+        for mut peer in host.peers() {
+            if peer.state() != PeerState::Connected {
+                continue;
+            }
+            send_message(&mut peer, &message);
+            delivered_count += 1;
+        }
+
+        println!(
+            "Sent broadcast {} to {} peers",
+            broadcast.id, delivered_count
+        );
+        if let Err(err) = runtime.block_on(Broadcast::record_delivery(
+            pool,
+            broadcast.id,
+            delivered_count,
+            now,
+        )) {
+            println!(
+                "Failed to record delivery of broadcast {}: {}",
+                broadcast.id, err
+            );
+        }
+    }
+}
+
+fn send_message(peer: &mut Peer<PeerData>, message: &MatchmakingMessage) {
+    peer.send_packet(
+        Packet::new(
+            &serde_json::to_string(message).unwrap().into_bytes(),
+            PacketMode::ReliableSequenced,
+        )
+        .unwrap(),
+        ENET_CHANNEL_ID,
+    )
+    .unwrap();
+}
+
+// Players searching outside of their queue's schedule are told when it
+// opens again
+fn get_closed_queue_error(
+    config: &Config,
+    tenant: &str,
+    mode: OnlinePlayMode,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let schedule = config.get_schedule(tenant, mode)?;
+    if schedule.is_open(now) {
+        return None;
     }
 
-    if mode == OnlinePlayMode::Unranked {
+    Some(match schedule.get_next_opening(now) {
+        Some(opening) => format!(
+            "The {} queue is closed until {}",
+            mode,
+            schedule.format_opening(opening)
+        ),
+        None => format!("The {} queue is closed", mode),
+    })
+}
+
+fn handle_matchmaking(
+    mode: OnlinePlayMode,
+    stages: Vec<Stage>,
+    peers: Vec<Peer<PeerData>>,
+    runtime: &Handle,
+    pool: &SqlitePool,
+    match_rate: &mut MatchRate,
+    config: &Config,
+) -> Vec<(SocketAddr, Uid, MatchmakingMessage)> {
+    let lan_mode = config.matchmaking_lan_mode;
+    let mut rng = thread_rng();
+    let mut matched_peers: Vec<Vec<Peer<PeerData>>> = vec![];
+    let mut responses = vec![];
+
+    if mode == OnlinePlayMode::Direct {
         peers
             .iter()
-            .sorted_by(|peer_a, peer_b| {
-                peer_b
-                    .data()
-                    .unwrap()
-                    .joined_at
-                    .partial_cmp(&peer_a.data().unwrap().joined_at)
-                    .unwrap()
+            .group_by(|peer| {
+                let CreateTicket { user, search, .. } = &peer.data().unwrap().ticket;
+                vec![
+                    user.connect_code.to_string(),
+                    search.connect_code.clone().unwrap(),
+                ]
+                .into_iter()
+                .collect::<HashSet<_>>()
             })
             .into_iter()
-            .chunks(2)
-            .into_iter()
-            .for_each(|peer_chunk| {
-                let peer_vec = peer_chunk.cloned().collect_vec();
+            .for_each(|(_, peer_group)| {
+                let peer_vec = peer_group.collect_vec();
                 if peer_vec.len() > 1 {
-                    matched_peers.push(peer_vec);
+                    matched_peers.push(peer_vec.clone().into_iter().cloned().collect_vec());
                 }
             });
     }
 
-    matched_peers.iter().for_each(|_peers| {
-        let mut randomized_peers = _peers.iter().cloned().collect_vec();
-        randomized_peers.shuffle(&mut rng);
+    if mode != OnlinePlayMode::Direct {
+        let now = Utc::now().timestamp();
+        let waiting_peers = peers
+            .iter()
+            .sorted_by_key(|peer| peer.data().unwrap().get_queue_order())
+            .collect_vec();
+        let candidates = waiting_peers
+            .iter()
+            .map(|peer| Candidate {
+                ip: *peer.address().ip(),
+                uid: &peer.data().unwrap().ticket.user.uid,
+                rtt_ms: peer.mean_rtt().as_millis() as u32,
+                waited_seconds: now - peer.data().unwrap().joined_at,
+                preferences: &peer.data().unwrap().preferences,
+                shadow_queued: peer.data().unwrap().shadow_queued,
+                rating: peer.data().unwrap().rating,
+                abandoned_opponents: &peer.data().unwrap().abandoned_opponents,
+            })
+            .collect_vec();
+
+        // The pairing algorithms only form pairs, Teams players are grouped
+        // by first fit whichever is configured
+        let groups = match mode {
+            OnlinePlayMode::Teams => group_candidates(&candidates, lan_mode),
+            _ => {
+                let pairs = pair_with(config.matchmaking_pairing_algorithm, &candidates, lan_mode);
+
+                // Shadow pairings are only logged, so algorithms can be
+                // compared on the real queue before switching
+                if let Some(shadow_algorithm) = config.matchmaking_shadow_pairing_algorithm {
+                    let shadow_pairs = pair_with(shadow_algorithm, &candidates, lan_mode);
+                    if shadow_pairs != pairs {
+                        println!(
+                            "Shadow pairing of {} waiting peers: {:?} would form {}, {:?} formed {}",
+                            candidates.len(),
+                            shadow_algorithm,
+                            PairingSummary::new(&candidates, &shadow_pairs),
+                            config.matchmaking_pairing_algorithm,
+                            PairingSummary::new(&candidates, &pairs),
+                        );
+                    }
+                }
+
+                pairs.into_iter().map(|(i, j)| vec![i, j]).collect_vec()
+            }
+        };
+
+        groups.into_iter().for_each(|group| {
+            matched_peers.push(
+                group
+                    .into_iter()
+                    .map(|i| waiting_peers[i].clone())
+                    .collect_vec(),
+            );
+        });
+    }
+
+    matched_peers.iter().for_each(|_peers| {
+        let mut randomized_peers = _peers.iter().cloned().collect_vec();
+        randomized_peers.shuffle(&mut rng);
+
+        // The first player hosts, sorting is stable so ties stay random
+        if config.matchmaking_host_selection == HostSelection::LowestLatency {
+            randomized_peers.sort_by_key(|peer| peer.mean_rtt().as_millis() / HOST_RTT_BUCKET_MS);
+        }
+
+        let set_game = if mode == OnlinePlayMode::Direct {
+            let player_uids = randomized_peers
+                .iter()
+                .map(|peer| peer.data().unwrap().ticket.user.uid.clone())
+                .collect_vec();
+            runtime.block_on(get_set_game(pool, player_uids, config))
+        } else {
+            None
+        };
+        let game_stages = match &set_game {
+            Some(set_game) => set_game.get_legal_stages(stages.clone()),
+            None => stages.clone(),
+        };
+
+        let messages = create_game(
+            randomized_peers
+                .clone()
+                .iter()
+                .map(|peer| {
+                    let data = peer.data().unwrap();
+                    (data.ticket.clone(), peer.address(), data.nat_type)
+                })
+                .collect(),
+            mode,
+            game_stages,
+            set_game.as_ref().map(|set_game| set_game.game),
+            randomized_peers
+                .first()
+                .and_then(|peer| peer.data())
+                .and_then(|data| config.get_matchmaking_message(&data.ticket.tenant))
+                .map(str::to_string),
+            lan_mode,
+        );
+
+        let formation = get_formation(&messages, &randomized_peers, mode, config);
+        runtime.block_on(save_match(
+            pool,
+            mode,
+            &messages,
+            set_game.as_ref(),
+            formation.as_ref(),
+        ));
+        let now = Utc::now().timestamp();
+        match_rate.record(mode, now);
+        telemetry::record_match_created();
+        for peer in &randomized_peers {
+            TICKET_MATCH_SECONDS.observe((now - peer.data().unwrap().joined_at) as f64);
+        }
+        for (peer, message) in randomized_peers.iter().zip(&messages) {
+            let uid = peer.data().unwrap().ticket.user.uid.clone();
+            responses.push((get_peer_address(peer), uid, message.clone()));
+        }
+
+        randomized_peers
+            .iter()
+            .zip(messages)
+            .map(|(_peer, message)| {
+                let peer = &mut _peer.clone();
+                let message_str = &serde_json::to_string(&message).unwrap();
+                println!(
+                    "Sending message to {:?}: \n{:?}",
+                    peer.data().unwrap().ticket.user.connect_code,
+                    message_str,
+                );
+                peer.send_packet(
+                    Packet::new(
+                        &message_str.clone().into_bytes(),
+                        PacketMode::ReliableSequenced,
+                    )
+                    .unwrap(),
+                    ENET_CHANNEL_ID,
+                )
+                .unwrap();
+                // Matched peers are done searching, don't pair them again
+                peer.set_data(None);
+            })
+            .for_each(drop);
+    });
+
+    responses
+}
+
+// Lets peers still searching after matchmaking know where they stand.
+// Direct mode peers wait for a specific opponent, so there is no queue.
+fn send_queue_status(
+    mode: OnlinePlayMode,
+    peers: Vec<Peer<PeerData>>,
+    match_rate: &mut MatchRate,
+    interval: i64,
+) {
+    if mode == OnlinePlayMode::Direct {
+        return;
+    }
+
+    let now = Utc::now().timestamp();
+
+    peers
+        .into_iter()
+        .filter(|peer| peer.data().is_some())
+        .sorted_by_key(|peer| peer.data().unwrap().get_queue_order())
+        .enumerate()
+        .filter(|(_, peer)| now - peer.data().unwrap().last_queue_status_at >= interval)
+        .for_each(|(i, mut peer)| {
+            let message = MatchmakingMessage::QueueStatus {
+                position: i + 1,
+                estimated_wait_seconds: match_rate.estimate_wait_seconds(mode, i + 1, now),
+            };
+
+            peer.data_mut().unwrap().last_queue_status_at = now;
+            peer.send_packet(
+                Packet::new(
+                    &serde_json::to_string(&message).unwrap().into_bytes(),
+                    PacketMode::ReliableSequenced,
+                )
+                .unwrap(),
+                ENET_CHANNEL_ID,
+            )
+            .unwrap();
+        });
+}
+
+// Direct matches between the same players are games of a set, which
+// decides the stages they can play on
+async fn get_set_game(
+    pool: &SqlitePool,
+    player_uids: Vec<Uid>,
+    config: &Config,
+) -> Option<SetGame> {
+    let best_of = config.matchmaking_direct_best_of as i64;
+
+    match SetGame::get_next(pool, &player_uids, best_of, Utc::now().timestamp()).await {
+        Ok(set_game) => Some(set_game),
+        Err(err) => {
+            println!("Failed to get the set of {:?}: {}", player_uids, err);
+            None
+        }
+    }
+}
+
+// Who was matched, after how long, how far from the server and on which
+// stages, for the stats
+fn get_formation(
+    messages: &[MatchmakingMessage],
+    peers: &[Peer<PeerData>],
+    mode: OnlinePlayMode,
+    config: &Config,
+) -> Option<MatchFormation> {
+    let (match_id, stages) = match messages.first() {
+        Some(MatchmakingMessage::GetTicketResponse {
+            match_id, stages, ..
+        }) => (match_id, stages),
+        _ => return None,
+    };
+    let now = Utc::now().timestamp();
+
+    Some(MatchFormation {
+        match_id: match_id.clone(),
+        tenant_id: peers.first()?.data()?.ticket.tenant.clone(),
+        mode: mode.to_string(),
+        algorithm: match mode {
+            OnlinePlayMode::Direct => "direct".to_string(),
+            _ => config.matchmaking_pairing_algorithm.to_string(),
+        },
+        algorithm_version: PAIRING_VERSION,
+        stages: stages.clone(),
+        players: peers
+            .iter()
+            .filter_map(|peer| Some((peer.data()?, peer.mean_rtt())))
+            .map(|(data, rtt)| FormedPlayer {
+                uid: data.ticket.user.uid.clone(),
+                waited_seconds: now - data.joined_at,
+                rtt_ms: rtt.as_millis() as i64,
+            })
+            .collect(),
+        created_at: now,
+    })
+}
+
+// Matches are stored so that results reported for them can be checked
+// against the players that were actually paired.
+async fn save_match(
+    pool: &SqlitePool,
+    mode: OnlinePlayMode,
+    messages: &[MatchmakingMessage],
+    set_game: Option<&SetGame>,
+    formation: Option<&MatchFormation>,
+) {
+    if let Some(MatchmakingMessage::GetTicketResponse {
+        match_id, players, ..
+    }) = messages.first()
+    {
+        let players = players
+            .iter()
+            .map(|player| (player.uid.clone(), player.port))
+            .collect();
+
+        if let Err(err) = Match::create(pool, match_id.clone(), mode, players).await {
+            println!("Failed to save match {}: {}", match_id, err);
+            return;
+        }
+        if let Some(set_game) = set_game {
+            if let Err(err) = set_game.save(pool, match_id.clone()).await {
+                println!("Failed to save the set of match {}: {}", match_id, err);
+            }
+        }
+        if let Some(formation) = formation {
+            if let Err(err) = formation.save(pool).await {
+                println!(
+                    "Failed to save the formation of match {}: {}",
+                    match_id, err
+                );
+            }
+        }
+    }
+}
+
+struct Candidate<'a> {
+    ip: Ipv4Addr,
+    uid: &'a Uid,
+    rtt_ms: u32,
+    waited_seconds: i64,
+    preferences: &'a models::MatchmakingPreferences,
+    shadow_queued: bool,
+    rating: Option<f64>,
+    abandoned_opponents: &'a [Uid],
+}
+
+impl Candidate<'_> {
+    fn has_priority(&self) -> bool {
+        !self.abandoned_opponents.is_empty()
+    }
+
+    // Players aren't paired again with an opponent of a match they couldn't
+    // play, whichever of them came back
+    fn can_face(&self, other: &Candidate) -> bool {
+        !self.abandoned_opponents.contains(other.uid)
+            && !other.abandoned_opponents.contains(self.uid)
+    }
+
+    fn accepts_ping(&self, ping_ms: u32) -> bool {
+        match self.preferences.max_ping_ms {
+            None => true,
+            Some(max_ping_ms) if self.preferences.strict => ping_ms <= max_ping_ms,
+            Some(max_ping_ms) => {
+                let relaxed_by =
+                    PING_RELAX_STEP_MS * (self.waited_seconds / PING_RELAX_INTERVAL_SECONDS) as u32;
+                ping_ms <= max_ping_ms + relaxed_by
+            }
+        }
+    }
+
+    // The band is that of whoever waited longest, so players far from
+    // everyone else are eventually matched
+    fn is_within_rating_band(&self, other: &Candidate) -> bool {
+        match (self.rating, other.rating) {
+            (Some(rating), Some(other_rating)) => {
+                let waited_seconds = self.waited_seconds.max(other.waited_seconds);
+                (rating - other_rating).abs() <= get_rating_band(waited_seconds)
+            }
+            _ => true,
+        }
+    }
+}
+
+// Peers' latency to each other isn't known, but can't be worse than going
+// through the server, so their round-trip times to it are used as an estimate.
+fn estimate_ping_ms(a: &Candidate, b: &Candidate) -> u32 {
+    a.rtt_ms + b.rtt_ms
+}
+
+// Candidates sharing a public IP are most likely the same player running two
+// clients, and are only matched when allowed. Shadow queued candidates are
+// only matched with each other, candidates who couldn't play their last
+// match aren't matched with its opponents, and Ranked candidates are only
+// matched within their rating band.
+fn can_match(a: &Candidate, b: &Candidate, allow_same_ip: bool) -> bool {
+    let ping_ms = estimate_ping_ms(a, b);
+    (allow_same_ip || a.ip != b.ip)
+        && a.shadow_queued == b.shadow_queued
+        && a.can_face(b)
+        && a.is_within_rating_band(b)
+        && a.accepts_ping(ping_ms)
+        && b.accepts_ping(ping_ms)
+}
+
+// Pairs each candidate, in order, with the first following one that both
+// accept the estimated ping of. Returns the indices of paired candidates.
+fn pair_candidates(candidates: &[Candidate], allow_same_ip: bool) -> Vec<(usize, usize)> {
+    let mut paired = vec![false; candidates.len()];
+    let mut pairs = vec![];
+
+    for i in 0..candidates.len() {
+        if paired[i] {
+            continue;
+        }
+
+        let opponent = (i + 1..candidates.len())
+            .find(|&j| !paired[j] && can_match(&candidates[i], &candidates[j], allow_same_ip));
+
+        if let Some(j) = opponent {
+            paired[i] = true;
+            paired[j] = true;
+            pairs.push((i, j));
+        }
+    }
+
+    pairs
+}
+
+// Pairs candidates with the lowest estimated ping first, rather than
+// favoring those who waited longest. Pairs including a candidate who
+// couldn't play their last match still come before the others.
+fn pair_closest_candidates(candidates: &[Candidate], allow_same_ip: bool) -> Vec<(usize, usize)> {
+    let acceptable_pairs = (0..candidates.len())
+        .tuple_combinations()
+        .filter(|&(i, j)| can_match(&candidates[i], &candidates[j], allow_same_ip))
+        .sorted_by_key(|&(i, j)| {
+            (
+                !(candidates[i].has_priority() || candidates[j].has_priority()),
+                estimate_ping_ms(&candidates[i], &candidates[j]),
+                i,
+                j,
+            )
+        });
+
+    let mut paired = vec![false; candidates.len()];
+    let mut pairs = vec![];
+
+    for (i, j) in acceptable_pairs {
+        if !paired[i] && !paired[j] {
+            paired[i] = true;
+            paired[j] = true;
+            pairs.push((i, j));
+        }
+    }
+
+    pairs
+}
+
+// Groups each candidate, in order, with the first following ones that every
+// member of the group can be matched with, as all four players of a Teams
+// match connect to each other. Returns the indices of grouped candidates.
+fn group_candidates(candidates: &[Candidate], allow_same_ip: bool) -> Vec<Vec<usize>> {
+    let mut grouped = vec![false; candidates.len()];
+    let mut groups = vec![];
+
+    for i in 0..candidates.len() {
+        if grouped[i] {
+            continue;
+        }
+
+        let mut group = vec![i];
+        for j in i + 1..candidates.len() {
+            if group.len() == TEAMS_GROUP_SIZE {
+                break;
+            }
+            if !grouped[j]
+                && group
+                    .iter()
+                    .all(|&k| can_match(&candidates[k], &candidates[j], allow_same_ip))
+            {
+                group.push(j);
+            }
+        }
+
+        if group.len() == TEAMS_GROUP_SIZE {
+            group.iter().for_each(|&k| grouped[k] = true);
+            groups.push(group);
+        }
+    }
+
+    groups
+}
+
+fn pair_with(
+    algorithm: PairingAlgorithm,
+    candidates: &[Candidate],
+    allow_same_ip: bool,
+) -> Vec<(usize, usize)> {
+    match algorithm {
+        PairingAlgorithm::FirstFit => pair_candidates(candidates, allow_same_ip),
+        PairingAlgorithm::ClosestPing => pair_closest_candidates(candidates, allow_same_ip),
+    }
+}
+
+// What a set of pairs means for the players, to compare pairing algorithms
+#[derive(Debug, PartialEq, Eq)]
+struct PairingSummary {
+    pairs: usize,
+    mean_ping_ms: Option<u32>,
+    longest_wait_seconds: Option<i64>,
+}
+
+impl PairingSummary {
+    fn new(candidates: &[Candidate], pairs: &[(usize, usize)]) -> PairingSummary {
+        let pings = pairs
+            .iter()
+            .map(|&(i, j)| estimate_ping_ms(&candidates[i], &candidates[j]))
+            .collect_vec();
+
+        PairingSummary {
+            pairs: pairs.len(),
+            mean_ping_ms: (!pings.is_empty())
+                .then(|| pings.iter().sum::<u32>() / pings.len() as u32),
+            // Of the players left waiting
+            longest_wait_seconds: candidates
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| !pairs.iter().any(|&(i, j)| i == *k || j == *k))
+                .map(|(_, candidate)| candidate.waited_seconds)
+                .max(),
+        }
+    }
+}
+
+impl std::fmt::Display for PairingSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} pairs", self.pairs)?;
+        if let Some(mean_ping_ms) = self.mean_ping_ms {
+            write!(f, " with a mean ping of {} ms", mean_ping_ms)?;
+        }
+        if let Some(longest_wait_seconds) = self.longest_wait_seconds {
+            write!(
+                f,
+                ", leaving a player waiting for {} s",
+                longest_wait_seconds
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// In LAN mode, players behind the same public IP are told to connect to each
+// other's LAN address instead, as their router may not support hairpinning.
+fn create_game(
+    _players: Vec<(CreateTicket, Address, NatType)>,
+    mode: OnlinePlayMode,
+    stages: Vec<Stage>,
+    set_game: Option<i64>,
+    message: Option<String>,
+    lan_mode: bool,
+) -> Vec<MatchmakingMessage> {
+    let use_lan_addresses = lan_mode
+        && _players
+            .iter()
+            .map(|(_, address, _)| *address.ip())
+            .all_equal();
+    let match_id = MatchId::generate(mode);
+    let ports = ControllerPort::get_ports(mode);
+
+    _players
+        .iter()
+        .enumerate()
+        .map(|(i, _)| MatchmakingMessage::GetTicketResponse {
+            latest_version: LATEST_SLIPPI_CLIENT_VERSION.to_string(),
+            match_id: match_id.clone(),
+            is_host: i == 0,
+            is_assigned: true,
+            players: _players
+                .clone()
+                .iter()
+                .enumerate()
+                .map(|(j, (_ticket, _address, _nat_type))| {
+                    Player::new(
+                        _ticket.clone(),
+                        _address.clone(),
+                        *_nat_type,
+                        i == j,
+                        *ports.get(j).unwrap(),
+                        ports.get(j).unwrap().get_team(mode),
+                        use_lan_addresses,
+                    )
+                })
+                .collect(),
+            stages: stages.clone(),
+            set_game,
+            message: message.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::hash_map::Entry::{Occupied, Vacant};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    use once_cell::sync::Lazy;
+    use openmelee::tenants::DEFAULT_TENANT;
+    use proptest::prelude::*;
+    use rand::Rng;
+
+    use crate::matchmaking::*;
+
+    #[test]
+    fn can_parse_create_ticket_direct_message() {
+        let CreateTicket {
+            app_version,
+            search,
+            ..
+        } = serde_json::from_str(
+            r#"
+            {
+                "type": "create-ticket",
+                "appVersion": "2.5.1",
+                "ipAddressLan": "127.0.0.2:50285",
+                "search": {
+                    "connectCode": [130, 115, 130, 100, 130, 114, 130, 115, 129, 148, 130, 79, 130, 79, 130, 81],
+                    "mode": 2
+                },
+                "user": {
+                    "connectCode": "TEST#001",
+                    "displayName": "test",
+                    "playKey": "1",
+                    "uid": "1"
+                }
+            }
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(app_version, "2.5.1");
+        assert_eq!(search.connect_code.as_ref().unwrap(), "TEST#002");
+    }
+
+    // Clients send connect codes as Shift-JIS, with full-width Latin
+    // letters and digits
+    fn to_full_width(connect_code: &str) -> String {
+        connect_code
+            .chars()
+            .map(|c| match c {
+                '!'..='~' => char::from_u32(c as u32 + 0xfee0).unwrap(),
+                c => c,
+            })
+            .collect()
+    }
+
+    fn parse_search(connect_code: &str) -> Search {
+        let (bytes, _, had_errors) = SHIFT_JIS.encode(connect_code);
+        assert!(!had_errors, "{} can't be encoded", connect_code);
+
+        serde_json::from_value(serde_json::json!({
+            "connectCode": bytes.to_vec(),
+            "mode": 2,
+        }))
+        .unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn full_width_connect_codes_are_decoded(
+            prefix in "[A-Z0-9]{1,4}",
+            discriminant in "[0-9]{1,3}",
+        ) {
+            let connect_code = format!("{}#{}", prefix, discriminant);
+            let search = parse_search(&to_full_width(&connect_code));
+
+            prop_assert_eq!(search.connect_code, Some(connect_code));
+        }
+
+        // Kana Shift-JIS can encode, which excludes a few rare forms
+        #[test]
+        fn kana_connect_codes_are_decoded(
+            prefix in "[ぁ-んァ-ヶ]{1,4}",
+            discriminant in "[0-9]{1,3}",
+        ) {
+            let connect_code = format!("{}#{}", prefix, discriminant);
+            let search = parse_search(&to_full_width(&connect_code));
+
+            prop_assert!(openmelee::models::User::is_valid_connect_code(
+                search.connect_code.as_ref().unwrap()
+            ));
+            prop_assert_eq!(search.connect_code, Some(connect_code));
+        }
+    }
+
+    #[test]
+    fn can_parse_create_ticket_unranked_message() {
+        let CreateTicket { app_version, .. } = serde_json::from_str(
+            r#"
+            {
+                "type": "create-ticket",
+                "appVersion": "2.5.1",
+                "ipAddressLan": "127.0.0.2:51000",
+                "search": {
+                    "mode": 1
+                },
+                "user": {
+                    "connectCode": "TEST#001",
+                    "displayName": "test",
+                    "playKey": "1",
+                    "uid": "1"
+                }
+            }
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(app_version, "2.5.1");
+    }
+
+    #[test]
+    fn can_serialize_get_ticket_response_message() {
+        let message = MatchmakingMessage::GetTicketResponse {
+            latest_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
+            match_id: MatchId::generate(OnlinePlayMode::Direct),
+            is_host: false,
+            is_assigned: true,
+            players: vec![Player {
+                is_local_player: false,
+                uid: "1".parse().unwrap(),
+                display_name: String::from("test"),
+                connect_code: "TEST#001".parse().unwrap(),
+                ip_address: String::from("127.0.0.1:48593"),
+                ip_address_lan: String::from("127.0.0.1:48593"),
+                port: ControllerPort::One,
+                team_id: None,
+                nat_type: NatType::Open,
+            }],
+            stages: Stage::get_allowed_stages(OnlinePlayMode::Direct, &[]),
+            set_game: Some(1),
+            message: Some("Ranked resets Sunday".to_string()),
+        };
+
+        assert!(serde_json::to_string(&message)
+            .unwrap()
+            .contains(r#""message":"Ranked resets Sunday""#));
+    }
+
+    #[test]
+    fn can_serialize_paused_queue_response_message() {
+        let message = MatchmakingMessage::CreateTicketResponse {
+            error: Some("The ranked queue is temporarily unavailable".to_string()),
+            error_code: Some(QUEUE_PAUSED_ERROR_CODE.to_string()),
+            message: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"create-ticket-resp","error":"The ranked queue is temporarily unavailable","errorCode":"queue-paused"}"#
+        );
+    }
+
+    #[test]
+    fn can_serialize_broadcast_message() {
+        let message = MatchmakingMessage::Broadcast {
+            message: "Restarting in 5 minutes".to_string(),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"broadcast","message":"Restarting in 5 minutes"}"#
+        );
+    }
+
+    #[test]
+    fn test_only_broadcast_events_are_picked_up() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(2);
+        assert!(!has_new_broadcasts(&mut receiver));
+
+        sender
+            .send(events::Event::MatchConfirmed {
+                match_id: MatchId::generate(OnlinePlayMode::Ranked),
+                winner_uids: vec![],
+                player_uids: vec![],
+            })
+            .unwrap();
+        assert!(!has_new_broadcasts(&mut receiver));
+
+        sender
+            .send(events::Event::BroadcastCreated { id: 1 })
+            .unwrap();
+        assert!(has_new_broadcasts(&mut receiver));
+        assert!(!has_new_broadcasts(&mut receiver));
+
+        for id in 0..3 {
+            sender.send(events::Event::BroadcastCreated { id }).unwrap();
+        }
+        assert!(has_new_broadcasts(&mut receiver));
+    }
+
+    #[test]
+    fn can_serialize_queue_status_message() {
+        let message = MatchmakingMessage::QueueStatus {
+            position: 2,
+            estimated_wait_seconds: Some(30),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"queue-status","position":2,"estimatedWaitSeconds":30}"#
+        );
+    }
+
+    #[test]
+    fn test_check_packet() {
+        let config = Config {
+            matchmaking_max_packet_bytes: 64,
+            matchmaking_max_json_depth: 2,
+            matchmaking_max_json_string_bytes: 8,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            check_packet(br#"{"user":{"uid":"1"},"search":[1,2]}"#, &config),
+            Ok(())
+        );
+        // Escaped quotes and brackets within strings don't end them
+        assert_eq!(check_packet(br#"{"a":"\"[[[\""}"#, &config), Ok(()));
+        assert_eq!(
+            check_packet(br#"{"a":[[1]]}"#, &config),
+            Err("nested deeper than 2 levels".to_string())
+        );
+        assert_eq!(
+            check_packet(br#"{"a":"123456789"}"#, &config),
+            Err("string longer than 8 bytes".to_string())
+        );
+        assert_eq!(
+            check_packet(&[b' '; 65], &config),
+            Err("packet of 65 bytes is larger than 64".to_string())
+        );
+    }
+
+    #[test]
+    fn test_host_limits_default_to_enet_maximums() {
+        let config = Config::default();
+        assert!(matches!(get_channel_limit(&config), ChannelLimit::Maximum));
+        assert!(matches!(
+            get_bandwidth_limit(config.matchmaking_incoming_bandwidth_bytes),
+            BandwidthLimit::Unlimited
+        ));
+
+        let config = Config {
+            matchmaking_channel_limit: Some(1),
+            matchmaking_outgoing_bandwidth_bytes: Some(125_000),
+            ..Config::default()
+        };
+        assert!(matches!(
+            get_channel_limit(&config),
+            ChannelLimit::Limited(1)
+        ));
+        assert!(matches!(
+            get_bandwidth_limit(config.matchmaking_outgoing_bandwidth_bytes),
+            BandwidthLimit::Limited(125_000)
+        ));
+    }
+
+    #[test]
+    fn test_ticket_limiter() {
+        let mut limiter = TicketLimiter::default();
+        let address = SocketAddr::from((Ipv4Addr::new(203, 0, 113, 7), 50000));
+        let other = SocketAddr::from((Ipv4Addr::new(203, 0, 113, 8), 50000));
+        let check = |limiter: &mut TicketLimiter, address, ticket: &str, now| {
+            limiter.check(address, ticket.as_bytes(), now, 3)
+        };
+
+        assert_eq!(
+            check(&mut limiter, address, "a", 0),
+            TicketVerdict::Accepted
+        );
+        assert_eq!(
+            check(&mut limiter, address, "a", 1),
+            TicketVerdict::Duplicate
+        );
+        assert_eq!(
+            check(&mut limiter, address, "b", 2),
+            TicketVerdict::Accepted
+        );
+        assert_eq!(check(&mut limiter, other, "a", 2), TicketVerdict::Accepted);
+        assert_eq!(
+            check(&mut limiter, address, "c", 3),
+            TicketVerdict::RateExceeded
+        );
+
+        // Older submissions stop counting once out of the window
+        assert_eq!(
+            check(&mut limiter, address, "b", 61),
+            TicketVerdict::Accepted
+        );
+        assert_eq!(
+            check(
+                &mut limiter,
+                address,
+                "b",
+                61 + DUPLICATE_TICKET_WINDOW_SECONDS
+            ),
+            TicketVerdict::Accepted
+        );
+
+        limiter.remove(address);
+        assert!(!limiter.history.contains_key(&address));
+    }
+
+    #[test]
+    fn test_peer_reaper() {
+        let mut reaper = PeerReaper::default();
+        let address = SocketAddr::from((Ipv4Addr::new(203, 0, 113, 7), 50000));
+        let other = SocketAddr::from((Ipv4Addr::new(203, 0, 113, 8), 50000));
+        let check = |reaper: &mut PeerReaper, address, state, has_ticket, now| {
+            reaper.check(address, state, has_ticket, now, 30)
+        };
+
+        assert_eq!(
+            check(&mut reaper, address, PeerState::Connected, false, 0),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, address, PeerState::Connected, false, 29),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, address, PeerState::Connected, false, 30),
+            Some(DisconnectReason::NoTicket)
+        );
+
+        // Peers which don't acknowledge the disconnection get as long again
+        assert_eq!(
+            check(&mut reaper, address, PeerState::DisconnectLater, false, 31),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, address, PeerState::DisconnectLater, false, 60),
+            Some(DisconnectReason::Unreachable)
+        );
+
+        // Searching resets the timeout
+        assert_eq!(
+            check(&mut reaper, other, PeerState::Connected, false, 0),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, other, PeerState::Connected, true, 20),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, other, PeerState::Connected, false, 40),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, other, PeerState::Connected, true, 100),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, other, PeerState::Zombie, true, 110),
+            None
+        );
+        assert_eq!(
+            check(&mut reaper, other, PeerState::Zombie, true, 140),
+            Some(DisconnectReason::Unreachable)
+        );
+
+        assert_eq!(
+            check(&mut reaper, address, PeerState::Disconnected, false, 200),
+            None
+        );
+        reaper.remove(other);
+        assert!(reaper.stale_since.is_empty());
+    }
+
+    // ENet can only be initialized once per process
+    static ENET: Lazy<Enet> = Lazy::new(|| Enet::new().unwrap());
+
+    fn searching_peer_data(i: usize, mode: OnlinePlayMode) -> PeerData {
+        PeerData {
+            ticket: CreateTicket {
+                app_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
+                ip_address_lan: address(i).to_string(),
+                search: Search {
+                    mode,
+                    connect_code: None,
+                },
+                user: User {
+                    uid: i.to_string().parse().unwrap(),
+                    play_key: "5678".parse().unwrap(),
+                    display_name: String::from("test"),
+                    connect_code: format!("TEST#{}", i).parse().unwrap(),
+                },
+                tenant: DEFAULT_TENANT.to_string(),
+                match_id: None,
+            },
+            joined_at: 0,
+            last_queue_status_at: 0,
+            preferences: models::MatchmakingPreferences::default(),
+            nat_type: NatType::Unknown,
+            shadow_queued: false,
+            rating: None,
+            abandoned_opponents: vec![],
+        }
+    }
+
+    #[test]
+    fn test_unreachable_peers_are_reaped_without_their_ticket() {
+        let config = Config::default();
+        let mut host = ENET
+            .create_host::<PeerData>(
+                None,
+                1,
+                ChannelLimit::Maximum,
+                BandwidthLimit::Unlimited,
+                BandwidthLimit::Unlimited,
+            )
+            .unwrap();
+        // Never serviced, so the peer stays connecting
+        let mut peer = host
+            .connect(&Address::new(Ipv4Addr::LOCALHOST, 1), 1, 0)
+            .unwrap();
+        peer.set_data(Some(searching_peer_data(1, OnlinePlayMode::Unranked)));
+
+        let mut peer_reaper = PeerReaper::default();
+        let mut queue_index = QueueIndex::default();
+        let mut ticket_limiter = TicketLimiter::default();
+        let timeout_seconds = config.matchmaking_stale_peer_timeout_seconds as i64;
+        for now in [0, timeout_seconds] {
+            reap_stale_peers(
+                &mut host,
+                &config,
+                &mut peer_reaper,
+                &mut queue_index,
+                &mut ticket_limiter,
+                now,
+            );
+        }
+
+        let peer = host.peers().next().unwrap();
+        assert_eq!(peer.state(), PeerState::Disconnected);
+        assert!(peer.data().is_none());
+    }
+
+    #[test]
+    fn test_reconnect_window() {
+        let mut reconnect_window = ReconnectWindow::default();
+        let response = |match_id: &str| MatchmakingMessage::GetTicketResponse {
+            latest_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
+            match_id: match_id.parse().unwrap(),
+            is_host: true,
+            is_assigned: true,
+            players: vec![],
+            stages: vec![Stage::Battlefield],
+            set_game: None,
+            message: None,
+        };
+        reconnect_window.record("1".parse().unwrap(), &response("mode.unranked-1"), 100);
+        reconnect_window.record("2".parse().unwrap(), &response("mode.unranked-2"), 150);
+
+        assert_eq!(
+            reconnect_window.get(
+                &"mode.unranked-1".parse().unwrap(),
+                &"1".parse().unwrap(),
+                219,
+                120
+            ),
+            Some(response("mode.unranked-1"))
+        );
+        assert_eq!(
+            reconnect_window.get(
+                &"mode.unranked-1".parse().unwrap(),
+                &"1".parse().unwrap(),
+                220,
+                120
+            ),
+            None
+        );
+        // Only the players of the match can rejoin it
+        assert_eq!(
+            reconnect_window.get(
+                &"mode.unranked-1".parse().unwrap(),
+                &"2".parse().unwrap(),
+                150,
+                120
+            ),
+            None
+        );
+        assert_eq!(
+            reconnect_window.get(
+                &"mode.unranked-3".parse().unwrap(),
+                &"1".parse().unwrap(),
+                150,
+                120
+            ),
+            None
+        );
+
+        reconnect_window.expire(220, 120);
+        assert_eq!(reconnect_window.responses.len(), 1);
+        assert!(reconnect_window
+            .get(
+                &"mode.unranked-2".parse().unwrap(),
+                &"2".parse().unwrap(),
+                220,
+                120
+            )
+            .is_some());
+    }
+
+    #[test]
+    fn test_closed_queues_tell_when_they_open() {
+        let config = Config {
+            matchmaking_schedules: vec![openmelee::schedule::QueueSchedule {
+                mode: "unranked".to_string(),
+                timezone: "UTC".to_string(),
+                windows: vec!["Mon-Fri 18:00-23:00".to_string()],
+            }],
+            ..Config::default()
+        };
+        // On a Monday
+        let error_at = |mode, time: &str| {
+            let now = DateTime::parse_from_rfc3339(&format!("2022-10-24T{}Z", time))
+                .unwrap()
+                .with_timezone(&Utc);
+            get_closed_queue_error(&config, DEFAULT_TENANT, mode, now)
+        };
+
+        assert_eq!(error_at(OnlinePlayMode::Unranked, "19:00:00"), None);
+        assert_eq!(error_at(OnlinePlayMode::Direct, "12:00:00"), None);
+
+        let error = error_at(OnlinePlayMode::Unranked, "12:00:00");
+        assert_eq!(
+            error,
+            Some("The unranked queue is closed until Mon 2022-10-24 18:00 (UTC)".to_string())
+        );
+        assert_eq!(
+            serde_json::to_string(&MatchmakingMessage::CreateTicketResponse {
+                error,
+                error_code: None,
+                message: None
+            })
+            .unwrap(),
+            r#"{"type":"create-ticket-resp","error":"The unranked queue is closed until Mon 2022-10-24 18:00 (UTC)"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&MatchmakingMessage::CreateTicketResponse {
+                error: None,
+                error_code: None,
+                message: None
+            })
+            .unwrap(),
+            r#"{"type":"create-ticket-resp"}"#
+        );
+    }
+
+    #[test]
+    fn test_match_rate_estimates_wait_from_recent_matches() {
+        let mut match_rate = MatchRate::default();
+
+        assert_eq!(
+            match_rate.estimate_wait_seconds(OnlinePlayMode::Unranked, 1, 1000),
+            None
+        );
+
+        match_rate.record(
+            OnlinePlayMode::Unranked,
+            1000 - MATCH_RATE_WINDOW_SECONDS - 1,
+        );
+        for _ in 0..10 {
+            match_rate.record(OnlinePlayMode::Unranked, 1000);
+        }
+
+        assert_eq!(
+            match_rate.estimate_wait_seconds(OnlinePlayMode::Unranked, 1, 1000),
+            Some(60)
+        );
+        assert_eq!(
+            match_rate.estimate_wait_seconds(OnlinePlayMode::Unranked, 3, 1000),
+            Some(120)
+        );
+        assert_eq!(
+            match_rate.estimate_wait_seconds(OnlinePlayMode::Teams, 1, 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pair_candidates_honors_ping_preferences() {
+        let uid = "1".parse::<Uid>().unwrap();
+        let no_limit = models::MatchmakingPreferences::default();
+        let strict = models::MatchmakingPreferences {
+            max_ping_ms: Some(50),
+            strict: true,
+        };
+        let relaxed = models::MatchmakingPreferences {
+            max_ping_ms: Some(50),
+            strict: false,
+        };
+        let candidate = |rtt_ms, waited_seconds, preferences| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid: &uid,
+            rtt_ms,
+            waited_seconds,
+            preferences,
+            shadow_queued: false,
+            rating: None,
+            abandoned_opponents: &[],
+        };
+
+        assert_eq!(
+            pair_candidates(
+                &[
+                    candidate(40, 0, &strict),
+                    candidate(40, 0, &no_limit),
+                    candidate(10, 0, &no_limit),
+                ],
+                true
+            ),
+            vec![(0, 2)]
+        );
+        assert_eq!(
+            pair_candidates(
+                &[
+                    candidate(40, 0, &no_limit),
+                    candidate(10, 0, &no_limit),
+                    candidate(40, 0, &no_limit),
+                ],
+                true
+            ),
+            vec![(0, 1)]
+        );
+        assert_eq!(
+            pair_candidates(
+                &[candidate(40, 0, &relaxed), candidate(40, 0, &no_limit)],
+                true
+            ),
+            vec![]
+        );
+        assert_eq!(
+            pair_candidates(
+                &[
+                    candidate(40, 2 * PING_RELAX_INTERVAL_SECONDS, &relaxed),
+                    candidate(40, 0, &no_limit)
+                ],
+                true
+            ),
+            vec![(0, 1)]
+        );
+        assert_eq!(
+            pair_candidates(
+                &[
+                    candidate(40, 2 * PING_RELAX_INTERVAL_SECONDS, &strict),
+                    candidate(40, 0, &no_limit)
+                ],
+                true
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_pair_candidates_only_pairs_same_ip_when_allowed() {
+        let uid = "1".parse::<Uid>().unwrap();
+        let preferences = models::MatchmakingPreferences::default();
+        let candidates = [
+            Candidate {
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+                uid: &uid,
+                rtt_ms: 10,
+                waited_seconds: 0,
+                preferences: &preferences,
+                shadow_queued: false,
+                rating: None,
+                abandoned_opponents: &[],
+            },
+            Candidate {
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+                uid: &uid,
+                rtt_ms: 10,
+                waited_seconds: 0,
+                preferences: &preferences,
+                shadow_queued: false,
+                rating: None,
+                abandoned_opponents: &[],
+            },
+        ];
+
+        assert_eq!(pair_candidates(&candidates, false), vec![]);
+        assert_eq!(pair_candidates(&candidates, true), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_shadow_queued_candidates_are_only_paired_together() {
+        let uid = "1".parse::<Uid>().unwrap();
+        let preferences = models::MatchmakingPreferences::default();
+        let candidate = |rtt_ms, shadow_queued| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid: &uid,
+            rtt_ms,
+            waited_seconds: 0,
+            preferences: &preferences,
+            shadow_queued,
+            rating: None,
+            abandoned_opponents: &[],
+        };
+        let candidates = [
+            candidate(10, true),
+            candidate(10, false),
+            candidate(50, false),
+            candidate(90, true),
+        ];
+
+        for algorithm in [PairingAlgorithm::FirstFit, PairingAlgorithm::ClosestPing] {
+            let mut pairs = pair_with(algorithm, &candidates, true);
+            pairs.sort_unstable();
+            assert_eq!(pairs, vec![(0, 3), (1, 2)], "{:?}", algorithm);
+        }
+        assert_eq!(pair_candidates(&candidates[..2], true), vec![]);
+    }
+
+    #[test]
+    fn test_ranked_candidates_are_paired_within_their_rating_band() {
+        let uid = "1".parse::<Uid>().unwrap();
+        let preferences = models::MatchmakingPreferences::default();
+        let candidate = |waited_seconds, rating| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid: &uid,
+            rtt_ms: 10,
+            waited_seconds,
+            preferences: &preferences,
+            shadow_queued: false,
+            rating: Some(rating),
+            abandoned_opponents: &[],
+        };
+        let candidates = [
+            candidate(0, 1500.0),
+            candidate(0, 1800.0),
+            candidate(0, 1550.0),
+        ];
+
+        for algorithm in [PairingAlgorithm::FirstFit, PairingAlgorithm::ClosestPing] {
+            assert_eq!(
+                pair_with(algorithm, &candidates, true),
+                vec![(0, 2)],
+                "{:?}",
+                algorithm
+            );
+        }
+
+        // The band widens while they wait
+        let candidates = [candidate(5 * 60, 1500.0), candidate(0, 1800.0)];
+        assert_eq!(pair_candidates(&candidates, true), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_closest_ping_pairs_lowest_pings_first() {
+        let uid = "1".parse::<Uid>().unwrap();
+        let preferences = models::MatchmakingPreferences::default();
+        let candidate = |rtt_ms, waited_seconds| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid: &uid,
+            rtt_ms,
+            waited_seconds,
+            preferences: &preferences,
+            shadow_queued: false,
+            rating: None,
+            abandoned_opponents: &[],
+        };
+        let candidates = [
+            candidate(80, 30),
+            candidate(10, 20),
+            candidate(100, 10),
+            candidate(10, 0),
+        ];
+
+        let first_fit = pair_with(PairingAlgorithm::FirstFit, &candidates, true);
+        let closest_ping = pair_with(PairingAlgorithm::ClosestPing, &candidates, true);
+        assert_eq!(first_fit, vec![(0, 1), (2, 3)]);
+        assert_eq!(closest_ping, vec![(1, 3), (0, 2)]);
+
+        assert_eq!(
+            PairingSummary::new(&candidates, &first_fit[..1]),
+            PairingSummary {
+                pairs: 1,
+                mean_ping_ms: Some(90),
+                longest_wait_seconds: Some(10),
+            }
+        );
+        assert_eq!(
+            PairingSummary::new(&candidates, &[]).to_string(),
+            "0 pairs, leaving a player waiting for 30 s"
+        );
+    }
+
+    #[test]
+    fn test_abandoned_opponents_are_not_paired_again() {
+        let preferences = models::MatchmakingPreferences::default();
+        let uids = ["1", "2", "3"].map(|uid| uid.parse::<Uid>().unwrap());
+        let abandoned_opponents = [uids[1].clone()];
+        let candidate = |uid, rtt_ms, abandoned_opponents| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid,
+            rtt_ms,
+            waited_seconds: 0,
+            preferences: &preferences,
+            shadow_queued: false,
+            rating: None,
+            abandoned_opponents,
+        };
+        let candidates = [
+            candidate(&uids[0], 10, &abandoned_opponents[..]),
+            candidate(&uids[1], 10, &[]),
+            candidate(&uids[2], 90, &[]),
+        ];
+
+        for algorithm in [PairingAlgorithm::FirstFit, PairingAlgorithm::ClosestPing] {
+            assert_eq!(
+                pair_with(algorithm, &candidates, true),
+                vec![(0, 2)],
+                "{:?}",
+                algorithm
+            );
+        }
+        assert_eq!(pair_candidates(&candidates[..2], true), vec![]);
+    }
+
+    #[test]
+    fn test_group_candidates_forms_teams_everyone_can_play_in() {
+        let preferences = models::MatchmakingPreferences::default();
+        let uids = ["1", "2", "3", "4", "5"].map(|uid| uid.parse::<Uid>().unwrap());
+        let abandoned_opponents = [uids[0].clone()];
+        let candidate = |uid, abandoned_opponents| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid,
+            rtt_ms: 10,
+            waited_seconds: 0,
+            preferences: &preferences,
+            shadow_queued: false,
+            rating: None,
+            abandoned_opponents,
+        };
+        let candidates = [
+            candidate(&uids[0], &[]),
+            candidate(&uids[1], &abandoned_opponents[..]),
+            candidate(&uids[2], &[]),
+            candidate(&uids[3], &[]),
+            candidate(&uids[4], &[]),
+        ];
+
+        assert_eq!(group_candidates(&candidates, true), vec![vec![0, 2, 3, 4]]);
+        assert_eq!(
+            group_candidates(&candidates[..4], true),
+            Vec::<Vec<usize>>::new()
+        );
+        // Every candidate shares the same IP
+        assert_eq!(
+            group_candidates(&candidates, false),
+            Vec::<Vec<usize>>::new()
+        );
+    }
+
+    #[test]
+    fn test_closest_ping_pairs_candidates_with_priority_first() {
+        let preferences = models::MatchmakingPreferences::default();
+        let uids = ["1", "2", "3", "4"].map(|uid| uid.parse::<Uid>().unwrap());
+        let abandoned_opponents = ["5".parse::<Uid>().unwrap()];
+        let candidate = |uid, rtt_ms, abandoned_opponents| Candidate {
+            ip: Ipv4Addr::LOCALHOST,
+            uid,
+            rtt_ms,
+            waited_seconds: 0,
+            preferences: &preferences,
+            shadow_queued: false,
+            rating: None,
+            abandoned_opponents,
+        };
+        let candidates = [
+            candidate(&uids[0], 10, &[]),
+            candidate(&uids[1], 10, &[]),
+            candidate(&uids[2], 50, &abandoned_opponents[..]),
+        ];
 
-        let messages = create_game(
-            randomized_peers
-                .clone()
-                .iter()
-                .map(|peer| (peer.data().unwrap().ticket.clone(), peer.address()))
-                .collect(),
-            mode,
+        assert_eq!(
+            pair_with(PairingAlgorithm::ClosestPing, &candidates, true),
+            vec![(0, 2)]
         );
+    }
 
-        randomized_peers
-            .iter()
-            .zip(messages)
-            .map(|(_peer, message)| {
-                let peer = &mut _peer.clone();
-                let message_str = &serde_json::to_string(&message).unwrap();
-                println!(
-                    "Sending message to {:?}: \n{:?}",
-                    peer.data().unwrap().ticket.user.connect_code,
-                    message_str,
-                );
-                peer.send_packet(
-                    Packet::new(
-                        &message_str.clone().into_bytes(),
-                        PacketMode::ReliableSequenced,
-                    )
-                    .unwrap(),
-                    ENET_CHANNEL_ID,
-                )
-                .unwrap();
-            })
-            .for_each(drop);
-    })
-}
+    fn formed_match(match_id: &str, uids: &[&str]) -> MatchmakingMessage {
+        let player = |uid: &str| Player {
+            is_local_player: false,
+            ip_address: String::from("127.0.0.1:1234"),
+            ip_address_lan: String::from("192.168.1.2:1234"),
+            port: ControllerPort::One,
+            team_id: None,
+            uid: uid.parse().unwrap(),
+            display_name: String::from("test"),
+            connect_code: "TEST#001".parse().unwrap(),
+            nat_type: NatType::Unknown,
+        };
+        MatchmakingMessage::GetTicketResponse {
+            latest_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
+            match_id: match_id.parse().unwrap(),
+            is_host: true,
+            is_assigned: true,
+            players: uids.iter().map(|uid| player(uid)).collect(),
+            stages: vec![Stage::Battlefield],
+            set_game: None,
+            message: None,
+        }
+    }
 
-fn get_match_id(mode: OnlinePlayMode) -> String {
-    let now = Utc::now();
-    format!("mode.{}-{}", mode, now.to_rfc3339())
-}
+    #[test]
+    fn test_abandoned_matches() {
+        let mut abandoned_matches = AbandonedMatches::default();
+        let response = formed_match("mode.unranked-1", &["1", "2"]);
+        abandoned_matches.record("1".parse().unwrap(), &response, 100);
+        abandoned_matches.record("2".parse().unwrap(), &response, 100);
 
-fn create_game(
-    _players: Vec<(CreateTicket, Address)>,
-    mode: OnlinePlayMode,
-) -> Vec<MatchmakingMessage> {
-    let match_id = get_match_id(mode);
-    let stages = Stage::get_allowed_stages(mode);
-    let ports = ControllerPort::get_ports(mode);
+        // Player 2 left first, player 1 was left waiting
+        assert_eq!(abandoned_matches.take(&"2".parse().unwrap(), 110), None);
+        assert_eq!(
+            abandoned_matches.take(&"1".parse().unwrap(), 120),
+            Some((
+                "mode.unranked-1".parse().unwrap(),
+                vec!["2".parse().unwrap()]
+            ))
+        );
+        assert_eq!(abandoned_matches.take(&"1".parse().unwrap(), 120), None);
 
-    _players
-        .iter()
-        .enumerate()
-        .map(|(i, _)| MatchmakingMessage::GetTicketResponse {
-            latest_version: LATEST_SLIPPI_CLIENT_VERSION.to_string(),
-            match_id: match_id.clone(),
-            is_host: i == 0,
-            is_assigned: true,
-            players: _players
-                .clone()
-                .iter()
-                .enumerate()
-                .map(|(j, (_ticket, _address))| {
-                    Player::new(
-                        _ticket.clone(),
-                        _address.clone(),
-                        i == j,
-                        *ports.get(j).unwrap(),
-                    )
-                })
-                .collect(),
-            stages: stages.clone(),
-        })
-        .collect()
-}
+        let response = formed_match("mode.unranked-2", &["1", "2"]);
+        abandoned_matches.record("1".parse().unwrap(), &response, 100);
+        abandoned_matches.record("2".parse().unwrap(), &response, 100);
+        assert_eq!(abandoned_matches.take(&"2".parse().unwrap(), 110), None);
+        assert_eq!(
+            abandoned_matches.take(&"1".parse().unwrap(), 100 + ABANDONED_MATCH_SECONDS),
+            None
+        );
 
-#[cfg(test)]
-mod test {
-    use std::collections::hash_map::Entry::{Occupied, Vacant};
-    use std::collections::HashMap;
-    use std::net::Ipv4Addr;
+        abandoned_matches.record("1".parse().unwrap(), &response, 100);
+        abandoned_matches.expire(100 + ABANDONED_MATCH_SECONDS);
+        assert!(abandoned_matches.formed.is_empty());
+        assert!(abandoned_matches.returned.is_empty());
+    }
 
-    use rand::Rng;
+    #[test]
+    fn test_players_whose_opponent_never_connected_get_priority() {
+        let mut abandoned_matches = AbandonedMatches::default();
+        let response = formed_match("mode.unranked-1", &["1", "2"]);
+        abandoned_matches.record("1".parse().unwrap(), &response, 100);
+        abandoned_matches.record("2".parse().unwrap(), &response, 100);
 
-    use crate::matchmaking::*;
+        // Player 1 gave up on player 2, who went offline instead of connecting
+        let gave_up_at = 100 + OPPONENT_CONNECT_SECONDS + 2;
+        assert_eq!(
+            abandoned_matches.take(&"1".parse().unwrap(), gave_up_at),
+            Some((
+                "mode.unranked-1".parse().unwrap(),
+                vec!["2".parse().unwrap()]
+            ))
+        );
+        // Back online well after player 1 stopped waiting
+        assert_eq!(
+            abandoned_matches.take(&"2".parse().unwrap(), gave_up_at + OPPONENT_CONNECT_SECONDS),
+            None
+        );
+
+        // Teammates and opponents of a player who never connected give up at
+        // about the same time
+        let response = formed_match("mode.teams-1", &["1", "2", "3", "4"]);
+        for uid in ["1", "2", "3", "4"] {
+            abandoned_matches.record(uid.parse().unwrap(), &response, 200);
+        }
+        for (uid, returned_at) in [("2", 216), ("3", 217), ("4", 219)] {
+            let (_, opponents) = abandoned_matches
+                .take(&uid.parse().unwrap(), returned_at)
+                .unwrap();
+            assert!(opponents.contains(&"1".parse().unwrap()));
+        }
+        assert_eq!(abandoned_matches.take(&"1".parse().unwrap(), 240), None);
+    }
 
     #[test]
-    fn can_parse_create_ticket_direct_message() {
-        let CreateTicket {
-            app_version,
-            search,
-            ..
-        } = serde_json::from_str(
-            r#"
-            {
-                "type": "create-ticket",
-                "appVersion": "2.5.1",
-                "ipAddressLan": "127.0.0.2:50285",
-                "search": {
-                    "connectCode": [130, 115, 130, 100, 130, 114, 130, 115, 129, 148, 130, 79, 130, 79, 130, 81],
-                    "mode": 2
-                },
-                "user": {
-                    "connectCode": "TEST#001",
-                    "displayName": "test",
-                    "playKey": "1",
-                    "uid": "1"
-                }
-            }
-        "#,
-        )
-        .unwrap();
+    fn test_players_leaving_a_match_first_get_no_priority() {
+        let mut abandoned_matches = AbandonedMatches::default();
+        let response = formed_match("mode.teams-1", &["1", "2", "3", "4"]);
+        for uid in ["1", "2", "3", "4"] {
+            abandoned_matches.record(uid.parse().unwrap(), &response, 100);
+        }
 
-        assert_eq!(app_version, "2.5.1");
-        assert_eq!(search.connect_code.as_ref().unwrap(), "TEST#002");
+        // Player 1 dodged while the others were still joining
+        assert_eq!(abandoned_matches.take(&"1".parse().unwrap(), 102), None);
+        assert_eq!(abandoned_matches.take(&"1".parse().unwrap(), 103), None);
+
+        for uid in ["2", "3"] {
+            let (match_id, opponents) = abandoned_matches.take(&uid.parse().unwrap(), 120).unwrap();
+            assert_eq!(match_id, "mode.teams-1".parse::<MatchId>().unwrap());
+            assert!(opponents.contains(&"1".parse().unwrap()));
+        }
     }
 
     #[test]
-    fn can_parse_create_ticket_unranked_message() {
-        let CreateTicket { app_version, .. } = serde_json::from_str(
-            r#"
-            {
-                "type": "create-ticket",
-                "appVersion": "2.5.1",
-                "ipAddressLan": "127.0.0.2:51000",
-                "search": {
-                    "mode": 1
-                },
-                "user": {
-                    "connectCode": "TEST#001",
-                    "displayName": "test",
-                    "playKey": "1",
-                    "uid": "1"
-                }
-            }
-        "#,
-        )
-        .unwrap();
+    fn create_game_uses_lan_addresses_for_same_ip_in_lan_mode() {
+        let ticket = |uid: &str, ip_address_lan: &str| CreateTicket {
+            app_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
+            ip_address_lan: String::from(ip_address_lan),
+            search: Search {
+                mode: OnlinePlayMode::Unranked,
+                connect_code: None,
+            },
+            user: User {
+                uid: uid.parse().unwrap(),
+                play_key: "5678".parse().unwrap(),
+                display_name: String::from("test"),
+                connect_code: "TEST#001".parse().unwrap(),
+            },
+            tenant: DEFAULT_TENANT.to_string(),
+            match_id: None,
+        };
+        let players = vec![
+            (
+                ticket("1", "192.168.1.2:51000"),
+                Address::new(Ipv4Addr::new(203, 0, 113, 1), 51000),
+                NatType::PortPreserving,
+            ),
+            (
+                ticket("2", "192.168.1.3:51000"),
+                Address::new(Ipv4Addr::new(203, 0, 113, 1), 51001),
+                NatType::PortTranslating,
+            ),
+        ];
 
-        assert_eq!(app_version, "2.5.1");
+        for (lan_mode, expected_ip_address) in
+            [(true, "192.168.1.3:51000"), (false, "203.0.113.1:51001")]
+        {
+            let messages = create_game(
+                players.clone(),
+                OnlinePlayMode::Unranked,
+                Stage::get_allowed_stages(OnlinePlayMode::Unranked, &[]),
+                None,
+                None,
+                lan_mode,
+            );
+
+            if let MatchmakingMessage::GetTicketResponse { players, .. } = &messages[0] {
+                assert_eq!(players[1].ip_address, expected_ip_address);
+                assert_eq!(players[1].nat_type, NatType::PortTranslating);
+            } else {
+                panic!("Expected a get-ticket-resp message");
+            }
+        }
     }
 
     #[test]
-    fn can_serialize_get_ticket_response_message() {
-        let message = MatchmakingMessage::GetTicketResponse {
-            latest_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
-            match_id: get_match_id(OnlinePlayMode::Direct),
-            is_host: false,
-            is_assigned: true,
-            players: vec![Player {
-                is_local_player: false,
-                uid: String::from("1"),
+    fn create_game_splits_teams_across_four_ports() {
+        let ticket = |uid: &str| CreateTicket {
+            app_version: String::from(LATEST_SLIPPI_CLIENT_VERSION),
+            ip_address_lan: String::from("192.168.1.2:51000"),
+            search: Search {
+                mode: OnlinePlayMode::Teams,
+                connect_code: None,
+            },
+            user: User {
+                uid: uid.parse().unwrap(),
+                play_key: "5678".parse().unwrap(),
                 display_name: String::from("test"),
-                connect_code: String::from("TEST#001"),
-                ip_address: String::from("127.0.0.1:48593"),
-                ip_address_lan: String::from("127.0.0.1:48593"),
-                port: ControllerPort::One,
-            }],
-            stages: Stage::get_allowed_stages(OnlinePlayMode::Direct),
+                connect_code: "TEST#001".parse().unwrap(),
+            },
+            tenant: DEFAULT_TENANT.to_string(),
+            match_id: None,
         };
+        let players = (1..=4)
+            .map(|i| {
+                (
+                    ticket(&i.to_string()),
+                    Address::new(Ipv4Addr::new(203, 0, 113, i), 51000),
+                    NatType::Open,
+                )
+            })
+            .collect_vec();
+
+        let messages = create_game(
+            players,
+            OnlinePlayMode::Teams,
+            Stage::get_allowed_stages(OnlinePlayMode::Teams, &[]),
+            None,
+            None,
+            false,
+        );
+        assert_eq!(messages.len(), 4);
 
-        assert!(serde_json::to_string(&message).is_ok());
+        if let MatchmakingMessage::GetTicketResponse { players, .. } = &messages[0] {
+            assert_eq!(
+                players
+                    .iter()
+                    .map(|player| (player.port, player.team_id))
+                    .collect_vec(),
+                vec![
+                    (ControllerPort::One, Some(Team::Red)),
+                    (ControllerPort::Two, Some(Team::Blue)),
+                    (ControllerPort::Three, Some(Team::Red)),
+                    (ControllerPort::Four, Some(Team::Blue)),
+                ]
+            );
+        } else {
+            panic!("Expected a get-ticket-resp message");
+        }
+        assert!(serde_json::to_string(&messages[0])
+            .unwrap()
+            .contains(r#""port":2,"teamId":1"#));
     }
 
     #[test]
@@ -426,11 +3159,13 @@ mod test {
                 connect_code: Some(String::from("TEST#002")),
             },
             user: User {
-                uid: String::from("1234"),
-                play_key: String::from("5678"),
+                uid: "1234".parse().unwrap(),
+                play_key: "5678".parse().unwrap(),
                 display_name: String::from("test"),
-                connect_code: String::from("TEST#001"),
+                connect_code: "TEST#001".parse().unwrap(),
             },
+            tenant: DEFAULT_TENANT.to_string(),
+            match_id: None,
         };
         let first_address = Address::new(Ipv4Addr::LOCALHOST, first_port);
         let second_ticket = CreateTicket {
@@ -441,26 +3176,32 @@ mod test {
                 connect_code: Some(String::from("TEST#001")),
             },
             user: User {
-                uid: String::from("4321"),
-                play_key: String::from("8765"),
+                uid: "4321".parse().unwrap(),
+                play_key: "8765".parse().unwrap(),
                 display_name: String::from("test-2"),
-                connect_code: String::from("TEST#002"),
+                connect_code: "TEST#002".parse().unwrap(),
             },
+            tenant: DEFAULT_TENANT.to_string(),
+            match_id: None,
         };
         let second_address = Address::new(Ipv4Addr::LOCALHOST, second_port);
 
         let messages = create_game(
             vec![
-                (first_ticket, first_address),
-                (second_ticket, second_address),
+                (first_ticket, first_address, NatType::Open),
+                (second_ticket, second_address, NatType::Open),
             ],
             OnlinePlayMode::Direct,
+            Stage::get_allowed_stages(OnlinePlayMode::Direct, &[]),
+            Some(1),
+            None,
+            false,
         );
 
         assert_eq!(messages.len(), 2);
 
         let mut is_host_count = 0;
-        let mut port_by_uid: HashMap<String, ControllerPort> = HashMap::new();
+        let mut port_by_uid: HashMap<Uid, ControllerPort> = HashMap::new();
         let mut _stages: Option<Vec<Stage>> = None;
         messages.iter().for_each(|message| {
             if let MatchmakingMessage::GetTicketResponse {
@@ -502,14 +3243,24 @@ mod test {
 
         // Each set of messages has one message with is_host: true
         assert_eq!(is_host_count, 1);
+
+        // Spectators are told about the match once, without any address
+        let formed = messages
+            .iter()
+            .filter_map(|message| get_match_formed(OnlinePlayMode::Direct, message))
+            .collect_vec();
+        assert_eq!(formed.len(), 1);
+        let formed = serde_json::to_string(&formed[0]).unwrap();
+        assert!(formed.starts_with(r#"{"type":"match-formed","matchId":"mode.direct-"#));
+        assert!(!formed.contains("127.0.0.1"));
     }
 
     #[test]
     fn test_get_allowed_stages_includes_battlefield_for_all_modes() {
-        let unranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked);
-        let ranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked);
-        let direct_stages = Stage::get_allowed_stages(OnlinePlayMode::Direct);
-        let teams_stages = Stage::get_allowed_stages(OnlinePlayMode::Teams);
+        let unranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked, &[]);
+        let ranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked, &[]);
+        let direct_stages = Stage::get_allowed_stages(OnlinePlayMode::Direct, &[]);
+        let teams_stages = Stage::get_allowed_stages(OnlinePlayMode::Teams, &[]);
         assert!(unranked_stages.contains(&Stage::Battlefield));
         assert!(ranked_stages.contains(&Stage::Battlefield));
         assert!(direct_stages.contains(&Stage::Battlefield));
@@ -518,16 +3269,16 @@ mod test {
 
     #[test]
     fn test_get_allowed_stages_does_not_include_fountain_of_dreams_for_teams() {
-        let stages = Stage::get_allowed_stages(OnlinePlayMode::Teams);
+        let stages = Stage::get_allowed_stages(OnlinePlayMode::Teams, &[]);
         assert!(!stages.contains(&Stage::FountainOfDreams));
         assert_eq!(stages.into_iter().unique().collect_vec().len(), 5);
     }
 
     #[test]
     fn test_get_allowed_stages_does_include_fountain_of_dreams_for_other_modes() {
-        let unranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked);
-        let ranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked);
-        let direct_stages = Stage::get_allowed_stages(OnlinePlayMode::Direct);
+        let unranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked, &[]);
+        let ranked_stages = Stage::get_allowed_stages(OnlinePlayMode::Unranked, &[]);
+        let direct_stages = Stage::get_allowed_stages(OnlinePlayMode::Direct, &[]);
         assert!(unranked_stages.contains(&Stage::FountainOfDreams));
         assert_eq!(unranked_stages.into_iter().unique().collect_vec().len(), 6);
         assert!(ranked_stages.contains(&Stage::FountainOfDreams));
@@ -547,4 +3298,299 @@ mod test {
         assert_eq!(direct_ports.into_iter().unique().collect_vec().len(), 2);
         assert_eq!(teams_ports.into_iter().unique().collect_vec().len(), 4);
     }
+
+    fn address(i: usize) -> SocketAddr {
+        SocketAddr::from((
+            Ipv4Addr::new(10, 0, (i / 256) as u8, (i % 256) as u8),
+            51000,
+        ))
+    }
+
+    #[test]
+    fn test_queue_index_follows_tickets() {
+        let mut queue_index = QueueIndex::default();
+
+        queue_index.insert(
+            address(1),
+            DEFAULT_TENANT.to_string(),
+            OnlinePlayMode::Direct,
+        );
+        queue_index.insert(
+            address(2),
+            DEFAULT_TENANT.to_string(),
+            OnlinePlayMode::Unranked,
+        );
+        // A new ticket moves the peer to another queue
+        queue_index.insert(
+            address(1),
+            DEFAULT_TENANT.to_string(),
+            OnlinePlayMode::Unranked,
+        );
+        assert_eq!(queue_index.queued.get(&address(1)), Some(&1));
+        assert_eq!(queue_index.queued.get(&address(2)), Some(&1));
+        assert_eq!(
+            queue_index.queues[1],
+            (DEFAULT_TENANT.to_string(), OnlinePlayMode::Unranked)
+        );
+        assert_eq!(
+            queue_index.count_waiting(DEFAULT_TENANT, OnlinePlayMode::Unranked),
+            2
+        );
+        assert_eq!(
+            queue_index.count_waiting(DEFAULT_TENANT, OnlinePlayMode::Direct),
+            0
+        );
+        assert_eq!(
+            queue_index.count_waiting("other", OnlinePlayMode::Unranked),
+            0
+        );
+
+        queue_index.remove(address(1));
+        queue_index.remove(address(2));
+        queue_index.remove(address(3));
+        assert_eq!(queue_index.queued.get(&address(1)), None);
+        assert!(queue_index.is_empty());
+    }
+
+    #[test]
+    fn test_spectators_hold_their_own_slots() {
+        let config = Config {
+            matchmaking_max_peers: 3,
+            matchmaking_spectator_slots: 1,
+            ..Config::default()
+        };
+        let mut queue_index = QueueIndex::default();
+
+        assert_eq!(
+            serde_json::from_str::<SpectatorMessage>(
+                r#"{"type":"spectate","uid":"1","playKey":"2"}"#
+            )
+            .unwrap(),
+            SpectatorMessage::Spectate {
+                uid: "1".parse().unwrap(),
+                play_key: "2".parse().unwrap(),
+                tenant: DEFAULT_TENANT.to_string(),
+            }
+        );
+        assert!(serde_json::from_str::<SpectatorMessage>(r#"{"type":"create-ticket"}"#).is_err());
+
+        queue_index.connect(address(1));
+        queue_index.connect(address(2));
+        queue_index.insert(
+            address(2),
+            DEFAULT_TENANT.to_string(),
+            OnlinePlayMode::Unranked,
+        );
+        assert!(queue_index.has_free_slot(false, &config));
+        // Peers count as players until admitted as spectators, even before
+        // sending anything
+        queue_index.connect(address(3));
+        assert!(!queue_index.has_free_slot(false, &config));
+        assert!(queue_index.has_free_slot(true, &config));
+
+        queue_index.insert_spectator(address(3), DEFAULT_TENANT.to_string());
+        assert!(queue_index.is_spectator(address(3)));
+        assert!(queue_index.has_free_slot(false, &config));
+        assert!(!queue_index.has_free_slot(true, &config));
+        // Spectators aren't searching
+        assert_eq!(queue_index.queued.get(&address(3)), None);
+
+        // Matched players keep their slot until they disconnect
+        queue_index.remove(address(2));
+        queue_index.connect(address(4));
+        assert!(!queue_index.has_free_slot(false, &config));
+        queue_index.disconnect(address(2));
+        assert!(queue_index.has_free_slot(false, &config));
+
+        queue_index.disconnect(address(3));
+        assert!(queue_index.has_free_slot(true, &config));
+        assert!(!queue_index.is_spectator(address(3)));
+    }
+
+    // Stands in for the clients of a matchmaking server, each with its own
+    // host since peers are told apart by their address
+    fn connect_clients(
+        server: &mut Host<PeerData>,
+        server_address: &Address,
+        queue_index: &mut QueueIndex,
+        count: usize,
+    ) -> Vec<Host<()>> {
+        let mut clients = (0..count)
+            .map(|_| {
+                ENET.create_host::<()>(
+                    None,
+                    1,
+                    ChannelLimit::Maximum,
+                    BandwidthLimit::Unlimited,
+                    BandwidthLimit::Unlimited,
+                )
+                .unwrap()
+            })
+            .collect_vec();
+        for client in &mut clients {
+            client.connect(server_address, 1, 0).unwrap();
+        }
+
+        let mut connected = 0;
+        while connected < count {
+            for client in &mut clients {
+                client.service(0).unwrap();
+            }
+            if let Some(Event::Connect(peer)) = server.service(1).unwrap() {
+                queue_index.connect(get_peer_address(&peer));
+                connected += 1;
+            }
+            queue_index.assign_slots(server);
+        }
+
+        clients
+    }
+
+    fn create_server(max_peers: u64) -> (Host<PeerData>, Address) {
+        let address = Address::new(
+            Ipv4Addr::LOCALHOST,
+            rand::thread_rng().gen_range(20_000..30_000),
+        );
+        let server = ENET
+            .create_host::<PeerData>(
+                Some(&address),
+                max_peers,
+                ChannelLimit::Maximum,
+                BandwidthLimit::Unlimited,
+                BandwidthLimit::Unlimited,
+            )
+            .unwrap();
+
+        (server, address)
+    }
+
+    #[test]
+    fn test_queue_index_takes_queued_peers_from_their_slots() {
+        let (mut server, server_address) = create_server(16);
+        let mut queue_index = QueueIndex::default();
+        let _clients = connect_clients(&mut server, &server_address, &mut queue_index, 3);
+        assert!(queue_index.unassigned.is_empty());
+        assert_eq!(
+            queue_index.slots.values().copied().sorted().collect_vec(),
+            [0, 1, 2]
+        );
+
+        // The peer between the two searching ones isn't searching
+        let addresses = server
+            .peers()
+            .take(3)
+            .map(|peer| get_peer_address(&peer))
+            .collect_vec();
+        let modes = [
+            Some(OnlinePlayMode::Unranked),
+            None,
+            Some(OnlinePlayMode::Direct),
+        ];
+        for (i, (mut peer, mode)) in server.peers().zip(modes).enumerate() {
+            if let Some(mode) = mode {
+                peer.set_data(Some(searching_peer_data(i, mode)));
+                queue_index.insert(get_peer_address(&peer), DEFAULT_TENANT.to_string(), mode);
+            }
+        }
+        let peers_by_queue = queue_index.get_peers_by_queue(&mut server);
+        assert_eq!(
+            peers_by_queue
+                .iter()
+                .map(|peers| peers.iter().map(get_peer_address).collect_vec())
+                .collect_vec(),
+            [vec![addresses[0]], vec![addresses[2]]]
+        );
+
+        // A slot now held by another peer isn't trusted
+        queue_index.slots.insert(addresses[0], 1);
+        let peers_by_queue = queue_index.get_peers_by_queue(&mut server);
+        assert!(peers_by_queue[0].is_empty());
+        assert_eq!(peers_by_queue[1].len(), 1);
+
+        queue_index.disconnect(addresses[2]);
+        assert!(!queue_index.slots.contains_key(&addresses[2]));
+        assert!(queue_index.get_peers_by_queue(&mut server)[1].is_empty());
+    }
+
+    // Compares the pass over every host slot each tick used to make with
+    // taking queued peers from their slots, on a host of the default size.
+    // Run with cargo test --release bench_queue_index -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_queue_index() {
+        const CONNECTED: usize = 200;
+        const TICKS: u32 = 1_000;
+
+        let config = Config::default();
+        let (mut server, server_address) = create_server(config.matchmaking_max_peers);
+        let mut connected_index = QueueIndex::default();
+        let _clients = connect_clients(
+            &mut server,
+            &server_address,
+            &mut connected_index,
+            CONNECTED,
+        );
+
+        // Most connected peers are playing rather than searching
+        for searching in [0, CONNECTED / 10, CONNECTED] {
+            let mut queue_index = QueueIndex {
+                slots: connected_index.slots.clone(),
+                ..QueueIndex::default()
+            };
+            for (i, mut peer) in server
+                .peers()
+                .filter(|peer| peer.state() == PeerState::Connected)
+                .enumerate()
+            {
+                if i < searching {
+                    let mode = [OnlinePlayMode::Unranked, OnlinePlayMode::Ranked][i % 2];
+                    peer.set_data(Some(searching_peer_data(i, mode)));
+                    queue_index.insert(get_peer_address(&peer), format!("tenant-{}", i % 4), mode);
+                } else {
+                    peer.set_data(None);
+                }
+            }
+
+            let started_at = std::time::Instant::now();
+            for _ in 0..TICKS {
+                if queue_index.is_empty() {
+                    continue;
+                }
+                let mut peers_by_queue: Vec<Vec<Peer<PeerData>>> =
+                    queue_index.queues.iter().map(|_| vec![]).collect();
+                for peer in server.peers() {
+                    if peer.state() != PeerState::Connected || peer.data().is_none() {
+                        continue;
+                    }
+                    if let Some(&queue) = queue_index.queued.get(&get_peer_address(&peer)) {
+                        peers_by_queue[queue].push(peer);
+                    }
+                }
+                std::hint::black_box(peers_by_queue);
+            }
+            let scanned_in = started_at.elapsed() / TICKS;
+
+            let started_at = std::time::Instant::now();
+            for _ in 0..TICKS {
+                if queue_index.is_empty() {
+                    continue;
+                }
+                std::hint::black_box(queue_index.get_peers_by_queue(&mut server));
+            }
+            let indexed_in = started_at.elapsed() / TICKS;
+
+            assert_eq!(
+                queue_index
+                    .get_peers_by_queue(&mut server)
+                    .iter()
+                    .map(Vec::len)
+                    .sum::<usize>(),
+                searching
+            );
+            println!(
+                "{} slots, {} connected, {} searching: {:?} per tick scanning the host, {:?} from the queue index",
+                config.matchmaking_max_peers, CONNECTED, searching, scanned_in, indexed_in
+            );
+        }
+    }
 }
@@ -0,0 +1,223 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+use crate::game::Stage;
+
+// The names and IDs of stages and characters, spelled out once for
+// templates, stats and the API. Stage slugs are also how stages are stored.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StageData {
+    #[serde(skip)]
+    pub stage: Stage,
+    // The in-game ID, as sent by clients
+    pub id: u8,
+    pub slug: &'static str,
+    pub name: &'static str,
+    // Path among the static assets
+    pub icon: &'static str,
+}
+
+const fn stage(
+    stage: Stage,
+    slug: &'static str,
+    name: &'static str,
+    icon: &'static str,
+) -> StageData {
+    StageData {
+        stage,
+        id: stage as u8,
+        slug,
+        name,
+        icon,
+    }
+}
+
+// Only the stages legal in competitive play, which are the only ones
+// matchmaking offers
+pub static STAGES: [StageData; 6] = [
+    stage(
+        Stage::FountainOfDreams,
+        "fountain_of_dreams",
+        "Fountain of Dreams",
+        "static/stages/fountain_of_dreams.svg",
+    ),
+    stage(
+        Stage::PokemonStadium,
+        "pokemon_stadium",
+        "Pokémon Stadium",
+        "static/stages/pokemon_stadium.svg",
+    ),
+    stage(
+        Stage::YoshisStory,
+        "yoshis_story",
+        "Yoshi's Story",
+        "static/stages/yoshis_story.svg",
+    ),
+    stage(
+        Stage::DreamLand,
+        "dream_land",
+        "Dream Land",
+        "static/stages/dream_land.svg",
+    ),
+    stage(
+        Stage::Battlefield,
+        "battlefield",
+        "Battlefield",
+        "static/stages/battlefield.svg",
+    ),
+    stage(
+        Stage::FinalDestination,
+        "final_destination",
+        "Final Destination",
+        "static/stages/final_destination.svg",
+    ),
+];
+
+// Numbered by their external ID, the order of the character select screen
+// that Slippi replays and the Slippi API use
+#[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Deserialize_repr, Serialize_repr)]
+#[repr(u8)]
+pub enum Character {
+    CaptainFalcon = 0,
+    DonkeyKong = 1,
+    Fox = 2,
+    GameAndWatch = 3,
+    Kirby = 4,
+    Bowser = 5,
+    Link = 6,
+    Luigi = 7,
+    Mario = 8,
+    Marth = 9,
+    Mewtwo = 10,
+    Ness = 11,
+    Peach = 12,
+    Pikachu = 13,
+    IceClimbers = 14,
+    Jigglypuff = 15,
+    Samus = 16,
+    Yoshi = 17,
+    Zelda = 18,
+    Sheik = 19,
+    Falco = 20,
+    YoungLink = 21,
+    DrMario = 22,
+    Roy = 23,
+    Pichu = 24,
+    Ganondorf = 25,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CharacterData {
+    #[serde(skip)]
+    pub character: Character,
+    pub id: u8,
+    pub slug: &'static str,
+    pub name: &'static str,
+}
+
+const fn character(character: Character, slug: &'static str, name: &'static str) -> CharacterData {
+    CharacterData {
+        character,
+        id: character as u8,
+        slug,
+        name,
+    }
+}
+
+pub static CHARACTERS: [CharacterData; 26] = [
+    character(Character::CaptainFalcon, "captain_falcon", "Captain Falcon"),
+    character(Character::DonkeyKong, "donkey_kong", "Donkey Kong"),
+    character(Character::Fox, "fox", "Fox"),
+    character(
+        Character::GameAndWatch,
+        "game_and_watch",
+        "Mr. Game & Watch",
+    ),
+    character(Character::Kirby, "kirby", "Kirby"),
+    character(Character::Bowser, "bowser", "Bowser"),
+    character(Character::Link, "link", "Link"),
+    character(Character::Luigi, "luigi", "Luigi"),
+    character(Character::Mario, "mario", "Mario"),
+    character(Character::Marth, "marth", "Marth"),
+    character(Character::Mewtwo, "mewtwo", "Mewtwo"),
+    character(Character::Ness, "ness", "Ness"),
+    character(Character::Peach, "peach", "Peach"),
+    character(Character::Pikachu, "pikachu", "Pikachu"),
+    character(Character::IceClimbers, "ice_climbers", "Ice Climbers"),
+    character(Character::Jigglypuff, "jigglypuff", "Jigglypuff"),
+    character(Character::Samus, "samus", "Samus"),
+    character(Character::Yoshi, "yoshi", "Yoshi"),
+    character(Character::Zelda, "zelda", "Zelda"),
+    character(Character::Sheik, "sheik", "Sheik"),
+    character(Character::Falco, "falco", "Falco"),
+    character(Character::YoungLink, "young_link", "Young Link"),
+    character(Character::DrMario, "dr_mario", "Dr. Mario"),
+    character(Character::Roy, "roy", "Roy"),
+    character(Character::Pichu, "pichu", "Pichu"),
+    character(Character::Ganondorf, "ganondorf", "Ganondorf"),
+];
+
+impl Character {
+    pub fn from_id(id: u8) -> Option<Character> {
+        CHARACTERS
+            .iter()
+            .find(|data| data.id == id)
+            .map(|data| data.character)
+    }
+
+    pub fn get_data(&self) -> &'static CharacterData {
+        &CHARACTERS[*self as usize]
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        self.get_data().name
+    }
+}
+
+impl fmt::Display for Character {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_data().slug)
+    }
+}
+
+impl FromStr for Character {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CHARACTERS
+            .iter()
+            .find(|data| data.slug == s)
+            .map(|data| data.character)
+            .ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assets::get_asset;
+    use crate::melee::*;
+
+    #[test]
+    fn test_characters_are_listed_by_external_id() {
+        for (id, data) in CHARACTERS.iter().enumerate() {
+            assert_eq!(data.id as usize, id);
+            assert_eq!(Character::from_id(data.id), Some(data.character));
+            assert_eq!(data.character.to_string().parse(), Ok(data.character));
+        }
+        assert_eq!(Character::GameAndWatch.get_name(), "Mr. Game & Watch");
+        assert_eq!(Character::from_id(26), None);
+    }
+
+    #[test]
+    fn test_stage_icons_are_bundled() {
+        for data in &STAGES {
+            assert!(get_asset(data.icon).is_some(), "{} is missing", data.icon);
+            assert_eq!(data.stage.get_data(), data);
+        }
+    }
+}
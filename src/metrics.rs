@@ -0,0 +1,140 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Counts observations at or below each upper bound, in seconds, in the
+// Prometheus text format. Observations above the last bound are only in
+// the total count.
+pub struct Histogram<const N: usize> {
+    name: &'static str,
+    help: &'static str,
+    bounds: [f64; N],
+    buckets: [AtomicU64; N],
+    count: AtomicU64,
+    // f64 bits, atomics don't hold floats
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(name: &'static str, help: &'static str, bounds: [f64; N]) -> Self {
+        Histogram {
+            name,
+            help,
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, seconds: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + seconds).to_bits())
+            });
+    }
+
+    // Observes the time until the timer is dropped
+    pub fn start_timer(&self) -> Timer<'_, N> {
+        Timer {
+            histogram: self,
+            started_at: Instant::now(),
+        }
+    }
+
+    // Prometheus buckets are cumulative, each one includes those before it
+    fn render(&self, output: &mut String) {
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} histogram", self.name).unwrap();
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(
+                output,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, cumulative
+            )
+            .unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", self.name, count).unwrap();
+        writeln!(
+            output,
+            "{}_sum {}",
+            self.name,
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
+        )
+        .unwrap();
+        writeln!(output, "{}_count {}", self.name, count).unwrap();
+    }
+}
+
+pub struct Timer<'a, const N: usize> {
+    histogram: &'a Histogram<N>,
+    started_at: Instant,
+}
+
+impl<const N: usize> Drop for Timer<'_, N> {
+    fn drop(&mut self) {
+        self.histogram
+            .observe(self.started_at.elapsed().as_secs_f64());
+    }
+}
+
+pub static TICKET_MATCH_SECONDS: Histogram<9> = Histogram::new(
+    "openmelee_ticket_match_seconds",
+    "Time from a ticket being accepted to its player being matched.",
+    [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0],
+);
+
+pub static SERVICE_LOOP_SECONDS: Histogram<9> = Histogram::new(
+    "openmelee_service_loop_seconds",
+    "Time the matchmaking server spends on an iteration of its ENet loop, not counting waiting for events.",
+    [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0],
+);
+
+pub static PACKET_PARSE_SECONDS: Histogram<7> = Histogram::new(
+    "openmelee_packet_parse_seconds",
+    "Time taken to parse a packet sent to the matchmaking server.",
+    [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01],
+);
+
+pub fn render() -> String {
+    let mut output = String::new();
+    TICKET_MATCH_SECONDS.render(&mut output);
+    SERVICE_LOOP_SECONDS.render(&mut output);
+    PACKET_PARSE_SECONDS.render(&mut output);
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::*;
+
+    #[test]
+    fn test_histogram_renders_cumulative_buckets() {
+        let histogram = Histogram::new("test_seconds", "Test.", [0.5, 1.0]);
+        histogram.observe(0.25);
+        histogram.observe(0.75);
+        histogram.observe(2.0);
+
+        let mut output = String::new();
+        histogram.render(&mut output);
+        assert_eq!(
+            output,
+            "# HELP test_seconds Test.\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"0.5\"} 1\n\
+             test_seconds_bucket{le=\"1\"} 2\n\
+             test_seconds_bucket{le=\"+Inf\"} 3\n\
+             test_seconds_sum 3\n\
+             test_seconds_count 3\n"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::{BTreeMap, HashMap};
+
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
@@ -8,14 +10,18 @@ use axum::{
     Json,
 };
 use axum_sqlx_tx::Tx;
-use bson::{oid::ObjectId, Uuid};
+use chrono_tz::Tz;
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use sqlx::{Acquire, FromRow, Row, Sqlite, SqliteExecutor};
 use validator::{Validate, ValidationError, ValidationErrors};
 use wana_kana::utils::{is_char_hiragana, is_char_katakana};
 
-use crate::Config;
+use crate::{
+    crypto,
+    ids::{ConnectCode, PlayKey, Uid},
+    Config, LATEST_SLIPPI_CLIENT_VERSION,
+};
 
 const CONNECT_CODE_SEPARATOR: &str = "#";
 const CONNECT_CODE_MAX_LENGTH: usize = 8;
@@ -27,8 +33,8 @@ const OTHER_DISPLAYABLE_PUNCTUATION: &[&char] = &[&'/'];
 #[derive(Debug, PartialEq, Eq, FromRow, Clone, Validate, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
-    pub uid: String,
-    pub play_key: String,
+    pub uid: Uid,
+    pub play_key: PlayKey,
     #[validate(
         length(min = 1, message = "Display name is too short"),
         custom(
@@ -40,9 +46,8 @@ pub struct User {
     )]
     pub display_name: String,
     #[validate(
-        length(
-            min = 1,
-            max = "CONNECT_CODE_MAX_LENGTH",
+        custom(
+            function = "connect_code_has_valid_length",
             message = "Must be at least 1 and at most 8 characters long"
         ),
         custom(
@@ -72,7 +77,7 @@ pub struct User {
             message = "Only numbers may be present after the # symbol"
         )
     )]
-    pub connect_code: String,
+    pub connect_code: ConnectCode,
     pub latest_version: Option<String>,
 }
 
@@ -82,9 +87,40 @@ impl IntoResponse for User {
     }
 }
 
+// What the JSON API shows of a user, without their credentials
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicUser {
+    pub uid: Uid,
+    pub display_name: String,
+    pub connect_code: ConnectCode,
+    pub latest_version: String,
+}
+
+impl IntoResponse for PublicUser {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+impl From<&User> for PublicUser {
+    fn from(user: &User) -> PublicUser {
+        PublicUser {
+            uid: user.uid.clone(),
+            display_name: user.display_name.to_string(),
+            connect_code: user.connect_code.clone(),
+            latest_version: match &user.latest_version {
+                Some(str) => str.to_string(),
+                _ => LATEST_SLIPPI_CLIENT_VERSION.to_string(),
+            },
+        }
+    }
+}
+
 impl User {
     pub fn is_valid_connect_code(connect_code: &str) -> bool {
-        connect_code_contains_separator(connect_code).is_ok()
+        connect_code_has_valid_length(connect_code).is_ok()
+            && connect_code_contains_separator(connect_code).is_ok()
             && connect_code_prefix_is_not_empty(connect_code).is_ok()
             && connect_code_prefix_contains_only_valid_characters(connect_code).is_ok()
             && connect_code_discriminant_is_not_empty(connect_code).is_ok()
@@ -93,10 +129,10 @@ impl User {
 
     pub fn new(display_name: String, connect_code: String) -> Result<User, ValidationErrors> {
         let user = User {
-            uid: format!("{}", Uuid::new()),
-            play_key: ObjectId::new().to_hex(),
+            uid: Uid::generate(),
+            play_key: PlayKey::generate(),
             display_name,
-            connect_code,
+            connect_code: ConnectCode::new_unchecked(connect_code),
             latest_version: None,
         };
 
@@ -105,7 +141,7 @@ impl User {
 
     pub async fn get<'a, T: SqliteExecutor<'a>>(
         executor: T,
-        uid: String,
+        uid: Uid,
     ) -> Result<User, sqlx::Error> {
         sqlx::query_as::<_, User>("select * from users where uid = $1")
             .bind(uid)
@@ -113,21 +149,289 @@ impl User {
             .await
     }
 
+    pub async fn get_by_connect_code<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        connect_code: ConnectCode,
+    ) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>("select * from users where tenant_id = $1 and connect_code = $2")
+            .bind(tenant_id)
+            .bind(connect_code)
+            .fetch_one(executor)
+            .await
+    }
+
+    // Changes with every update of the user, for ETags
+    pub async fn get_version<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        uid: Uid,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>("select version from users where tenant_id = $1 and uid = $2")
+            .bind(tenant_id)
+            .bind(uid)
+            .fetch_one(executor)
+            .await
+    }
+
+    // Codes can move between users, so the user is needed alongside the
+    // version to tell whether the code still refers to the same profile
+    pub async fn get_version_by_connect_code<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        connect_code: ConnectCode,
+    ) -> Result<(Uid, i64), sqlx::Error> {
+        sqlx::query_as::<_, (Uid, i64)>(
+            "select uid, version from users where tenant_id = $1 and connect_code = $2",
+        )
+        .bind(tenant_id)
+        .bind(connect_code)
+        .fetch_one(executor)
+        .await
+    }
+
+    pub async fn get_all<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>("select * from users where tenant_id = $1 order by connect_code")
+            .bind(tenant_id)
+            .fetch_all(executor)
+            .await
+    }
+
+    // Users of the tenant ordered by connect code, starting after the given
+    // connect code
+    pub async fn get_page<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        after: Option<String>,
+        limit: i64,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>("select * from users where tenant_id = $1 and ($2 is null or connect_code > $2) order by connect_code limit $3")
+            .bind(tenant_id)
+            .bind(after)
+            .bind(limit)
+            .fetch_all(executor)
+            .await
+    }
+
+    pub async fn count_in_tenant<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query("select count(uid) from users where tenant_id = $1")
+            .bind(tenant_id)
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<i64, usize>(0))
+    }
+
+    pub async fn count<'a, T: SqliteExecutor<'a>>(executor: T) -> Result<i64, sqlx::Error> {
+        sqlx::query("select count(uid) from users")
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<i64, usize>(0))
+    }
+
+    pub async fn is_admin<'a, T: SqliteExecutor<'a>>(executor: T, uid: Uid) -> bool {
+        sqlx::query("select is_admin from users where uid = $1")
+            .bind(uid)
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<bool, usize>(0))
+            .unwrap_or(false)
+    }
+
+    pub async fn is_banned<'a, T: SqliteExecutor<'a>>(executor: T, uid: Uid) -> bool {
+        sqlx::query("select is_banned from users where uid = $1")
+            .bind(uid)
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<bool, usize>(0))
+            .unwrap_or(false)
+    }
+
+    // Banned users can neither log in nor use their play key or API tokens,
+    // session tokens they already hold stay valid until they expire
+    pub async fn set_banned<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        banned: bool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set is_banned = $1 where uid = $2")
+            .bind(banned)
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn is_shadow_queued<'a, T: SqliteExecutor<'a>>(executor: T, uid: Uid) -> bool {
+        sqlx::query("select is_shadow_queued from users where uid = $1")
+            .bind(uid)
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<bool, usize>(0))
+            .unwrap_or(false)
+    }
+
+    // Users suspected of cheating are only matched with each other in the
+    // unranked queue while moderators investigate, without being told so
+    pub async fn set_shadow_queued<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        shadow_queued: bool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set is_shadow_queued = $1 where uid = $2")
+            .bind(shadow_queued)
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // Callers validate the new name, see User::validate
+    pub async fn set_display_name<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        display_name: String,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set display_name = $1 where uid = $2")
+            .bind(display_name)
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // Callers make sure the code is valid and free, see
+    // User::find_free_connect_code
+    pub async fn set_connect_code<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        connect_code: ConnectCode,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set connect_code = $1 where uid = $2")
+            .bind(connect_code)
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // The code with the given prefix and the lowest discriminant nobody uses
+    pub async fn find_free_connect_code<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        prefix: &str,
+    ) -> Result<ConnectCode, sqlx::Error> {
+        let used = sqlx::query(
+            "select upper(connect_code) from users where tenant_id = $1 and upper(connect_code) like upper($2) || '#%'",
+        )
+        .bind(tenant_id)
+        .bind(prefix)
+        .fetch_all(executor)
+        .await?
+        .iter()
+        .map(|row| row.get::<String, usize>(0))
+        .collect::<Vec<String>>();
+
+        Ok((1..)
+            .map(|discriminant| {
+                format!(
+                    "{}{}{}",
+                    prefix.to_uppercase(),
+                    CONNECT_CODE_SEPARATOR,
+                    discriminant
+                )
+            })
+            .find(|connect_code| !used.contains(connect_code))
+            .map(ConnectCode::new_unchecked)
+            .unwrap())
+    }
+
     pub async fn check_play_key<'a, T: SqliteExecutor<'a>>(
         executor: T,
-        uid: String,
-        play_key: String,
+        uid: Uid,
+        play_key: PlayKey,
     ) -> bool {
-        sqlx::query_as::<_, User>("select * from users where uid = $1 and play_key = $2")
+        sqlx::query_as::<_, User>(
+            "select * from users where uid = $1 and play_key = $2 and is_banned = false",
+        )
+        .bind(uid)
+        .bind(play_key)
+        .fetch_one(executor)
+        .await
+        .is_ok()
+    }
+
+    pub async fn set_password<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        password: SecretString,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set password = $1 where uid = $2")
+            .bind(Self::hash_password(password).unwrap())
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // Timestamps on the pages a user sees are shown in their time zone,
+    // UTC unless they chose one
+    pub async fn get_timezone<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Tz, sqlx::Error> {
+        sqlx::query_scalar::<_, String>("select timezone from users where uid = $1")
             .bind(uid)
-            .bind(play_key)
             .fetch_one(executor)
             .await
-            .is_ok()
+            .map(|timezone| timezone.parse::<Tz>().unwrap_or(Tz::UTC))
+    }
+
+    pub async fn set_timezone<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        timezone: Tz,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set timezone = $1 where uid = $2")
+            .bind(timezone.name())
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn set_latest_version<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        latest_version: String,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("update users set latest_version = $1 where uid = $2")
+            .bind(latest_version)
+            .bind(uid)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // Client versions are dotted numbers, like 3.0.2
+    pub fn is_valid_client_version(version: &str) -> bool {
+        let parts: Vec<&str> = version.split('.').collect();
+
+        version.len() <= 32
+            && parts.len() == 3
+            && parts
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
     }
 
     pub async fn check_constraints_and_create(
         mut tx: Tx<Sqlite>,
+        tenant_id: String,
         username: String,
         password: SecretString,
         display_name: String,
@@ -148,7 +452,9 @@ impl User {
             errors.add("password", error);
         }
 
-        if let Some(in_use) = Self::is_connect_code_in_use(conn, connect_code.clone()).await {
+        if let Some(in_use) =
+            Self::is_connect_code_in_use(conn, tenant_id.clone(), connect_code.clone()).await
+        {
             if in_use {
                 let mut error = ValidationError::new("duplicated");
                 error.message = Some(std::borrow::Cow::Borrowed("Connect code is already in use"));
@@ -158,7 +464,9 @@ impl User {
 
         conn = tx.acquire().await.unwrap();
 
-        if let Some(in_use) = Self::is_username_in_use(conn, username.clone()).await {
+        if let Some(in_use) =
+            Self::is_username_in_use(conn, tenant_id.clone(), username.clone()).await
+        {
             if in_use {
                 let mut error = ValidationError::new("duplicated");
                 error.message = Some(std::borrow::Cow::Borrowed("Username is already in use"));
@@ -172,7 +480,15 @@ impl User {
 
         conn = tx.acquire().await.unwrap();
 
-        let result = Self::create(conn, username, password, display_name, connect_code).await;
+        let result = Self::create_in_tenant(
+            conn,
+            tenant_id,
+            username,
+            password,
+            display_name,
+            connect_code,
+        )
+        .await;
 
         let tx_result = tx.commit().await;
 
@@ -185,12 +501,16 @@ impl User {
 
     async fn is_connect_code_in_use<'a, T: SqliteExecutor<'a>>(
         executor: T,
+        tenant_id: String,
         connect_code: String,
     ) -> Option<bool> {
-        match sqlx::query("select count(uid) from users where connect_code = $1")
-            .bind(connect_code)
-            .fetch_one(executor)
-            .await
+        match sqlx::query(
+            "select count(uid) from users where tenant_id = $1 and upper(connect_code) = upper($2)",
+        )
+        .bind(tenant_id)
+        .bind(connect_code)
+        .fetch_one(executor)
+        .await
         {
             Ok(row) => Some(row.get::<i64, usize>(0) > 0),
             _ => None,
@@ -199,20 +519,45 @@ impl User {
 
     async fn is_username_in_use<'a, T: SqliteExecutor<'a>>(
         executor: T,
+        tenant_id: String,
         username: String,
     ) -> Option<bool> {
-        match sqlx::query("select count(uid) from users where username = $1")
-            .bind(username)
-            .fetch_one(executor)
-            .await
+        match sqlx::query(
+            "select count(uid) from users where tenant_id = $1 and lower(username) = lower($2)",
+        )
+        .bind(tenant_id)
+        .bind(username)
+        .fetch_one(executor)
+        .await
         {
             Ok(row) => Some(row.get::<i64, usize>(0) > 0),
             _ => None,
         }
     }
 
-    async fn create<'a, T: SqliteExecutor<'a>>(
+    // Tests mostly deal with the default tenant
+    #[cfg(test)]
+    pub(crate) async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        username: String,
+        password: SecretString,
+        display_name: String,
+        connect_code: String,
+    ) -> Result<User, ValidationErrors> {
+        Self::create_in_tenant(
+            executor,
+            crate::tenants::DEFAULT_TENANT.to_string(),
+            username,
+            password,
+            display_name,
+            connect_code,
+        )
+        .await
+    }
+
+    pub(crate) async fn create_in_tenant<'a, T: SqliteExecutor<'a>>(
         executor: T,
+        tenant_id: String,
         username: String,
         password: SecretString,
         display_name: String,
@@ -221,7 +566,7 @@ impl User {
         match Self::new(display_name, connect_code) {
             Ok(user) => {
                 let _user = user.clone();
-                let query_result = sqlx::query("insert into users (uid, username, password, play_key, display_name, connect_code, latest_version) values ($1, $2, $3, $4, $5, $6, $7)")
+                let query_result = sqlx::query("insert into users (uid, username, password, play_key, display_name, connect_code, latest_version, tenant_id) values ($1, $2, $3, $4, $5, $6, $7, $8)")
                     .bind(user.uid)
                     .bind(username)
                     .bind(Self::hash_password(password).unwrap())
@@ -229,6 +574,7 @@ impl User {
                     .bind(user.display_name)
                     .bind(user.connect_code)
                     .bind(user.latest_version)
+                    .bind(tenant_id)
                     .execute(executor)
                     .await;
 
@@ -256,10 +602,12 @@ impl User {
 
     pub async fn get_user_from_credentials<'a, T: SqliteExecutor<'a>>(
         executor: T,
+        tenant_id: String,
         username: String,
         password: SecretString,
     ) -> Option<User> {
-        let user = sqlx::query("select * from users where username = $1")
+        let user = sqlx::query("select * from users where tenant_id = $1 and lower(username) = lower($2) and is_banned = false")
+            .bind(tenant_id)
             .bind(username)
             .fetch_one(executor)
             .await;
@@ -291,10 +639,55 @@ impl User {
             user: self,
             matchmaking_host: config.clone().format_matchmaking_host(),
             user_discovery_url: config.clone().format_user_discovery_url(),
+            signature: None,
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Default, Validate, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchmakingPreferences {
+    // Highest acceptable estimated ping to an opponent, no limit if unset
+    #[validate(range(
+        min = 10,
+        max = 1000,
+        message = "Must be between 10 and 1000 milliseconds"
+    ))]
+    pub max_ping_ms: Option<u32>,
+    // Relaxed preferences accept higher pings the longer the player waits
+    #[serde(default)]
+    pub strict: bool,
+}
+
+impl MatchmakingPreferences {
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<MatchmakingPreferences, sqlx::Error> {
+        sqlx::query_as::<_, MatchmakingPreferences>(
+            "select max_ping_ms, strict from matchmaking_preferences where uid = $1",
+        )
+        .bind(uid)
+        .fetch_optional(executor)
+        .await
+        .map(Option::unwrap_or_default)
+    }
+
+    pub async fn save<'a, T: SqliteExecutor<'a>>(
+        &self,
+        executor: T,
+        uid: Uid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into matchmaking_preferences (uid, max_ping_ms, strict) values ($1, $2, $3) on conflict (uid) do update set max_ping_ms = excluded.max_ping_ms, strict = excluded.strict")
+            .bind(uid)
+            .bind(self.max_ping_ms)
+            .bind(self.strict)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserJson {
@@ -302,13 +695,86 @@ pub struct UserJson {
     user: User,
     matchmaking_host: String,
     user_discovery_url: String,
+    // Ignored by clients, only there to tell whether the file was edited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl UserJson {
+    pub fn sign(mut self, key: &[u8]) -> UserJson {
+        let user_json = serde_json::to_value(&self).unwrap();
+        self.signature = get_signed_body(&user_json).map(|body| crypto::sign(key, &body));
+        self
+    }
+
+    pub fn verify(user_json: &serde_json::Value, key: &[u8]) -> UserJsonVerification {
+        let signature = user_json
+            .get("signature")
+            .and_then(|signature| signature.as_str());
+
+        UserJsonVerification {
+            signed: signature.is_some(),
+            valid: match (signature, get_signed_body(user_json)) {
+                (Some(signature), Some(body)) => crypto::verify(key, &body, signature),
+                _ => false,
+            },
+        }
+    }
+}
+
+// HMAC-SHA256 of every other field, sorted by name, so that reformatting a
+// file doesn't count as editing it
+fn get_signed_body(user_json: &serde_json::Value) -> Option<Vec<u8>> {
+    let fields = user_json
+        .as_object()?
+        .iter()
+        .filter(|(name, _)| name.as_str() != "signature")
+        .collect::<BTreeMap<_, _>>();
+
+    Some(serde_json::to_vec(&fields).unwrap())
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserJsonVerification {
+    // Files downloaded before signing was set up have no signature
+    pub signed: bool,
+    // Whether the signature matches the rest of the file
+    pub valid: bool,
+}
+
+// Maps each field to the messages shown next to it when a form is rendered
+// again, for errors raised without a message of their own
+pub fn error_messages(errors: &ValidationErrors) -> HashMap<String, Vec<String>> {
+    errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages = errors
+                .iter()
+                .map(|error| match &error.message {
+                    Some(message) => message.to_string(),
+                    None => match error.code.as_ref() {
+                        "length" => "Has an invalid length",
+                        "duplicated" => "Is already in use",
+                        "range" => "Is out of range",
+                        "unknown" => "Something went wrong, please try again",
+                        _ => "Is invalid",
+                    }
+                    .to_string(),
+                })
+                .collect();
+
+            (field.to_string(), messages)
+        })
+        .collect()
 }
 
 fn is_selectable_in_name_entry(s: &str) -> Result<(), ValidationError> {
     if s.chars().all(|c| {
         is_char_hiragana(c)
             || is_char_katakana(c)
-            || char::is_numeric(c)
+            || char::is_ascii_digit(&c)
             || char::is_ascii_uppercase(&c)
             || NAME_ENTRY_SELECTABLE_PUNCTUATION.contains(&&c)
     }) {
@@ -332,6 +798,14 @@ fn is_displayable_in_game(s: &str) -> Result<(), ValidationError> {
     Err(ValidationError::new("not_displayable_in_game"))
 }
 
+fn connect_code_has_valid_length(s: &str) -> Result<(), ValidationError> {
+    if !(1..=CONNECT_CODE_MAX_LENGTH).contains(&s.chars().count()) {
+        return Err(ValidationError::new("length"));
+    }
+
+    Ok(())
+}
+
 fn connect_code_contains_separator(s: &str) -> Result<(), ValidationError> {
     if !s.contains(CONNECT_CODE_SEPARATOR) {
         return Err(ValidationError::new("separator_missing"));
@@ -378,7 +852,7 @@ fn connect_code_discriminant_contains_only_numeric_characters(
     s: &str,
 ) -> Result<(), ValidationError> {
     if let Some((_, discriminant)) = s.split_once(CONNECT_CODE_SEPARATOR) {
-        if !discriminant.chars().all(char::is_numeric) {
+        if !discriminant.chars().all(|c| c.is_ascii_digit()) {
             return Err(ValidationError::new("discriminant_invalid_characters"));
         }
     }
@@ -391,10 +865,105 @@ mod test {
     use std::str::FromStr;
 
     use bson::{oid::ObjectId, Uuid};
+    use proptest::prelude::*;
     use secrecy::SecretString;
     use sqlx::{Pool, Row, Sqlite};
 
     use crate::models::*;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::UserBuilder;
+
+    // Prefixes use the letters, digits and kana of the in-game name entry
+    // screen
+    fn connect_code() -> impl Strategy<Value = String> {
+        ("[A-Z0-9ぁ-ゖァ-ヺ]{1,4}", "[0-9]{1,3}")
+            .prop_map(|(prefix, discriminant)| format!("{}#{}", prefix, discriminant))
+    }
+
+    proptest! {
+        #[test]
+        fn generated_connect_codes_are_valid(connect_code in connect_code()) {
+            prop_assert!(User::is_valid_connect_code(&connect_code));
+            prop_assert!(User::new("TEST".to_string(), connect_code).is_ok());
+        }
+
+        #[test]
+        fn connect_codes_without_separator_are_invalid(connect_code in "[A-Z0-9]{1,8}") {
+            prop_assert!(!User::is_valid_connect_code(&connect_code));
+        }
+
+        #[test]
+        fn connect_codes_with_invalid_prefix_characters_are_invalid(
+            connect_code in connect_code(),
+            invalid in prop_oneof![
+                "[a-z]",
+                "[+=!?@%&$. ｡､/]",
+                "[Ａ-Ｚ０-９]",
+                any::<char>()
+                    .prop_filter("must be outside of name entry", |c| {
+                        !(c.is_ascii_uppercase()
+                            || c.is_ascii_digit()
+                            || is_char_hiragana(*c)
+                            || is_char_katakana(*c))
+                    })
+                    .prop_map(String::from),
+            ],
+        ) {
+            let connect_code = format!("{}{}", invalid, connect_code);
+            prop_assert!(!User::is_valid_connect_code(&connect_code));
+        }
+
+        #[test]
+        fn connect_codes_with_non_digit_discriminants_are_invalid(
+            connect_code in connect_code(),
+            invalid in any::<char>().prop_filter("must not be a digit", |c| !c.is_ascii_digit()),
+        ) {
+            let connect_code = format!("{}{}", connect_code, invalid);
+            prop_assert!(!User::is_valid_connect_code(&connect_code));
+        }
+
+        #[test]
+        fn long_connect_codes_are_rejected(
+            prefix in "[A-Z]{5,10}",
+            discriminant in "[0-9]{3,5}",
+        ) {
+            let connect_code = format!("{}#{}", prefix, discriminant);
+            prop_assert!(User::new("TEST".to_string(), connect_code).is_err());
+        }
+
+        #[test]
+        fn name_entry_display_names_are_valid(
+            display_name in "[A-Z0-9ぁ-ゖァ-ヺ+=!?@%&#$. ｡､-]{1,15}",
+        ) {
+            prop_assert!(is_displayable_in_game(&display_name).is_ok());
+        }
+
+        #[test]
+        fn ascii_display_names_are_valid(display_name in "[A-Za-z0-9+=!?@%&#$. /-]{1,15}") {
+            prop_assert!(is_displayable_in_game(&display_name).is_ok());
+        }
+
+        #[test]
+        fn display_names_with_undisplayable_characters_are_invalid(
+            display_name in "[A-Z0-9]{0,8}",
+            invalid in prop::sample::select(
+                vec!['é', 'ß', '漢', 'Ａ', '１', '٣', '½', '😀', '_', '\n']
+            ),
+        ) {
+            let display_name = format!("{}{}", display_name, invalid);
+            prop_assert!(is_displayable_in_game(&display_name).is_err());
+        }
+
+        // Kana can't be mixed with characters only the ASCII font has
+        #[test]
+        fn display_names_mixing_kana_and_lowercase_are_invalid(
+            kana in "[ぁ-ゖァ-ヺ]{1,4}",
+            ascii in "[a-z/]{1,4}",
+        ) {
+            let display_name = format!("{}{}", kana, ascii);
+            prop_assert!(is_displayable_in_game(&display_name).is_err());
+        }
+    }
 
     #[test]
     fn connect_code_with_letters_is_valid() {
@@ -526,15 +1095,7 @@ mod test {
 
     #[sqlx::test]
     async fn cannot_create_two_users_with_same_connect_code(pool: Pool<Sqlite>) {
-        User::create(
-            &pool,
-            "test".to_string(),
-            SecretString::from_str("password").unwrap(),
-            "test".to_string(),
-            "TEST#001".to_string(),
-        )
-        .await
-        .expect("Could not create user");
+        UserBuilder::new().username("test").insert(&pool).await;
 
         let user_with_same_connect_code = User::create(
             &pool,
@@ -566,15 +1127,7 @@ mod test {
 
     #[sqlx::test]
     async fn cannot_create_two_users_with_same_username(pool: Pool<Sqlite>) {
-        User::create(
-            &pool,
-            "test".to_string(),
-            SecretString::from_str("password").unwrap(),
-            "test".to_string(),
-            "TEST#001".to_string(),
-        )
-        .await
-        .expect("Could not create user");
+        UserBuilder::new().username("test").insert(&pool).await;
 
         let user_with_same_username = User::create(
             &pool,
@@ -605,19 +1158,74 @@ mod test {
     }
 
     #[sqlx::test]
-    fn test_can_get_user_from_correct_credentials(pool: Pool<Sqlite>) {
-        User::create(
+    async fn cannot_create_two_users_with_usernames_differing_in_case(pool: Pool<Sqlite>) {
+        UserBuilder::new().username("test").insert(&pool).await;
+
+        let user_with_same_username = User::create(
             &pool,
-            "test".to_string(),
+            "Test".to_string(),
             SecretString::from_str("password").unwrap(),
             "test".to_string(),
-            "TEST#001".to_string(),
+            "TEST#002".to_string(),
+        )
+        .await;
+
+        assert!(user_with_same_username.is_err());
+        assert!(
+            User::is_username_in_use(&pool, DEFAULT_TENANT.to_string(), "TEST".to_string())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[sqlx::test]
+    async fn tenants_have_separate_namespaces(pool: Pool<Sqlite>) {
+        for tenant_id in [DEFAULT_TENANT, "pal"] {
+            User::create_in_tenant(
+                &pool,
+                tenant_id.to_string(),
+                "test".to_string(),
+                SecretString::from_str("password").unwrap(),
+                "test".to_string(),
+                "TEST#001".to_string(),
+            )
+            .await
+            .expect("Could not create user");
+        }
+
+        let user = User::get_by_connect_code(&pool, "pal".to_string(), "TEST#001".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            crate::tenants::Tenant::get_for_user(&pool, user.uid)
+                .await
+                .unwrap(),
+            "pal"
+        );
+        assert_eq!(
+            User::get_all(&pool, DEFAULT_TENANT.to_string())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(User::get_user_from_credentials(
+            &pool,
+            "ntsc".to_string(),
+            "test".to_string(),
+            SecretString::from_str("password").unwrap(),
         )
         .await
-        .expect("Could not create user");
+        .is_none());
+    }
+
+    #[sqlx::test]
+    fn test_can_get_user_from_correct_credentials(pool: Pool<Sqlite>) {
+        UserBuilder::new().username("test").insert(&pool).await;
 
         let user = User::get_user_from_credentials(
             &pool,
+            DEFAULT_TENANT.to_string(),
             "test".to_string(),
             SecretString::from_str("password").unwrap(),
         )
@@ -625,23 +1233,16 @@ mod test {
         .unwrap();
 
         assert_eq!(user.display_name, "test".to_string());
-        assert_eq!(user.connect_code, "TEST#001".to_string());
+        assert_eq!(user.connect_code, "TEST#001");
     }
 
     #[sqlx::test]
     fn test_cannot_get_user_with_wrong_username(pool: Pool<Sqlite>) {
-        User::create(
-            &pool,
-            "test".to_string(),
-            SecretString::from_str("password").unwrap(),
-            "test".to_string(),
-            "TEST#001".to_string(),
-        )
-        .await
-        .expect("Could not create user");
+        UserBuilder::new().username("test").insert(&pool).await;
 
         let user = User::get_user_from_credentials(
             &pool,
+            DEFAULT_TENANT.to_string(),
             "test2".to_string(),
             SecretString::from_str("password").unwrap(),
         )
@@ -652,18 +1253,11 @@ mod test {
 
     #[sqlx::test]
     fn test_cannot_get_user_with_wrong_password(pool: Pool<Sqlite>) {
-        User::create(
-            &pool,
-            "test".to_string(),
-            SecretString::from_str("password").unwrap(),
-            "test".to_string(),
-            "TEST#001".to_string(),
-        )
-        .await
-        .expect("Could not create user");
+        UserBuilder::new().username("test").insert(&pool).await;
 
         let user = User::get_user_from_credentials(
             &pool,
+            DEFAULT_TENANT.to_string(),
             "test".to_string(),
             SecretString::from_str("hunter2").unwrap(),
         )
@@ -672,19 +1266,214 @@ mod test {
         assert!(user.is_none());
     }
 
+    #[sqlx::test]
+    fn test_is_admin(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        assert!(!User::is_admin(&pool, user.uid.clone()).await);
+
+        sqlx::query("update users set is_admin = true where uid = $1")
+            .bind(user.uid.clone())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(User::is_admin(&pool, user.uid).await);
+        assert!(!User::is_admin(&pool, "missing".parse().unwrap()).await);
+    }
+
+    #[sqlx::test]
+    fn test_find_free_connect_code(pool: Pool<Sqlite>) {
+        for connect_code in ["TEST#1", "TEST#2", "TESTS#3"] {
+            UserBuilder::new()
+                .connect_code(connect_code)
+                .insert(&pool)
+                .await;
+        }
+
+        assert_eq!(
+            User::find_free_connect_code(&pool, DEFAULT_TENANT.to_string(), "test")
+                .await
+                .unwrap(),
+            "TEST#3"
+        );
+        assert_eq!(
+            User::find_free_connect_code(&pool, "other".to_string(), "TEST")
+                .await
+                .unwrap(),
+            "TEST#1"
+        );
+    }
+
     #[sqlx::test]
     fn test_check_play_key(pool: Pool<Sqlite>) {
-        let user = User::create(
-            &pool,
-            "test".to_string(),
-            SecretString::from_str("password").unwrap(),
-            "test".to_string(),
-            "TEST#001".to_string(),
-        )
-        .await
-        .expect("Could not create user");
+        let user = UserBuilder::new().insert(&pool).await;
 
-        assert!(!User::check_play_key(&pool, user.uid.clone(), "foo".to_string()).await);
+        assert!(!User::check_play_key(&pool, user.uid.clone(), "foo".parse().unwrap()).await);
         assert!(User::check_play_key(&pool, user.uid.clone(), user.play_key).await);
     }
+
+    #[sqlx::test]
+    fn test_banned_users_cannot_log_in_or_play(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().username("test").insert(&pool).await;
+
+        let can_log_in = || {
+            User::get_user_from_credentials(
+                &pool,
+                DEFAULT_TENANT.to_string(),
+                "test".to_string(),
+                SecretString::from_str("password").unwrap(),
+            )
+        };
+
+        User::set_banned(&pool, user.uid.clone(), true)
+            .await
+            .unwrap();
+        assert!(User::is_banned(&pool, user.uid.clone()).await);
+        assert!(can_log_in().await.is_none());
+        assert!(!User::check_play_key(&pool, user.uid.clone(), user.play_key.clone()).await);
+
+        User::set_banned(&pool, user.uid.clone(), false)
+            .await
+            .unwrap();
+        assert!(!User::is_banned(&pool, user.uid.clone()).await);
+        assert!(can_log_in().await.is_some());
+        assert!(User::check_play_key(&pool, user.uid, user.play_key).await);
+    }
+
+    #[sqlx::test]
+    fn test_set_display_name(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        User::set_display_name(&pool, user.uid.clone(), "FOX".to_string())
+            .await
+            .unwrap();
+
+        let user = User::get(&pool, user.uid).await.unwrap();
+        assert_eq!(user.display_name, "FOX");
+    }
+
+    #[test]
+    fn test_is_valid_client_version() {
+        assert!(User::is_valid_client_version("3.0.2"));
+        assert!(User::is_valid_client_version("2.10.12"));
+        assert!(!User::is_valid_client_version("3.0"));
+        assert!(!User::is_valid_client_version("3.0.2-beta"));
+        assert!(!User::is_valid_client_version("3..2"));
+        assert!(!User::is_valid_client_version(&"1".repeat(40)));
+    }
+
+    #[sqlx::test]
+    fn test_set_latest_version(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        assert_eq!(user.latest_version, None);
+
+        User::set_latest_version(&pool, user.uid.clone(), "3.0.2".to_string())
+            .await
+            .unwrap();
+
+        let user = User::get(&pool, user.uid).await.unwrap();
+        assert_eq!(user.latest_version, Some("3.0.2".to_string()));
+    }
+
+    #[test]
+    fn test_user_json_signature() {
+        let user = User {
+            uid: "1234".parse().unwrap(),
+            play_key: "5678".parse().unwrap(),
+            display_name: "FOX".to_string(),
+            connect_code: "FOX#001".parse().unwrap(),
+            latest_version: None,
+        };
+        let user_json =
+            serde_json::to_value(user.get_user_json(Config::default()).sign(b"secret")).unwrap();
+        let verify = |user_json: &serde_json::Value, key: &[u8]| {
+            let verification = UserJson::verify(user_json, key);
+            (verification.signed, verification.valid)
+        };
+
+        assert_eq!(verify(&user_json, b"secret"), (true, true));
+        assert_eq!(verify(&user_json, b"other"), (true, false));
+
+        // Formatting and field order don't matter
+        let reordered = serde_json::Value::Object(
+            user_json
+                .as_object()
+                .unwrap()
+                .iter()
+                .rev()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        );
+        let reformatted = serde_json::to_string_pretty(&reordered).unwrap();
+        assert_eq!(
+            verify(&serde_json::from_str(&reformatted).unwrap(), b"secret"),
+            (true, true)
+        );
+
+        let mut edited = user_json.clone();
+        edited["playKey"] = serde_json::Value::String("0000".to_string());
+        assert_eq!(verify(&edited, b"secret"), (true, false));
+
+        let mut unsigned = user_json;
+        unsigned.as_object_mut().unwrap().remove("signature");
+        assert_eq!(verify(&unsigned, b"secret"), (false, false));
+    }
+
+    #[sqlx::test]
+    fn test_matchmaking_preferences(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        assert_eq!(
+            MatchmakingPreferences::get(&pool, user.uid.clone())
+                .await
+                .unwrap(),
+            MatchmakingPreferences::default()
+        );
+
+        for max_ping_ms in [Some(80), None] {
+            let preferences = MatchmakingPreferences {
+                max_ping_ms,
+                strict: true,
+            };
+            preferences.save(&pool, user.uid.clone()).await.unwrap();
+
+            assert_eq!(
+                MatchmakingPreferences::get(&pool, user.uid.clone())
+                    .await
+                    .unwrap(),
+                preferences
+            );
+        }
+    }
+
+    #[test]
+    fn cannot_set_max_ping_out_of_range() {
+        assert!(MatchmakingPreferences {
+            max_ping_ms: Some(5),
+            strict: false
+        }
+        .validate()
+        .is_err());
+        assert!(MatchmakingPreferences {
+            max_ping_ms: None,
+            strict: false
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn error_messages_fall_back_to_readable_messages() {
+        let errors = User::new("test".to_string(), "TEST".to_string()).unwrap_err();
+        let messages = error_messages(&errors);
+        assert!(messages.get("connect_code").unwrap()[0].starts_with("Must consist of"));
+
+        let mut errors = ValidationErrors::new();
+        errors.add("database", ValidationError::new("unknown"));
+        assert_eq!(
+            error_messages(&errors).get("database").unwrap(),
+            &vec!["Something went wrong, please try again".to_string()]
+        );
+    }
 }
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqliteExecutor};
+use tokio::net::UdpSocket;
+
+use crate::ids::Uid;
+use crate::Config;
+
+// Probes older than this are too old to belong to the ticket being created
+const PROBE_MAX_AGE_SECONDS: i64 = 60;
+// User IDs are UUIDs, anything longer isn't a probe
+const PROBE_MAX_BYTES: usize = 64;
+
+// Address each user's last probe was seen from, by UID
+static PROBES: Lazy<Mutex<HashMap<Uid, (SocketAddr, i64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// How a player's traffic is seen from the outside, reported to help
+// troubleshoot connection failures between players
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatType {
+    // The client didn't report a usable LAN address
+    Unknown,
+    // The client is reachable at its own address
+    Open,
+    // The probe showed the same public port for another destination, so
+    // peers can reach the player at the address the server saw
+    Cone,
+    // The probe showed another public port for another destination, so
+    // peers can't reach the player at the address the server saw
+    Symmetric,
+    // Without a probe: the public port is the client's own
+    PortPreserving,
+    // Without a probe: the public port differs from the client's own
+    PortTranslating,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            NatType::Unknown => "unknown",
+            NatType::Open => "open",
+            NatType::Cone => "cone",
+            NatType::Symmetric => "symmetric",
+            NatType::PortPreserving => "port_preserving",
+            NatType::PortTranslating => "port_translating",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl FromStr for NatType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unknown" => Ok(NatType::Unknown),
+            "open" => Ok(NatType::Open),
+            "cone" => Ok(NatType::Cone),
+            "symmetric" => Ok(NatType::Symmetric),
+            "port_preserving" => Ok(NatType::PortPreserving),
+            "port_translating" => Ok(NatType::PortTranslating),
+            _ => Err(()),
+        }
+    }
+}
+
+impl NatType {
+    // Compares the address the client reported with the one the
+    // matchmaking server saw and, if the client sent one, the one its probe
+    // was seen from
+    pub fn classify(
+        lan_address: Option<SocketAddr>,
+        observed_address: SocketAddr,
+        probe_address: Option<SocketAddr>,
+    ) -> NatType {
+        let lan_address = match lan_address {
+            Some(lan_address) => lan_address,
+            None => return NatType::Unknown,
+        };
+
+        if lan_address.ip() == observed_address.ip() {
+            return NatType::Open;
+        }
+
+        match probe_address {
+            Some(probe_address) if probe_address.port() == observed_address.port() => NatType::Cone,
+            Some(_) => NatType::Symmetric,
+            None if lan_address.port() == observed_address.port() => NatType::PortPreserving,
+            None => NatType::PortTranslating,
+        }
+    }
+}
+
+// What was seen of a user's connection when they last searched for a match
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NatReport {
+    pub nat_type: NatType,
+    // Port of the client on its own network, which would have to be
+    // forwarded
+    pub lan_port: Option<u16>,
+    pub updated_at: i64,
+}
+
+impl NatReport {
+    pub fn new(nat_type: NatType, lan_address: Option<SocketAddr>) -> NatReport {
+        NatReport {
+            nat_type,
+            lan_port: lan_address.map(|lan_address| lan_address.port()),
+            updated_at: Utc::now().timestamp(),
+        }
+    }
+
+    pub async fn save<'a, T: SqliteExecutor<'a>>(
+        &self,
+        executor: T,
+        uid: Uid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into nat_types (uid, nat_type, lan_port, updated_at) values ($1, $2, $3, $4) on conflict (uid) do update set nat_type = excluded.nat_type, lan_port = excluded.lan_port, updated_at = excluded.updated_at")
+            .bind(uid)
+            .bind(self.nat_type.to_string())
+            .bind(self.lan_port)
+            .bind(self.updated_at)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // None if the user never searched for a match
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Option<NatReport>, sqlx::Error> {
+        sqlx::query("select nat_type, lan_port, updated_at from nat_types where uid = $1")
+            .bind(uid)
+            .fetch_optional(executor)
+            .await
+            .map(|row| {
+                row.map(|row| NatReport {
+                    nat_type: row
+                        .get::<String, usize>(0)
+                        .parse()
+                        .unwrap_or(NatType::Unknown),
+                    lan_port: row.get(1),
+                    updated_at: row.get(2),
+                })
+            })
+    }
+}
+
+fn record_probe(uid: Uid, address: SocketAddr, now: i64) {
+    let mut probes = PROBES.lock().unwrap();
+    probes.retain(|_, (_, seen_at)| now - *seen_at <= PROBE_MAX_AGE_SECONDS);
+    probes.insert(uid, (address, now));
+}
+
+// Probes are only believed from the IP the ticket came from, so nobody can
+// skew another player's classification
+pub fn take_probe(uid: &Uid, ip: IpAddr, now: i64) -> Option<SocketAddr> {
+    let mut probes = PROBES.lock().unwrap();
+    match probes.remove(uid) {
+        Some((address, seen_at))
+            if address.ip() == ip && now - seen_at <= PROBE_MAX_AGE_SECONDS =>
+        {
+            Some(address)
+        }
+        _ => None,
+    }
+}
+
+// Clients that support it send their UID to the probe port, from the socket
+// they use for matchmaking, just before creating a ticket
+pub async fn start_probe_listener(config: Config) {
+    let port = match config.matchmaking_nat_probe_port {
+        Some(port) => port,
+        None => return,
+    };
+    let socket = match UdpSocket::bind((config.matchmaking_server_address, port)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("Could not listen for NAT probes on port {}: {}", port, err);
+            return;
+        }
+    };
+
+    println!("Listening for NAT probes on port {}", port);
+
+    let mut buffer = [0; PROBE_MAX_BYTES];
+    loop {
+        let (length, address) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                println!("Failed to receive NAT probe: {}", err);
+                continue;
+            }
+        };
+
+        let uid = std::str::from_utf8(&buffer[..length])
+            .ok()
+            .and_then(|uid| uid.trim().parse::<Uid>().ok());
+        if let Some(uid) = uid {
+            record_probe(uid, address, Utc::now().timestamp());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::nat::*;
+    use crate::test_support::UserBuilder;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_classify() {
+        let observed = address("203.0.113.7:40000");
+
+        assert_eq!(NatType::classify(None, observed, None), NatType::Unknown);
+        assert_eq!(
+            NatType::classify(Some(address("203.0.113.7:40000")), observed, None),
+            NatType::Open
+        );
+        assert_eq!(
+            NatType::classify(Some(address("192.168.1.2:40000")), observed, None),
+            NatType::PortPreserving
+        );
+        assert_eq!(
+            NatType::classify(Some(address("192.168.1.2:51000")), observed, None),
+            NatType::PortTranslating
+        );
+        assert_eq!(
+            NatType::classify(
+                Some(address("192.168.1.2:51000")),
+                observed,
+                Some(address("203.0.113.7:40000"))
+            ),
+            NatType::Cone
+        );
+        assert_eq!(
+            NatType::classify(
+                Some(address("192.168.1.2:51000")),
+                observed,
+                Some(address("203.0.113.7:40001"))
+            ),
+            NatType::Symmetric
+        );
+    }
+
+    #[test]
+    fn test_probes_are_only_taken_from_the_ticket_ip() {
+        let probe = address("203.0.113.7:40001");
+        let uid = "probed".parse::<Uid>().unwrap();
+
+        record_probe(uid.clone(), probe, 0);
+        assert_eq!(take_probe(&uid, "198.51.100.1".parse().unwrap(), 0), None);
+
+        record_probe(uid.clone(), probe, 0);
+        assert_eq!(
+            take_probe(&uid, probe.ip(), PROBE_MAX_AGE_SECONDS + 1),
+            None
+        );
+
+        record_probe(uid.clone(), probe, 0);
+        assert_eq!(take_probe(&uid, probe.ip(), 10), Some(probe));
+        assert_eq!(take_probe(&uid, probe.ip(), 10), None);
+    }
+
+    #[sqlx::test]
+    async fn can_save_nat_report(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+
+        assert_eq!(
+            NatReport::get_for_user(&pool, user.uid.clone())
+                .await
+                .unwrap(),
+            None
+        );
+
+        for (nat_type, lan_address) in [
+            (NatType::Symmetric, Some(address("192.168.1.2:51000"))),
+            (NatType::Unknown, None),
+        ] {
+            let report = NatReport::new(nat_type, lan_address);
+            report.save(&pool, user.uid.clone()).await.unwrap();
+            assert_eq!(
+                NatReport::get_for_user(&pool, user.uid.clone())
+                    .await
+                    .unwrap(),
+                Some(report)
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+use validator::Validate;
+
+use crate::ids::Uid;
+
+// Context moderators record about a user, such as prior warnings. Only
+// ever shown to admins.
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserNote {
+    pub id: i64,
+    pub uid: Uid,
+    pub author_uid: Uid,
+    pub author_display_name: String,
+    pub content: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct UserNoteForm {
+    #[validate(length(
+        min = 1,
+        max = 2000,
+        message = "Must be at least 1 and at most 2000 characters long"
+    ))]
+    pub content: String,
+}
+
+impl UserNote {
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        author_uid: Uid,
+        form: &UserNoteForm,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query(
+            "insert into user_notes (uid, author_uid, content, created_at) values ($1, $2, $3, $4)",
+        )
+        .bind(uid)
+        .bind(author_uid)
+        .bind(form.content.trim())
+        .bind(Utc::now().timestamp())
+        .execute(executor)
+        .await
+        .map(|result| result.last_insert_rowid())
+    }
+
+    // Newest first
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Vec<UserNote>, sqlx::Error> {
+        sqlx::query_as::<_, UserNote>("select user_notes.*, users.display_name as author_display_name from user_notes join users on users.uid = user_notes.author_uid where user_notes.uid = $1 order by user_notes.created_at desc, user_notes.id desc")
+            .bind(uid)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::notes::*;
+    use crate::test_support::UserBuilder;
+
+    #[sqlx::test]
+    async fn notes_are_listed_newest_first_with_their_author(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let admin = UserBuilder::new()
+            .connect_code("ADMN#001")
+            .admin()
+            .insert(&pool)
+            .await;
+
+        for content in ["Warned for stalling", "  Verified on Discord  "] {
+            UserNote::create(
+                &pool,
+                user.uid.clone(),
+                admin.uid.clone(),
+                &UserNoteForm {
+                    content: content.to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let notes = UserNote::get_for_user(&pool, user.uid).await.unwrap();
+        assert_eq!(
+            notes
+                .iter()
+                .map(|note| note.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["Verified on Discord", "Warned for stalling"]
+        );
+        assert_eq!(notes[0].author_uid, admin.uid);
+        assert_eq!(notes[0].author_display_name, admin.display_name);
+
+        assert!(UserNote::get_for_user(&pool, admin.uid)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use chrono::{TimeZone, Utc};
+use sqlx::SqlitePool;
+use validator::Validate;
+
+use openmelee::{
+    audit::{AuditAction, AuditLogEntry},
+    ids::ConnectCode,
+    logins::Login,
+    models::{error_messages, User},
+    nat::NatReport,
+    user_csv,
+};
+
+type Result<T> = std::result::Result<T, String>;
+
+async fn get_user(pool: &SqlitePool, tenant_id: &str, connect_code: &ConnectCode) -> Result<User> {
+    User::get_by_connect_code(pool, tenant_id.to_string(), connect_code.clone())
+        .await
+        .map_err(|_| format!("No user with connect code {}", connect_code))
+}
+
+// Operators act outside of the web UI, so entries have no actor
+async fn record(
+    pool: &SqlitePool,
+    action: AuditAction,
+    user: &User,
+    details: Option<String>,
+) -> Result<()> {
+    AuditLogEntry::record(pool, None, None, action, Some(user.uid.clone()), details)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+pub async fn show(pool: &SqlitePool, tenant_id: &str, connect_code: &ConnectCode) -> Result<()> {
+    let user = get_user(pool, tenant_id, connect_code).await?;
+    let last_login = Login::get_recent(pool, user.uid.clone(), 1)
+        .await
+        .map_err(|err| err.to_string())?
+        .pop();
+
+    println!("UID:            {}", user.uid);
+    println!("Display name:   {}", user.display_name);
+    println!("Connect code:   {}", user.connect_code);
+    println!(
+        "Client version: {}",
+        user.latest_version.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "Admin:          {}",
+        User::is_admin(pool, user.uid.clone()).await
+    );
+    println!(
+        "Banned:         {}",
+        User::is_banned(pool, user.uid.clone()).await
+    );
+    let nat_report = NatReport::get_for_user(pool, user.uid.clone())
+        .await
+        .map_err(|err| err.to_string())?;
+    println!(
+        "NAT type:       {}",
+        nat_report.map_or("unknown".to_string(), |report| report.nat_type.to_string())
+    );
+    match last_login {
+        Some(login) => println!(
+            "Last login:     {} from {}",
+            Utc.timestamp_opt(login.created_at, 0).unwrap(),
+            login.ip_address
+        ),
+        None => println!("Last login:     never"),
+    }
+
+    Ok(())
+}
+
+pub async fn set_display_name(
+    pool: &SqlitePool,
+    tenant_id: &str,
+    connect_code: &ConnectCode,
+    display_name: &str,
+) -> Result<()> {
+    let user = get_user(pool, tenant_id, connect_code).await?;
+
+    let renamed = User {
+        display_name: display_name.to_string(),
+        ..user.clone()
+    };
+    if let Err(errors) = renamed.validate() {
+        return Err(error_messages(&errors)
+            .into_values()
+            .flatten()
+            .collect::<Vec<String>>()
+            .join("\n"));
+    }
+
+    User::set_display_name(pool, user.uid.clone(), renamed.display_name.clone())
+        .await
+        .map_err(|err| err.to_string())?;
+    record(
+        pool,
+        AuditAction::SetDisplayName,
+        &user,
+        Some(renamed.display_name),
+    )
+    .await
+}
+
+pub async fn set_banned(
+    pool: &SqlitePool,
+    tenant_id: &str,
+    connect_code: &ConnectCode,
+    banned: bool,
+) -> Result<()> {
+    let user = get_user(pool, tenant_id, connect_code).await?;
+
+    User::set_banned(pool, user.uid.clone(), banned)
+        .await
+        .map_err(|err| err.to_string())?;
+    record(
+        pool,
+        if banned {
+            AuditAction::Ban
+        } else {
+            AuditAction::Unban
+        },
+        &user,
+        None,
+    )
+    .await
+}
+
+pub async fn export(pool: &SqlitePool, tenant_id: &str, output: Option<&str>) -> Result<()> {
+    let mut conn = pool.acquire().await.map_err(|err| err.to_string())?;
+    let data = user_csv::export(&mut conn, tenant_id.to_string())
+        .await
+        .map_err(|err| err.to_string())?;
+
+    match output {
+        Some(path) => std::fs::write(path, data).map_err(|err| err.to_string()),
+        None => {
+            print!("{}", data);
+            Ok(())
+        }
+    }
+}
+
+// Nothing is applied unless every row is valid
+pub async fn import(pool: &SqlitePool, tenant_id: &str, file: &str, dry_run: bool) -> Result<()> {
+    let data = std::fs::read(file).map_err(|err| format!("Unable to read {}: {}", file, err))?;
+    let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+    let plan = user_csv::plan(&mut tx, tenant_id.to_string(), &data)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    for change in &plan.changes {
+        if let Some(display_name) = &change.display_name {
+            println!(
+                "Line {}: rename {} to {}",
+                change.line, change.connect_code, display_name
+            );
+        }
+        match change.banned {
+            Some(true) => println!("Line {}: ban {}", change.line, change.connect_code),
+            Some(false) => println!("Line {}: unban {}", change.line, change.connect_code),
+            None => (),
+        }
+    }
+    for error in &plan.errors {
+        for message in &error.messages {
+            println!("Line {}: {}", error.line, message);
+        }
+    }
+
+    if !plan.errors.is_empty() {
+        return Err(format!(
+            "{} rows are invalid, nothing was changed",
+            plan.errors.len()
+        ));
+    }
+    if dry_run {
+        println!("Dry run, nothing was changed");
+        return Ok(());
+    }
+
+    user_csv::apply(&mut tx, None, None, &plan.changes)
+        .await
+        .map_err(|err| err.to_string())?;
+    tx.commit().await.map_err(|err| err.to_string())?;
+    println!("Updated {} users", plan.changes.len());
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::sync::RwLock;
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use pulldown_cmark::{html, Event, Options, Parser};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+use validator::{Validate, ValidationError};
+
+// First path segments already taken by routes, which a page could never be
+// reached at
+const RESERVED_SLUGS: &[&str] = &[
+    "admin",
+    "api",
+    "graphql",
+    "login",
+    "logo",
+    "logout",
+    "openmelee-user.json",
+    "profile",
+    "register",
+    "static",
+    "user",
+];
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Validate, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page {
+    #[validate(
+        length(
+            min = 1,
+            max = 50,
+            message = "Must be at least 1 and at most 50 characters long"
+        ),
+        custom(
+            function = "is_valid_slug",
+            message = "Only lowercase English letters, numbers and hyphens are allowed"
+        ),
+        custom(
+            function = "is_not_reserved_slug",
+            message = "This path is already used by another page of the site"
+        )
+    )]
+    pub slug: String,
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be at least 1 and at most 100 characters long"
+    ))]
+    pub title: String,
+    pub content: String,
+    pub show_in_navbar: bool,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct NavbarLink {
+    pub slug: String,
+    pub title: String,
+}
+
+// Pages linked in the navbar, kept in memory since templates can't query
+// the database while rendering
+static NAVBAR_LINKS: Lazy<RwLock<Vec<NavbarLink>>> = Lazy::new(|| RwLock::new(vec![]));
+
+impl Page {
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        slug: String,
+    ) -> Result<Page, sqlx::Error> {
+        sqlx::query_as::<_, Page>("select * from pages where slug = $1")
+            .bind(slug)
+            .fetch_one(executor)
+            .await
+    }
+
+    pub async fn get_all<'a, T: SqliteExecutor<'a>>(executor: T) -> Result<Vec<Page>, sqlx::Error> {
+        sqlx::query_as::<_, Page>("select * from pages order by slug")
+            .fetch_all(executor)
+            .await
+    }
+
+    pub async fn save<'a, T: SqliteExecutor<'a>>(&self, executor: T) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into pages (slug, title, content, show_in_navbar, updated_at) values ($1, $2, $3, $4, $5) on conflict (slug) do update set title = excluded.title, content = excluded.content, show_in_navbar = excluded.show_in_navbar, updated_at = excluded.updated_at")
+            .bind(&self.slug)
+            .bind(&self.title)
+            .bind(&self.content)
+            .bind(self.show_in_navbar)
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn delete<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        slug: String,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from pages where slug = $1")
+            .bind(slug)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub fn render_content(&self) -> String {
+        render_markdown(&self.content)
+    }
+}
+
+// Raw HTML in the document is escaped rather than passed through
+pub fn render_markdown(markdown: &str) -> String {
+    let parser = Parser::new_ext(markdown, Options::ENABLE_TABLES).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        event => event,
+    });
+
+    let mut output = String::new();
+    html::push_html(&mut output, parser);
+    output
+}
+
+pub async fn refresh_navbar_links<'a, T: SqliteExecutor<'a>>(
+    executor: T,
+) -> Result<(), sqlx::Error> {
+    let links = sqlx::query_as::<_, (String, String)>(
+        "select slug, title from pages where show_in_navbar = true order by slug",
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(|(slug, title)| NavbarLink { slug, title })
+    .collect();
+
+    *NAVBAR_LINKS.write().unwrap() = links;
+    Ok(())
+}
+
+pub fn get_navbar_links() -> Vec<NavbarLink> {
+    NAVBAR_LINKS.read().unwrap().clone()
+}
+
+fn is_valid_slug(slug: &str) -> Result<(), ValidationError> {
+    if slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Ok(());
+    }
+
+    Err(ValidationError::new("invalid_characters"))
+}
+
+fn is_not_reserved_slug(slug: &str) -> Result<(), ValidationError> {
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(ValidationError::new("reserved"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+    use validator::Validate;
+
+    use crate::pages::*;
+
+    fn page(slug: &str, content: &str) -> Page {
+        Page {
+            slug: slug.to_string(),
+            title: "Rules".to_string(),
+            content: content.to_string(),
+            show_in_navbar: true,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_slug_validation() {
+        assert!(page("rules", "").validate().is_ok());
+        assert!(page("code-of-conduct", "").validate().is_ok());
+        assert!(page("Rules", "").validate().is_err());
+        assert!(page("rules/2022", "").validate().is_err());
+        assert!(page("admin", "").validate().is_err());
+        assert!(page("", "").validate().is_err());
+    }
+
+    #[test]
+    fn test_render_content_escapes_html() {
+        assert_eq!(
+            page("rules", "# Rules\n\n<script>alert(1)</script>").render_content(),
+            "<h1>Rules</h1>\n&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+    }
+
+    #[sqlx::test]
+    async fn navbar_links_follow_saved_pages(pool: Pool<Sqlite>) {
+        page("rules", "").save(&pool).await.unwrap();
+        Page {
+            show_in_navbar: false,
+            ..page("about", "")
+        }
+        .save(&pool)
+        .await
+        .unwrap();
+
+        refresh_navbar_links(&pool).await.unwrap();
+        assert_eq!(
+            get_navbar_links(),
+            vec![NavbarLink {
+                slug: "rules".to_string(),
+                title: "Rules".to_string(),
+            }]
+        );
+
+        Page::delete(&pool, "rules".to_string()).await.unwrap();
+        refresh_navbar_links(&pool).await.unwrap();
+        assert!(get_navbar_links().is_empty());
+    }
+}
@@ -0,0 +1,399 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor, SqlitePool};
+
+use crate::ids::{MatchId, Uid};
+use crate::matches::Match;
+
+pub const MAX_SCORE: i64 = 100;
+// Players have this long to report a match before it's scored as abandoned
+pub const SCORING_DELAY_SECONDS: i64 = 60 * 60;
+const SCORING_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Pings up to this aren't noticeable, every 2 ms above cost a point
+const PING_ALLOWANCE_MS: i64 = 30;
+const MAX_PING_PENALTY: i64 = 50;
+// Most likely the players couldn't connect to each other
+const UNREPORTED_PENALTY: i64 = 50;
+
+pub const MIN_RATING: i64 = 1;
+pub const MAX_RATING: i64 = 5;
+// Players are only asked about their last match for this long
+pub const SURVEY_WINDOW_SECONDS: i64 = 24 * 60 * 60;
+
+// Players' latency to each other isn't known, but can't be worse than going
+// through the server, so the two furthest players are assumed to be that far
+pub fn estimate_ping_ms(rtts_ms: &[i64]) -> Option<i64> {
+    let mut rtts_ms = rtts_ms.to_vec();
+    rtts_ms.sort_unstable_by(|a, b| b.cmp(a));
+
+    match rtts_ms[..] {
+        [first, second, ..] => Some(first + second),
+        _ => None,
+    }
+}
+
+// Only ranked players have ratings, so how evenly matched players were isn't
+// part of the score
+pub fn compute_score(ping_ms: Option<i64>, reported: bool) -> i64 {
+    let ping_penalty = ping_ms
+        .map(|ping_ms| ((ping_ms - PING_ALLOWANCE_MS).max(0) / 2).min(MAX_PING_PENALTY))
+        .unwrap_or(0);
+    let completion_penalty = if reported { 0 } else { UNREPORTED_PENALTY };
+
+    MAX_SCORE - ping_penalty - completion_penalty
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchQuality {
+    pub match_id: MatchId,
+    pub score: i64,
+    // Unknown for matches formed before latency was recorded
+    pub ping_ms: Option<i64>,
+    // Whether any player reported a result
+    pub reported: bool,
+    pub scored_at: i64,
+}
+
+impl MatchQuality {
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+    ) -> Result<MatchQuality, sqlx::Error> {
+        sqlx::query_as::<_, MatchQuality>("select * from match_quality where match_id = $1")
+            .bind(match_id)
+            .fetch_one(executor)
+            .await
+    }
+
+    // Scores the matches formed before the given time which aren't scored
+    // yet, and returns how many there were
+    pub async fn score_formed_before(pool: &SqlitePool, before: i64) -> Result<usize, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let match_ids = sqlx::query_scalar::<_, String>("select match_id from match_formations where created_at < $1 and not exists (select 1 from match_quality where match_quality.match_id = match_formations.match_id)")
+            .bind(before)
+            .fetch_all(&mut tx)
+            .await?;
+
+        for match_id in &match_ids {
+            let rtts_ms = sqlx::query_scalar::<_, Option<i64>>(
+                "select rtt_ms from match_formation_players where match_id = $1",
+            )
+            .bind(match_id.clone())
+            .fetch_all(&mut tx)
+            .await?
+            .into_iter()
+            .collect::<Option<Vec<i64>>>();
+            let ping_ms = rtts_ms.and_then(|rtts_ms| estimate_ping_ms(&rtts_ms));
+            let reported = sqlx::query_scalar::<_, bool>(
+                "select exists (select 1 from match_results where match_id = $1)",
+            )
+            .bind(match_id.clone())
+            .fetch_one(&mut tx)
+            .await?;
+
+            sqlx::query("insert into match_quality (match_id, score, ping_ms, reported, scored_at) values ($1, $2, $3, $4, $5)")
+                .bind(match_id.clone())
+                .bind(compute_score(ping_ms, reported))
+                .bind(ping_ms)
+                .bind(reported)
+                .bind(Utc::now().timestamp())
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(match_ids.len())
+    }
+}
+
+pub async fn start_scoring(pool: SqlitePool) {
+    let mut interval = tokio::time::interval(SCORING_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let before = Utc::now().timestamp() - SCORING_DELAY_SECONDS;
+        if let Err(err) = MatchQuality::score_formed_before(&pool, before).await {
+            println!("Failed to score matches: {}", err);
+        }
+    }
+}
+
+// A player's answer to the survey about their last match
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchRating {
+    pub match_id: MatchId,
+    pub uid: Uid,
+    pub rating: i64,
+    pub created_at: i64,
+}
+
+impl MatchRating {
+    // False if the user already rated the match
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        match_id: MatchId,
+        uid: Uid,
+        rating: i64,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("insert into match_ratings (match_id, uid, rating, created_at) values ($1, $2, $3, $4) on conflict do nothing")
+            .bind(match_id)
+            .bind(uid)
+            .bind(rating)
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    // The last match the user played since the given time, unless they
+    // already rated it
+    pub async fn get_unrated_match<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        since: i64,
+    ) -> Result<Option<Match>, sqlx::Error> {
+        sqlx::query_as::<_, Match>("select * from (select matches.* from matches join match_players on match_players.match_id = matches.match_id where match_players.uid = $1 and matches.created_at >= $2 order by matches.created_at desc, matches.match_id desc limit 1) as last_match where not exists (select 1 from match_ratings where match_ratings.match_id = last_match.match_id and match_ratings.uid = $1)")
+            .bind(uid)
+            .bind(since)
+            .fetch_optional(executor)
+            .await
+    }
+}
+
+// Quality of a tenant's matches per queue and pairing algorithm, to compare
+// matchmaking settings
+#[derive(Debug, PartialEq, FromRow, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QualitySummary {
+    pub mode: String,
+    pub algorithm: String,
+    pub matches: i64,
+    pub mean_score: i64,
+    pub mean_ping_ms: Option<i64>,
+    pub reported_percent: i64,
+    pub ratings: i64,
+    // Mean survey answer, rounded to a tenth
+    pub mean_rating: Option<f64>,
+}
+
+impl QualitySummary {
+    pub async fn get_since<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        since: i64,
+    ) -> Result<Vec<QualitySummary>, sqlx::Error> {
+        sqlx::query_as::<_, QualitySummary>("select match_formations.mode, match_formations.algorithm, count(match_quality.match_id) as matches, cast(round(avg(match_quality.score)) as integer) as mean_score, cast(round(avg(match_quality.ping_ms)) as integer) as mean_ping_ms, cast(round(100.0 * avg(match_quality.reported)) as integer) as reported_percent, coalesce(sum(ratings.count), 0) as ratings, round(1.0 * sum(ratings.total) / sum(ratings.count), 1) as mean_rating from match_quality join match_formations on match_formations.match_id = match_quality.match_id left join (select match_id, count(rating) as count, sum(rating) as total from match_ratings group by match_id) as ratings on ratings.match_id = match_quality.match_id where match_formations.tenant_id = $1 and match_formations.created_at >= $2 group by match_formations.mode, match_formations.algorithm order by match_formations.mode, match_formations.algorithm")
+            .bind(tenant_id)
+            .bind(since)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::formations::{FormedPlayer, MatchFormation};
+    use crate::game::Stage;
+    use crate::matches::MatchResult;
+    use crate::quality::*;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    #[test]
+    fn test_compute_score() {
+        assert_eq!(estimate_ping_ms(&[10, 40, 20]), Some(60));
+        assert_eq!(estimate_ping_ms(&[10]), None);
+
+        assert_eq!(compute_score(Some(20), true), MAX_SCORE);
+        assert_eq!(compute_score(None, true), MAX_SCORE);
+        assert_eq!(compute_score(Some(70), true), MAX_SCORE - 20);
+        assert_eq!(
+            compute_score(Some(1000), true),
+            MAX_SCORE - MAX_PING_PENALTY
+        );
+        assert_eq!(
+            compute_score(Some(70), false),
+            MAX_SCORE - 20 - UNREPORTED_PENALTY
+        );
+    }
+
+    #[sqlx::test]
+    async fn matches_are_scored_once_and_summarized(pool: Pool<Sqlite>) {
+        let user_a = UserBuilder::new().insert(&pool).await;
+        let user_b = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+
+        for match_id in ["mode.unranked-1", "mode.unranked-2"] {
+            MatchBuilder::new(match_id)
+                .players(&[user_a.clone(), user_b.clone()])
+                .insert(&pool)
+                .await;
+            MatchFormation {
+                match_id: match_id.parse().unwrap(),
+                tenant_id: DEFAULT_TENANT.to_string(),
+                mode: "unranked".to_string(),
+                algorithm: "first_fit".to_string(),
+                algorithm_version: 1,
+                stages: vec![Stage::Battlefield],
+                players: vec![
+                    FormedPlayer {
+                        uid: user_a.uid.clone(),
+                        waited_seconds: 10,
+                        rtt_ms: 20,
+                    },
+                    FormedPlayer {
+                        uid: user_b.uid.clone(),
+                        waited_seconds: 10,
+                        rtt_ms: 50,
+                    },
+                ],
+                created_at: 100,
+            }
+            .save(&pool)
+            .await
+            .unwrap();
+        }
+        MatchResult::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            user_a.uid.clone(),
+            user_a.uid.clone(),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            MatchQuality::score_formed_before(&pool, 100).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            MatchQuality::score_formed_before(&pool, 200).await.unwrap(),
+            2
+        );
+        assert_eq!(
+            MatchQuality::score_formed_before(&pool, 200).await.unwrap(),
+            0
+        );
+
+        let quality = MatchQuality::get(&pool, "mode.unranked-1".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            (quality.score, quality.ping_ms, quality.reported),
+            (MAX_SCORE - 20, Some(70), true)
+        );
+
+        assert!(MatchRating::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            user_a.uid.clone(),
+            4
+        )
+        .await
+        .unwrap());
+        assert!(!MatchRating::create(
+            &pool,
+            "mode.unranked-1".parse().unwrap(),
+            user_a.uid.clone(),
+            1
+        )
+        .await
+        .unwrap());
+        MatchRating::create(
+            &pool,
+            "mode.unranked-2".parse().unwrap(),
+            user_b.uid.clone(),
+            1,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            QualitySummary::get_since(&pool, DEFAULT_TENANT.to_string(), 0)
+                .await
+                .unwrap(),
+            vec![QualitySummary {
+                mode: "unranked".to_string(),
+                algorithm: "first_fit".to_string(),
+                matches: 2,
+                mean_score: MAX_SCORE - 20 - UNREPORTED_PENALTY / 2,
+                mean_ping_ms: Some(70),
+                reported_percent: 50,
+                ratings: 2,
+                mean_rating: Some(2.5),
+            }]
+        );
+        assert_eq!(
+            QualitySummary::get_since(&pool, "other".to_string(), 0)
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[sqlx::test]
+    async fn only_the_last_match_is_offered_for_rating(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let now = Utc::now().timestamp();
+
+        assert_eq!(
+            MatchRating::get_unrated_match(&pool, user.uid.clone(), 0)
+                .await
+                .unwrap(),
+            None
+        );
+
+        MatchBuilder::new("mode.unranked-1")
+            .player(&user)
+            .insert(&pool)
+            .await;
+        sqlx::query("update matches set created_at = $1")
+            .bind(now - 10)
+            .execute(&pool)
+            .await
+            .unwrap();
+        MatchBuilder::new("mode.unranked-2")
+            .player(&user)
+            .insert(&pool)
+            .await;
+
+        let unrated = MatchRating::get_unrated_match(&pool, user.uid.clone(), now - 60)
+            .await
+            .unwrap();
+        assert_eq!(unrated.unwrap().match_id, "mode.unranked-2");
+        assert_eq!(
+            MatchRating::get_unrated_match(&pool, user.uid.clone(), now + 1)
+                .await
+                .unwrap(),
+            None
+        );
+
+        MatchRating::create(
+            &pool,
+            "mode.unranked-2".parse().unwrap(),
+            user.uid.clone(),
+            5,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            MatchRating::get_unrated_match(&pool, user.uid.clone(), now - 60)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, Row, SqliteExecutor, SqlitePool};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    events::{self, Event},
+    game::OnlinePlayMode,
+    ids::Uid,
+    matches::{Match, MatchStatus},
+};
+
+pub const PLAY_3_GAMES: &str = "play_3_games";
+pub const WIN_3_STAGES: &str = "win_3_stages";
+
+struct Quest {
+    id: &'static str,
+    name: &'static str,
+    goal: i64,
+}
+
+// Quests start over every day, at midnight UTC
+const QUESTS: [Quest; 2] = [
+    Quest {
+        id: PLAY_3_GAMES,
+        name: "Play 3 games",
+        goal: 3,
+    },
+    Quest {
+        id: WIN_3_STAGES,
+        name: "Win on 3 different stages",
+        goal: 3,
+    },
+];
+
+fn format_day(day: NaiveDate) -> String {
+    day.format("%Y-%m-%d").to_string()
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyQuest {
+    pub id: String,
+    pub name: String,
+    pub progress: i64,
+    pub goal: i64,
+    pub completed_at: Option<i64>,
+}
+
+impl DailyQuest {
+    // Every quest of the day, including those the user made no progress on
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        day: NaiveDate,
+    ) -> Result<Vec<DailyQuest>, sqlx::Error> {
+        let rows = sqlx::query(
+            "select quest_id, progress, completed_at from user_daily_quests where uid = $1 and day = $2",
+        )
+        .bind(uid)
+        .bind(format_day(day))
+        .fetch_all(executor)
+        .await?;
+
+        Ok(QUESTS
+            .iter()
+            .map(|quest| {
+                let row = rows
+                    .iter()
+                    .find(|row| row.get::<String, usize>(0) == quest.id);
+                DailyQuest {
+                    id: quest.id.to_string(),
+                    name: quest.name.to_string(),
+                    progress: row.map(|row| row.get(1)).unwrap_or(0).min(quest.goal),
+                    goal: quest.goal,
+                    completed_at: row.and_then(|row| row.get(2)),
+                }
+            })
+            .collect())
+    }
+
+    // Completed quests keep the time they were first completed at
+    async fn save<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+        day: NaiveDate,
+        quest: &Quest,
+        progress: i64,
+    ) -> Result<(), sqlx::Error> {
+        let completed_at = (progress >= quest.goal).then(|| Utc::now().timestamp());
+
+        sqlx::query("insert into user_daily_quests (uid, day, quest_id, progress, completed_at) values ($1, $2, $3, $4, $5) on conflict (uid, day, quest_id) do update set progress = excluded.progress, completed_at = coalesce(user_daily_quests.completed_at, excluded.completed_at)")
+            .bind(uid)
+            .bind(format_day(day))
+            .bind(quest.id)
+            .bind(progress)
+            .bind(completed_at)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+}
+
+// Confirmed matches the user played on the given day, and how many
+// different stages they or their teammate won on
+async fn count_progress<'a, T: SqliteExecutor<'a>>(
+    executor: T,
+    uid: Uid,
+    day: NaiveDate,
+) -> Result<(i64, i64), sqlx::Error> {
+    let from = Utc
+        .from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap())
+        .timestamp();
+
+    sqlx::query("select count(matches.match_id), count(distinct case when winner.uid = $1 or (matches.mode = $6 and winner.port % 2 = match_players.port % 2) then matches.stage end) from matches join match_players on match_players.match_id = matches.match_id join match_players winner on winner.match_id = matches.match_id and winner.uid = matches.winner_uid where match_players.uid = $1 and matches.status in ($2, $3) and matches.created_at >= $4 and matches.created_at < $5")
+        .bind(uid)
+        .bind(MatchStatus::Confirmed.to_string())
+        .bind(MatchStatus::Resolved.to_string())
+        .bind(from)
+        .bind(from + Duration::days(1).num_seconds())
+        .bind(OnlinePlayMode::Teams.to_string())
+        .fetch_one(executor)
+        .await
+        .map(|row| (row.get::<i64, usize>(0), row.get::<i64, usize>(1)))
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Streak {
+    // Consecutive days with at least one confirmed match
+    pub play_days: i64,
+    pub longest_play_days: i64,
+    pub last_played_on: String,
+    // Consecutive confirmed matches won
+    pub wins: i64,
+    pub longest_wins: i64,
+}
+
+impl Streak {
+    // Matches confirmed late may be from a day already counted, which
+    // leaves the play streak as it is
+    pub fn record(streak: Option<Streak>, day: NaiveDate, won: bool) -> Streak {
+        let streak = streak.unwrap_or(Streak {
+            play_days: 0,
+            longest_play_days: 0,
+            last_played_on: String::new(),
+            wins: 0,
+            longest_wins: 0,
+        });
+        let last_played_on = NaiveDate::parse_from_str(&streak.last_played_on, "%Y-%m-%d").ok();
+
+        let (play_days, last_played_on) = match last_played_on {
+            Some(last_played_on) if day <= last_played_on => (streak.play_days, last_played_on),
+            Some(last_played_on) if day - last_played_on == Duration::days(1) => {
+                (streak.play_days + 1, day)
+            }
+            _ => (1, day),
+        };
+        let wins = if won { streak.wins + 1 } else { 0 };
+
+        Streak {
+            play_days,
+            longest_play_days: streak.longest_play_days.max(play_days),
+            last_played_on: format_day(last_played_on),
+            wins,
+            longest_wins: streak.longest_wins.max(wins),
+        }
+    }
+
+    // The play streak is over once a whole day passed without a match
+    pub fn get_current_play_days(&self, today: NaiveDate) -> i64 {
+        match NaiveDate::parse_from_str(&self.last_played_on, "%Y-%m-%d") {
+            Ok(last_played_on) if today - last_played_on <= Duration::days(1) => self.play_days,
+            _ => 0,
+        }
+    }
+
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Option<Streak>, sqlx::Error> {
+        sqlx::query_as::<_, Streak>("select play_days, longest_play_days, last_played_on, wins, longest_wins from user_streaks where uid = $1")
+            .bind(uid)
+            .fetch_optional(executor)
+            .await
+    }
+
+    async fn save<'a, T: SqliteExecutor<'a>>(
+        &self,
+        executor: T,
+        uid: Uid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into user_streaks (uid, play_days, longest_play_days, last_played_on, wins, longest_wins) values ($1, $2, $3, $4, $5, $6) on conflict (uid) do update set play_days = excluded.play_days, longest_play_days = excluded.longest_play_days, last_played_on = excluded.last_played_on, wins = excluded.wins, longest_wins = excluded.longest_wins")
+            .bind(uid)
+            .bind(self.play_days)
+            .bind(self.longest_play_days)
+            .bind(self.last_played_on.clone())
+            .bind(self.wins)
+            .bind(self.longest_wins)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+}
+
+// Matches count towards the streaks and quests of the day they were
+// created on
+pub async fn track_event(pool: &SqlitePool, event: &Event) -> Result<(), sqlx::Error> {
+    match event {
+        Event::MatchConfirmed {
+            match_id,
+            winner_uids,
+            player_uids,
+        } => {
+            let confirmed_match = Match::get(pool, match_id.clone()).await?;
+            let day = Utc
+                .timestamp_opt(confirmed_match.created_at, 0)
+                .unwrap()
+                .date_naive();
+
+            for uid in player_uids {
+                let mut tx = pool.begin().await?;
+
+                let streak = Streak::get_for_user(&mut tx, uid.clone()).await?;
+                Streak::record(streak, day, winner_uids.contains(uid))
+                    .save(&mut tx, uid.clone())
+                    .await?;
+
+                let (games, stages_won) = count_progress(&mut tx, uid.clone(), day).await?;
+                for quest in &QUESTS {
+                    let progress = match quest.id {
+                        PLAY_3_GAMES => games,
+                        _ => stages_won,
+                    };
+                    DailyQuest::save(&mut tx, uid.clone(), day, quest, progress).await?;
+                }
+
+                tx.commit().await?;
+            }
+        }
+        Event::BroadcastCreated { .. } => {}
+    }
+
+    Ok(())
+}
+
+pub async fn start_tracking(pool: SqlitePool) {
+    let mut events = events::subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                println!("Quest tracking missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if let Err(err) = track_event(&pool, &event).await {
+            println!("Failed to track quests for {:?}: {}", event, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::game::Stage;
+    use crate::quests::*;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    fn day(day: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn test_streaks_count_consecutive_days_and_wins() {
+        let streak = Streak::record(None, day("2022-10-01"), true);
+        let streak = Streak::record(Some(streak), day("2022-10-02"), true);
+        let streak = Streak::record(Some(streak), day("2022-10-02"), false);
+        assert_eq!(
+            streak,
+            Streak {
+                play_days: 2,
+                longest_play_days: 2,
+                last_played_on: "2022-10-02".to_string(),
+                wins: 0,
+                longest_wins: 2,
+            }
+        );
+        assert_eq!(streak.get_current_play_days(day("2022-10-03")), 2);
+        assert_eq!(streak.get_current_play_days(day("2022-10-04")), 0);
+
+        // A match from an earlier day confirmed late
+        let streak = Streak::record(Some(streak), day("2022-10-01"), true);
+        assert_eq!(streak.play_days, 2);
+        assert_eq!(streak.last_played_on, "2022-10-02");
+
+        let streak = Streak::record(Some(streak), day("2022-10-05"), true);
+        assert_eq!((streak.play_days, streak.longest_play_days), (1, 2));
+    }
+
+    #[sqlx::test]
+    async fn tracks_daily_quests_from_confirmed_matches(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let opponent = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+
+        for (match_id, stage, winner) in [
+            ("mode.unranked-1", Stage::Battlefield, &user),
+            ("mode.unranked-2", Stage::Battlefield, &user),
+            ("mode.unranked-3", Stage::DreamLand, &opponent),
+        ] {
+            MatchBuilder::new(match_id)
+                .players(&[user.clone(), opponent.clone()])
+                .stage(stage)
+                .confirmed(winner)
+                .insert(&pool)
+                .await;
+            track_event(
+                &pool,
+                &Event::MatchConfirmed {
+                    match_id: match_id.parse().unwrap(),
+                    winner_uids: vec![winner.uid.clone()],
+                    player_uids: vec![user.uid.clone(), opponent.uid.clone()],
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let today = Utc::now().date_naive();
+        let quests = DailyQuest::get_for_user(&pool, user.uid.clone(), today)
+            .await
+            .unwrap();
+        assert_eq!(
+            quests
+                .iter()
+                .map(|quest| (
+                    quest.id.as_str(),
+                    quest.progress,
+                    quest.completed_at.is_some()
+                ))
+                .collect::<Vec<(&str, i64, bool)>>(),
+            vec![(PLAY_3_GAMES, 3, true), (WIN_3_STAGES, 1, false)]
+        );
+
+        let streak = Streak::get_for_user(&pool, user.uid)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(streak.get_current_play_days(today), 1);
+        assert_eq!((streak.wins, streak.longest_wins), (0, 2));
+    }
+}
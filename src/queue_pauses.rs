@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+use validator::Validate;
+
+use crate::game::OnlinePlayMode;
+use crate::ids::Uid;
+
+// A queue an admin stopped, during an incident for instance. Tickets for it
+// are turned away and its players aren't paired until it's resumed, while
+// the other queues keep working. Pauses are kept in the database, so they
+// outlast restarts.
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuePause {
+    pub mode: String,
+    // Shown to players along with the queue being unavailable, may be empty
+    pub reason: String,
+    pub paused_by: Option<Uid>,
+    pub paused_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct QueuePauseForm {
+    #[validate(length(max = 200, message = "Must be at most 200 characters long"))]
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl QueuePause {
+    // False if the queue was already paused, which keeps the first pause
+    pub async fn pause<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        mode: OnlinePlayMode,
+        reason: &str,
+        paused_by: Option<Uid>,
+        now: i64,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("insert into queue_pauses (mode, reason, paused_by, paused_at) values ($1, $2, $3, $4) on conflict (mode) do nothing")
+            .bind(mode.to_string())
+            .bind(reason.trim())
+            .bind(paused_by)
+            .bind(now)
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    // False if the queue wasn't paused
+    pub async fn resume<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        mode: OnlinePlayMode,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("delete from queue_pauses where mode = $1")
+            .bind(mode.to_string())
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        mode: OnlinePlayMode,
+    ) -> Result<Option<QueuePause>, sqlx::Error> {
+        sqlx::query_as::<_, QueuePause>("select * from queue_pauses where mode = $1")
+            .bind(mode.to_string())
+            .fetch_optional(executor)
+            .await
+    }
+
+    pub async fn get_all<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+    ) -> Result<Vec<QueuePause>, sqlx::Error> {
+        sqlx::query_as::<_, QueuePause>("select * from queue_pauses order by mode")
+            .fetch_all(executor)
+            .await
+    }
+
+    pub fn get_message(&self) -> String {
+        match self.reason.is_empty() {
+            true => format!("The {} queue is temporarily unavailable", self.mode),
+            false => format!(
+                "The {} queue is temporarily unavailable: {}",
+                self.mode, self.reason
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::queue_pauses::*;
+
+    #[sqlx::test]
+    async fn queues_are_paused_until_resumed(pool: Pool<Sqlite>) {
+        let mode = OnlinePlayMode::Ranked;
+        assert!(QueuePause::pause(&pool, mode, " Incident ", None, 100)
+            .await
+            .unwrap());
+        assert!(!QueuePause::pause(&pool, mode, "Again", None, 200)
+            .await
+            .unwrap());
+
+        let pause = QueuePause::get(&pool, mode).await.unwrap().unwrap();
+        assert_eq!(pause.paused_at, 100);
+        assert_eq!(
+            pause.get_message(),
+            "The ranked queue is temporarily unavailable: Incident"
+        );
+        assert_eq!(
+            QueuePause::get(&pool, OnlinePlayMode::Unranked)
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(QueuePause::resume(&pool, mode).await.unwrap());
+        assert!(!QueuePause::resume(&pool, mode).await.unwrap());
+        assert!(QueuePause::get_all(&pool).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use serde::Serialize;
+use sqlx::{FromRow, SqliteConnection, SqliteExecutor};
+
+use crate::game::OnlinePlayMode;
+use crate::ids::Uid;
+use crate::matches::Match;
+
+pub const DEFAULT_RATING: f64 = 1500.0;
+// Ratings move faster until there are enough games to trust them
+const PROVISIONAL_GAMES: i64 = 10;
+const PROVISIONAL_K_FACTOR: f64 = 64.0;
+const K_FACTOR: f64 = 32.0;
+
+// Ranked players are only paired within this many points of each other at
+// first, the band widens the longer they wait so nobody waits forever
+const RATING_BAND: f64 = 100.0;
+const RATING_BAND_STEP: f64 = 50.0;
+const RATING_BAND_INTERVAL_SECONDS: i64 = 30;
+const MAX_RATING_BAND: f64 = 400.0;
+
+#[derive(Debug, PartialEq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rating {
+    pub uid: Uid,
+    pub rating: f64,
+    // Ranked matches counted, the update count of the Slippi API
+    pub games: i64,
+    pub updated_at: i64,
+}
+
+impl Rating {
+    fn get_k_factor(&self) -> f64 {
+        if self.games < PROVISIONAL_GAMES {
+            PROVISIONAL_K_FACTOR
+        } else {
+            K_FACTOR
+        }
+    }
+
+    // Players who never played ranked start from the default rating
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<Rating, sqlx::Error> {
+        let rating = sqlx::query_as::<_, Rating>("select * from ratings where uid = $1")
+            .bind(uid.clone())
+            .fetch_optional(executor)
+            .await?;
+
+        Ok(rating.unwrap_or(Rating {
+            uid,
+            rating: DEFAULT_RATING,
+            games: 0,
+            updated_at: 0,
+        }))
+    }
+
+    async fn save<'a, T: SqliteExecutor<'a>>(&self, executor: T) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into ratings (uid, rating, games, updated_at) values ($1, $2, $3, $4) on conflict (uid) do update set rating = excluded.rating, games = excluded.games, updated_at = excluded.updated_at")
+            .bind(self.uid.clone())
+            .bind(self.rating)
+            .bind(self.games)
+            .bind(self.updated_at)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+}
+
+// Elo's probability of a player beating their opponent
+pub fn get_expected_score(rating: f64, opponent_rating: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0))
+}
+
+pub fn get_rating_band(waited_seconds: i64) -> f64 {
+    let widened_by = RATING_BAND_STEP * (waited_seconds / RATING_BAND_INTERVAL_SECONDS) as f64;
+    (RATING_BAND + widened_by).min(MAX_RATING_BAND)
+}
+
+// Updates the ratings of both players of a confirmed ranked match, and
+// returns them as they are after it
+pub async fn record_result(
+    conn: &mut SqliteConnection,
+    winner_uid: Uid,
+    loser_uid: Uid,
+    now: i64,
+) -> Result<(Rating, Rating), sqlx::Error> {
+    let mut winner = Rating::get(&mut *conn, winner_uid).await?;
+    let mut loser = Rating::get(&mut *conn, loser_uid).await?;
+
+    let expected = get_expected_score(winner.rating, loser.rating);
+    winner.rating += winner.get_k_factor() * (1.0 - expected);
+    loser.rating -= loser.get_k_factor() * (1.0 - expected);
+    for rating in [&mut winner, &mut loser] {
+        rating.games += 1;
+        rating.updated_at = now;
+        rating.save(&mut *conn).await?;
+    }
+
+    Ok((winner, loser))
+}
+
+// Only ranked matches are rated, once they are confirmed or resolved
+pub async fn rate_match(
+    conn: &mut SqliteConnection,
+    rated_match: &Match,
+    player_uids: &[Uid],
+    winner_uid: &Uid,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    if rated_match.mode != OnlinePlayMode::Ranked.to_string() {
+        return Ok(());
+    }
+
+    if let Some(loser_uid) = player_uids.iter().find(|uid| *uid != winner_uid) {
+        record_result(conn, winner_uid.clone(), loser_uid.clone(), now).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::ranking::*;
+    use crate::test_support::UserBuilder;
+
+    #[test]
+    fn test_get_expected_score() {
+        assert_eq!(get_expected_score(1500.0, 1500.0), 0.5);
+        assert!((get_expected_score(1900.0, 1500.0) - 10.0 / 11.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rating_band_widens_with_waiting() {
+        assert_eq!(get_rating_band(0), RATING_BAND);
+        assert_eq!(get_rating_band(RATING_BAND_INTERVAL_SECONDS), 150.0);
+        assert_eq!(get_rating_band(60 * 60), MAX_RATING_BAND);
+    }
+
+    #[sqlx::test]
+    async fn results_update_both_ratings(pool: Pool<Sqlite>) {
+        let winner = UserBuilder::new().insert(&pool).await;
+        let loser = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        let mut conn = pool.acquire().await.unwrap();
+
+        let (winner_rating, loser_rating) =
+            record_result(&mut conn, winner.uid.clone(), loser.uid.clone(), 1000)
+                .await
+                .unwrap();
+        assert_eq!(
+            (winner_rating.rating, loser_rating.rating),
+            (DEFAULT_RATING + 32.0, DEFAULT_RATING - 32.0)
+        );
+
+        assert_eq!(Rating::get(&pool, loser.uid).await.unwrap(), loser_rating);
+        assert_eq!(loser_rating.games, 1);
+        assert_eq!(
+            Rating::get(&pool, winner.uid).await.unwrap().updated_at,
+            1000
+        );
+    }
+}
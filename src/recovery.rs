@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+
+use sqlx::SqliteExecutor;
+
+use crate::ids::Uid;
+
+// Failed recoveries allowed from an address, and for a user, within the
+// window. Play keys are too long to guess, this mostly keeps the password
+// hashing from being used to load the server.
+pub const MAX_FAILED_ATTEMPTS: i64 = 5;
+pub const ATTEMPT_WINDOW_SECONDS: i64 = 60 * 60;
+
+// Attempts older than the window are removed whenever a new one is recorded
+pub async fn record_failed_attempt<'a, T: SqliteExecutor<'a>>(
+    executor: T,
+    ip_address: IpAddr,
+    uid: Option<Uid>,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("delete from recovery_attempts where created_at <= $1; insert into recovery_attempts (ip_address, uid, created_at) values ($2, $3, $4)")
+        .bind(now - ATTEMPT_WINDOW_SECONDS)
+        .bind(ip_address.to_string())
+        .bind(uid)
+        .bind(now)
+        .execute(executor)
+        .await
+        .map(|_| ())
+}
+
+pub async fn is_rate_limited<'a, T: SqliteExecutor<'a>>(
+    executor: T,
+    ip_address: IpAddr,
+    uid: Option<Uid>,
+    now: i64,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("select count(*) from recovery_attempts where created_at > $1 and (ip_address = $2 or uid = $3)")
+        .bind(now - ATTEMPT_WINDOW_SECONDS)
+        .bind(ip_address.to_string())
+        .bind(uid)
+        .fetch_one(executor)
+        .await
+        .map(|failed_attempts| failed_attempts >= MAX_FAILED_ATTEMPTS)
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::recovery::*;
+
+    #[sqlx::test]
+    async fn failed_attempts_are_limited_by_address_and_user(pool: Pool<Sqlite>) {
+        let ip_address = IpAddr::from([203, 0, 113, 7]);
+        let other_ip_address = IpAddr::from([198, 51, 100, 1]);
+        let uid = "1234".parse::<Uid>().unwrap();
+
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            assert!(!is_rate_limited(&pool, ip_address, None, 100).await.unwrap());
+            record_failed_attempt(&pool, ip_address, Some(uid.clone()), 100)
+                .await
+                .unwrap();
+        }
+
+        assert!(is_rate_limited(&pool, ip_address, None, 100).await.unwrap());
+        assert!(
+            is_rate_limited(&pool, other_ip_address, Some(uid.clone()), 100)
+                .await
+                .unwrap()
+        );
+        assert!(!is_rate_limited(&pool, other_ip_address, None, 100)
+            .await
+            .unwrap());
+        assert!(
+            !is_rate_limited(&pool, ip_address, None, 100 + ATTEMPT_WINDOW_SECONDS)
+                .await
+                .unwrap()
+        );
+    }
+}
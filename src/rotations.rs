@@ -0,0 +1,346 @@
+use std::borrow::Cow;
+
+use chrono::{Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::{sqlite::SqliteRow, Row, SqliteExecutor};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::game::Stage;
+use crate::stats::get_start_timestamp;
+
+pub const UPCOMING_ROTATIONS: i64 = 5;
+
+// A stage list used by a mode for a season instead of its default stages.
+// Rotations run from the start of their first day to the end of their last
+// day in UTC, the latest one to start wins when they overlap.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StageRotation {
+    pub id: i64,
+    pub name: String,
+    pub mode: String,
+    #[serde(serialize_with = "serialize_stage_data")]
+    pub stages: Vec<Stage>,
+    pub starts_at: i64,
+    pub ends_at: i64,
+}
+
+fn serialize_stage_data<S: Serializer>(stages: &[Stage], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(stages.iter().map(Stage::get_data))
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct StageRotationForm {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be at least 1 and at most 100 characters long"
+    ))]
+    pub name: String,
+    #[validate(custom(
+        function = "validate_mode",
+        message = "Must be ranked, unranked, direct or teams"
+    ))]
+    pub mode: String,
+    // Separated by commas, such as battlefield, dream_land
+    #[validate(custom(
+        function = "validate_stages",
+        message = "Must list stages among fountain_of_dreams, pokemon_stadium, yoshis_story, dream_land, battlefield and final_destination"
+    ))]
+    pub stages: String,
+    #[validate(custom(function = "validate_date", message = "Must be a date"))]
+    pub starts_on: String,
+    #[validate(custom(function = "validate_date", message = "Must be a date"))]
+    pub ends_on: String,
+}
+
+fn validate_mode(mode: &str) -> Result<(), ValidationError> {
+    match ["ranked", "unranked", "direct", "teams"].contains(&mode) {
+        true => Ok(()),
+        false => Err(ValidationError::new("mode")),
+    }
+}
+
+fn validate_stages(stages: &str) -> Result<(), ValidationError> {
+    match parse_stages(stages) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("stages")),
+    }
+}
+
+fn validate_date(date: &str) -> Result<(), ValidationError> {
+    match parse_date(date) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("date")),
+    }
+}
+
+fn parse_stages(stages: &str) -> Option<Vec<Stage>> {
+    let stages = stages
+        .split(',')
+        .map(|stage| stage.trim().parse().ok())
+        .collect::<Option<Vec<Stage>>>()?;
+
+    match stages.is_empty() {
+        true => None,
+        false => Some(stages),
+    }
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()
+}
+
+impl StageRotationForm {
+    // Also checks that the rotation doesn't end before it starts
+    pub fn check(&self) -> Result<(), ValidationErrors> {
+        let mut errors = match self.validate() {
+            Ok(()) => ValidationErrors::new(),
+            Err(errors) => errors,
+        };
+
+        if let (Some(starts_on), Some(ends_on)) =
+            (parse_date(&self.starts_on), parse_date(&self.ends_on))
+        {
+            if ends_on < starts_on {
+                let mut error = ValidationError::new("order");
+                error.message = Some(Cow::Borrowed("Must not be before the first day"));
+                errors.add("ends_on", error);
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+impl StageRotation {
+    fn from_row(row: SqliteRow) -> StageRotation {
+        StageRotation {
+            id: row.get("id"),
+            name: row.get("name"),
+            mode: row.get("mode"),
+            stages: row
+                .get::<String, &str>("stages")
+                .split(',')
+                .filter_map(|id| id.parse().ok().and_then(Stage::from_id))
+                .collect(),
+            starts_at: row.get("starts_at"),
+            ends_at: row.get("ends_at"),
+        }
+    }
+
+    // The form must have been checked
+    pub async fn create<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        form: &StageRotationForm,
+    ) -> Result<i64, sqlx::Error> {
+        let stages = parse_stages(&form.stages).unwrap();
+        let starts_on = parse_date(&form.starts_on).unwrap();
+        let ends_on = parse_date(&form.ends_on).unwrap();
+
+        sqlx::query("insert into stage_rotations (name, mode, stages, starts_at, ends_at, created_at) values ($1, $2, $3, $4, $5, $6)")
+            .bind(form.name.trim())
+            .bind(&form.mode)
+            .bind(
+                stages
+                    .iter()
+                    .map(|stage| (*stage as u8).to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+            .bind(get_start_timestamp(starts_on))
+            .bind(get_start_timestamp(ends_on + Duration::days(1)))
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|result| result.last_insert_rowid())
+    }
+
+    pub async fn delete<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from stage_rotations where id = $1")
+            .bind(id)
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    // The rotation in effect for each mode which has one
+    pub async fn get_active<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        now: i64,
+    ) -> Result<Vec<StageRotation>, sqlx::Error> {
+        sqlx::query("select * from stage_rotations where starts_at <= $1 and ends_at > $1 and not exists (select 1 from stage_rotations as newer where newer.mode = stage_rotations.mode and newer.starts_at <= $1 and newer.ends_at > $1 and (newer.starts_at > stage_rotations.starts_at or (newer.starts_at = stage_rotations.starts_at and newer.id > stage_rotations.id))) order by mode")
+            .bind(now)
+            .map(StageRotation::from_row)
+            .fetch_all(executor)
+            .await
+    }
+
+    pub async fn get_upcoming<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        now: i64,
+        limit: i64,
+    ) -> Result<Vec<StageRotation>, sqlx::Error> {
+        sqlx::query(
+            "select * from stage_rotations where starts_at > $1 order by starts_at, id limit $2",
+        )
+        .bind(now)
+        .bind(limit)
+        .map(StageRotation::from_row)
+        .fetch_all(executor)
+        .await
+    }
+
+    // Rotations which haven't ended yet, including those overridden by
+    // another one
+    pub async fn get_unfinished<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        now: i64,
+    ) -> Result<Vec<StageRotation>, sqlx::Error> {
+        sqlx::query("select * from stage_rotations where ends_at > $1 order by starts_at, id")
+            .bind(now)
+            .map(StageRotation::from_row)
+            .fetch_all(executor)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::game::OnlinePlayMode;
+    use crate::rotations::*;
+
+    fn form(
+        name: &str,
+        mode: &str,
+        stages: &str,
+        starts_on: &str,
+        ends_on: &str,
+    ) -> StageRotationForm {
+        StageRotationForm {
+            name: name.to_string(),
+            mode: mode.to_string(),
+            stages: stages.to_string(),
+            starts_on: starts_on.to_string(),
+            ends_on: ends_on.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_rotation_form() {
+        assert!(form(
+            "Season 1",
+            "unranked",
+            "battlefield, dream_land",
+            "2022-11-01",
+            "2022-11-01"
+        )
+        .check()
+        .is_ok());
+
+        let errors = form(
+            "",
+            "solo",
+            "battlefield, corneria",
+            "2022-11-02",
+            "2022-11-01",
+        )
+        .check()
+        .unwrap_err();
+        let mut fields = errors.field_errors().into_keys().collect::<Vec<&str>>();
+        fields.sort_unstable();
+        assert_eq!(fields, vec!["ends_on", "mode", "name", "stages"]);
+
+        let errors = form("Season 1", "unranked", "", "November", "2022-11-01")
+            .check()
+            .unwrap_err();
+        let mut fields = errors.field_errors().into_keys().collect::<Vec<&str>>();
+        fields.sort_unstable();
+        assert_eq!(fields, vec!["stages", "starts_on"]);
+    }
+
+    #[sqlx::test]
+    async fn latest_active_rotation_of_each_mode_is_used(pool: Pool<Sqlite>) {
+        let november = get_start_timestamp(NaiveDate::from_ymd_opt(2022, 11, 1).unwrap());
+        let day = 24 * 60 * 60;
+
+        for rotation in [
+            form(
+                "Autumn",
+                "unranked",
+                "battlefield,dream_land",
+                "2022-11-01",
+                "2022-11-30",
+            ),
+            form(
+                "Weekend",
+                "unranked",
+                "final_destination",
+                "2022-11-05",
+                "2022-11-06",
+            ),
+            form("Teams", "teams", "yoshis_story", "2022-11-01", "2022-11-01"),
+            form(
+                "Winter",
+                "unranked",
+                "pokemon_stadium",
+                "2022-12-01",
+                "2023-02-28",
+            ),
+        ] {
+            StageRotation::create(&pool, &rotation).await.unwrap();
+        }
+
+        let names = |rotations: Vec<StageRotation>| {
+            rotations
+                .into_iter()
+                .map(|rotation| rotation.name)
+                .collect::<Vec<String>>()
+        };
+        let active = StageRotation::get_active(&pool, november).await.unwrap();
+        assert_eq!(names(active.clone()), vec!["Teams", "Autumn"]);
+        assert_eq!(
+            Stage::get_allowed_stages(OnlinePlayMode::Unranked, &active),
+            vec![Stage::Battlefield, Stage::DreamLand]
+        );
+        assert_eq!(
+            Stage::get_allowed_stages(OnlinePlayMode::Direct, &active),
+            Stage::get_allowed_stages(OnlinePlayMode::Direct, &[])
+        );
+
+        let active = StageRotation::get_active(&pool, november + 5 * day)
+            .await
+            .unwrap();
+        assert_eq!(names(active), vec!["Weekend"]);
+        // Until the end of the last day
+        let active = StageRotation::get_active(&pool, november + 30 * day - 1)
+            .await
+            .unwrap();
+        assert_eq!(names(active), vec!["Autumn"]);
+
+        assert_eq!(
+            names(
+                StageRotation::get_upcoming(&pool, november, 1)
+                    .await
+                    .unwrap()
+            ),
+            vec!["Weekend"]
+        );
+        assert_eq!(
+            names(
+                StageRotation::get_unfinished(&pool, november + 2 * day)
+                    .await
+                    .unwrap()
+            ),
+            vec!["Autumn", "Weekend", "Winter"]
+        );
+    }
+}
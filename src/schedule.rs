@@ -0,0 +1,289 @@
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Hours during which a matchmaking queue accepts players, queues without a schedule are always open
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct QueueSchedule {
+    /// Mode the schedule applies to: ranked, unranked, direct or teams
+    pub mode: String,
+    /// IANA name of the time zone the windows are in, such as Europe/Paris
+    pub timezone: String,
+    /// When the queue is open, such as "Mon-Fri 18:00-23:00" or "Sat,Sun 20:00-02:00", ending past midnight if the end is earlier than the start
+    pub windows: Vec<String>,
+}
+
+// A schedule as shown in the web UI
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleStatus {
+    pub mode: String,
+    pub timezone: String,
+    pub windows: Vec<String>,
+    pub is_open: bool,
+    pub next_opening: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Window {
+    // Indexed from Monday
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+fn parse_day(day: &str) -> Result<usize, String> {
+    DAYS.iter()
+        .position(|name| name.eq_ignore_ascii_case(day))
+        .ok_or_else(|| format!("unknown day {:?}", day))
+}
+
+// Lists of days and ranges of days, such as "Mon,Wed-Fri", or * for every
+// day
+fn parse_days(days: &str) -> Result<[bool; 7], String> {
+    if days == "*" {
+        return Ok([true; 7]);
+    }
+
+    let mut parsed = [false; 7];
+    for part in days.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_day(first)?, parse_day(last)?);
+                // Ranges may wrap around the week, such as Sat-Mon
+                let mut day = first;
+                loop {
+                    parsed[day] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => parsed[parse_day(part)?] = true,
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| format!("invalid time {:?}", time))
+}
+
+impl Window {
+    fn parse(window: &str) -> Result<Window, String> {
+        let (days, hours) = window
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("{:?} should look like \"Mon-Fri 18:00-23:00\"", window))?;
+        let (start, end) = hours
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| format!("{:?} should look like \"Mon-Fri 18:00-23:00\"", window))?;
+
+        Ok(Window {
+            days: parse_days(days)?,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+
+    // Start and end of the window if it opens on the given day
+    fn on(&self, timezone: &Tz, day: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.days[day.weekday().num_days_from_monday() as usize] {
+            return None;
+        }
+
+        let end_day = if self.end > self.start {
+            day
+        } else {
+            day.succ_opt()?
+        };
+
+        Some((
+            localize(timezone, day, self.start)?,
+            localize(timezone, end_day, self.end)?,
+        ))
+    }
+}
+
+// Times skipped when clocks go forward are taken as the hour after
+fn localize(timezone: &Tz, day: NaiveDate, time: NaiveTime) -> Option<DateTime<Utc>> {
+    let local = day.and_time(time);
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => Some(time),
+        LocalResult::None => timezone
+            .from_local_datetime(&(local + Duration::hours(1)))
+            .earliest(),
+    }
+    .map(|time| time.with_timezone(&Utc))
+}
+
+impl QueueSchedule {
+    // Problems are reported by the configuration validation, schedules
+    // failing to parse are treated as always open
+    pub fn check(&self) -> Result<(), String> {
+        if !["ranked", "unranked", "direct", "teams"].contains(&self.mode.as_str()) {
+            return Err(format!("unknown mode {:?}", self.mode));
+        }
+        self.parse().map(|_| ())
+    }
+
+    fn parse(&self) -> Result<(Tz, Vec<Window>), String> {
+        let timezone = self
+            .timezone
+            .parse::<Tz>()
+            .map_err(|_| format!("unknown time zone {:?}", self.timezone))?;
+        let windows = self
+            .windows
+            .iter()
+            .map(|window| Window::parse(window))
+            .collect::<Result<Vec<Window>, String>>()?;
+
+        Ok((timezone, windows))
+    }
+
+    // Windows opening on the days around now, which are the only ones that
+    // can contain it or be the next to open
+    fn get_openings(&self, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let (timezone, windows) = match self.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => return vec![],
+        };
+        let today = now.with_timezone(&timezone).date_naive();
+
+        (-1..=7)
+            .filter_map(|offset| today.checked_add_signed(Duration::days(offset)))
+            .flat_map(|day| {
+                windows
+                    .iter()
+                    .filter_map(|window| window.on(&timezone, day))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        self.parse().is_err()
+            || self
+                .get_openings(now)
+                .iter()
+                .any(|(start, end)| start <= &now && &now < end)
+    }
+
+    // None while the queue is open, or if it never opens
+    pub fn get_next_opening(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.is_open(now) {
+            return None;
+        }
+
+        self.get_openings(now)
+            .into_iter()
+            .map(|(start, _)| start)
+            .filter(|start| start > &now)
+            .min()
+    }
+
+    pub fn get_status(&self, now: DateTime<Utc>) -> ScheduleStatus {
+        ScheduleStatus {
+            mode: self.mode.clone(),
+            timezone: self.timezone.clone(),
+            windows: self.windows.clone(),
+            is_open: self.is_open(now),
+            next_opening: self
+                .get_next_opening(now)
+                .map(|opening| self.format_opening(opening)),
+        }
+    }
+
+    // The opening time in the schedule's own time zone, for players
+    pub fn format_opening(&self, opening: DateTime<Utc>) -> String {
+        match self.timezone.parse::<Tz>() {
+            Ok(timezone) => opening
+                .with_timezone(&timezone)
+                .format(&format!("%a %Y-%m-%d %H:%M ({})", self.timezone))
+                .to_string(),
+            Err(_) => opening.format("%a %Y-%m-%d %H:%M (UTC)").to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::schedule::*;
+
+    fn schedule(windows: &[&str]) -> QueueSchedule {
+        QueueSchedule {
+            mode: "ranked".to_string(),
+            timezone: "Europe/Paris".to_string(),
+            windows: windows.iter().map(|window| window.to_string()).collect(),
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_check_reports_invalid_schedules() {
+        assert_eq!(schedule(&["Mon-Fri 18:00-23:00"]).check(), Ok(()));
+        assert_eq!(schedule(&["* 00:00-00:00"]).check(), Ok(()));
+        assert_eq!(
+            schedule(&["Someday 18:00-23:00"]).check(),
+            Err("unknown day \"Someday\"".to_string())
+        );
+        assert_eq!(
+            schedule(&["Mon 18:00"]).check(),
+            Err("\"Mon 18:00\" should look like \"Mon-Fri 18:00-23:00\"".to_string())
+        );
+        assert_eq!(
+            QueueSchedule {
+                timezone: "Mars/Olympus".to_string(),
+                ..schedule(&[])
+            }
+            .check(),
+            Err("unknown time zone \"Mars/Olympus\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_windows_are_in_the_schedule_time_zone() {
+        let schedule = schedule(&["Mon-Fri 18:00-23:00"]);
+
+        // Monday 2022-10-24, Paris is at UTC+2
+        assert!(!schedule.is_open(utc("2022-10-24T15:59:00Z")));
+        assert!(schedule.is_open(utc("2022-10-24T16:00:00Z")));
+        assert!(!schedule.is_open(utc("2022-10-24T21:00:00Z")));
+        assert_eq!(
+            schedule.get_next_opening(utc("2022-10-24T21:00:00Z")),
+            Some(utc("2022-10-25T16:00:00Z"))
+        );
+        // Opens again on Monday, after clocks went back to UTC+1
+        assert_eq!(
+            schedule.get_next_opening(utc("2022-10-28T21:00:00Z")),
+            Some(utc("2022-10-31T17:00:00Z"))
+        );
+        assert_eq!(
+            schedule.format_opening(utc("2022-10-31T17:00:00Z")),
+            "Mon 2022-10-31 18:00 (Europe/Paris)"
+        );
+    }
+
+    #[test]
+    fn test_windows_can_end_past_midnight() {
+        let schedule = schedule(&["Sat-Sun 20:00-02:00"]);
+
+        // Sunday 2022-10-23 at 01:00 in Paris, in Saturday's window
+        assert!(schedule.is_open(utc("2022-10-22T23:00:00Z")));
+        // Monday at 01:00, in Sunday's window
+        assert!(schedule.is_open(utc("2022-10-23T23:00:00Z")));
+        assert!(!schedule.is_open(utc("2022-10-24T01:00:00Z")));
+        assert_eq!(schedule.get_next_opening(utc("2022-10-22T23:00:00Z")), None);
+    }
+}
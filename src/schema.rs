@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{FromRow, SqlitePool};
+
+// Tables, indexes, triggers and views, without SQLite's own objects and the
+// migrations bookkeeping table
+#[derive(Debug, PartialEq, Eq, FromRow, Clone)]
+pub struct SchemaObject {
+    #[sqlx(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub sql: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SchemaDrift {
+    Missing {
+        kind: String,
+        name: String,
+    },
+    Unexpected {
+        kind: String,
+        name: String,
+    },
+    Changed {
+        kind: String,
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl std::fmt::Display for SchemaDrift {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SchemaDrift::Missing { kind, name } => write!(f, "missing {} {}", kind, name),
+            SchemaDrift::Unexpected { kind, name } => write!(f, "unexpected {} {}", kind, name),
+            SchemaDrift::Changed {
+                kind,
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} {} differs\n  expected: {}\n  actual:   {}",
+                kind, name, expected, actual
+            ),
+        }
+    }
+}
+
+// Whitespace depends on how a statement was written, not what it does
+fn normalize_sql(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+pub async fn get_schema(pool: &SqlitePool) -> Result<Vec<SchemaObject>, sqlx::Error> {
+    sqlx::query_as::<_, SchemaObject>("select type, name, sql from sqlite_master where sql is not null and name not like 'sqlite_%' and name != '_sqlx_migrations' order by type, name")
+        .fetch_all(pool)
+        .await
+}
+
+// The schema every instance should have, built by running the migrations
+// on an empty in-memory database
+pub async fn get_expected_schema() -> Result<Vec<SchemaObject>, sqlx::Error> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await?;
+    sqlx::migrate!().run(&pool).await?;
+
+    get_schema(&pool).await
+}
+
+pub fn compare_schemas(expected: &[SchemaObject], actual: &[SchemaObject]) -> Vec<SchemaDrift> {
+    let key = |object: &SchemaObject| (object.kind.clone(), object.name.clone());
+    let expected: BTreeMap<_, _> = expected
+        .iter()
+        .map(|object| (key(object), object))
+        .collect();
+    let actual: BTreeMap<_, _> = actual.iter().map(|object| (key(object), object)).collect();
+    let mut drift = vec![];
+
+    for ((kind, name), expected_object) in &expected {
+        match actual.get(&(kind.clone(), name.clone())) {
+            None => drift.push(SchemaDrift::Missing {
+                kind: kind.clone(),
+                name: name.clone(),
+            }),
+            Some(actual_object)
+                if normalize_sql(&expected_object.sql) != normalize_sql(&actual_object.sql) =>
+            {
+                drift.push(SchemaDrift::Changed {
+                    kind: kind.clone(),
+                    name: name.clone(),
+                    expected: normalize_sql(&expected_object.sql),
+                    actual: normalize_sql(&actual_object.sql),
+                })
+            }
+            Some(_) => (),
+        }
+    }
+
+    for (kind, name) in actual.keys() {
+        if !expected.contains_key(&(kind.clone(), name.clone())) {
+            drift.push(SchemaDrift::Unexpected {
+                kind: kind.clone(),
+                name: name.clone(),
+            });
+        }
+    }
+
+    drift
+}
+
+pub async fn verify_schema(pool: &SqlitePool) -> Result<Vec<SchemaDrift>, sqlx::Error> {
+    let expected = get_expected_schema().await?;
+    let actual = get_schema(pool).await?;
+
+    Ok(compare_schemas(&expected, &actual))
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnDescription {
+    pub name: String,
+    #[sqlx(rename = "type")]
+    pub kind: String,
+    pub not_null: bool,
+    pub default_value: Option<String>,
+    // Position within the primary key, from 1, or 0 if not part of it
+    pub primary_key: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexDescription {
+    pub name: String,
+    pub unique: bool,
+    // Separated by commas, expressions are left out
+    pub columns: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableDescription {
+    pub name: String,
+    pub row_count: i64,
+    pub columns: Vec<ColumnDescription>,
+    pub indexes: Vec<IndexDescription>,
+}
+
+// Every table of the live database, as SQLite reports it rather than as the
+// migrations wrote it. Counting rows scans each table, so this is meant for
+// occasional use by operators.
+pub async fn describe_tables(pool: &SqlitePool) -> Result<Vec<TableDescription>, sqlx::Error> {
+    let names = sqlx::query_scalar::<_, String>("select name from sqlite_master where type = 'table' and name not like 'sqlite_%' and name != '_sqlx_migrations' order by name")
+        .fetch_all(pool)
+        .await?;
+    let mut tables = vec![];
+
+    for name in names {
+        let columns = sqlx::query_as::<_, ColumnDescription>("select name, type, \"notnull\" as not_null, dflt_value as default_value, pk as primary_key from pragma_table_info($1) order by cid")
+            .bind(&name)
+            .fetch_all(pool)
+            .await?;
+        let indexes = sqlx::query_as::<_, IndexDescription>("select index_list.name, index_list.\"unique\", coalesce(group_concat(index_info.name, ', '), '') as columns from pragma_index_list($1) as index_list join pragma_index_info(index_list.name) as index_info group by index_list.name order by index_list.name")
+            .bind(&name)
+            .fetch_all(pool)
+            .await?;
+        // Names come from sqlite_master, quotes are doubled all the same
+        let row_count = sqlx::query_scalar::<_, i64>(&format!(
+            "select count(*) from \"{}\"",
+            name.replace('"', "\"\"")
+        ))
+        .fetch_one(pool)
+        .await?;
+
+        tables.push(TableDescription {
+            name,
+            row_count,
+            columns,
+            indexes,
+        });
+    }
+
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::schema::*;
+
+    #[sqlx::test]
+    async fn migrated_database_has_no_drift(pool: Pool<Sqlite>) {
+        assert_eq!(verify_schema(&pool).await.unwrap(), vec![]);
+    }
+
+    #[sqlx::test]
+    async fn reports_dropped_and_added_objects(pool: Pool<Sqlite>) {
+        sqlx::query("drop index users_username_normalized")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("create index users_display_name on users (display_name)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            verify_schema(&pool).await.unwrap(),
+            vec![
+                SchemaDrift::Missing {
+                    kind: "index".to_string(),
+                    name: "users_username_normalized".to_string(),
+                },
+                SchemaDrift::Unexpected {
+                    kind: "index".to_string(),
+                    name: "users_display_name".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn describes_tables_with_their_indexes_and_rows(pool: Pool<Sqlite>) {
+        crate::test_support::UserBuilder::new().insert(&pool).await;
+
+        let tables = describe_tables(&pool).await.unwrap();
+        assert!(tables.iter().all(|table| table.name != "_sqlx_migrations"));
+
+        let users = tables.iter().find(|table| table.name == "users").unwrap();
+        assert_eq!(users.row_count, 1);
+        let uid = users
+            .columns
+            .iter()
+            .find(|column| column.name == "uid")
+            .unwrap();
+        assert_eq!(uid.primary_key, 1);
+        let is_banned = users
+            .columns
+            .iter()
+            .find(|column| column.name == "is_banned")
+            .unwrap();
+        assert_eq!(
+            (is_banned.not_null, is_banned.default_value.as_deref()),
+            (true, Some("FALSE"))
+        );
+        assert!(users
+            .indexes
+            .iter()
+            .any(|index| index.name == "users_username_normalized"));
+    }
+
+    #[test]
+    fn test_compare_schemas_ignores_whitespace() {
+        let object = |sql: &str| SchemaObject {
+            kind: "table".to_string(),
+            name: "users".to_string(),
+            sql: sql.to_string(),
+        };
+
+        assert!(compare_schemas(
+            &[object("CREATE TABLE users (\n    uid VARCHAR\n)")],
+            &[object("CREATE TABLE users ( uid VARCHAR )")]
+        )
+        .is_empty());
+        assert_eq!(
+            compare_schemas(
+                &[object("CREATE TABLE users (uid VARCHAR)")],
+                &[object("CREATE TABLE users (uid VARCHAR, extra VARCHAR)")]
+            )
+            .len(),
+            1
+        );
+    }
+}
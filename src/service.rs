@@ -0,0 +1,656 @@
+use std::path::Path;
+use std::process::Command;
+
+use openmelee::Config;
+use tokio::sync::oneshot;
+
+const SERVICE_NAME: &str = "openmelee";
+// Options naming files or directories, which are always passed on resolved
+// as Windows services don't start in the directory they were installed from
+const PATH_OPTIONS: &[&str] = &[
+    "database_url",
+    "database_key_path",
+    "database_read_url",
+    "jwt_secret_path",
+    "cookie_secret_path",
+    "user_json_secret_path",
+    "robots_txt_path",
+    "assets_path",
+    "achievements_webhook_secret_path",
+];
+
+type Result<T> = std::result::Result<T, String>;
+
+pub fn install(config: Config, config_file: Option<&Path>) -> Result<()> {
+    if config.jwt_secret_path.is_none() {
+        return Err("JWT secret path not configured".to_string());
+    }
+
+    let executable = std::env::current_exe().map_err(|err| err.to_string())?;
+    let working_directory = std::env::current_dir().map_err(|err| err.to_string())?;
+    let file_config = Config::load_file(config_file).map_err(|problems| problems.join("\n"))?;
+    let environment = get_environment(config, file_config, &working_directory)?;
+    let config_file = config_file.map(|file| working_directory.join(file));
+
+    platform::install(
+        &executable,
+        config_file.as_deref(),
+        &working_directory,
+        &environment,
+    )
+}
+
+pub fn uninstall() -> Result<()> {
+    platform::uninstall()
+}
+
+// Hands the process over to the service control manager, which runs server
+// once the service has started, and sends on the channel given to it when
+// the service is asked to stop. Windows stops services that don't report
+// their status to it.
+#[cfg(windows)]
+pub fn run_service(server: impl FnOnce(oneshot::Receiver<()>) + Send + 'static) -> Result<()> {
+    platform::run_service(server)
+}
+
+#[cfg(not(windows))]
+pub fn run_service(_server: impl FnOnce(oneshot::Receiver<()>) + Send + 'static) -> Result<()> {
+    Err("run-service is only used by Windows services".to_string())
+}
+
+// Options the environment of the current invocation sets on top of the config
+// file, as OPENMELEE_* variables for the service, which reads the file itself
+// through --config. Lists and tables can't be parsed back from a variable, so
+// those have to come from the file. Paths are always passed resolved, so the
+// service finds the same files regardless of where it's started from.
+fn get_environment(
+    config: Config,
+    file_config: Config,
+    working_directory: &Path,
+) -> Result<Vec<(String, String)>> {
+    let options = serde_json::to_value(config).unwrap();
+    let file_options = serde_json::to_value(file_config).unwrap();
+    let mut environment = vec![];
+
+    for (key, value) in options.as_object().unwrap() {
+        let is_path = PATH_OPTIONS.contains(&key.as_str());
+        if value.is_null() || (!is_path && file_options.get(key) == Some(value)) {
+            continue;
+        }
+
+        let variable = format!("OPENMELEE_{}", key.to_uppercase());
+        let value = match value {
+            serde_json::Value::String(string) => string.clone(),
+            serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+            _ => {
+                return Err(format!(
+                    "{} can't be passed on to the service, set {} in the file passed with --config instead",
+                    variable, key
+                ))
+            }
+        };
+        let value = if is_path {
+            resolve_path(&value, working_directory)
+        } else {
+            value
+        };
+
+        environment.push((variable, value));
+    }
+
+    Ok(environment)
+}
+
+// Relative SQLite URLs are resolved the same as paths, within the part sqlx
+// reads as the file name, up to any query
+fn resolve_path(value: &str, working_directory: &Path) -> String {
+    let url = value
+        .trim_start_matches("sqlite://")
+        .trim_start_matches("sqlite:");
+    if url.len() == value.len() {
+        return working_directory.join(value).to_string_lossy().to_string();
+    }
+
+    let file = url.split('?').next().unwrap_or_default();
+    if file.is_empty() || file == ":memory:" || Path::new(file).is_absolute() {
+        return value.to_string();
+    }
+
+    // sqlx percent decodes the file name, which the directory isn't yet
+    let directory = working_directory
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('?', "%3F");
+    format!(
+        "sqlite://{}",
+        Path::new(&directory).join(url).to_string_lossy()
+    )
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command.status().map_err(|err| err.to_string())?;
+
+    if !status.success() {
+        return Err(format!("{:?} exited with {}", command, status));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // systemd expands % specifiers in the values of most settings
+    fn escape_specifiers(value: &str) -> String {
+        value.replace('%', "%%")
+    }
+
+    // Command lines are split at spaces outside of quotes, within which
+    // backslashes escape, and $ expands variables
+    fn quote_argument(argument: &Path) -> String {
+        format!(
+            "\"{}\"",
+            escape_specifiers(&argument.to_string_lossy())
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "$$")
+        )
+    }
+
+    pub(super) fn generate_systemd_unit(
+        executable: &Path,
+        config_file: Option<&Path>,
+        working_directory: &Path,
+        environment: &[(String, String)],
+        user: Option<String>,
+    ) -> String {
+        let mut unit = format!(
+            "[Unit]\n\
+             Description=OpenMelee matchmaking server\n\
+             After=network.target\n\
+             \n\
+             [Service]\n\
+             ExecStart={}{}\n\
+             WorkingDirectory={}\n\
+             Restart=always\n",
+            quote_argument(executable),
+            config_file
+                .map(|file| format!(" --config {}", quote_argument(file)))
+                .unwrap_or_default(),
+            // Taken as is up to the end of the line, quotes included
+            escape_specifiers(&working_directory.to_string_lossy()),
+        );
+
+        if let Some(user) = user {
+            unit.push_str(&format!("User={}\n", user));
+        }
+
+        for (key, value) in environment {
+            unit.push_str(&format!(
+                "Environment=\"{}={}\"\n",
+                key,
+                escape_specifiers(value)
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+            ));
+        }
+
+        unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+        unit
+    }
+
+    fn unit_path() -> PathBuf {
+        PathBuf::from(format!("/etc/systemd/system/{}.service", SERVICE_NAME))
+    }
+
+    pub fn install(
+        executable: &Path,
+        config_file: Option<&Path>,
+        working_directory: &Path,
+        environment: &[(String, String)],
+    ) -> Result<()> {
+        let unit = generate_systemd_unit(
+            executable,
+            config_file,
+            working_directory,
+            environment,
+            std::env::var("SUDO_USER").ok(),
+        );
+
+        std::fs::write(unit_path(), unit).map_err(|err| err.to_string())?;
+        run(Command::new("systemctl").arg("daemon-reload"))?;
+        run(Command::new("systemctl").args(["enable", SERVICE_NAME]))?;
+
+        println!(
+            "Installed {}, start it with `systemctl start {}`",
+            unit_path().display(),
+            SERVICE_NAME
+        );
+
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        run(Command::new("systemctl").args(["disable", "--now", SERVICE_NAME]))?;
+        std::fs::remove_file(unit_path()).map_err(|err| err.to_string())?;
+        run(Command::new("systemctl").arg("daemon-reload"))?;
+
+        println!("Removed {}", unit_path().display());
+
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use once_cell::sync::OnceCell;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{
+        self, ServiceControlHandlerResult, ServiceStatusHandle,
+    };
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use super::*;
+
+    const SERVICE_KEY: &str = "HKLM\\SYSTEM\\CurrentControlSet\\Services\\openmelee";
+
+    // The service control manager calls back into the process without a way
+    // to pass the server along, so it's kept here, as is the handle the
+    // service reports its status with
+    static SERVER: Mutex<Option<Box<dyn FnOnce(oneshot::Receiver<()>) + Send>>> = Mutex::new(None);
+    static STATUS_HANDLE: OnceCell<ServiceStatusHandle> = OnceCell::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    // sc.exe splits its own arguments at "option= value" pairs, the path of
+    // the binary and its arguments go in binPath as a single command line
+    pub(super) fn generate_sc_create_arguments(
+        executable: &Path,
+        config_file: Option<&Path>,
+    ) -> Vec<String> {
+        let mut command_line = format!("\"{}\"", executable.display());
+        if let Some(config_file) = config_file {
+            command_line.push_str(&format!(" --config \"{}\"", config_file.display()));
+        }
+        command_line.push_str(" run-service");
+
+        vec![
+            "create".to_string(),
+            SERVICE_NAME.to_string(),
+            "binPath=".to_string(),
+            command_line,
+            "start=".to_string(),
+            "auto".to_string(),
+            "DisplayName=".to_string(),
+            "OpenMelee matchmaking server".to_string(),
+        ]
+    }
+
+    // Services read extra variables from the Environment value of their
+    // registry key, one KEY=value per string of the multi-string, which
+    // reg.exe takes joined by a separator that doesn't appear in them
+    pub(super) fn generate_reg_environment_arguments(
+        environment: &[(String, String)],
+    ) -> Result<Vec<String>> {
+        let variables: Vec<String> = environment
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let separator = ['|', '~', '^', '`']
+            .into_iter()
+            .find(|separator| {
+                !variables
+                    .iter()
+                    .any(|variable| variable.contains(*separator))
+            })
+            .ok_or_else(|| "No separator left to pass the environment to reg.exe".to_string())?;
+
+        Ok(vec![
+            "add".to_string(),
+            SERVICE_KEY.to_string(),
+            "/v".to_string(),
+            "Environment".to_string(),
+            "/t".to_string(),
+            "REG_MULTI_SZ".to_string(),
+            "/s".to_string(),
+            separator.to_string(),
+            "/d".to_string(),
+            variables.join(&separator.to_string()),
+            "/f".to_string(),
+        ])
+    }
+
+    pub fn install(
+        executable: &Path,
+        config_file: Option<&Path>,
+        _working_directory: &Path,
+        environment: &[(String, String)],
+    ) -> Result<()> {
+        run(Command::new("sc.exe").args(generate_sc_create_arguments(executable, config_file)))?;
+        if !environment.is_empty() {
+            run(Command::new("reg.exe").args(generate_reg_environment_arguments(environment)?))?;
+        }
+
+        println!(
+            "Installed the {} service, start it with `sc.exe start {}`",
+            SERVICE_NAME, SERVICE_NAME
+        );
+
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        // Fails if the service isn't running, which doesn't keep it from
+        // being deleted
+        let _ = Command::new("sc.exe").args(["stop", SERVICE_NAME]).status();
+        run(Command::new("sc.exe").args(["delete", SERVICE_NAME]))?;
+
+        println!("Removed the {} service", SERVICE_NAME);
+
+        Ok(())
+    }
+
+    fn set_status(current_state: ServiceState) {
+        let status = ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted: if current_state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            // Long enough to close the database connections
+            wait_hint: if current_state == ServiceState::StopPending {
+                Duration::from_secs(10)
+            } else {
+                Duration::default()
+            },
+            process_id: None,
+        };
+
+        if let Some(handle) = STATUS_HANDLE.get() {
+            let _ = handle.set_service_status(status);
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let (stop, stopped) = oneshot::channel();
+        let mut stop = Some(stop);
+        let handle = service_control_handler::register(SERVICE_NAME, move |control| {
+            match control {
+                // Called on the service control manager's thread, the server
+                // is only told to stop, and reports once it has
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    set_status(ServiceState::StopPending);
+                    if let Some(stop) = stop.take() {
+                        let _ = stop.send(());
+                    }
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            }
+        });
+        let handle = match handle {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let _ = STATUS_HANDLE.set(handle);
+
+        set_status(ServiceState::Running);
+        let server = SERVER.lock().unwrap().take();
+        if let Some(server) = server {
+            server(stopped);
+        }
+        set_status(ServiceState::Stopped);
+    }
+
+    pub fn run_service(server: impl FnOnce(oneshot::Receiver<()>) + Send + 'static) -> Result<()> {
+        *SERVER.lock().unwrap() = Some(Box::new(server));
+
+        // Returns once the service has stopped
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(|err| format!("Failed to connect to the service control manager: {}", err))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use super::*;
+
+    pub fn install(
+        _executable: &Path,
+        _config_file: Option<&Path>,
+        _working_directory: &Path,
+        _environment: &[(String, String)],
+    ) -> Result<()> {
+        Err("Installing a service is not supported on this platform".to_string())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        Err("Uninstalling a service is not supported on this platform".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use figment::providers::{Format, Serialized, Toml};
+    use figment::value::{Dict, Value};
+    use figment::Figment;
+
+    use crate::service::*;
+
+    #[test]
+    fn test_get_environment_resolves_relative_paths() {
+        let config = Config {
+            jwt_secret_path: Some("/run/secrets/jwt".to_string()),
+            webserver_port: 8080,
+            ..Config::default()
+        };
+
+        let environment =
+            get_environment(config, Config::default(), Path::new("/var/lib/openmelee")).unwrap();
+
+        assert!(environment.contains(&(
+            "OPENMELEE_DATABASE_URL".to_string(),
+            "/var/lib/openmelee/openmelee.sqlite".to_string()
+        )));
+        assert!(environment.contains(&(
+            "OPENMELEE_JWT_SECRET_PATH".to_string(),
+            "/run/secrets/jwt".to_string()
+        )));
+        assert!(environment.contains(&("OPENMELEE_WEBSERVER_PORT".to_string(), "8080".to_string())));
+        assert!(!environment
+            .iter()
+            .any(|(key, _)| key == "OPENMELEE_MATCHMAKING_PORT" || key == "OPENMELEE_PUBLIC_URL"));
+    }
+
+    #[test]
+    fn test_get_environment_resolves_relative_sqlite_urls() {
+        let config = Config {
+            database_url: "sqlite://data/openmelee.sqlite?mode=rwc".to_string(),
+            database_read_url: Some("sqlite::memory:".to_string()),
+            ..Config::default()
+        };
+
+        let environment =
+            get_environment(config, Config::default(), Path::new("/var/lib/open%melee")).unwrap();
+
+        assert!(environment.contains(&(
+            "OPENMELEE_DATABASE_URL".to_string(),
+            "sqlite:///var/lib/open%25melee/data/openmelee.sqlite?mode=rwc".to_string()
+        )));
+        assert!(environment.contains(&(
+            "OPENMELEE_DATABASE_READ_URL".to_string(),
+            "sqlite::memory:".to_string()
+        )));
+        assert_eq!(
+            resolve_path("sqlite:///srv/openmelee.sqlite", Path::new("/var/lib")),
+            "sqlite:///srv/openmelee.sqlite"
+        );
+    }
+
+    #[test]
+    fn test_path_options_cover_every_path() {
+        let options = serde_json::to_value(Config::default()).unwrap();
+
+        for key in options.as_object().unwrap().keys() {
+            // The path of URLs rather than of a file
+            if key.ends_with("_path") && key != "webserver_base_path" {
+                assert!(PATH_OPTIONS.contains(&key.as_str()), "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_environment_round_trips_config_file() {
+        let directory = std::env::temp_dir().join(format!("openmelee-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let file = directory.join("openmelee.toml");
+        std::fs::write(
+            &file,
+            "webserver_port = 8080\n\
+             [[tenants]]\n\
+             id = \"pal\"\n\
+             hostname = \"pal.example.com\"\n\
+             name = \"PAL Melee\"\n",
+        )
+        .unwrap();
+
+        let file_config = Config::load_file(Some(&file)).unwrap();
+        let resolve = |path: &str| directory.join(path).to_string_lossy().to_string();
+        let config = Config {
+            server_name: "OpenMelee EU".to_string(),
+            matchmaking_port: 43114,
+            database_url: resolve(&file_config.database_url),
+            cookie_secret_path: file_config.cookie_secret_path.as_deref().map(resolve),
+            ..file_config.clone()
+        };
+        let environment = get_environment(config.clone(), file_config.clone(), &directory).unwrap();
+        assert!(!environment
+            .iter()
+            .any(|(key, _)| key == "OPENMELEE_TENANTS"));
+
+        // What the service ends up with: the file, overridden by the
+        // variables parsed the way figment's Env provider does
+        let variables: Dict = environment
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.trim_start_matches("OPENMELEE_").to_lowercase(),
+                    value.parse::<Value>().unwrap(),
+                )
+            })
+            .collect();
+        let service_config: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::file(&file))
+            .merge(Serialized::defaults(variables))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(service_config).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+
+        let config = Config {
+            tenants: vec![],
+            ..config
+        };
+        assert!(get_environment(config, file_config, &directory)
+            .unwrap_err()
+            .contains("OPENMELEE_TENANTS"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_generate_systemd_unit() {
+        let unit = platform::generate_systemd_unit(
+            Path::new("/usr/bin/openmelee"),
+            Some(Path::new("/etc/openmelee/openmelee.toml")),
+            Path::new("/var/lib/openmelee"),
+            &[("OPENMELEE_WEBSERVER_PORT".to_string(), "5000".to_string())],
+            Some("openmelee".to_string()),
+        );
+
+        assert!(unit.contains(
+            "ExecStart=\"/usr/bin/openmelee\" --config \"/etc/openmelee/openmelee.toml\"\n"
+        ));
+        assert!(unit.contains("WorkingDirectory=/var/lib/openmelee\n"));
+        assert!(unit.contains("User=openmelee\n"));
+        assert!(unit.contains("Environment=\"OPENMELEE_WEBSERVER_PORT=5000\"\n"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_generate_systemd_unit_escapes_paths() {
+        let unit = platform::generate_systemd_unit(
+            Path::new("/opt/Open Melee/100%/openmelee"),
+            Some(Path::new("/opt/Open Melee/100%/openmelee.toml")),
+            Path::new("/opt/Open Melee/100%"),
+            &[(
+                "OPENMELEE_DATABASE_URL".to_string(),
+                "/opt/Open Melee/100%/openmelee.sqlite".to_string(),
+            )],
+            None,
+        );
+
+        assert!(unit.contains(
+            "ExecStart=\"/opt/Open Melee/100%%/openmelee\" \
+             --config \"/opt/Open Melee/100%%/openmelee.toml\"\n"
+        ));
+        assert!(unit.contains("WorkingDirectory=/opt/Open Melee/100%%\n"));
+        assert!(unit.contains(
+            "Environment=\"OPENMELEE_DATABASE_URL=/opt/Open Melee/100%%/openmelee.sqlite\"\n"
+        ));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_generate_sc_create_arguments() {
+        let arguments = platform::generate_sc_create_arguments(
+            Path::new("C:\\OpenMelee\\openmelee.exe"),
+            Some(Path::new("C:\\OpenMelee\\openmelee.toml")),
+        );
+
+        assert_eq!(&arguments[..2], ["create", "openmelee"]);
+        assert_eq!(
+            arguments[3],
+            "\"C:\\OpenMelee\\openmelee.exe\" --config \"C:\\OpenMelee\\openmelee.toml\" run-service"
+        );
+        assert_eq!(&arguments[4..6], ["start=", "auto"]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_generate_reg_environment_arguments() {
+        let arguments = platform::generate_reg_environment_arguments(&[
+            ("OPENMELEE_SERVER_NAME".to_string(), "A|B".to_string()),
+            ("OPENMELEE_WEBSERVER_PORT".to_string(), "8080".to_string()),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            &arguments[6..10],
+            [
+                "/s",
+                "~",
+                "/d",
+                "OPENMELEE_SERVER_NAME=A|B~OPENMELEE_WEBSERVER_PORT=8080"
+            ]
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use sqlx::{FromRow, SqliteExecutor, SqlitePool};
+
+use crate::game::{OnlinePlayMode, Stage};
+use crate::ids::{MatchId, Uid};
+
+// Players searching for each other again within this long after their last
+// game are still playing the same set
+pub const SET_GAP_SECONDS: i64 = 10 * 60;
+
+// A game of a Direct set, and the stages that can't be played on in it
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SetGame {
+    // None for the first game, whose match ID becomes the set's
+    pub set_id: Option<String>,
+    pub game: i64,
+    pub banned_stages: Vec<Stage>,
+}
+
+#[derive(Debug, FromRow)]
+struct PlayedGame {
+    set_id: String,
+    set_game: i64,
+    created_at: i64,
+    winner_uid: Option<Uid>,
+    stage: Option<i64>,
+}
+
+impl SetGame {
+    fn first() -> SetGame {
+        SetGame {
+            set_id: None,
+            game: 1,
+            banned_stages: vec![],
+        }
+    }
+
+    // The next game of the set the players are playing, or the first of a
+    // new one. Following Dave's Stupid Rule, the loser of the previous game
+    // picks the stage and can't pick the one they last won on in the set.
+    pub async fn get_next(
+        pool: &SqlitePool,
+        player_uids: &[Uid],
+        best_of: i64,
+        now: i64,
+    ) -> Result<SetGame, sqlx::Error> {
+        let (uid_a, uid_b) = match player_uids {
+            [uid_a, uid_b] => (uid_a, uid_b),
+            _ => return Ok(SetGame::first()),
+        };
+
+        let last_game = sqlx::query_as::<_, PlayedGame>("select set_id, set_game, created_at, winner_uid, stage from matches where mode = $1 and set_id is not null and match_id in (select match_id from match_players where uid = $2) and match_id in (select match_id from match_players where uid = $3) order by created_at desc, set_game desc limit 1")
+            .bind(OnlinePlayMode::Direct.to_string())
+            .bind(uid_a)
+            .bind(uid_b)
+            .fetch_optional(pool)
+            .await?;
+        let last_game = match last_game {
+            Some(last_game) if now - last_game.created_at <= SET_GAP_SECONDS => last_game,
+            _ => return Ok(SetGame::first()),
+        };
+
+        let games = sqlx::query_as::<_, PlayedGame>("select set_id, set_game, created_at, winner_uid, stage from matches where set_id = $1 order by set_game")
+            .bind(last_game.set_id.clone())
+            .fetch_all(pool)
+            .await?;
+        let wins = |uid: &Uid| {
+            games
+                .iter()
+                .filter(|game| game.winner_uid.as_ref() == Some(uid))
+                .count() as i64
+        };
+        let is_decided = [uid_a, uid_b].iter().any(|uid| wins(uid) * 2 > best_of);
+        if is_decided || last_game.set_game >= best_of {
+            return Ok(SetGame::first());
+        }
+
+        // Nothing is banned while the previous game wasn't reported
+        let counterpicker = match &last_game.winner_uid {
+            Some(winner_uid) if winner_uid == uid_a => Some(uid_b),
+            Some(winner_uid) if winner_uid == uid_b => Some(uid_a),
+            _ => None,
+        };
+        let banned_stages = counterpicker
+            .and_then(|uid| {
+                games
+                    .iter()
+                    .rev()
+                    .find(|game| game.winner_uid.as_ref() == Some(uid))
+            })
+            .and_then(|game| game.stage)
+            .and_then(|stage| Stage::from_id(stage as u8))
+            .into_iter()
+            .collect();
+
+        Ok(SetGame {
+            set_id: Some(last_game.set_id),
+            game: last_game.set_game + 1,
+            banned_stages,
+        })
+    }
+
+    // The stages are left as they are if every one of them is banned
+    pub fn get_legal_stages(&self, stages: Vec<Stage>) -> Vec<Stage> {
+        let legal_stages = stages
+            .iter()
+            .copied()
+            .filter(|stage| !self.banned_stages.contains(stage))
+            .collect::<Vec<Stage>>();
+
+        if legal_stages.is_empty() {
+            stages
+        } else {
+            legal_stages
+        }
+    }
+
+    pub async fn save<'a, T: SqliteExecutor<'a>>(
+        &self,
+        executor: T,
+        match_id: MatchId,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "update matches set set_id = coalesce($1, match_id), set_game = $2 where match_id = $3",
+        )
+        .bind(self.set_id.clone())
+        .bind(self.game)
+        .bind(match_id)
+        .execute(executor)
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use sqlx::{Pool, Sqlite};
+
+    use crate::matches::Match;
+    use crate::models::User;
+    use crate::sets::*;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    async fn play(
+        pool: &SqlitePool,
+        match_id: &str,
+        players: &[User],
+        stage: Stage,
+        winner: &User,
+    ) -> SetGame {
+        let uids = players
+            .iter()
+            .map(|player| player.uid.clone())
+            .collect::<Vec<Uid>>();
+        let set_game = SetGame::get_next(pool, &uids, 5, Utc::now().timestamp())
+            .await
+            .unwrap();
+
+        MatchBuilder::new(match_id)
+            .mode(OnlinePlayMode::Direct)
+            .players(players)
+            .stage(stage)
+            .confirmed(winner)
+            .insert(pool)
+            .await;
+        set_game
+            .save(pool, match_id.parse().unwrap())
+            .await
+            .unwrap();
+
+        set_game
+    }
+
+    #[sqlx::test]
+    async fn sets_ban_the_stage_the_counterpicker_last_won_on(pool: Pool<Sqlite>) {
+        let user_a = UserBuilder::new().insert(&pool).await;
+        let user_b = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        let players = [user_a.clone(), user_b.clone()];
+
+        let game = play(
+            &pool,
+            "mode.direct-1",
+            &players,
+            Stage::Battlefield,
+            &user_a,
+        )
+        .await;
+        assert_eq!(game, SetGame::first());
+
+        // B lost game 1 and hasn't won yet, so nothing is banned
+        let game = play(&pool, "mode.direct-2", &players, Stage::DreamLand, &user_b).await;
+        assert_eq!(
+            (game.set_id.as_deref(), game.game, game.banned_stages),
+            (Some("mode.direct-1"), 2, vec![])
+        );
+
+        // A lost game 2 and can't go back to Battlefield
+        let game = play(
+            &pool,
+            "mode.direct-3",
+            &players,
+            Stage::YoshisStory,
+            &user_a,
+        )
+        .await;
+        assert_eq!(
+            (game.game, game.banned_stages),
+            (3, vec![Stage::Battlefield])
+        );
+
+        let game = play(
+            &pool,
+            "mode.direct-4",
+            &players,
+            Stage::FinalDestination,
+            &user_a,
+        )
+        .await;
+        assert_eq!((game.game, game.banned_stages), (4, vec![Stage::DreamLand]));
+
+        // A won 3 of 5, the next game starts a new set
+        let uids = [user_a.uid.clone(), user_b.uid.clone()];
+        let next = SetGame::get_next(&pool, &uids, 5, Utc::now().timestamp())
+            .await
+            .unwrap();
+        assert_eq!(next, SetGame::first());
+    }
+
+    #[sqlx::test]
+    async fn sets_end_after_a_break(pool: Pool<Sqlite>) {
+        let user_a = UserBuilder::new().insert(&pool).await;
+        let user_b = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        let players = [user_a.clone(), user_b.clone()];
+        play(
+            &pool,
+            "mode.direct-1",
+            &players,
+            Stage::Battlefield,
+            &user_a,
+        )
+        .await;
+
+        let uids = [user_a.uid.clone(), user_b.uid.clone()];
+        let created_at = Match::get(&pool, "mode.direct-1".parse().unwrap())
+            .await
+            .unwrap()
+            .created_at;
+        assert_eq!(
+            SetGame::get_next(&pool, &uids, 5, created_at + SET_GAP_SECONDS)
+                .await
+                .unwrap()
+                .game,
+            2
+        );
+        assert_eq!(
+            SetGame::get_next(&pool, &uids, 5, created_at + SET_GAP_SECONDS + 1)
+                .await
+                .unwrap(),
+            SetGame::first()
+        );
+    }
+
+    #[test]
+    fn test_legal_stages_are_never_empty() {
+        let game = SetGame {
+            set_id: None,
+            game: 2,
+            banned_stages: vec![Stage::Battlefield],
+        };
+
+        assert_eq!(
+            game.get_legal_stages(vec![Stage::Battlefield, Stage::DreamLand]),
+            vec![Stage::DreamLand]
+        );
+        assert_eq!(
+            game.get_legal_stages(vec![Stage::Battlefield]),
+            vec![Stage::Battlefield]
+        );
+    }
+}
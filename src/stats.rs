@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor, SqlitePool};
+
+pub const STATS_DAYS: i64 = 30;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Matches of the previous day are still counted for a while after midnight
+const REFRESH_DAYS: i64 = 2;
+
+const CHART_HEIGHT: i64 = 100;
+const BAR_WIDTH: i64 = 20;
+const BAR_GAP: i64 = 4;
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyMatchCount {
+    pub day: String,
+    pub mode: String,
+    pub matches: i64,
+}
+
+impl DailyMatchCount {
+    // Recounts the matches created since the start of the given day, for
+    // every tenant
+    pub async fn refresh(pool: &SqlitePool, since: NaiveDate) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("delete from match_stats_daily where day >= $1")
+            .bind(format_day(since))
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("insert into match_stats_daily (tenant_id, day, mode, matches) select users.tenant_id, date(matches.created_at, 'unixepoch'), matches.mode, count(distinct matches.match_id) from matches join match_players on match_players.match_id = matches.match_id join users on users.uid = match_players.uid where matches.created_at >= $1 group by users.tenant_id, date(matches.created_at, 'unixepoch'), matches.mode")
+            .bind(get_start_timestamp(since))
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn get_since<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        tenant_id: String,
+        since: NaiveDate,
+    ) -> Result<Vec<DailyMatchCount>, sqlx::Error> {
+        sqlx::query_as::<_, DailyMatchCount>("select day, mode, matches from match_stats_daily where tenant_id = $1 and day >= $2 order by day, mode")
+            .bind(tenant_id)
+            .bind(format_day(since))
+            .fetch_all(executor)
+            .await
+    }
+}
+
+fn format_day(day: NaiveDate) -> String {
+    day.format("%Y-%m-%d").to_string()
+}
+
+pub fn get_start_timestamp(day: NaiveDate) -> i64 {
+    Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap())
+        .timestamp()
+}
+
+// First day of the window shown on the stats page, which ends today
+pub fn get_first_day(today: NaiveDate) -> NaiveDate {
+    today - chrono::Duration::days(STATS_DAYS - 1)
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bar {
+    pub day: String,
+    pub matches: i64,
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+// Bars are laid out for an SVG, as inline styles are ruled out by the
+// content security policy
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chart {
+    pub mode: String,
+    pub total: i64,
+    pub peak: i64,
+    pub width: i64,
+    pub height: i64,
+    pub bars: Vec<Bar>,
+}
+
+impl Chart {
+    // One chart per mode, with a bar for every day from first_day to
+    // today, including days without matches
+    pub fn build(counts: &[DailyMatchCount], first_day: NaiveDate, today: NaiveDate) -> Vec<Chart> {
+        let mut modes: BTreeMap<&str, BTreeMap<&str, i64>> = BTreeMap::new();
+        for count in counts {
+            modes
+                .entry(&count.mode)
+                .or_default()
+                .insert(&count.day, count.matches);
+        }
+
+        let days = first_day
+            .iter_days()
+            .take_while(|day| day <= &today)
+            .map(format_day)
+            .collect::<Vec<String>>();
+
+        modes
+            .into_iter()
+            .map(|(mode, matches_per_day)| {
+                let peak = matches_per_day.values().copied().max().unwrap_or(0).max(1);
+                let bars = days
+                    .iter()
+                    .enumerate()
+                    .map(|(i, day)| {
+                        let matches = matches_per_day.get(day.as_str()).copied().unwrap_or(0);
+                        let height = matches * CHART_HEIGHT / peak;
+                        Bar {
+                            day: day.clone(),
+                            matches,
+                            x: i as i64 * (BAR_WIDTH + BAR_GAP),
+                            y: CHART_HEIGHT - height,
+                            width: BAR_WIDTH,
+                            height,
+                        }
+                    })
+                    .collect::<Vec<Bar>>();
+
+                Chart {
+                    mode: mode.to_string(),
+                    total: matches_per_day.values().sum(),
+                    peak,
+                    width: days.len() as i64 * (BAR_WIDTH + BAR_GAP),
+                    height: CHART_HEIGHT,
+                    bars,
+                }
+            })
+            .collect()
+    }
+}
+
+pub async fn start_aggregating(pool: SqlitePool) {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    // The whole window is counted once at startup, in case the server was
+    // down when days ended
+    let mut days = STATS_DAYS;
+
+    loop {
+        interval.tick().await;
+
+        let since = Utc::now().date_naive() - chrono::Duration::days(days - 1);
+        match DailyMatchCount::refresh(&pool, since).await {
+            Ok(()) => days = REFRESH_DAYS,
+            Err(err) => println!("Failed to refresh match stats: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::game::OnlinePlayMode;
+    use crate::stats::*;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::{MatchBuilder, UserBuilder};
+
+    fn day(day: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d").unwrap()
+    }
+
+    #[sqlx::test]
+    async fn refresh_counts_matches_per_tenant_and_mode(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let other_tenant_user = UserBuilder::new().tenant("other").insert(&pool).await;
+
+        for (match_id, mode) in [
+            ("mode.unranked-1", OnlinePlayMode::Unranked),
+            ("mode.unranked-2", OnlinePlayMode::Unranked),
+            ("mode.ranked-1", OnlinePlayMode::Ranked),
+        ] {
+            MatchBuilder::new(match_id)
+                .mode(mode)
+                .player(&user)
+                .insert(&pool)
+                .await;
+        }
+        MatchBuilder::new("mode.unranked-3")
+            .player(&other_tenant_user)
+            .insert(&pool)
+            .await;
+
+        let today = Utc::now().date_naive();
+        DailyMatchCount::refresh(&pool, today).await.unwrap();
+        // Refreshing again doesn't count matches twice
+        DailyMatchCount::refresh(&pool, today).await.unwrap();
+
+        let counts = DailyMatchCount::get_since(&pool, DEFAULT_TENANT.to_string(), today)
+            .await
+            .unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                DailyMatchCount {
+                    day: format_day(today),
+                    mode: "ranked".to_string(),
+                    matches: 1,
+                },
+                DailyMatchCount {
+                    day: format_day(today),
+                    mode: "unranked".to_string(),
+                    matches: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_charts_cover_every_day() {
+        let count = |day: &str, matches| DailyMatchCount {
+            day: day.to_string(),
+            mode: "unranked".to_string(),
+            matches,
+        };
+        let charts = Chart::build(
+            &[count("2022-10-01", 4), count("2022-10-03", 2)],
+            day("2022-10-01"),
+            day("2022-10-03"),
+        );
+
+        assert_eq!(charts.len(), 1);
+        assert_eq!(charts[0].total, 6);
+        assert_eq!(charts[0].peak, 4);
+        assert_eq!(
+            charts[0]
+                .bars
+                .iter()
+                .map(|bar| (bar.matches, bar.height))
+                .collect::<Vec<(i64, i64)>>(),
+            vec![(4, CHART_HEIGHT), (0, 0), (2, CHART_HEIGHT / 2)]
+        );
+        assert_eq!(charts[0].bars[2].y, CHART_HEIGHT / 2);
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{build_info, models::User, Config};
+
+static MATCHES_CREATED: AtomicU64 = AtomicU64::new(0);
+static TICKETS_DROPPED: AtomicU64 = AtomicU64::new(0);
+static PEERS_REAPED: AtomicU64 = AtomicU64::new(0);
+static TICKETS_REJECTED: AtomicU64 = AtomicU64::new(0);
+static SPECTATORS_ADMITTED: AtomicU64 = AtomicU64::new(0);
+static SPECTATORS_REJECTED: AtomicU64 = AtomicU64::new(0);
+static MATCHMAKING_STALLS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_match_created() {
+    MATCHES_CREATED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Matchmaking tickets ignored as duplicates or sent by peers over the rate
+// limit
+pub fn record_ticket_dropped() {
+    TICKETS_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Matchmaking peers disconnected for holding a slot without searching, or
+// for not responding
+pub fn record_peer_reaped() {
+    PEERS_REAPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Matchmaking tickets turned away because every slot not kept for
+// spectators was taken
+pub fn record_ticket_rejected() {
+    TICKETS_REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_spectator_admitted() {
+    SPECTATORS_ADMITTED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Spectators turned away because every slot kept for them was taken
+pub fn record_spectator_rejected() {
+    SPECTATORS_REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Times the matchmaking server stopped servicing its peers for longer than
+// the watchdog threshold
+pub fn record_matchmaking_stall() {
+    MATCHMAKING_STALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+// What happened since the last report
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Counts {
+    matches_created: u64,
+    tickets_dropped: u64,
+    peers_reaped: u64,
+    tickets_rejected: u64,
+    spectators_admitted: u64,
+    spectators_rejected: u64,
+    matchmaking_stalls: u64,
+}
+
+impl Counts {
+    fn take() -> Counts {
+        Counts {
+            matches_created: MATCHES_CREATED.swap(0, Ordering::Relaxed),
+            tickets_dropped: TICKETS_DROPPED.swap(0, Ordering::Relaxed),
+            peers_reaped: PEERS_REAPED.swap(0, Ordering::Relaxed),
+            tickets_rejected: TICKETS_REJECTED.swap(0, Ordering::Relaxed),
+            spectators_admitted: SPECTATORS_ADMITTED.swap(0, Ordering::Relaxed),
+            spectators_rejected: SPECTATORS_REJECTED.swap(0, Ordering::Relaxed),
+            matchmaking_stalls: MATCHMAKING_STALLS.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+// Only aggregate, bucketed numbers are reported, nothing that identifies
+// the instance or its users.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Report {
+    version: String,
+    user_count: String,
+    matches_per_day: u64,
+    dropped_tickets_per_day: u64,
+    reaped_peers_per_day: u64,
+    rejected_tickets_per_day: u64,
+    admitted_spectators_per_day: u64,
+    rejected_spectators_per_day: u64,
+    matchmaking_stalls_per_day: u64,
+}
+
+impl Report {
+    fn new(user_count: i64, counts: Counts, interval_hours: u64) -> Report {
+        let per_day = |count: u64| count * 24 / interval_hours.max(1);
+
+        Report {
+            version: build_info::VERSION.to_string(),
+            user_count: get_user_count_bucket(user_count).to_string(),
+            matches_per_day: per_day(counts.matches_created),
+            dropped_tickets_per_day: per_day(counts.tickets_dropped),
+            reaped_peers_per_day: per_day(counts.peers_reaped),
+            rejected_tickets_per_day: per_day(counts.tickets_rejected),
+            admitted_spectators_per_day: per_day(counts.spectators_admitted),
+            rejected_spectators_per_day: per_day(counts.spectators_rejected),
+            matchmaking_stalls_per_day: per_day(counts.matchmaking_stalls),
+        }
+    }
+}
+
+fn get_user_count_bucket(user_count: i64) -> &'static str {
+    match user_count {
+        i64::MIN..=9 => "0-9",
+        10..=99 => "10-99",
+        100..=999 => "100-999",
+        1000..=9999 => "1000-9999",
+        _ => "10000+",
+    }
+}
+
+pub async fn start_reporting(config: Config, pool: SqlitePool) {
+    let url = match config.telemetry_url {
+        Some(url) => url,
+        None => return,
+    };
+    let interval_hours = config.telemetry_interval_hours.max(1);
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_hours * 60 * 60));
+
+    println!(
+        "Telemetry enabled, reporting to {} every {} hours",
+        url, interval_hours
+    );
+
+    // The first tick completes immediately, skip it so that the first
+    // report covers a full interval
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let user_count = match User::count(&pool).await {
+            Ok(user_count) => user_count,
+            Err(err) => {
+                println!("Failed to count users for telemetry report: {}", err);
+                continue;
+            }
+        };
+        let report = Report::new(user_count, Counts::take(), interval_hours);
+
+        println!("Sending telemetry report: {:?}", report);
+
+        if let Err(err) = client.post(url.clone()).json(&report).send().await {
+            println!("Failed to send telemetry report: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::telemetry::*;
+
+    #[test]
+    fn test_get_user_count_bucket() {
+        assert_eq!(get_user_count_bucket(0), "0-9");
+        assert_eq!(get_user_count_bucket(10), "10-99");
+        assert_eq!(get_user_count_bucket(999), "100-999");
+        assert_eq!(get_user_count_bucket(1000), "1000-9999");
+        assert_eq!(get_user_count_bucket(123456), "10000+");
+    }
+
+    #[test]
+    fn test_report_scales_matches_to_one_day() {
+        let counts = Counts {
+            matches_created: 10,
+            tickets_dropped: 3,
+            peers_reaped: 2,
+            tickets_rejected: 1,
+            spectators_admitted: 5,
+            spectators_rejected: 0,
+            matchmaking_stalls: 1,
+        };
+        let report = Report::new(42, counts, 6);
+        assert_eq!(report.user_count, "10-99");
+        assert_eq!(report.matches_per_day, 40);
+        assert_eq!(report.dropped_tickets_per_day, 12);
+        assert_eq!(report.reaped_peers_per_day, 8);
+        assert_eq!(report.rejected_tickets_per_day, 4);
+        assert_eq!(report.admitted_spectators_per_day, 20);
+        assert_eq!(report.rejected_spectators_per_day, 0);
+        assert_eq!(report.matchmaking_stalls_per_day, 4);
+    }
+}
@@ -0,0 +1,156 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequest, RequestParts},
+    http::{header, StatusCode},
+    Extension,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqliteExecutor};
+
+use crate::{
+    game::{OnlinePlayMode, Stage},
+    ids::Uid,
+    rotations::StageRotation,
+    schedule::QueueSchedule,
+    Config,
+};
+
+// Users registered on the main hostname, and clients not sending a tenant
+pub const DEFAULT_TENANT: &str = "";
+
+/// A community served alongside the default one, with its own users
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Tenant {
+    /// Identifier clients send as the tenant of their matchmaking tickets
+    pub id: String,
+    /// Hostname the web UI of the community is served at
+    pub hostname: String,
+    /// Name shown in the web UI instead of server_name
+    pub name: String,
+    /// IDs of the stages matches are played on, the usual ones for each mode if unset
+    #[serde(default)]
+    #[schemars(with = "Option<Vec<u8>>")]
+    pub stages: Option<Vec<Stage>>,
+    /// Hours the community's queues are open, the server's matchmaking_schedules if unset
+    #[serde(default)]
+    pub schedules: Option<Vec<QueueSchedule>>,
+    /// Message shown in the community's clients, the server's matchmaking_message if unset
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+impl Tenant {
+    // Stages configured for the community win over the active rotations
+    pub fn get_stages(
+        tenant: Option<&Tenant>,
+        mode: OnlinePlayMode,
+        rotations: &[StageRotation],
+    ) -> Vec<Stage> {
+        match tenant.and_then(|tenant| tenant.stages.clone()) {
+            Some(stages) => stages,
+            None => Stage::get_allowed_stages(mode, rotations),
+        }
+    }
+
+    pub async fn get_for_user<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        uid: Uid,
+    ) -> Result<String, sqlx::Error> {
+        sqlx::query("select tenant_id from users where uid = $1")
+            .bind(uid)
+            .fetch_one(executor)
+            .await
+            .map(|row| row.get::<String, usize>(0))
+    }
+}
+
+// The tenant whose hostname a request was sent to, None for the default one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrentTenant(pub Option<Tenant>);
+
+impl CurrentTenant {
+    pub fn id(&self) -> String {
+        match &self.0 {
+            Some(tenant) => tenant.id.clone(),
+            None => DEFAULT_TENANT.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for CurrentTenant
+where
+    B: Send,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(config) = Extension::<Config>::from_request(req)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let tenant = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| config.get_tenant_for_host(host))
+            .cloned();
+
+        Ok(CurrentTenant(tenant))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::game::{OnlinePlayMode, Stage};
+    use crate::tenants::*;
+
+    fn tenant(stages: Option<Vec<Stage>>) -> Tenant {
+        Tenant {
+            id: "pal".to_string(),
+            hostname: "pal.example.org".to_string(),
+            name: "PAL Melee".to_string(),
+            stages,
+            schedules: None,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn test_get_stages() {
+        assert_eq!(
+            Tenant::get_stages(None, OnlinePlayMode::Unranked, &[]),
+            Stage::get_allowed_stages(OnlinePlayMode::Unranked, &[])
+        );
+        assert_eq!(
+            Tenant::get_stages(Some(&tenant(None)), OnlinePlayMode::Teams, &[]),
+            Stage::get_allowed_stages(OnlinePlayMode::Teams, &[])
+        );
+        assert_eq!(
+            Tenant::get_stages(
+                Some(&tenant(Some(vec![Stage::Battlefield]))),
+                OnlinePlayMode::Unranked,
+                &[]
+            ),
+            vec![Stage::Battlefield]
+        );
+    }
+
+    #[test]
+    fn test_get_tenant_for_host() {
+        let config = Config {
+            tenants: vec![tenant(None)],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.get_tenant_for_host("PAL.example.org:5000"),
+            Some(&tenant(None))
+        );
+        assert_eq!(config.get_tenant_for_host("example.org"), None);
+        assert!(config.is_known_tenant("pal"));
+        assert!(config.is_known_tenant(DEFAULT_TENANT));
+        assert!(!config.is_known_tenant("ntsc"));
+    }
+}
@@ -0,0 +1,213 @@
+// Builders for the rows tests rely on, so each test only spells out what
+// matters to it. Available to the binary's tests through the test-support
+// feature.
+use std::str::FromStr;
+
+use secrecy::SecretString;
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::{encode_claims, Claims},
+    game::{ControllerPort, OnlinePlayMode, Stage},
+    ids::{MatchId, Uid},
+    matches::{Match, MatchStatus},
+    models::User,
+    tenants::DEFAULT_TENANT,
+};
+
+pub const TEST_USER_PASSWORD: &str = "password";
+
+const PORTS: [ControllerPort; 4] = [
+    ControllerPort::One,
+    ControllerPort::Two,
+    ControllerPort::Three,
+    ControllerPort::Four,
+];
+
+// Session token as stored in the token cookie, which may already have
+// expired
+pub fn session_token(user: &User, expires_at: i64) -> String {
+    tenant_session_token(user, DEFAULT_TENANT, expires_at)
+}
+
+// Only accepted on the hostname of the given tenant
+pub fn tenant_session_token(user: &User, tenant_id: &str, expires_at: i64) -> String {
+    encode_claims(&Claims {
+        uid: user.uid.clone(),
+        exp: expires_at as usize,
+        impersonator_uid: None,
+        tenant_id: tenant_id.to_string(),
+    })
+    .unwrap()
+}
+
+pub struct UserBuilder {
+    tenant_id: String,
+    username: Option<String>,
+    password: String,
+    display_name: String,
+    connect_code: String,
+    is_admin: bool,
+    is_banned: bool,
+}
+
+impl Default for UserBuilder {
+    fn default() -> Self {
+        UserBuilder {
+            tenant_id: DEFAULT_TENANT.to_string(),
+            username: None,
+            password: TEST_USER_PASSWORD.to_string(),
+            display_name: "test".to_string(),
+            connect_code: "TEST#001".to_string(),
+            is_admin: false,
+            is_banned: false,
+        }
+    }
+}
+
+impl UserBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tenant(mut self, tenant_id: &str) -> Self {
+        self.tenant_id = tenant_id.to_string();
+        self
+    }
+
+    // Defaults to the connect code, so users only differing by it don't
+    // clash
+    pub fn username(mut self, username: &str) -> Self {
+        self.username = Some(username.to_string());
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = password.to_string();
+        self
+    }
+
+    pub fn display_name(mut self, display_name: &str) -> Self {
+        self.display_name = display_name.to_string();
+        self
+    }
+
+    pub fn connect_code(mut self, connect_code: &str) -> Self {
+        self.connect_code = connect_code.to_string();
+        self
+    }
+
+    pub fn admin(mut self) -> Self {
+        self.is_admin = true;
+        self
+    }
+
+    pub fn banned(mut self) -> Self {
+        self.is_banned = true;
+        self
+    }
+
+    pub async fn insert(self, pool: &SqlitePool) -> User {
+        let user = User::create_in_tenant(
+            pool,
+            self.tenant_id,
+            self.username.unwrap_or_else(|| self.connect_code.clone()),
+            SecretString::from_str(&self.password).unwrap(),
+            self.display_name,
+            self.connect_code,
+        )
+        .await
+        .expect("Could not create user");
+
+        if self.is_admin {
+            sqlx::query("update users set is_admin = true where uid = $1")
+                .bind(user.uid.clone())
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+        if self.is_banned {
+            User::set_banned(pool, user.uid.clone(), true)
+                .await
+                .unwrap();
+        }
+
+        user
+    }
+}
+
+pub struct MatchBuilder {
+    match_id: MatchId,
+    mode: OnlinePlayMode,
+    player_uids: Vec<Uid>,
+    status: Option<(MatchStatus, Option<Uid>)>,
+    stage: Option<Stage>,
+}
+
+impl MatchBuilder {
+    pub fn new(match_id: &str) -> Self {
+        MatchBuilder {
+            match_id: match_id.parse().unwrap(),
+            mode: OnlinePlayMode::Unranked,
+            player_uids: vec![],
+            status: None,
+            stage: None,
+        }
+    }
+
+    pub fn mode(mut self, mode: OnlinePlayMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // Players get controller ports in the order they're added
+    pub fn player(mut self, user: &User) -> Self {
+        self.player_uids.push(user.uid.clone());
+        self
+    }
+
+    pub fn players(self, users: &[User]) -> Self {
+        users
+            .iter()
+            .fold(self, |builder, user| builder.player(user))
+    }
+
+    pub fn status(mut self, status: MatchStatus, winner: Option<&User>) -> Self {
+        self.status = Some((status, winner.map(|winner| winner.uid.clone())));
+        self
+    }
+
+    pub fn confirmed(self, winner: &User) -> Self {
+        self.status(MatchStatus::Confirmed, Some(winner))
+    }
+
+    pub fn stage(mut self, stage: Stage) -> Self {
+        self.stage = Some(stage);
+        self
+    }
+
+    pub async fn insert(self, pool: &SqlitePool) -> Match {
+        let players = self
+            .player_uids
+            .into_iter()
+            .zip(PORTS)
+            .collect::<Vec<(Uid, ControllerPort)>>();
+
+        Match::create(pool, self.match_id.clone(), self.mode, players)
+            .await
+            .expect("Could not create match");
+
+        if let Some(stage) = self.stage {
+            Match::set_stage(pool, self.match_id.clone(), stage)
+                .await
+                .unwrap();
+        }
+        if let Some((status, winner_uid)) = self.status {
+            Match::set_status(pool, self.match_id.clone(), status, winner_uid)
+                .await
+                .unwrap();
+        }
+
+        Match::get(pool, self.match_id).await.unwrap()
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+
+use chrono::{Datelike, NaiveDate, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteExecutor};
+
+/// Look of the web UI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    /// The regular look
+    Default,
+    /// Shown from October 20th until Halloween
+    Halloween,
+    /// Shown from December 15th until January 6th
+    Winter,
+}
+
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string = match &self {
+            Theme::Default => "default",
+            Theme::Halloween => "halloween",
+            Theme::Winter => "winter",
+        };
+        write!(f, "{}", string)
+    }
+}
+
+impl Theme {
+    // Operators choosing a theme opt out of the events calendar
+    pub fn active(configured: Option<Theme>, today: NaiveDate) -> Theme {
+        if let Some(theme) = configured {
+            return theme;
+        }
+
+        match (today.month(), today.day()) {
+            (10, 20..=31) => Theme::Halloween,
+            (12, 15..=31) | (1, 1..=6) => Theme::Winter,
+            _ => Theme::Default,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThemeContext {
+    pub name: String,
+    // The default theme only uses main.css
+    pub stylesheet: Option<String>,
+}
+
+impl ThemeContext {
+    pub fn current(configured: Option<Theme>) -> ThemeContext {
+        let theme = Theme::active(configured, Utc::now().naive_utc().date());
+
+        ThemeContext {
+            name: theme.to_string(),
+            stylesheet: (theme != Theme::Default).then(|| format!("/static/themes/{}.css", theme)),
+        }
+    }
+}
+
+pub const MAX_LOGO_BYTES: usize = 48 * 1024;
+
+#[derive(Debug, PartialEq, Eq, FromRow, Clone)]
+pub struct InstanceLogo {
+    pub content_type: String,
+    pub data: Vec<u8>,
+    pub updated_at: i64,
+}
+
+impl InstanceLogo {
+    // The type is sniffed from the image itself rather than trusted from the
+    // upload, and SVGs are refused since they can carry scripts
+    pub fn detect_content_type(data: &[u8]) -> Option<&'static str> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some("image/png")
+        } else if data.starts_with(b"\xff\xd8\xff") {
+            Some("image/jpeg")
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some("image/gif")
+        } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            Some("image/webp")
+        } else {
+            None
+        }
+    }
+
+    pub async fn get<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+    ) -> Result<Option<InstanceLogo>, sqlx::Error> {
+        sqlx::query_as::<_, InstanceLogo>(
+            "select content_type, data, updated_at from instance_logo where id = 1",
+        )
+        .fetch_optional(executor)
+        .await
+    }
+
+    pub async fn save<'a, T: SqliteExecutor<'a>>(
+        executor: T,
+        content_type: &str,
+        data: Vec<u8>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into instance_logo (id, content_type, data, updated_at) values (1, $1, $2, $3) on conflict (id) do update set content_type = excluded.content_type, data = excluded.data, updated_at = excluded.updated_at")
+            .bind(content_type)
+            .bind(data)
+            .bind(Utc::now().timestamp())
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn delete<'a, T: SqliteExecutor<'a>>(executor: T) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from instance_logo")
+            .execute(executor)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+    use sqlx::{Pool, Sqlite};
+
+    use crate::theme::*;
+
+    #[test]
+    fn test_active_theme_follows_events_calendar() {
+        let date = |month, day| NaiveDate::from_ymd_opt(2022, month, day).unwrap();
+
+        assert_eq!(Theme::active(None, date(10, 19)), Theme::Default);
+        assert_eq!(Theme::active(None, date(10, 31)), Theme::Halloween);
+        assert_eq!(Theme::active(None, date(12, 24)), Theme::Winter);
+        assert_eq!(Theme::active(None, date(1, 6)), Theme::Winter);
+        assert_eq!(Theme::active(None, date(1, 7)), Theme::Default);
+        assert_eq!(
+            Theme::active(Some(Theme::Default), date(10, 31)),
+            Theme::Default
+        );
+    }
+
+    #[test]
+    fn test_detect_content_type() {
+        assert_eq!(
+            InstanceLogo::detect_content_type(b"\x89PNG\r\n\x1a\nrest"),
+            Some("image/png")
+        );
+        assert_eq!(
+            InstanceLogo::detect_content_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            InstanceLogo::detect_content_type(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            None
+        );
+    }
+
+    #[sqlx::test]
+    async fn logo_can_be_replaced_and_removed(pool: Pool<Sqlite>) {
+        assert_eq!(InstanceLogo::get(&pool).await.unwrap(), None);
+
+        InstanceLogo::save(&pool, "image/png", vec![1])
+            .await
+            .unwrap();
+        InstanceLogo::save(&pool, "image/gif", vec![2])
+            .await
+            .unwrap();
+
+        let logo = InstanceLogo::get(&pool).await.unwrap().unwrap();
+        assert_eq!(logo.content_type, "image/gif");
+        assert_eq!(logo.data, vec![2]);
+
+        InstanceLogo::delete(&pool).await.unwrap();
+        assert_eq!(InstanceLogo::get(&pool).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,322 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteConnection};
+use validator::Validate;
+
+use crate::audit::{AuditAction, AuditLogEntry};
+use crate::ids::{ConnectCode, Uid};
+use crate::models::{error_messages, User};
+
+// A row of exported files, with the fields anyone can see besides the ban
+#[derive(Debug, PartialEq, Eq, FromRow, Serialize)]
+pub struct UserRecord {
+    pub uid: Uid,
+    pub connect_code: ConnectCode,
+    pub display_name: String,
+    #[sqlx(rename = "is_banned")]
+    pub banned: bool,
+}
+
+// Rows are matched to users by connect code, other columns than these are
+// ignored so exported files can be edited and imported back. Empty cells
+// leave the field unchanged.
+#[derive(Debug, Deserialize)]
+struct ImportRecord {
+    connect_code: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    banned: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserChange {
+    // Line of the file the change comes from, the header being line 1
+    pub line: u64,
+    pub uid: Uid,
+    pub connect_code: ConnectCode,
+    pub display_name: Option<String>,
+    pub banned: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RowError {
+    pub line: u64,
+    pub messages: Vec<String>,
+}
+
+// Rows which change nothing are left out of both lists
+#[derive(Debug, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportPlan {
+    pub changes: Vec<UserChange>,
+    pub errors: Vec<RowError>,
+}
+
+pub async fn export(conn: &mut SqliteConnection, tenant_id: String) -> Result<String, sqlx::Error> {
+    let records = sqlx::query_as::<_, UserRecord>("select uid, connect_code, display_name, is_banned from users where tenant_id = $1 order by connect_code")
+        .bind(tenant_id)
+        .fetch_all(conn)
+        .await?;
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    if records.is_empty() {
+        writer
+            .write_record(["uid", "connect_code", "display_name", "banned"])
+            .unwrap();
+    }
+    for record in records {
+        writer.serialize(record).unwrap();
+    }
+
+    Ok(String::from_utf8(writer.into_inner().unwrap()).unwrap())
+}
+
+fn parse_banned(banned: &str) -> Option<bool> {
+    match banned.trim().to_lowercase().as_str() {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Validates every row without changing anything, so errors can be shown
+// before any change is applied
+pub async fn plan(
+    conn: &mut SqliteConnection,
+    tenant_id: String,
+    data: &[u8],
+) -> Result<ImportPlan, sqlx::Error> {
+    let mut plan = ImportPlan::default();
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data);
+
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(err) => {
+            plan.errors.push(RowError {
+                line: 1,
+                messages: vec![err.to_string()],
+            });
+            return Ok(plan);
+        }
+    };
+
+    for result in reader.records() {
+        let parsed = result.and_then(|row| {
+            let line = row.position().map_or(0, |position| position.line());
+            row.deserialize::<ImportRecord>(Some(&headers))
+                .map(|record| (line, record))
+        });
+        let (line, record) = match parsed {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                plan.errors.push(RowError {
+                    line: err.position().map_or(0, |position| position.line()),
+                    messages: vec![err.to_string()],
+                });
+                continue;
+            }
+        };
+
+        let connect_code = match record.connect_code.to_uppercase().parse::<ConnectCode>() {
+            Ok(connect_code) => connect_code,
+            Err(err) => {
+                plan.errors.push(RowError {
+                    line,
+                    messages: vec![err.to_string()],
+                });
+                continue;
+            }
+        };
+        let user = match User::get_by_connect_code(&mut *conn, tenant_id.clone(), connect_code)
+            .await
+        {
+            Ok(user) => user,
+            Err(sqlx::Error::RowNotFound) => {
+                plan.errors.push(RowError {
+                    line,
+                    messages: vec![format!("No user with connect code {}", record.connect_code)],
+                });
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut messages = vec![];
+        let display_name = match record.display_name {
+            Some(display_name) if display_name != user.display_name => {
+                let renamed = User {
+                    display_name,
+                    ..user.clone()
+                };
+                if let Err(errors) = renamed.validate() {
+                    messages.extend(error_messages(&errors).into_values().flatten());
+                }
+                Some(renamed.display_name)
+            }
+            _ => None,
+        };
+        let is_banned = User::is_banned(&mut *conn, user.uid.clone()).await;
+        let banned = match record.banned.as_deref().map(parse_banned) {
+            Some(Some(banned)) if banned != is_banned => Some(banned),
+            Some(None) => {
+                messages.push("Banned must be true or false".to_string());
+                None
+            }
+            _ => None,
+        };
+
+        if !messages.is_empty() {
+            plan.errors.push(RowError { line, messages });
+        } else if display_name.is_some() || banned.is_some() {
+            plan.changes.push(UserChange {
+                line,
+                uid: user.uid,
+                connect_code: user.connect_code,
+                display_name,
+                banned,
+            });
+        }
+    }
+
+    Ok(plan)
+}
+
+// Changes are recorded in the audit log like the ones made one user at a
+// time, the caller commits them
+pub async fn apply(
+    conn: &mut SqliteConnection,
+    actor_uid: Option<Uid>,
+    ip: Option<IpAddr>,
+    changes: &[UserChange],
+) -> Result<(), sqlx::Error> {
+    for change in changes {
+        if let Some(display_name) = &change.display_name {
+            User::set_display_name(&mut *conn, change.uid.clone(), display_name.clone()).await?;
+            AuditLogEntry::record(
+                &mut *conn,
+                actor_uid.clone(),
+                ip,
+                AuditAction::SetDisplayName,
+                Some(change.uid.clone()),
+                Some(display_name.clone()),
+            )
+            .await?;
+        }
+        if let Some(banned) = change.banned {
+            User::set_banned(&mut *conn, change.uid.clone(), banned).await?;
+            AuditLogEntry::record(
+                &mut *conn,
+                actor_uid.clone(),
+                ip,
+                if banned {
+                    AuditAction::Ban
+                } else {
+                    AuditAction::Unban
+                },
+                Some(change.uid.clone()),
+                None,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::UserBuilder;
+    use crate::user_csv::*;
+
+    #[sqlx::test]
+    async fn imports_are_checked_before_being_applied(pool: Pool<Sqlite>) {
+        let first = UserBuilder::new()
+            .display_name("FIRST")
+            .connect_code("FRST#1")
+            .insert(&pool)
+            .await;
+        let second = UserBuilder::new()
+            .display_name("SECOND")
+            .connect_code("SCND#1")
+            .insert(&pool)
+            .await;
+        let mut conn = pool.acquire().await.unwrap();
+
+        let exported = export(&mut conn, DEFAULT_TENANT.to_string()).await.unwrap();
+        assert_eq!(
+            exported,
+            format!(
+                "uid,connect_code,display_name,banned\n{},FRST#1,FIRST,false\n{},SCND#1,SECOND,false\n",
+                first.uid, second.uid
+            )
+        );
+        // Importing an export back changes nothing
+        assert_eq!(
+            plan(&mut conn, DEFAULT_TENANT.to_string(), exported.as_bytes())
+                .await
+                .unwrap(),
+            ImportPlan::default()
+        );
+
+        let data = "connect_code,display_name,banned\nfrst#1,FIRST 2,\nSCND#1,SECOND*,maybe\nMISS#1,,true\nSCND#1,,yes\n";
+        let import = plan(&mut conn, DEFAULT_TENANT.to_string(), data.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            import.changes,
+            vec![
+                UserChange {
+                    line: 2,
+                    uid: first.uid.clone(),
+                    connect_code: "FRST#1".parse().unwrap(),
+                    display_name: Some("FIRST 2".to_string()),
+                    banned: None,
+                },
+                UserChange {
+                    line: 5,
+                    uid: second.uid.clone(),
+                    connect_code: "SCND#1".parse().unwrap(),
+                    display_name: None,
+                    banned: Some(true),
+                },
+            ]
+        );
+        assert_eq!(
+            import
+                .errors
+                .iter()
+                .map(|error| (error.line, error.messages.len()))
+                .collect::<Vec<(u64, usize)>>(),
+            vec![(3, 2), (4, 1)]
+        );
+        assert_eq!(User::get(&pool, first.uid.clone()).await.unwrap(), first);
+
+        apply(&mut conn, None, None, &import.changes).await.unwrap();
+        assert_eq!(
+            User::get(&pool, first.uid.clone())
+                .await
+                .unwrap()
+                .display_name,
+            "FIRST 2"
+        );
+        assert!(User::is_banned(&pool, second.uid.clone()).await);
+        assert_eq!(
+            AuditLogEntry::get_recent(&pool, 5)
+                .await
+                .unwrap()
+                .iter()
+                .map(|entry| entry.action.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["ban", "set_display_name"]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::{telemetry, Config};
+
+// Seconds between two checks of the matchmaking heartbeat
+const CHECK_INTERVAL_SECONDS: u64 = 5;
+
+// When a loop last went around, shared between the thread running it and
+// the ones watching it
+pub struct Heartbeat {
+    // 0 until the first tick
+    last_tick_at: AtomicI64,
+}
+
+impl Heartbeat {
+    pub const fn new() -> Heartbeat {
+        Heartbeat {
+            last_tick_at: AtomicI64::new(0),
+        }
+    }
+
+    pub fn tick(&self, now: i64) {
+        self.last_tick_at.store(now, Ordering::Relaxed);
+    }
+
+    // None if the loop never ticked
+    pub fn get_seconds_since_tick(&self, now: i64) -> Option<i64> {
+        match self.last_tick_at.load(Ordering::Relaxed) {
+            0 => None,
+            last_tick_at => Some(now - last_tick_at),
+        }
+    }
+
+    // A loop which hasn't started yet isn't ready either
+    pub fn is_alive(&self, now: i64, threshold_seconds: u64) -> bool {
+        self.get_seconds_since_tick(now)
+            .is_some_and(|seconds| seconds <= threshold_seconds as i64)
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new()
+    }
+}
+
+// Ticked by the ENet loop every time it services the host, at least once a
+// second unless something blocks it
+pub static MATCHMAKING_HEARTBEAT: Heartbeat = Heartbeat::new();
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    MatchmakingStalled,
+    MatchmakingRecovered,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub server_name: String,
+    // Since the last tick, or since the watchdog started if there was none
+    pub stalled_seconds: i64,
+    pub created_at: i64,
+}
+
+// Alerts once when the loop stops ticking and once when it ticks again,
+// rather than at every check
+#[derive(Debug)]
+struct Watch {
+    started_at: i64,
+    is_stalled: bool,
+}
+
+impl Watch {
+    fn check(
+        &mut self,
+        heartbeat: &Heartbeat,
+        threshold_seconds: u64,
+        now: i64,
+    ) -> Option<(AlertKind, i64)> {
+        let stalled_seconds = heartbeat
+            .get_seconds_since_tick(now)
+            .unwrap_or(now - self.started_at);
+        let is_stalled = stalled_seconds > threshold_seconds as i64;
+
+        match (self.is_stalled, is_stalled) {
+            (false, true) => {
+                self.is_stalled = true;
+                Some((AlertKind::MatchmakingStalled, stalled_seconds))
+            }
+            (true, false) => {
+                self.is_stalled = false;
+                Some((AlertKind::MatchmakingRecovered, stalled_seconds))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub async fn start_watching(config: Config) {
+    let threshold_seconds = config.matchmaking_watchdog_threshold_seconds;
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL_SECONDS));
+    let mut watch = Watch {
+        started_at: Utc::now().timestamp(),
+        is_stalled: false,
+    };
+
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now().timestamp();
+        let (kind, stalled_seconds) =
+            match watch.check(&MATCHMAKING_HEARTBEAT, threshold_seconds, now) {
+                Some(alert) => alert,
+                None => continue,
+            };
+
+        match kind {
+            AlertKind::MatchmakingStalled => {
+                telemetry::record_matchmaking_stall();
+                println!(
+                    "Matchmaking server hasn't ticked for {} seconds",
+                    stalled_seconds
+                );
+            }
+            AlertKind::MatchmakingRecovered => println!("Matchmaking server is ticking again"),
+        }
+
+        if let Some(url) = &config.matchmaking_watchdog_webhook_url {
+            let alert = Alert {
+                kind,
+                server_name: config.server_name.clone(),
+                stalled_seconds,
+                created_at: now,
+            };
+            if let Err(err) = client.post(url.clone()).json(&alert).send().await {
+                println!("Failed to send watchdog webhook: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::watchdog::*;
+
+    #[test]
+    fn test_heartbeat_is_alive_within_threshold() {
+        let heartbeat = Heartbeat::new();
+        assert_eq!(heartbeat.get_seconds_since_tick(100), None);
+        assert!(!heartbeat.is_alive(100, 30));
+
+        heartbeat.tick(100);
+        assert!(heartbeat.is_alive(130, 30));
+        assert!(!heartbeat.is_alive(131, 30));
+    }
+
+    #[test]
+    fn test_watch_alerts_once_per_stall() {
+        let heartbeat = Heartbeat::new();
+        let mut watch = Watch {
+            started_at: 100,
+            is_stalled: false,
+        };
+
+        // The loop never ticking is a stall too
+        assert_eq!(watch.check(&heartbeat, 30, 130), None);
+        assert_eq!(
+            watch.check(&heartbeat, 30, 131),
+            Some((AlertKind::MatchmakingStalled, 31))
+        );
+
+        heartbeat.tick(140);
+        assert_eq!(
+            watch.check(&heartbeat, 30, 140),
+            Some((AlertKind::MatchmakingRecovered, 0))
+        );
+        assert_eq!(watch.check(&heartbeat, 30, 170), None);
+        assert_eq!(
+            watch.check(&heartbeat, 30, 200),
+            Some((AlertKind::MatchmakingStalled, 60))
+        );
+        assert_eq!(watch.check(&heartbeat, 30, 260), None);
+    }
+}
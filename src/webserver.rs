@@ -1,14 +1,18 @@
+use std::fmt;
 use std::io::prelude::{Read, Write};
 use std::net::SocketAddr;
 
 use axum::{
-    body::{boxed, Full},
-    extract::Path,
+    body::{boxed, Body, Full},
+    error_handling::HandleErrorLayer,
+    extract::{Path, Query, TypedHeader},
     handler::Handler,
-    http::{header, StatusCode, Uri},
+    headers::{ETag, IfNoneMatch, UserAgent},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    middleware::{self, Next},
     response::{AppendHeaders, Html, IntoResponse, Redirect, Response},
-    routing::{get, post},
-    Extension, Form, Json, Router,
+    routing::{get, post, put},
+    BoxError, Extension, Form, Json, Router,
 };
 use axum_extra::extract::{
     cookie::{Cookie, SameSite},
@@ -19,9 +23,61 @@ use cookie::time::{Duration, OffsetDateTime};
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
 use sqlx::{Sqlite, SqlitePool};
-use tera::{Context, Tera};
+use tera::Context;
+use tower::{limit::GlobalConcurrencyLimitLayer, util::BoxCloneService, ServiceBuilder};
+use tower_http::{compression::CompressionLayer, timeout::TimeoutLayer};
+use validator::ValidationErrors;
 
-use openmelee::{auth::*, models::*, Asset, Config, LATEST_SLIPPI_CLIENT_VERSION};
+use openmelee::{
+    achievements::Achievement,
+    alerts,
+    api::{ApiError, Envelope, PageQuery, Paginated},
+    api_keys::{check_rate_limit, ApiKey, ApiKeyScope, API_KEY_HEADER},
+    api_tokens::{ApiScope, ApiToken},
+    assets,
+    auth::*,
+    build_info::BuildInfo,
+    claims::ConnectCodeClaim,
+    client_ip::ClientIp,
+    ids::{ConnectCode, PlayKey, Uid},
+    launcher::{LauncherLink, LAUNCHER_TOKEN_TTL_SECONDS},
+    logins::Login,
+    melee::{CharacterData, StageData, CHARACTERS, STAGES},
+    models::*,
+    quality::{MatchRating, SURVEY_WINDOW_SECONDS},
+    quests::{DailyQuest, Streak},
+    rotations::{StageRotation, UPCOMING_ROTATIONS},
+    schedule::ScheduleStatus,
+    tenants::{CurrentTenant, Tenant},
+    theme::InstanceLogo,
+    watchdog, Config, ReadPool, LATEST_SLIPPI_CLIENT_VERSION,
+};
+
+mod admin;
+mod api_tokens;
+mod claims;
+mod connection;
+#[cfg(feature = "sql-console")]
+mod console;
+mod feed;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod launcher;
+mod matches;
+mod overlay;
+mod pages;
+mod preferences;
+mod recovery;
+mod render;
+mod sitemap;
+mod slippi;
+mod stats;
+mod survey;
+mod widgets;
+
+use render::Renderer;
+
+const RECENT_LOGINS_LIMIT: i64 = 10;
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -43,22 +99,7 @@ impl IntoResponse for UserNotFound {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct PublicUser {
-    uid: String,
-    display_name: String,
-    connect_code: String,
-    latest_version: String,
-}
-
-impl IntoResponse for PublicUser {
-    fn into_response(self) -> Response {
-        (StatusCode::OK, Json(self)).into_response()
-    }
-}
-
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct UserForm {
     pub username: String,
     pub password: SecretString,
@@ -66,6 +107,17 @@ pub struct UserForm {
     pub connect_code: String,
 }
 
+// Leaves the password out, so logging a form can't leak it
+impl fmt::Debug for UserForm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserForm")
+            .field("username", &self.username)
+            .field("display_name", &self.display_name)
+            .field("connect_code", &self.connect_code)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PublicUserForm {
     pub username: String,
@@ -83,63 +135,275 @@ impl From<&UserForm> for PublicUserForm {
     }
 }
 
-impl From<&User> for PublicUser {
-    fn from(user: &User) -> PublicUser {
-        PublicUser {
-            uid: user.uid.to_string(),
-            display_name: user.display_name.to_string(),
-            connect_code: user.connect_code.to_string(),
-            latest_version: match &user.latest_version {
-                Some(str) => str.to_string(),
-                _ => LATEST_SLIPPI_CLIENT_VERSION.to_string(),
-            },
+async fn index(
+    mut tx: Tx<Sqlite>,
+    Extension(config): Extension<Config>,
+    tenant: CurrentTenant,
+    renderer: Renderer,
+) -> Response {
+    let mut context = Context::new();
+    let now = chrono::Utc::now();
+    context.insert(
+        "rotations",
+        &StageRotation::get_active(&mut tx, now.timestamp())
+            .await
+            .unwrap(),
+    );
+    context.insert(
+        "upcoming_rotations",
+        &StageRotation::get_upcoming(&mut tx, now.timestamp(), UPCOMING_ROTATIONS)
+            .await
+            .unwrap(),
+    );
+    context.insert(
+        "schedules",
+        &config
+            .get_schedules(&tenant.id())
+            .iter()
+            .map(|schedule| schedule.get_status(now))
+            .collect::<Vec<ScheduleStatus>>(),
+    );
+    renderer.render("index.html.tera", context)
+}
+
+async fn not_found(renderer: Renderer) -> Response {
+    renderer.render("404.html.tera", Context::new())
+}
+
+// Weak ETags name the version of the rows a response is built from, so
+// clients polling an unchanged resource are answered before it's loaded
+fn version_etag(version: impl fmt::Display) -> ETag {
+    format!("W/\"{}\"", version).parse::<ETag>().unwrap()
+}
+
+fn check_not_modified(
+    if_none_match: &Option<TypedHeader<IfNoneMatch>>,
+    etag: &ETag,
+) -> Option<Response> {
+    match if_none_match {
+        Some(TypedHeader(if_none_match)) if !if_none_match.precondition_passes(etag) => {
+            Some((StatusCode::NOT_MODIFIED, TypedHeader(etag.clone())).into_response())
         }
+        _ => None,
     }
 }
 
-async fn index(Extension(tera): Extension<Tera>, jar: PrivateCookieJar) -> Html<String> {
-    let mut context = Context::new();
-    context.insert("logged_in", &jar.get(JWT_COOKIE_NAME).is_some());
-    let content = tera.render("index.html.tera", &context).unwrap();
-    Html(content)
+fn json_with_etag<T: Serialize>(etag: ETag, value: &T) -> Response {
+    (TypedHeader(etag), Json(value)).into_response()
 }
 
-async fn not_found(Extension(tera): Extension<Tera>, jar: PrivateCookieJar) -> Html<String> {
-    let mut context = Context::new();
-    context.insert("logged_in", &jar.get(JWT_COOKIE_NAME).is_some());
-    let content = tera.render("404.html.tera", &context).unwrap();
-    Html(content)
+// Users of other communities are treated as unknown
+async fn get_tenant_user(tx: &mut Tx<Sqlite>, tenant_id: String, uid: Uid) -> Option<User> {
+    let user = User::get(&mut *tx, uid).await.ok()?;
+    if Tenant::get_for_user(&mut *tx, user.uid.clone()).await.ok() != Some(tenant_id) {
+        return None;
+    }
+
+    Some(user)
 }
 
-async fn get_user(mut tx: Tx<Sqlite>, Path(uid): Path<String>) -> Result<PublicUser, UserNotFound> {
+async fn get_user(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    Path(uid): Path<Uid>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, UserNotFound> {
+    let version = User::get_version(&mut tx, tenant.id(), uid.clone())
+        .await
+        .map_err(|_| UserNotFound::new())?;
+    let etag = version_etag(format!("{}-{}", uid, version));
+    if let Some(response) = check_not_modified(&if_none_match, &etag) {
+        return Ok(response);
+    }
+
     User::get(&mut tx, uid)
         .await
-        .map(|user| PublicUser::from(&user))
+        .map(|user| json_with_etag(etag, &PublicUser::from(&user)))
         .map_err(|_| UserNotFound::new())
 }
 
-async fn register(
-    Extension(tera): Extension<Tera>,
-    jar: PrivateCookieJar,
-) -> Result<Html<String>, Redirect> {
-    if jar.get(JWT_COOKIE_NAME).is_some() {
+// External tools key on connect codes rather than user IDs, see
+// ConnectCode::canonicalize for the forms accepted
+async fn get_user_by_connect_code(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    Path(connect_code): Path<String>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, UserNotFound> {
+    let connect_code = ConnectCode::canonicalize(&connect_code).map_err(|_| UserNotFound::new())?;
+
+    let (uid, version) = User::get_version_by_connect_code(&mut tx, tenant.id(), connect_code)
+        .await
+        .map_err(|_| UserNotFound::new())?;
+    let etag = version_etag(format!("{}-{}", uid, version));
+    if let Some(response) = check_not_modified(&if_none_match, &etag) {
+        return Ok(response);
+    }
+
+    User::get(&mut tx, uid)
+        .await
+        .map(|user| json_with_etag(etag, &PublicUser::from(&user)))
+        .map_err(|_| UserNotFound::new())
+}
+
+// Sent by clients when they start, so the version shown for the user is the
+// one they actually run
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LatestVersionUpdate {
+    uid: Uid,
+    play_key: PlayKey,
+    version: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum LatestVersionError {
+    InvalidPlayKey,
+    InvalidVersion,
+}
+
+impl IntoResponse for LatestVersionError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            LatestVersionError::InvalidPlayKey => (StatusCode::UNAUTHORIZED, "Invalid play key"),
+            LatestVersionError::InvalidVersion => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Version must consist of three numbers separated by dots",
+            ),
+        };
+        let body = Json(serde_json::json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+// The user a token was created by, for tools that don't know their UID
+async fn get_own_user(
+    mut tx: Tx<Sqlite>,
+    credentials: ApiCredentials,
+) -> Result<PublicUser, ApiError> {
+    let uid = credentials
+        .authorize(&mut tx, ApiScope::ReadProfile)
+        .await?;
+
+    Ok(PublicUser::from(&User::get(&mut tx, uid).await.unwrap()))
+}
+
+async fn update_latest_version(
+    mut tx: Tx<Sqlite>,
+    Json(update): Json<LatestVersionUpdate>,
+) -> Result<PublicUser, LatestVersionError> {
+    if !User::check_play_key(&mut tx, update.uid.clone(), update.play_key.clone()).await {
+        return Err(LatestVersionError::InvalidPlayKey);
+    }
+    if !User::is_valid_client_version(&update.version) {
+        return Err(LatestVersionError::InvalidVersion);
+    }
+
+    User::set_latest_version(&mut tx, update.uid.clone(), update.version)
+        .await
+        .unwrap();
+    let user = User::get(&mut tx, update.uid).await.unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(PublicUser::from(&user))
+}
+
+async fn list_users(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    Query(query): Query<PageQuery>,
+) -> Result<Envelope<Vec<PublicUser>>, ApiError> {
+    let users = User::get_page(&pool, tenant.id(), query.after()?, query.limit() + 1)
+        .await
+        .unwrap();
+    let total = User::count_in_tenant(&pool, tenant.id()).await.unwrap();
+
+    Ok(Paginated::new(users, query.limit(), total, |user| {
+        user.connect_code.to_string()
+    })
+    .map(|user| PublicUser::from(&user))
+    .into())
+}
+
+async fn get_user_achievements(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    Path(uid): Path<Uid>,
+) -> Result<Envelope<Vec<Achievement>>, ApiError> {
+    let user = User::get(&pool, uid)
+        .await
+        .map_err(|_| ApiError::new(StatusCode::NOT_FOUND, "User not found"))?;
+    if Tenant::get_for_user(&pool, user.uid.clone()).await.ok() != Some(tenant.id()) {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "User not found"));
+    }
+
+    Ok(Achievement::get_for_user(&pool, user.uid)
+        .await
+        .unwrap()
+        .into())
+}
+
+async fn get_version() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}
+
+async fn list_stages() -> Envelope<Vec<StageData>> {
+    STAGES.to_vec().into()
+}
+
+async fn list_characters() -> Envelope<Vec<CharacterData>> {
+    CHARACTERS.to_vec().into()
+}
+
+// For load balancers and orchestrators, which should stop sending players
+// here when the matchmaking server stalled even though pages are served
+async fn get_readiness(Extension(config): Extension<Config>) -> (StatusCode, String) {
+    let now = chrono::Utc::now().timestamp();
+    let heartbeat = &watchdog::MATCHMAKING_HEARTBEAT;
+
+    if heartbeat.is_alive(now, config.matchmaking_watchdog_threshold_seconds) {
+        return (StatusCode::OK, "ready".to_string());
+    }
+    match heartbeat.get_seconds_since_tick(now) {
+        Some(seconds) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("matchmaking server hasn't ticked for {} seconds", seconds),
+        ),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "matchmaking server hasn't started".to_string(),
+        ),
+    }
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        openmelee::metrics::render(),
+    )
+}
+
+async fn register(renderer: Renderer) -> Result<Response, Redirect> {
+    if renderer.is_logged_in() {
         return Err(Redirect::to("/profile"));
     }
 
     let mut context = Context::new();
     context.insert("field_errors", &false);
     context.insert("field_values", &false);
-    let content = tera.render("register.html.tera", &context).unwrap();
-    Ok(Html(content))
+    Ok(renderer.render("register.html.tera", context))
 }
 
 async fn register_form(
     tx: Tx<Sqlite>,
     Form(user_form): Form<UserForm>,
-    Extension(tera): Extension<Tera>,
+    renderer: Renderer,
 ) -> impl IntoResponse {
     User::check_constraints_and_create(
         tx,
+        renderer.tenant_id(),
         user_form.username.to_string(),
         user_form.password.clone(),
         user_form.display_name.to_string(),
@@ -150,66 +414,98 @@ async fn register_form(
     .map_err(|errors| {
         let mut context = Context::new();
         context.insert("error", &true);
-        context.insert("field_errors", &errors.field_errors());
+        context.insert("field_errors", &error_messages(&errors));
         context.insert("field_values", &PublicUserForm::from(&user_form));
-        let content = tera.render("register.html.tera", &context).unwrap();
-        (StatusCode::BAD_REQUEST, Html(content))
+        (
+            StatusCode::BAD_REQUEST,
+            renderer.render("register.html.tera", context),
+        )
     })
 }
 
-async fn login(
-    Extension(tera): Extension<Tera>,
-    jar: PrivateCookieJar,
-) -> Result<Html<String>, Redirect> {
-    if jar.get(JWT_COOKIE_NAME).is_some() {
+async fn login(renderer: Renderer) -> Result<Response, Redirect> {
+    if renderer.is_logged_in() {
         return Err(Redirect::to("/profile"));
     }
 
     let mut context = Context::new();
     context.insert("field_values", &false);
-    let content = tera
-        .render("login.html.tera", &context)
-        .unwrap()
-        .to_string();
-    Ok(Html(content))
+    Ok(renderer.render("login.html.tera", context))
 }
 
 async fn login_form(
     mut tx: Tx<Sqlite>,
     Form(payload): Form<AuthPayload>,
     jar: PrivateCookieJar,
-    Extension(tera): Extension<Tera>,
+    renderer: Renderer,
+    ClientIp(ip): ClientIp,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Extension(config): Extension<Config>,
-) -> impl IntoResponse {
-    create_token(&mut tx, &payload)
-        .await
-        .map(|token| {
-            Ok::<(PrivateCookieJar, Redirect), AuthError>((
-                jar.add(
-                    Cookie::build(JWT_COOKIE_NAME, token)
-                        .http_only(true)
-                        .same_site(SameSite::Strict)
-                        .secure(config.can_set_secure_cookie())
-                        .expires(
-                            OffsetDateTime::now_utc() + Duration::hours(JWT_COOKIE_DURATION_HOURS),
-                        )
-                        .finish(),
-                ),
-                Redirect::to("/profile"),
-            ))
-        })
-        .map_err(|_| {
+) -> Response {
+    let (uid, token) = match create_token(&mut tx, renderer.tenant_id(), &payload).await {
+        Ok(session) => session,
+        Err(_) => {
+            println!("Failed login for {} from {}", payload.username, ip);
+            alerts::record_failed_login();
+
             let mut context = Context::new();
             context.insert("error", &true);
             context.insert("field_values", &PublicAuthPayload::from(&payload));
-            let content = tera.render("login.html.tera", &context).unwrap();
-            (StatusCode::BAD_REQUEST, Html(content))
-        })
+            return (
+                StatusCode::BAD_REQUEST,
+                renderer.render("login.html.tera", context),
+            )
+                .into_response();
+        }
+    };
+
+    Login::record(
+        &mut tx,
+        uid,
+        ip,
+        user_agent.map(|TypedHeader(user_agent)| user_agent.to_string()),
+    )
+    .await
+    .unwrap();
+
+    (
+        jar.add(build_token_cookie(
+            JWT_COOKIE_NAME,
+            token,
+            &config,
+            Duration::hours(JWT_COOKIE_DURATION_HOURS),
+        )),
+        Redirect::to("/profile"),
+    )
+        .into_response()
+}
+
+pub fn build_token_cookie(
+    name: &'static str,
+    token: String,
+    config: &Config,
+    duration: Duration,
+) -> Cookie<'static> {
+    Cookie::build(name, token)
+        .path(config.get_cookie_path())
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(config.clone().can_set_secure_cookie())
+        .expires(OffsetDateTime::now_utc() + duration)
+        .finish()
+}
+
+// Cookies are only removed when the path matches the one they were set with
+pub fn build_removal_cookie(name: &'static str, config: &Config) -> Cookie<'static> {
+    Cookie::build(name, "")
+        .path(config.get_cookie_path())
+        .finish()
 }
 
-async fn logout(jar: PrivateCookieJar) -> impl IntoResponse {
+async fn logout(jar: PrivateCookieJar, Extension(config): Extension<Config>) -> impl IntoResponse {
     Ok::<(PrivateCookieJar, Redirect), AuthError>((
-        jar.remove(Cookie::named(JWT_COOKIE_NAME)),
+        jar.remove(build_removal_cookie(JWT_COOKIE_NAME, &config))
+            .remove(build_removal_cookie(IMPERSONATOR_COOKIE_NAME, &config)),
         Redirect::to("/login"),
     ))
 }
@@ -217,28 +513,134 @@ async fn logout(jar: PrivateCookieJar) -> impl IntoResponse {
 async fn profile(
     mut tx: Tx<Sqlite>,
     claims: Claims,
-    Extension(tera): Extension<Tera>,
-) -> Html<String> {
+    renderer: Renderer,
+    Extension(config): Extension<Config>,
+) -> Response {
+    render_profile(&mut tx, &claims, &renderer, &config, None, None, None).await
+}
+
+// Invalid preferences are shown with their errors instead of the stored ones,
+// and a newly created API token or launcher link is shown once
+async fn render_profile(
+    tx: &mut Tx<Sqlite>,
+    claims: &Claims,
+    renderer: &Renderer,
+    config: &Config,
+    invalid_preferences: Option<(&preferences::PreferencesForm, ValidationErrors)>,
+    created_api_token: Option<&str>,
+    launcher_link: Option<&LauncherLink>,
+) -> Response {
     let mut context = Context::new();
-    let user = PublicUser::from(&User::get(&mut tx, claims.uid).await.unwrap());
+    let user = PublicUser::from(&User::get(&mut *tx, claims.uid.clone()).await.unwrap());
     context.insert("user", &user);
-    context.insert("logged_in", &true);
+    context.insert(
+        "achievements",
+        &Achievement::get_for_user(&mut *tx, claims.uid.clone())
+            .await
+            .unwrap(),
+    );
+    let today = chrono::Utc::now().date_naive();
+    let streak = Streak::get_for_user(&mut *tx, claims.uid.clone())
+        .await
+        .unwrap();
+    context.insert(
+        "play_streak",
+        &streak
+            .as_ref()
+            .map(|streak| streak.get_current_play_days(today))
+            .unwrap_or(0),
+    );
+    context.insert("streak", &streak);
+    context.insert(
+        "quests",
+        &DailyQuest::get_for_user(&mut *tx, claims.uid.clone(), today)
+            .await
+            .unwrap(),
+    );
+    context.insert(
+        "logins",
+        &Login::get_recent(&mut *tx, claims.uid.clone(), RECENT_LOGINS_LIMIT)
+            .await
+            .unwrap(),
+    );
+    context.insert(
+        "api_tokens",
+        &ApiToken::get_for_user(&mut *tx, claims.uid.clone())
+            .await
+            .unwrap(),
+    );
+    context.insert("created_api_token", &created_api_token);
+    context.insert("launcher_link", &launcher_link);
+    context.insert("launcher_token_minutes", &(LAUNCHER_TOKEN_TTL_SECONDS / 60));
+    context.insert(
+        "connect_code_claim",
+        &ConnectCodeClaim::get_latest_for_user(&mut *tx, claims.uid.clone())
+            .await
+            .unwrap(),
+    );
+    let unrated_match = match config.match_survey {
+        true => MatchRating::get_unrated_match(
+            &mut *tx,
+            claims.uid.clone(),
+            chrono::Utc::now().timestamp() - SURVEY_WINDOW_SECONDS,
+        )
+        .await
+        .unwrap(),
+        false => None,
+    };
+    context.insert("unrated_match", &unrated_match);
+    context.insert(
+        "timezones",
+        &chrono_tz::TZ_VARIANTS
+            .iter()
+            .map(|timezone| timezone.name())
+            .collect::<Vec<&str>>(),
+    );
 
-    let content = tera
-        .render("profile.html.tera", &context)
-        .unwrap()
-        .to_string();
-    Html(content)
+    match invalid_preferences {
+        Some((form, errors)) => {
+            context.insert("field_errors", &error_messages(&errors));
+            context.insert("field_values", form);
+        }
+        None => {
+            let preferences = MatchmakingPreferences::get(&mut *tx, claims.uid.clone())
+                .await
+                .unwrap();
+            context.insert("field_errors", &false);
+            context.insert(
+                "field_values",
+                &preferences::PreferencesForm {
+                    max_ping_ms: preferences
+                        .max_ping_ms
+                        .map(|max_ping_ms| max_ping_ms.to_string())
+                        .unwrap_or_default(),
+                    strict: preferences.strict.then(|| "on".to_string()),
+                },
+            );
+        }
+    }
+
+    renderer.render("profile.html.tera", context)
 }
 
 async fn get_user_json(
     mut tx: Tx<Sqlite>,
     claims: Claims,
     Extension(config): Extension<Config>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, AuthError> {
+    // user.json contains the play key, which must not be handed out to
+    // admins viewing the site as this user
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
     let user = User::get(&mut tx, claims.uid).await.unwrap();
+    let mut user_json = User::get_user_json(user, config.clone());
+    if let Some(key) = read_user_json_key(&config) {
+        user_json = user_json.sign(key.as_bytes());
+    }
 
-    (
+    Ok((
         AppendHeaders([
             (header::CONTENT_TYPE, "application/json; charset=utf-8"),
             (
@@ -246,16 +648,44 @@ async fn get_user_json(
                 "attachment; filename=\"openmelee-user.json\"",
             ),
         ]),
-        Json(User::get_user_json(user, config)),
-    )
+        Json(user_json),
+    ))
+}
+
+// Read when needed, the file is checked to exist on startup
+fn read_user_json_key(config: &Config) -> Option<String> {
+    let path = config.user_json_secret_path.as_ref()?;
+    match std::fs::read_to_string(path) {
+        Ok(key) => Some(key.trim().to_string()),
+        Err(err) => {
+            println!("Unable to read {}: {}", path, err);
+            None
+        }
+    }
+}
+
+// Tells whether a user.json file was edited since it was downloaded, for
+// players whose client doesn't authenticate
+async fn verify_user_json(
+    Extension(config): Extension<Config>,
+    Json(user_json): Json<serde_json::Value>,
+) -> Result<Json<UserJsonVerification>, ApiError> {
+    let key = read_user_json_key(&config).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "user.json signatures can't be checked",
+        )
+    })?;
+
+    Ok(Json(UserJson::verify(&user_json, key.as_bytes())))
 }
 
 async fn static_handler(uri: Uri) -> impl IntoResponse {
     let path = uri.path().trim_start_matches('/').to_string();
 
-    match Asset::get(path.as_str()) {
+    match assets::get_asset(path.as_str()) {
         Some(content) => {
-            let body = boxed(Full::from(content.data));
+            let body = boxed(Full::from(content));
             let mime = mime_guess::from_path(path).first_or_octet_stream();
             Response::builder()
                 .header(header::CONTENT_TYPE, mime.as_ref())
@@ -269,6 +699,267 @@ async fn static_handler(uri: Uri) -> impl IntoResponse {
     }
 }
 
+// Falls back to the bundled logo until an admin uploads one
+async fn get_logo(mut tx: Tx<Sqlite>) -> Response {
+    let (content_type, body) = match InstanceLogo::get(&mut tx).await.unwrap() {
+        Some(logo) => (logo.content_type, boxed(Full::from(logo.data))),
+        None => (
+            "image/svg+xml".to_string(),
+            boxed(Full::from(assets::get_asset("static/logo.svg").unwrap())),
+        ),
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+// Bodies are buffered here, so that the request is rejected before any
+// handler starts working with it.
+async fn limit_body_size(req: Request<Body>, next: Next<Body>, max_bytes: usize) -> Response {
+    let (parts, body) = req.into_parts();
+
+    match hyper::body::to_bytes(http_body::Limited::new(body, max_bytes)).await {
+        Ok(bytes) => {
+            next.run(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+        Err(err) if err.is::<http_body::LengthLimitError>() => {
+            StatusCode::PAYLOAD_TOO_LARGE.into_response()
+        }
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+// The limit layers respond with empty bodies, replace those with a page
+// explaining what happened.
+async fn render_limit_errors<B>(req: Request<B>, next: Next<B>, base_path: String) -> Response {
+    let response = next.run(req).await;
+
+    let (title, message) = match response.status() {
+        StatusCode::REQUEST_TIMEOUT => (
+            "Request Timeout",
+            "The server took too long to respond. Please try again later.",
+        ),
+        StatusCode::PAYLOAD_TOO_LARGE => ("Payload Too Large", "The submitted data is too large."),
+        StatusCode::SERVICE_UNAVAILABLE => (
+            "Service Unavailable",
+            "The server is too busy right now. Please try again later.",
+        ),
+        _ => return response,
+    };
+
+    let mut context = Context::new();
+    context.insert("base_path", &base_path);
+    context.insert("title", title);
+    context.insert("message", message);
+    let content = openmelee::TEMPLATES
+        .render("error.html.tera", &context)
+        .unwrap();
+
+    (response.status(), Html(content)).into_response()
+}
+
+// Images, the only already compressed assets, are skipped by the default
+// compression predicate
+fn get_compression_layer(gzip: bool, brotli: bool) -> CompressionLayer {
+    CompressionLayer::new().gzip(gzip).br(brotli)
+}
+
+fn add_limit_layers(router: Router, config: &Config) -> Router {
+    let max_body_bytes = config.webserver_max_body_bytes;
+    let base_path = config.webserver_base_path.clone();
+
+    router
+        .layer(middleware::from_fn(move |req, next| {
+            limit_body_size(req, next, max_body_bytes)
+        }))
+        // Requests over the limit are refused right away instead of queueing
+        // behind the ones in flight
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(
+                    config.webserver_max_in_flight_requests,
+                )),
+        )
+        .layer(TimeoutLayer::new(std::time::Duration::from_secs(
+            config.webserver_request_timeout_seconds,
+        )))
+        .layer(middleware::from_fn(move |req, next| {
+            render_limit_errors(req, next, base_path.clone())
+        }))
+}
+
+// Headers the configuration leaves empty are not sent at all
+fn get_security_headers(config: &Config) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let mut insert = |name: header::HeaderName, value: String| {
+        if !value.is_empty() {
+            let value = HeaderValue::from_str(&value)
+                .unwrap_or_else(|_| panic!("Invalid value for the {} header", name));
+            headers.insert(name, value);
+        }
+    };
+
+    insert(
+        header::CONTENT_SECURITY_POLICY,
+        config.webserver_content_security_policy.clone(),
+    );
+    insert(
+        header::X_FRAME_OPTIONS,
+        config.webserver_frame_options.clone(),
+    );
+    insert(
+        header::REFERRER_POLICY,
+        config.webserver_referrer_policy.clone(),
+    );
+    insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string());
+
+    // Browsers ignore HSTS over plain HTTP, and it would lock them out of a
+    // server without TLS
+    if config.clone().can_set_secure_cookie() && config.webserver_hsts_max_age_seconds > 0 {
+        insert(
+            header::STRICT_TRANSPORT_SECURITY,
+            format!("max-age={}", config.webserver_hsts_max_age_seconds),
+        );
+    }
+
+    headers
+}
+
+// Handlers setting one of the headers themselves keep their value. Those
+// allowing frames through the content security policy aren't sent
+// X-Frame-Options, which can't allow other sites.
+async fn set_security_headers<B>(
+    req: Request<B>,
+    next: Next<B>,
+    security_headers: HeaderMap,
+) -> Response {
+    let mut response = next.run(req).await;
+
+    let sets_frame_ancestors = response
+        .headers()
+        .get(header::CONTENT_SECURITY_POLICY)
+        .and_then(|policy| policy.to_str().ok())
+        .is_some_and(|policy| policy.contains("frame-ancestors"));
+    for (name, value) in security_headers.iter() {
+        if name == header::X_FRAME_OPTIONS && sets_frame_ancestors {
+            continue;
+        }
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    response
+}
+
+// Counted for the error rate alert rules
+async fn count_responses<B>(req: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(req).await;
+    alerts::record_response(response.status().is_server_error());
+
+    response
+}
+
+fn add_security_headers(router: Router, config: &Config) -> Router {
+    let security_headers = get_security_headers(config);
+
+    router.layer(middleware::from_fn(move |req, next| {
+        set_security_headers(req, next, security_headers.clone())
+    }))
+}
+
+// Requests without a key are left to the handlers. Operator keys only read
+// the API, within the scopes and the rate limit they were issued with, and
+// every request made with one is counted whether it was throttled or not.
+async fn enforce_api_keys<B>(req: Request<B>, next: Next<B>, pool: SqlitePool) -> Response {
+    let key = match req.headers().get(API_KEY_HEADER) {
+        Some(key) => key.to_str().unwrap_or_default().to_string(),
+        None => return next.run(req).await,
+    };
+
+    let api_key = match ApiKey::authenticate(&pool, &key).await.unwrap() {
+        Some(api_key) => api_key,
+        None => return ApiError::new(StatusCode::UNAUTHORIZED, "Invalid API key").into_response(),
+    };
+
+    let scope = match ApiKeyScope::for_path(req.uri().path()) {
+        Some(scope) if req.method() == Method::GET => scope,
+        _ => {
+            return ApiError::new(StatusCode::FORBIDDEN, "API keys can't be used here")
+                .into_response()
+        }
+    };
+    if !api_key.has_scope(scope) {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            &format!("API key lacks the {} scope", scope),
+        )
+        .into_response();
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let limited = check_rate_limit(api_key.id, api_key.requests_per_minute, now);
+    ApiKey::record_use(&pool, api_key.id, limited.is_err(), now)
+        .await
+        .unwrap();
+
+    match limited {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => (
+            AppendHeaders([(header::RETRY_AFTER, retry_after.to_string())]),
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, "API key rate limit exceeded"),
+        )
+            .into_response(),
+    }
+}
+
+fn add_api_key_layer(router: Router, pool: SqlitePool) -> Router {
+    router.layer(middleware::from_fn(move |req, next| {
+        enforce_api_keys(req, next, pool.clone())
+    }))
+}
+
+// Handlers redirect to paths from the root of the site, which are only
+// prefixed here
+async fn prefix_redirects<B>(req: Request<B>, next: Next<B>, base_path: String) -> Response {
+    let mut response = next.run(req).await;
+
+    if let Some(location) = response.headers().get(header::LOCATION) {
+        let location = location.to_str().unwrap_or_default();
+        if location.starts_with('/') && !location.starts_with("//") {
+            let prefixed = HeaderValue::from_str(&format!("{}{}", base_path, location)).unwrap();
+            response.headers_mut().insert(header::LOCATION, prefixed);
+        }
+    }
+
+    response
+}
+
+// Requests outside of the base path are left unanswered, the reverse proxy
+// should only forward the ones under it
+fn nest_under_base_path(router: Router, config: &Config) -> Router {
+    let base_path = config.webserver_base_path.clone();
+    if base_path.is_empty() {
+        return router;
+    }
+
+    let router = router.layer(middleware::from_fn({
+        let base_path = base_path.clone();
+        move |req, next| prefix_redirects(req, next, base_path.clone())
+    }));
+
+    // Boxed, since nesting a Router with a fallback isn't supported
+    Router::new().nest(&base_path, BoxCloneService::new(router))
+}
+
 fn get_cookie_key(config: Config) -> cookie::Key {
     let cookie_secret_path = config
         .cookie_secret_path
@@ -296,31 +987,191 @@ fn get_cookie_key(config: Config) -> cookie::Key {
     }
 }
 
-async fn app(config: Config, pool: SqlitePool) -> Router {
-    Router::new()
+async fn app(config: Config, pool: SqlitePool, read_pool: ReadPool) -> Router {
+    openmelee::pages::refresh_navbar_links(&pool)
+        .await
+        .expect("Failed to load pages");
+
+    let mut api = Router::new()
+        .route("/api/v1/users", get(list_users))
+        .route("/api/v1/me", get(get_own_user))
+        .route("/api/v1/me/matches", get(matches::list_own_matches))
+        .route("/api/v1/overlay/:token", get(overlay::get_overlay_json))
+        .route("/api/v1/user/:uid", get(get_user))
+        .route("/api/v1/user/:uid/achievements", get(get_user_achievements))
+        .route(
+            "/api/v1/connect-code/:connect_code",
+            get(get_user_by_connect_code),
+        )
+        .route("/api/v1/user/latest-version", put(update_latest_version))
+        .route("/api/v1/matches", get(matches::list_matches))
+        .route("/api/v1/version", get(get_version))
+        .route("/api/v1/stages", get(list_stages))
+        .route("/api/v1/characters", get(list_characters))
+        .route("/api/v1/stats", get(stats::get_stats_json))
+        .route("/api/v1/stats/queues", get(stats::get_queue_stats_json))
+        .route(
+            "/api/v1/stats/queue-status",
+            get(stats::get_queue_status_json),
+        )
+        .route("/api/v1/stats/quality", get(stats::get_quality_stats_json))
+        .route(
+            "/api/v1/connection-health",
+            get(connection::get_connection_health_json),
+        )
+        .route("/api/v1/launcher/bootstrap", post(launcher::bootstrap))
+        .route("/api/v1/match-results", post(matches::report))
+        .route(
+            "/api/v1/preferences",
+            get(preferences::get_preferences).put(preferences::update_preferences),
+        );
+    if config.user_json_secret_path.is_some() {
+        api = api.route("/api/v1/user-json/verify", post(verify_user_json));
+    }
+    #[cfg(feature = "graphql")]
+    {
+        api = api.route("/api/v1/graphql", post(graphql::execute));
+    }
+
+    let mut router = Router::new()
         .route("/", get(index))
         .route("/register", get(register))
         .route("/register", post(register_form))
         .route("/login", get(login))
         .route("/login", post(login_form))
+        .route("/recover", get(recovery::show))
+        .route("/recover", post(recovery::recover))
         .route("/logout", get(logout))
         .route("/profile", get(profile))
+        .route("/profile/preferences", post(preferences::preferences_form))
+        .route("/profile/timezone", post(preferences::timezone_form))
+        .route("/profile/connection", get(connection::connection_health))
+        .route("/profile/api-tokens", post(api_tokens::create_api_token))
+        .route("/profile/connect-code-claim", post(claims::submit_claim))
+        .route(
+            "/profile/launcher-link",
+            post(launcher::create_launcher_link),
+        )
+        .route(
+            "/profile/api-tokens/:id/revoke",
+            post(api_tokens::revoke_api_token),
+        )
         .route("/openmelee-user.json", get(get_user_json))
         .route("/user/:uid", get(get_user))
-        .route("/static/*file", static_handler.into_service())
-        .fallback(get(not_found))
-        .layer(axum_sqlx_tx::Layer::new(pool))
+        .route("/readyz", get(get_readiness))
+        .route("/admin", get(admin::index))
+        .route("/admin/capacity", get(admin::capacity))
+        .route("/admin/schema", get(admin::schema))
+        .route("/admin/users/export", get(admin::export_users))
+        .route("/admin/users/import", post(admin::import_users))
+        .route("/admin/users/:uid", get(admin::show_user))
+        .route("/admin/users/:uid/notes", post(admin::add_user_note))
+        .route("/admin/users/:uid/impersonate", post(admin::impersonate))
+        .route(
+            "/admin/users/:uid/shadow-queue",
+            post(admin::add_to_shadow_queue),
+        )
+        .route(
+            "/admin/users/:uid/shadow-queue/remove",
+            post(admin::remove_from_shadow_queue),
+        )
+        .route("/admin/impersonate/stop", get(admin::stop_impersonating))
+        .route(
+            "/admin/matches/:match_id/resolve",
+            post(admin::resolve_dispute),
+        )
+        .route("/admin/pages", get(pages::new).post(pages::save))
+        .route("/admin/pages/:slug", get(pages::edit))
+        .route("/admin/pages/:slug/delete", post(pages::delete))
+        .route(
+            "/admin/connect-code-claims/:id/approve",
+            post(admin::approve_connect_code_claim),
+        )
+        .route(
+            "/admin/connect-code-claims/:id/reject",
+            post(admin::reject_connect_code_claim),
+        )
+        .route("/admin/logo", post(admin::upload_logo))
+        .route("/admin/logo/remove", post(admin::remove_logo))
+        .route("/logo", get(get_logo))
+        .route("/admin/announcements", post(admin::publish_announcement))
+        .route(
+            "/admin/announcements/:id/delete",
+            post(admin::delete_announcement),
+        )
+        .route("/admin/stage-rotations", post(admin::create_stage_rotation))
+        .route(
+            "/admin/stage-rotations/:id/delete",
+            post(admin::delete_stage_rotation),
+        )
+        .route("/admin/api-keys", post(admin::create_api_key))
+        .route("/admin/api-keys/:id/revoke", post(admin::revoke_api_key))
+        .route("/admin/queues/:mode/pause", post(admin::pause_queue))
+        .route("/admin/queues/:mode/resume", post(admin::resume_queue))
+        .route("/admin/broadcasts", post(admin::send_broadcast))
+        .route("/feed.xml", get(feed::get_feed))
+        .route("/overlay/:token", get(overlay::show_overlay))
+        .route("/stats", get(stats::get_stats))
+        .route("/robots.txt", get(sitemap::get_robots))
+        .route("/sitemap.xml", get(sitemap::get_sitemap))
+        .route("/static/*file", static_handler.into_service())
+        .route(
+            "/widgets/leaderboard/:token",
+            get(widgets::show_leaderboard),
+        )
+        .route(
+            "/widgets/queue-status/:token",
+            get(widgets::show_queue_status),
+        )
+        .fallback(get(pages::show));
+
+    if config.slippi_rank_api_compat {
+        router = router.route("/graphql", post(slippi::get_rank));
+    }
+    if config.match_survey {
+        router = router.route("/profile/match-rating", post(survey::rate_match));
+    }
+    if config.prometheus_metrics {
+        router = router.route("/metrics", get(get_metrics));
+    }
+    #[cfg(feature = "sql-console")]
+    {
+        router = router.route("/admin/console", get(console::show).post(console::run));
+    }
+
+    // Pages and the API are compressed as configured for each
+    let router = router
+        .layer(get_compression_layer(
+            config.compression_html_gzip,
+            config.compression_html_brotli,
+        ))
+        .merge(api.layer(get_compression_layer(
+            config.compression_json_gzip,
+            config.compression_json_brotli,
+        )));
+
+    let router = add_api_key_layer(router, pool.clone());
+    let router = add_security_headers(add_limit_layers(router, &config), &config)
+        .layer(middleware::from_fn(count_responses))
+        .layer(axum_sqlx_tx::Layer::new(pool))
+        .layer(Extension(read_pool))
         .layer(Extension(openmelee::TEMPLATES.clone()))
         .layer(Extension(get_cookie_key(config.clone())))
-        .layer(Extension(config.clone()))
+        .layer(Extension(config.clone()));
+
+    nest_under_base_path(router, &config)
 }
 
-pub async fn start_server(config: Config, pool: SqlitePool) -> Result<(), ()> {
+pub async fn start_server(config: Config, pool: SqlitePool, read_pool: ReadPool) -> Result<(), ()> {
     let server = axum::Server::bind(&SocketAddr::from((
         config.webserver_address,
         config.webserver_port,
     )))
-    .serve(app(config.clone(), pool).await.into_make_service());
+    .serve(
+        app(config.clone(), pool, read_pool)
+            .await
+            .into_make_service_with_connect_info::<SocketAddr>(),
+    );
 
     println!(
         "Web server listening on {}",
@@ -335,10 +1186,22 @@ mod test {
     use std::net::TcpListener;
     use std::str::FromStr;
 
+    use chrono::Utc;
     use rand::Rng;
     use serde_json::json;
     use sqlx::Pool;
 
+    use openmelee::broadcasts::Broadcast;
+    use openmelee::capacity::{QueueSnapshot, SNAPSHOT_INTERVAL_SECONDS};
+    use openmelee::formations::MatchFormation;
+    use openmelee::game::OnlinePlayMode;
+    use openmelee::quality::MatchQuality;
+    use openmelee::queue_pauses::QueuePause;
+    use openmelee::ranking::Rating;
+    use openmelee::stats::DailyMatchCount;
+    use openmelee::tenants::{Tenant, DEFAULT_TENANT};
+    use openmelee::test_support::{session_token, tenant_session_token, MatchBuilder, UserBuilder};
+
     use crate::webserver::*;
 
     const TEST_USER_PASSWORD: &str = "5~}Eau&b5C1df.LI_|mOXnl0";
@@ -346,10 +1209,10 @@ mod test {
     #[test]
     fn test_can_create_public_user_from_user() {
         let user = User {
-            uid: "1234".to_string(),
-            play_key: "5678".to_string(),
+            uid: "1234".parse().unwrap(),
+            play_key: "5678".parse().unwrap(),
             display_name: "test".to_string(),
-            connect_code: "TEST#001".to_string(),
+            connect_code: "TEST#001".parse().unwrap(),
             latest_version: None,
         };
 
@@ -366,12 +1229,14 @@ mod test {
 
     async fn test_register_form(
         tx: Tx<Sqlite>,
+        tenant: CurrentTenant,
         Form(user_form): Form<PublicUserForm>,
     ) -> impl IntoResponse {
         let password: SecretString = SecretString::from_str(TEST_USER_PASSWORD).unwrap();
 
         User::check_constraints_and_create(
             tx,
+            tenant.id(),
             user_form.username.to_string(),
             password,
             user_form.display_name.to_string(),
@@ -386,8 +1251,14 @@ mod test {
     }
 
     async fn start_test_server(pool: Pool<Sqlite>) -> (String, reqwest::Client) {
+        start_test_server_with_config(pool, Config::default()).await
+    }
+
+    async fn start_test_server_with_config(
+        pool: Pool<Sqlite>,
+        config: Config,
+    ) -> (String, reqwest::Client) {
         let mut rng = rand::thread_rng();
-        let config = Config::default();
         let port: u16 = rng.gen_range(config.webserver_port..10000);
         let addr = format!("{}:{}", config.webserver_address, port);
         let listener = TcpListener::bind(addr.parse::<SocketAddr>().unwrap()).unwrap();
@@ -395,20 +1266,65 @@ mod test {
         // Create a custom router which serves normal routes,
         // except for POST /register, which creates users with
         // a constant password and returns JSON
-        let test_app: Router = Router::new()
-            .route("/", get(index))
-            .route("/register", get(register))
-            .route("/register", post(test_register_form))
-            .route("/user/:uid", get(get_user))
-            .route("/static/*file", static_handler.into_service())
-            .fallback(get(not_found))
-            .layer(axum_sqlx_tx::Layer::new(pool))
-            .layer(Extension(openmelee::TEMPLATES.clone()));
+        let test_app: Router = add_limit_layers(
+            Router::new()
+                .route("/", get(index))
+                .route("/register", get(register))
+                .route("/register", post(test_register_form))
+                .route("/user/:uid", get(get_user))
+                .route("/api/v1/users", get(list_users))
+                .route("/api/v1/me", get(get_own_user))
+                .route("/api/v1/me/matches", get(matches::list_own_matches))
+                .route("/api/v1/overlay/:token", get(overlay::get_overlay_json))
+                .route("/api/v1/user/:uid", get(get_user))
+                .route(
+                    "/api/v1/connect-code/:connect_code",
+                    get(get_user_by_connect_code),
+                )
+                .route("/api/v1/user/latest-version", put(update_latest_version))
+                .route("/api/v1/matches", get(matches::list_matches))
+                .route("/api/v1/version", get(get_version))
+                .route("/api/v1/stages", get(list_stages))
+                .route("/api/v1/characters", get(list_characters))
+                .route("/readyz", get(get_readiness))
+                .route("/api/v1/stats", get(stats::get_stats_json))
+                .route("/api/v1/stats/queues", get(stats::get_queue_stats_json))
+                .route(
+                    "/api/v1/stats/queue-status",
+                    get(stats::get_queue_status_json),
+                )
+                .route("/api/v1/match-results", post(matches::report))
+                .route("/graphql", post(slippi::get_rank))
+                .route("/logo", get(get_logo))
+                .route("/feed.xml", get(feed::get_feed))
+                .route("/overlay/:token", get(overlay::show_overlay))
+                .route("/stats", get(stats::get_stats))
+                .route("/robots.txt", get(sitemap::get_robots))
+                .route("/sitemap.xml", get(sitemap::get_sitemap))
+                .route("/static/*file", static_handler.into_service())
+                .route(
+                    "/widgets/leaderboard/:token",
+                    get(widgets::show_leaderboard),
+                )
+                .route(
+                    "/widgets/queue-status/:token",
+                    get(widgets::show_queue_status),
+                )
+                .fallback(get(pages::show)),
+            &config,
+        );
+        let test_app = add_api_key_layer(test_app, pool.clone());
+        let test_app = add_security_headers(test_app, &config)
+            .layer(axum_sqlx_tx::Layer::new(pool.clone()))
+            .layer(Extension(ReadPool(pool)))
+            .layer(Extension(openmelee::TEMPLATES.clone()))
+            .layer(Extension(cookie::Key::generate()))
+            .layer(Extension(config));
 
         tokio::spawn(async move {
             axum::Server::from_tcp(listener)
                 .unwrap()
-                .serve(test_app.into_make_service())
+                .serve(test_app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .unwrap();
         });
@@ -416,6 +1332,50 @@ mod test {
         (addr, reqwest::Client::new())
     }
 
+    // Serves the full router, with the session cookies of tests encrypted by
+    // the returned key
+    async fn start_app(
+        pool: Pool<Sqlite>,
+        config: Config,
+    ) -> (String, reqwest::Client, cookie::Key) {
+        let mut rng = rand::thread_rng();
+        let config = Config {
+            cookie_secret_path: Some(
+                std::env::temp_dir()
+                    .join(format!("openmelee-cookie-{}.key", rng.gen::<u64>()))
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            ..config
+        };
+        let key = get_cookie_key(config.clone());
+
+        let port: u16 = rng.gen_range(config.webserver_port..10000);
+        let addr = format!("{}:{}", config.webserver_address, port);
+        let listener = TcpListener::bind(addr.parse::<SocketAddr>().unwrap()).unwrap();
+        // Reads the key written above
+        let app = app(config.clone(), pool.clone(), ReadPool(pool)).await;
+        std::fs::remove_file(config.cookie_secret_path.unwrap()).unwrap();
+
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        });
+
+        (addr, reqwest::Client::new(), key)
+    }
+
+    fn session_cookie(key: &cookie::Key, token: &str) -> String {
+        let mut jar = cookie::CookieJar::new();
+        jar.private_mut(key)
+            .add(Cookie::new(JWT_COOKIE_NAME, token.to_string()));
+
+        jar.get(JWT_COOKIE_NAME).unwrap().to_string()
+    }
+
     fn extract_errors<'a>(res: &'a serde_json::Value, field: &str) -> Vec<&'a str> {
         let error_codes = res
             .get("errors")
@@ -453,76 +1413,2754 @@ mod test {
             .expect("Could not convert register_response to JSON");
 
         assert_eq!(created_user.display_name, "test".to_string());
-        assert_eq!(created_user.connect_code, "TEST#001".to_string());
+        assert_eq!(created_user.connect_code, "TEST#001");
 
         assert!(User::get(&pool, created_user.uid).await.is_ok());
     }
 
     #[sqlx::test]
-    async fn cannot_register_with_errors(pool: Pool<Sqlite>) {
-        let (addr, client) = start_test_server(pool).await;
+    async fn get_user_honors_if_none_match(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
 
-        let register_response = client
-            .post(format!("http://{}/register", addr))
-            .form(&PublicUserForm {
-                username: "test".to_string(),
-                display_name: "".to_string(),
-                connect_code: "TEST#".to_string(),
-            })
+        let created_user = PublicUser::from(&UserBuilder::new().insert(&pool).await);
+
+        let user_response = client
+            .get(format!("http://{}/user/{}", addr, created_user.uid))
             .send()
-            .await;
+            .await
+            .unwrap();
 
-        let res: serde_json::Value =
-            serde_json::from_str(&register_response.unwrap().text().await.unwrap()).unwrap();
+        assert_eq!(user_response.status(), reqwest::StatusCode::OK);
+
+        let etag = user_response
+            .headers()
+            .get(header::ETAG)
+            .expect("Response is missing an ETag")
+            .clone();
 
         assert_eq!(
-            extract_errors(&res.clone(), "connect_code"),
-            vec!["discriminant_length"]
+            user_response.json::<PublicUser>().await.unwrap(),
+            created_user
         );
 
-        assert_eq!(extract_errors(&res.clone(), "display_name"), vec!["length"]);
+        let not_modified_response = client
+            .get(format!("http://{}/user/{}", addr, created_user.uid))
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            not_modified_response.status(),
+            reqwest::StatusCode::NOT_MODIFIED
+        );
+
+        let modified_response = client
+            .get(format!("http://{}/user/{}", addr, created_user.uid))
+            .header(header::IF_NONE_MATCH, "W/\"0\"")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(modified_response.status(), reqwest::StatusCode::OK);
+
+        sqlx::query("update users set display_name = 'Renamed' where uid = $1")
+            .bind(&created_user.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let updated_response = client
+            .get(format!("http://{}/user/{}", addr, created_user.uid))
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(updated_response.status(), reqwest::StatusCode::OK);
+        assert_ne!(updated_response.headers()[header::ETAG], etag);
+        assert_eq!(
+            updated_response
+                .json::<PublicUser>()
+                .await
+                .unwrap()
+                .display_name,
+            "Renamed"
+        );
     }
 
     #[sqlx::test]
-    async fn cannot_register_with_existing_connect_code_or_username(pool: Pool<Sqlite>) {
-        let (addr, client) = start_test_server(pool).await;
-
-        client
-            .post(format!("http://{}/register", addr))
-            .form(&PublicUserForm {
-                username: "test".to_string(),
-                display_name: "test".to_string(),
-                connect_code: "TEST#001".to_string(),
-            })
+    async fn queue_status_honors_if_none_match(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+        let now = Utc::now().timestamp();
+        let save_snapshot = |taken_at: i64, waiting: i64| {
+            QueueSnapshot::save(
+                &pool,
+                DEFAULT_TENANT.to_string(),
+                OnlinePlayMode::Unranked,
+                taken_at,
+                SNAPSHOT_INTERVAL_SECONDS,
+                waiting,
+            )
+        };
+        let get_queue_status = |etag: Option<HeaderValue>| {
+            let request = client.get(format!("http://{}/api/v1/stats/queue-status", addr));
+            match etag {
+                Some(etag) => request.header(header::IF_NONE_MATCH, etag),
+                None => request,
+            }
             .send()
+        };
+
+        save_snapshot(now - 1, 3).await.unwrap();
+        let response = get_queue_status(None).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let snapshots = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(snapshots["data"][0]["waiting"], 3);
+
+        let response = get_queue_status(Some(etag.clone())).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_MODIFIED);
+
+        save_snapshot(now, 5).await.unwrap();
+        let response = get_queue_status(Some(etag)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let snapshots = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(snapshots["data"][0]["waiting"], 5);
+    }
+
+    #[sqlx::test]
+    async fn list_endpoints_are_paginated(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let mut users = vec![];
+        for connect_code in ["TEST#001", "TEST#002", "TEST#003"] {
+            users.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+
+        let get_users = |query: String| {
+            client
+                .get(format!("http://{}/api/v1/users?{}", addr, query))
+                .send()
+        };
+
+        let first_page = get_users("limit=2".to_string())
             .await
-            .expect("First registration attempt failed");
+            .unwrap()
+            .json::<Envelope<Vec<PublicUser>>>()
+            .await
+            .unwrap();
+        let meta = first_page.meta.unwrap();
+        assert_eq!(first_page.data.unwrap().len(), 2);
+        assert_eq!(meta.total, 3);
 
-        let register_response = client
-            .post(format!("http://{}/register", addr))
-            .form(&PublicUserForm {
-                username: "test".to_string(),
-                display_name: "test".to_string(),
-                connect_code: "TEST#001".to_string(),
-            })
+        let second_page = get_users(format!("limit=2&cursor={}", meta.next_cursor.unwrap()))
+            .await
+            .unwrap()
+            .json::<Envelope<Vec<PublicUser>>>()
+            .await
+            .unwrap();
+        assert_eq!(second_page.data.unwrap()[0].connect_code, "TEST#003");
+        assert_eq!(second_page.meta.unwrap().next_cursor, None);
+
+        let invalid_cursor = get_users("cursor=zz".to_string()).await.unwrap();
+        assert_eq!(invalid_cursor.status(), reqwest::StatusCode::BAD_REQUEST);
+        let errors = invalid_cursor
+            .json::<Envelope<Vec<PublicUser>>>()
+            .await
+            .unwrap()
+            .errors;
+        assert_eq!(errors[0].message, "Invalid cursor");
+
+        for (match_id, players) in [
+            ("mode.unranked-1", &users[0..2]),
+            ("mode.unranked-2", &users[1..3]),
+        ] {
+            MatchBuilder::new(match_id)
+                .players(players)
+                .insert(&pool)
+                .await;
+        }
+
+        let matches = client
+            .get(format!(
+                "http://{}/api/v1/matches?uid={}",
+                addr, users[0].uid
+            ))
             .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(matches["data"][0]["matchId"], "mode.unranked-1");
+        assert_eq!(
+            matches["data"][0]["createdAtUtc"],
+            openmelee::api::format_timestamp(matches["data"][0]["createdAt"].as_i64().unwrap())
+        );
+        assert_eq!(matches["meta"]["total"], 1);
+        assert_eq!(matches["errors"], json!([]));
+    }
+
+    #[cfg(feature = "client")]
+    #[sqlx::test]
+    async fn client_reads_the_json_api(pool: Pool<Sqlite>) {
+        use openmelee::client::{Client, ClientError};
+
+        let (addr, _) = start_test_server(pool.clone()).await;
+        let user = UserBuilder::new().insert(&pool).await;
+        let token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Bot".to_string(),
+            &[ApiScope::ReadProfile],
+        )
+        .await
+        .unwrap();
+        let client = Client::new(url::Url::parse(&format!("http://{}", addr)).unwrap());
+
+        assert_eq!(
+            client.get_version().await.unwrap().version,
+            openmelee::build_info::VERSION
+        );
+        assert_eq!(
+            client.get_user(&user.uid).await.unwrap(),
+            Some(PublicUser::from(&user))
+        );
+        assert_eq!(
+            client.get_user(&"unknown".parse().unwrap()).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            client
+                .list_users(&PageQuery::default())
+                .await
+                .unwrap()
+                .data
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(matches!(
+            client.get_own_user().await,
+            Err(ClientError::Api { status: 401, .. })
+        ));
+
+        let client = client.with_token(&token);
+        assert_eq!(client.get_own_user().await.unwrap().uid, user.uid);
+        match client.list_own_matches(&PageQuery::default()).await {
+            Err(ClientError::Api { status, message }) => {
+                assert_eq!(status, 403);
+                assert_eq!(message, "API token lacks the read-history scope");
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[sqlx::test]
+    async fn api_tokens_only_allow_their_scopes(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let user = UserBuilder::new().insert(&pool).await;
+        let opponent = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        MatchBuilder::new("mode.unranked-1")
+            .players(&[user.clone(), opponent])
+            .insert(&pool)
             .await;
+        let token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Stream overlay".to_string(),
+            &[ApiScope::ReadProfile],
+        )
+        .await
+        .unwrap();
 
-        let res: serde_json::Value =
-            serde_json::from_str(&register_response.unwrap().text().await.unwrap()).unwrap();
+        let get = |path: &str, token: &str| {
+            client
+                .get(format!("http://{}{}", addr, path))
+                .bearer_auth(token)
+                .send()
+        };
+
+        let me = get("/api/v1/me", &token).await.unwrap();
+        assert_eq!(me.status(), reqwest::StatusCode::OK);
+        assert_eq!(me.json::<PublicUser>().await.unwrap().uid, user.uid);
 
+        let matches = get("/api/v1/me/matches", &token).await.unwrap();
+        assert_eq!(matches.status(), reqwest::StatusCode::FORBIDDEN);
         assert_eq!(
-            extract_errors(&res.clone(), "connect_code"),
-            vec!["duplicated"]
+            matches
+                .json::<Envelope<Vec<serde_json::Value>>>()
+                .await
+                .unwrap()
+                .errors[0]
+                .message,
+            "API token lacks the read-history scope"
         );
 
-        assert_eq!(extract_errors(&res.clone(), "username"), vec!["duplicated"]);
+        let unknown = get("/api/v1/me", "omt_guess").await.unwrap();
+        assert_eq!(unknown.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let anonymous = client
+            .get(format!("http://{}/api/v1/me", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let history_token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Stats site".to_string(),
+            &[ApiScope::ReadHistory],
+        )
+        .await
+        .unwrap();
+        let matches = get("/api/v1/me/matches", &history_token)
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(matches["data"][0]["matchId"], "mode.unranked-1");
+        assert_eq!(matches["meta"]["total"], 1);
     }
 
-    #[test]
-    fn can_render_index() {
+    #[sqlx::test]
+    async fn overlay_shows_record_and_last_result(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let user = UserBuilder::new().insert(&pool).await;
+        let opponent = UserBuilder::new()
+            .connect_code("TEST#002")
+            .display_name("Opponent")
+            .insert(&pool)
+            .await;
+        for (match_id, winner) in [("mode.unranked-1", &user), ("mode.unranked-2", &opponent)] {
+            MatchBuilder::new(match_id)
+                .players(&[user.clone(), opponent.clone()])
+                .confirmed(winner)
+                .insert(&pool)
+                .await;
+        }
+        let token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "OBS".to_string(),
+            &[ApiScope::Overlay],
+        )
+        .await
+        .unwrap();
+
+        let res = client
+            .get(format!("http://{}/api/v1/overlay/{}", addr, token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[header::REFERRER_POLICY], "no-referrer");
+        let overlay = res.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(
+            (&overlay["wins"], &overlay["losses"]),
+            (&json!(1), &json!(1))
+        );
+        assert_eq!(
+            overlay["lastResult"]["opponentDisplayName"],
+            json!("Opponent")
+        );
+        assert_eq!(overlay["lastResult"]["won"], json!(false));
+
+        let page = client
+            .get(format!("http://{}/overlay/{}", addr, token))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(page.contains("1W 1L"));
+
+        // Tokens that don't allow showing an overlay can't be used for one
+        let profile_token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Stats site".to_string(),
+            &[ApiScope::ReadProfile],
+        )
+        .await
+        .unwrap();
+        let res = client
+            .get(format!("http://{}/overlay/{}", addr, profile_token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn widgets_can_be_embedded_on_other_sites(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let user = UserBuilder::new().insert(&pool).await;
+        let opponent = UserBuilder::new()
+            .connect_code("TEST#002")
+            .display_name("Opponent")
+            .insert(&pool)
+            .await;
+        for match_id in ["mode.unranked-1", "mode.unranked-2"] {
+            MatchBuilder::new(match_id)
+                .players(&[user.clone(), opponent.clone()])
+                .confirmed(&opponent)
+                .insert(&pool)
+                .await;
+        }
+        QueueSnapshot::save(
+            &pool,
+            DEFAULT_TENANT.to_string(),
+            OnlinePlayMode::Unranked,
+            Utc::now().timestamp(),
+            SNAPSHOT_INTERVAL_SECONDS,
+            3,
+        )
+        .await
+        .unwrap();
+        let token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "Community wiki".to_string(),
+            &[ApiScope::Widget],
+        )
+        .await
+        .unwrap();
+
+        let res = client
+            .get(format!("http://{}/widgets/leaderboard/{}", addr, token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(!res.headers().contains_key(header::X_FRAME_OPTIONS));
+        assert!(res.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .ends_with("; frame-ancestors *"));
+        assert_eq!(res.headers()[header::REFERRER_POLICY], "no-referrer");
+        let page = res.text().await.unwrap();
+        assert!(page.contains("Opponent <samp>TEST#002</samp> &middot; 2W"));
+
+        let page = client
+            .get(format!("http://{}/widgets/queue-status/{}", addr, token))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(page.contains("unranked: 3 waiting"));
+
+        // Tokens that don't allow widgets can't be used for them
+        let overlay_token = ApiToken::create(
+            &pool,
+            user.uid.clone(),
+            "OBS".to_string(),
+            &[ApiScope::Overlay],
+        )
+        .await
+        .unwrap();
+        let res = client
+            .get(format!(
+                "http://{}/widgets/leaderboard/{}",
+                addr, overlay_token
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[header::X_FRAME_OPTIONS], "DENY");
+    }
+
+    #[sqlx::test]
+    async fn can_get_stats(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let user = UserBuilder::new().insert(&pool).await;
+        MatchBuilder::new("mode.unranked-1")
+            .player(&user)
+            .insert(&pool)
+            .await;
+        DailyMatchCount::refresh(&pool, Utc::now().date_naive())
+            .await
+            .unwrap();
+
+        let stats = client
+            .get(format!("http://{}/api/v1/stats", addr))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(stats["data"][0]["mode"], "unranked");
+        assert_eq!(stats["data"][0]["matches"], 1);
+
+        let page = client
+            .get(format!("http://{}/stats", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(page.contains("1 match, at most 1 in a day"));
+
+        let queue_stats = client
+            .get(format!("http://{}/api/v1/stats/queues", addr))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(queue_stats["data"], serde_json::json!([]));
+    }
+
+    #[sqlx::test]
+    async fn retried_match_report_is_counted_once(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let mut players = vec![];
+        for connect_code in ["TEST#001", "TEST#002"] {
+            players.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+
+        MatchBuilder::new("mode.unranked-1")
+            .players(&players)
+            .insert(&pool)
+            .await;
+
+        let report = json!({
+            "uid": players[0].uid,
+            "playKey": players[0].play_key,
+            "matchId": "mode.unranked-1",
+            "winnerUid": players[1].uid,
+        });
+        let send_report = |idempotency_key: Option<&str>| {
+            let mut request = client
+                .post(format!("http://{}/api/v1/match-results", addr))
+                .json(&report);
+            if let Some(idempotency_key) = idempotency_key {
+                request = request.header("Idempotency-Key", idempotency_key);
+            }
+            request.send()
+        };
+
+        let first_response = send_report(Some("retry-me")).await.unwrap();
+        assert_eq!(first_response.status(), reqwest::StatusCode::CREATED);
+        let first_result = first_response.json::<serde_json::Value>().await.unwrap();
+
+        let retried_response = send_report(Some("retry-me")).await.unwrap();
+        assert_eq!(retried_response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            retried_response.json::<serde_json::Value>().await.unwrap(),
+            first_result
+        );
+
+        let duplicate_response = send_report(None).await.unwrap();
+        assert_eq!(duplicate_response.status(), reqwest::StatusCode::CONFLICT);
+
+        let opponent_response = client
+            .post(format!("http://{}/api/v1/match-results", addr))
+            .json(&json!({
+                "uid": players[1].uid,
+                "playKey": players[1].play_key,
+                "matchId": "mode.unranked-1",
+                "winnerUid": players[1].uid,
+                "stage": 31,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(opponent_response.status(), reqwest::StatusCode::CREATED);
+        assert_eq!(
+            openmelee::matches::Match::get(&pool, "mode.unranked-1".parse().unwrap())
+                .await
+                .unwrap()
+                .stage,
+            Some(31)
+        );
+
+        assert_eq!(
+            openmelee::matches::MatchResult::count_wins(&pool, players[1].uid.clone())
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[sqlx::test]
+    async fn teams_match_reports_credit_both_teammates(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let mut players = vec![];
+        for connect_code in ["TEST#001", "TEST#002", "TEST#003", "TEST#004"] {
+            players.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+
+        // Ports 1 and 3 against 2 and 4
+        MatchBuilder::new("mode.teams-1")
+            .mode(OnlinePlayMode::Teams)
+            .players(&players)
+            .insert(&pool)
+            .await;
+
+        // Each player of the winning team names themselves as the winner
+        for (reporter, winner) in [(0, 0), (1, 2), (2, 2), (3, 0)] {
+            let response = client
+                .post(format!("http://{}/api/v1/match-results", addr))
+                .json(&json!({
+                    "uid": players[reporter].uid,
+                    "playKey": players[reporter].play_key,
+                    "matchId": "mode.teams-1",
+                    "winnerUid": players[winner].uid,
+                }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        }
+
+        assert_eq!(
+            openmelee::matches::Match::get(&pool, "mode.teams-1".parse().unwrap())
+                .await
+                .unwrap()
+                .status,
+            "confirmed"
+        );
+        for (player, wins, losses) in [(0, 1, 0), (1, 0, 1), (2, 1, 0), (3, 0, 1)] {
+            let uid = players[player].uid.clone();
+            assert_eq!(
+                openmelee::matches::MatchResult::count_wins(&pool, uid.clone())
+                    .await
+                    .unwrap(),
+                wins
+            );
+            assert_eq!(
+                openmelee::matches::MatchResult::count_losses(&pool, uid)
+                    .await
+                    .unwrap(),
+                losses
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn confirmed_ranked_matches_update_ratings(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let mut players = vec![];
+        for connect_code in ["TEST#001", "TEST#002"] {
+            players.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+        for (match_id, mode) in [
+            ("mode.ranked-1", OnlinePlayMode::Ranked),
+            ("mode.unranked-1", OnlinePlayMode::Unranked),
+        ] {
+            MatchBuilder::new(match_id)
+                .mode(mode)
+                .players(&players)
+                .insert(&pool)
+                .await;
+            for player in &players {
+                let response = client
+                    .post(format!("http://{}/api/v1/match-results", addr))
+                    .json(&json!({
+                        "uid": player.uid,
+                        "playKey": player.play_key,
+                        "matchId": match_id,
+                        "winnerUid": players[0].uid,
+                    }))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+            }
+        }
+
+        // Only the ranked match is rated
+        let rating = Rating::get(&pool, players[1].uid.clone()).await.unwrap();
+        assert_eq!((rating.rating, rating.games), (1468.0, 1));
+
+        let rank = client
+            .post(format!("http://{}/graphql", addr))
+            .json(&json!({ "variables": { "cc": "TEST#001" } }))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        let profile = &rank["data"]["getConnectCode"]["user"]["rankedNetplayProfile"];
+        assert_eq!(
+            (&profile["ratingOrdinal"], &profile["ratingUpdateCount"]),
+            (&json!(1532.0), &json!(1))
+        );
+    }
+
+    #[sqlx::test]
+    async fn disputes_show_end_of_game_summaries(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new()
+            .connect_code("ADMN#001")
+            .admin()
+            .insert(&pool)
+            .await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+
+        let mut players = vec![];
+        for connect_code in ["TEST#001", "TEST#002"] {
+            players.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+        MatchBuilder::new("mode.ranked-1")
+            .mode(OnlinePlayMode::Ranked)
+            .players(&players)
+            .insert(&pool)
+            .await;
+
+        let summary = |lras: bool| {
+            json!([
+                { "uid": players[0].uid, "stocks": 2, "percent": 87 },
+                { "uid": players[1].uid, "stocks": 3, "percent": 12, "lras": lras },
+            ])
+        };
+        let report = |player: &User, summary: serde_json::Value| {
+            client
+                .post(format!("http://{}/api/v1/match-results", addr))
+                .json(&json!({
+                    "uid": player.uid,
+                    "playKey": player.play_key,
+                    "matchId": "mode.ranked-1",
+                    "winnerUid": player.uid,
+                    "summary": summary,
+                }))
+                .send()
+        };
+
+        let outsider = json!([{ "uid": admin.uid, "stocks": 4, "percent": 0 }]);
+        let response = report(&players[0], outsider).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+        for (player, lras) in [(&players[0], true), (&players[1], false)] {
+            let response = report(player, summary(lras)).await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        }
+
+        let admin_page = client
+            .get(format!("http://{}/admin", addr))
+            .header(
+                header::COOKIE,
+                session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600)),
+            )
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(admin_page.contains("3 stocks, 12%, quit with LRAS"));
+        assert_eq!(admin_page.matches("quit with LRAS").count(), 1);
+        assert_eq!(admin_page.matches("2 stocks, 87%").count(), 2);
+    }
+
+    #[sqlx::test]
+    async fn clients_can_report_their_version(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let user = UserBuilder::new().insert(&pool).await;
+
+        let update_version = |play_key: &str, version: &str| {
+            client
+                .put(format!("http://{}/api/v1/user/latest-version", addr))
+                .json(&json!({
+                    "uid": user.uid,
+                    "playKey": play_key,
+                    "version": version,
+                }))
+                .send()
+        };
+
+        let response = update_version("wrong", "3.0.2").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = update_version(&user.play_key, "latest").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = update_version(&user.play_key, "3.0.2").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let public_user = client
+            .get(format!("http://{}/api/v1/user/{}", addr, user.uid))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(public_user["latestVersion"], "3.0.2");
+    }
+
+    #[sqlx::test]
+    async fn tenants_are_selected_by_hostname(pool: Pool<Sqlite>) {
+        let config = Config {
+            tenants: vec![Tenant {
+                id: "pal".to_string(),
+                hostname: "pal.example.org".to_string(),
+                name: "PAL Melee".to_string(),
+                stages: None,
+                schedules: None,
+                message: None,
+            }],
+            ..Config::default()
+        };
+        let (addr, client) = start_test_server_with_config(pool, config).await;
+
+        let register = |host: &'static str| {
+            client
+                .post(format!("http://{}/register", addr))
+                .header(header::HOST, host)
+                .form(&PublicUserForm {
+                    username: "test".to_string(),
+                    display_name: "test".to_string(),
+                    connect_code: "TEST#001".to_string(),
+                })
+                .send()
+        };
+
+        let default_user = register("localhost").await.unwrap();
+        assert_eq!(default_user.status(), reqwest::StatusCode::OK);
+        let pal_user = register("pal.example.org").await.unwrap();
+        assert_eq!(pal_user.status(), reqwest::StatusCode::OK);
+        let duplicate_user = register("pal.example.org:5000").await.unwrap();
+        assert_eq!(duplicate_user.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let pal_user = pal_user.json::<PublicUser>().await.unwrap();
+        let rank = client
+            .post(format!("http://{}/graphql", addr))
+            .header(header::HOST, "pal.example.org")
+            .json(&json!({ "variables": { "cc": "TEST#001" } }))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(
+            rank["data"]["getConnectCode"]["user"]["rankedNetplayProfile"]["id"],
+            pal_user.uid.as_str()
+        );
+
+        let index = client
+            .get(format!("http://{}/", addr))
+            .header(header::HOST, "pal.example.org")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(index.contains("PAL Melee"));
+    }
+
+    #[sqlx::test]
+    async fn tenants_cannot_reach_each_others_users_and_disputes(pool: Pool<Sqlite>) {
+        let config = Config {
+            tenants: vec![Tenant {
+                id: "pal".to_string(),
+                hostname: "pal.example.org".to_string(),
+                name: "PAL Melee".to_string(),
+                stages: None,
+                schedules: None,
+                message: None,
+            }],
+            ..Config::default()
+        };
+        let pal_admin = UserBuilder::new()
+            .tenant("pal")
+            .connect_code("ADMN#001")
+            .admin()
+            .insert(&pool)
+            .await;
+        let mut players = vec![];
+        for connect_code in ["TEST#001", "TEST#002"] {
+            players.push(
+                UserBuilder::new()
+                    .connect_code(connect_code)
+                    .insert(&pool)
+                    .await,
+            );
+        }
+        MatchBuilder::new("mode.ranked-1")
+            .mode(OnlinePlayMode::Ranked)
+            .players(&players)
+            .status(openmelee::matches::MatchStatus::Disputed, None)
+            .insert(&pool)
+            .await;
+        let (addr, client, key) = start_app(pool.clone(), config).await;
+        let cookie = session_cookie(
+            &key,
+            &tenant_session_token(&pal_admin, "pal", Utc::now().timestamp() + 3600),
+        );
+
+        for path in ["user", "api/v1/user"] {
+            let user = client
+                .get(format!("http://{}/{}/{}", addr, path, players[0].uid))
+                .header(header::HOST, "pal.example.org")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(user.status(), reqwest::StatusCode::NOT_FOUND);
+        }
+
+        let admin_page = client
+            .get(format!("http://{}/admin", addr))
+            .header(header::HOST, "pal.example.org")
+            .header(header::COOKIE, cookie.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(admin_page.status(), reqwest::StatusCode::OK);
+        let admin_page = admin_page.text().await.unwrap();
+        assert!(admin_page.contains("ADMN#001"));
+        assert!(admin_page.contains("No disputed matches."));
+        assert!(!admin_page.contains("TEST#001"));
+
+        client
+            .post(format!(
+                "http://{}/admin/matches/mode.ranked-1/resolve",
+                addr
+            ))
+            .header(header::HOST, "pal.example.org")
+            .header(header::COOKIE, cookie)
+            .form(&[("winner_uid", players[0].uid.as_str())])
+            .send()
+            .await
+            .unwrap();
+        let disputed_match =
+            openmelee::matches::Match::get(&pool, "mode.ranked-1".parse().unwrap())
+                .await
+                .unwrap();
+        assert_eq!(disputed_match.status, "disputed");
+    }
+
+    #[sqlx::test]
+    async fn site_is_served_under_the_base_path(pool: Pool<Sqlite>) {
+        let config = Config {
+            webserver_base_path: "/slippi".to_string(),
+            ..Config::default()
+        };
+        let (addr, _, _) = start_app(pool, config).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        let root = client
+            .get(format!("http://{}/slippi", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(root.status(), reqwest::StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(root.headers()[header::LOCATION], "/slippi/");
+
+        let index = client
+            .get(format!("http://{}/slippi/", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(index.status(), reqwest::StatusCode::OK);
+        assert!(index
+            .text()
+            .await
+            .unwrap()
+            .contains("href=\"/slippi/login\""));
+
+        let logout = client
+            .get(format!("http://{}/slippi/logout", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(logout.headers()[header::LOCATION], "/slippi/login");
+        assert!(logout
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .all(|cookie| cookie.to_str().unwrap().contains("Path=/slippi")));
+
+        let outside = client
+            .get(format!("http://{}/login", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(outside.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn security_headers_are_set(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let res = client
+            .get(format!("http://{}/register", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(res.headers()[header::REFERRER_POLICY], "same-origin");
+        assert!(res.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .contains("frame-ancestors 'none'"));
+        // Not served over HTTPS
+        assert!(!res
+            .headers()
+            .contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        let config = Config {
+            public_url: Some("https://example.org".parse().unwrap()),
+            webserver_hsts_max_age_seconds: 3600,
+            webserver_frame_options: String::new(),
+            ..Config::default()
+        };
+        let (addr, client) = start_test_server_with_config(pool, config).await;
+
+        let res = client
+            .get(format!("http://{}/register", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            res.headers()[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=3600"
+        );
+        assert!(!res.headers().contains_key(header::X_FRAME_OPTIONS));
+    }
+
+    const SESSION_ROUTES: [(&str, &str); 16] = [
+        ("GET", "/profile"),
+        ("GET", "/profile/connection"),
+        ("POST", "/profile/launcher-link"),
+        ("POST", "/profile/preferences"),
+        ("POST", "/profile/timezone"),
+        ("POST", "/profile/api-tokens"),
+        ("POST", "/profile/api-tokens/1/revoke"),
+        ("POST", "/profile/connect-code-claim"),
+        ("PUT", "/api/v1/preferences"),
+        ("GET", "/admin"),
+        ("GET", "/admin/users/someone"),
+        ("GET", "/admin/pages"),
+        ("POST", "/admin/logo"),
+        ("POST", "/admin/users/import"),
+        ("POST", "/admin/announcements"),
+        ("POST", "/admin/connect-code-claims/1/approve"),
+    ];
+
+    const API_ROUTES: [&str; 4] = [
+        "/api/v1/me",
+        "/api/v1/me/matches",
+        "/api/v1/preferences",
+        "/api/v1/connection-health",
+    ];
+
+    const ADMIN_ROUTES: [(&str, &str); 27] = [
+        ("GET", "/admin"),
+        ("GET", "/admin/capacity"),
+        ("GET", "/admin/schema"),
+        ("GET", "/admin/users/export"),
+        ("GET", "/admin/users/someone"),
+        ("POST", "/admin/users/someone/notes"),
+        ("POST", "/admin/users/someone/impersonate"),
+        ("POST", "/admin/users/someone/shadow-queue"),
+        ("POST", "/admin/users/someone/shadow-queue/remove"),
+        ("GET", "/admin/impersonate/stop"),
+        ("POST", "/admin/matches/mode.unranked-1/resolve"),
+        ("GET", "/admin/pages"),
+        ("POST", "/admin/pages"),
+        ("GET", "/admin/pages/about"),
+        ("POST", "/admin/pages/about/delete"),
+        ("POST", "/admin/connect-code-claims/1/approve"),
+        ("POST", "/admin/connect-code-claims/1/reject"),
+        ("POST", "/admin/logo/remove"),
+        ("POST", "/admin/announcements"),
+        ("POST", "/admin/announcements/1/delete"),
+        ("POST", "/admin/stage-rotations"),
+        ("POST", "/admin/stage-rotations/1/delete"),
+        ("POST", "/admin/api-keys"),
+        ("POST", "/admin/api-keys/1/revoke"),
+        ("POST", "/admin/queues/ranked/pause"),
+        ("POST", "/admin/queues/ranked/resume"),
+        ("POST", "/admin/broadcasts"),
+    ];
+
+    // Sent as multipart/form-data, which their handlers only read once the
+    // sender is known to be an admin
+    const ADMIN_UPLOAD_ROUTES: [&str; 2] = ["/admin/users/import", "/admin/logo"];
+
+    #[cfg(feature = "sql-console")]
+    const CONSOLE_ROUTES: [(&str, &str); 2] =
+        [("GET", "/admin/console"), ("POST", "/admin/console")];
+    #[cfg(not(feature = "sql-console"))]
+    const CONSOLE_ROUTES: [(&str, &str); 0] = [];
+
+    // Holds every field of the forms behind these routes, so requests are
+    // only ever refused because of who sends them
+    const FORM_BODY: [(&str, &str); 16] = [
+        ("content", "Content"),
+        ("title", "Title"),
+        ("slug", "about"),
+        ("winner_uid", "someone"),
+        ("name", "Token"),
+        ("user_json", "{}"),
+        ("max_ping_ms", "100"),
+        ("timezone", "UTC"),
+        ("mode", "unranked"),
+        ("stages", "battlefield"),
+        ("starts_on", "2022-11-01"),
+        ("ends_on", "2022-11-30"),
+        ("requests_per_minute", "60"),
+        ("reason", "Incident"),
+        ("message", "Restarting soon"),
+        ("query", "select 1"),
+    ];
+
+    #[sqlx::test]
+    async fn authenticated_routes_reject_invalid_sessions(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let (addr, client, key) = start_app(pool, Config::default()).await;
+
+        let now = Utc::now().timestamp();
+        let forged_token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &Claims {
+                uid: user.uid.clone(),
+                exp: (now + 3600) as usize,
+                impersonator_uid: None,
+                tenant_id: DEFAULT_TENANT.to_string(),
+            },
+            &jsonwebtoken::EncodingKey::from_secret(b"not the server's secret"),
+        )
+        .unwrap();
+        let cookies = [
+            ("missing", None),
+            (
+                "expired",
+                Some(session_cookie(&key, &session_token(&user, now - 3600))),
+            ),
+            ("forged", Some(session_cookie(&key, &forged_token))),
+            // Valid token, but not encrypted by the server
+            (
+                "unencrypted",
+                Some(format!(
+                    "{}={}",
+                    JWT_COOKIE_NAME,
+                    session_token(&user, now + 3600)
+                )),
+            ),
+        ];
+
+        for (kind, cookie) in &cookies {
+            for (method, path) in SESSION_ROUTES {
+                let mut req = client
+                    .request(method.parse().unwrap(), format!("http://{}{}", addr, path))
+                    .form(&FORM_BODY);
+                if let Some(cookie) = cookie {
+                    req = req.header(header::COOKIE, cookie);
+                }
+                let res = req.send().await.unwrap();
+
+                assert_eq!(
+                    res.status(),
+                    StatusCode::BAD_REQUEST,
+                    "{} session on {} {}",
+                    kind,
+                    method,
+                    path
+                );
+                assert!(res.text().await.unwrap().contains("Unauthorized"));
+            }
+
+            for path in API_ROUTES {
+                let mut req = client.get(format!("http://{}{}", addr, path));
+                if let Some(cookie) = cookie {
+                    req = req.header(header::COOKIE, cookie);
+                }
+                let res = req.send().await.unwrap();
+
+                assert_eq!(
+                    res.status(),
+                    StatusCode::UNAUTHORIZED,
+                    "{} session on GET {}",
+                    kind,
+                    path
+                );
+            }
+        }
+
+        // The same session is accepted once valid
+        let res = client
+            .get(format!("http://{}/profile", addr))
+            .header(
+                header::COOKIE,
+                session_cookie(&key, &session_token(&user, now + 3600)),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn admin_routes_reject_non_admins(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let admin = UserBuilder::new()
+            .connect_code("ADMN#001")
+            .admin()
+            .insert(&pool)
+            .await;
+        let (addr, client, key) = start_app(pool, Config::default()).await;
+        let expires_at = Utc::now().timestamp() + 3600;
+
+        for (method, path) in ADMIN_ROUTES.into_iter().chain(CONSOLE_ROUTES) {
+            let res = client
+                .request(method.parse().unwrap(), format!("http://{}{}", addr, path))
+                .header(
+                    header::COOKIE,
+                    session_cookie(&key, &session_token(&user, expires_at)),
+                )
+                .form(&FORM_BODY)
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(
+                res.status(),
+                StatusCode::FORBIDDEN,
+                "non-admin on {} {}",
+                method,
+                path
+            );
+        }
+
+        for path in ADMIN_UPLOAD_ROUTES {
+            let res = client
+                .post(format!("http://{}{}", addr, path))
+                .header(
+                    header::COOKIE,
+                    session_cookie(&key, &session_token(&user, expires_at)),
+                )
+                .header(
+                    header::CONTENT_TYPE,
+                    "multipart/form-data; boundary=boundary",
+                )
+                .body("--boundary--\r\n")
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(
+                res.status(),
+                StatusCode::FORBIDDEN,
+                "non-admin on POST {}",
+                path
+            );
+        }
+
+        let res = client
+            .get(format!("http://{}/admin", addr))
+            .header(
+                header::COOKIE,
+                session_cookie(&key, &session_token(&admin, expires_at)),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    // Every admin route the router declares has to be checked above, so new
+    // ones can't be left out
+    #[test]
+    fn admin_routes_cover_the_router() {
+        let source = include_str!("webserver.rs");
+        let source = &source[..source.find("#[cfg(test)]\nmod test").unwrap()];
+        let checked = ADMIN_ROUTES
+            .iter()
+            .map(|(_, path)| *path)
+            .chain(ADMIN_UPLOAD_ROUTES)
+            .chain(["/admin/console"])
+            .collect::<Vec<_>>();
+
+        let declared = source
+            .split(".route(")
+            .skip(1)
+            .filter_map(|route| route.trim_start().strip_prefix('"'))
+            .filter_map(|route| route.split('"').next())
+            .filter(|path| path.starts_with("/admin"))
+            .collect::<Vec<_>>();
+        assert!(declared.contains(&"/admin/console"));
+
+        for pattern in declared {
+            let segments = pattern.split('/').collect::<Vec<_>>();
+            assert!(
+                checked.iter().any(|path| {
+                    let path = path.split('/').collect::<Vec<_>>();
+                    path.len() == segments.len()
+                        && path
+                            .iter()
+                            .zip(&segments)
+                            .all(|(part, segment)| segment.starts_with(':') || part == segment)
+                }),
+                "{} isn't in ADMIN_ROUTES",
+                pattern
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn admins_schedule_stage_rotations(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new().admin().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+        let today = Utc::now().date_naive();
+        let last_day = (today + chrono::Duration::days(6))
+            .format("%Y-%m-%d")
+            .to_string();
+        let form = |stages: &str| {
+            vec![
+                ("name", "Season 2".to_string()),
+                ("mode", "unranked".to_string()),
+                ("stages", stages.to_string()),
+                ("starts_on", today.format("%Y-%m-%d").to_string()),
+                ("ends_on", last_day.clone()),
+            ]
+        };
+
+        let res = client
+            .post(format!("http://{}/admin/stage-rotations", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&form("battlefield, corneria"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.text().await.unwrap().contains("Must list stages"));
+
+        let res = client
+            .post(format!("http://{}/admin/stage-rotations", addr))
+            .header(header::COOKIE, cookie)
+            .form(&form("battlefield, dream_land"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let index = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(index.contains("Season 2 <small>unranked, until"));
+        assert!(index.contains(&last_day));
+        assert!(index.contains("> Battlefield, <img"));
+        assert!(index.contains("/static/stages/dream_land.svg\" alt=\"\"> Dream Land</p>"));
+
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 1)
+            .await
+            .unwrap();
+        assert_eq!(audit_log[0].action, "create_stage_rotation");
+    }
+
+    #[sqlx::test]
+    async fn admins_broadcast_to_matchmaking_peers(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new().admin().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+        let mut events = openmelee::events::subscribe();
+
+        let res = client
+            .post(format!("http://{}/admin/broadcasts", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&[("message", "")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.text().await.unwrap().contains("Must be at least 1"));
+
+        let res = client
+            .post(format!("http://{}/admin/broadcasts", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&[("message", "Restarting in 5 minutes")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.text().await.unwrap().contains("Not delivered yet"));
+
+        let broadcast = Broadcast::get_recent(&pool, 1).await.unwrap().remove(0);
+        assert_eq!(broadcast.message, "Restarting in 5 minutes");
+        assert_eq!(broadcast.sent_by, Some(admin.uid.clone()));
+        // Other tests publish events too
+        let mut published = vec![];
+        while let Ok(event) = events.try_recv() {
+            published.push(event);
+        }
+        assert!(
+            published.contains(&openmelee::events::Event::BroadcastCreated { id: broadcast.id })
+        );
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 1)
+            .await
+            .unwrap();
+        assert_eq!(audit_log[0].action, "send_broadcast");
+
+        Broadcast::record_delivery(&pool, broadcast.id, 3, Utc::now().timestamp())
+            .await
+            .unwrap();
+        let admin_page = client
+            .get(format!("http://{}/admin", addr))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(admin_page.contains("<td>3 peers</td>"));
+    }
+
+    #[sqlx::test]
+    async fn admins_pause_and_resume_queues(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new().admin().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+
+        let res = client
+            .post(format!("http://{}/admin/queues/ranked/pause", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&[("reason", "Investigating desyncs")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res
+            .text()
+            .await
+            .unwrap()
+            .contains(": Investigating desyncs"));
+
+        let pause = QueuePause::get(&pool, OnlinePlayMode::Ranked)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pause.paused_by, Some(admin.uid.clone()));
+        assert_eq!(
+            pause.get_message(),
+            "The ranked queue is temporarily unavailable: Investigating desyncs"
+        );
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 1)
+            .await
+            .unwrap();
+        assert_eq!(audit_log[0].action, "pause_queue");
+        assert_eq!(audit_log[0].details.as_deref(), Some("ranked"));
+
+        let res = client
+            .post(format!("http://{}/admin/queues/ranked/resume", addr))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert_eq!(
+            QueuePause::get(&pool, OnlinePlayMode::Ranked)
+                .await
+                .unwrap(),
+            None
+        );
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 1)
+            .await
+            .unwrap();
+        assert_eq!(audit_log[0].action, "resume_queue");
+    }
+
+    #[sqlx::test]
+    async fn api_keys_are_limited_to_their_scopes_and_rate(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new().admin().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+
+        let res = client
+            .post(format!("http://{}/admin/api-keys", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&[("name", "Stats site"), ("requests_per_minute", "1")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = res.text().await.unwrap();
+        assert!(body.contains("Must allow reading something"));
+
+        let res = client
+            .post(format!("http://{}/admin/api-keys", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&[
+                ("name", "Stats site"),
+                ("requests_per_minute", "1"),
+                ("read-stats", "on"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.text().await.unwrap();
+        let start = body.find("omk_").unwrap();
+        let api_key = body[start..start + 68].to_string();
+
+        let get = |path: &str, api_key: &str| {
+            client
+                .get(format!("http://{}{}", addr, path))
+                .header(API_KEY_HEADER, api_key)
+                .send()
+        };
+        assert_eq!(
+            get("/api/v1/stats", "omk_invalid").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get("/api/v1/users", &api_key).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            get("/api/v1/me", &api_key).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+
+        // Requests may straddle two windows, but not three
+        let mut statuses = vec![];
+        for _ in 0..3 {
+            let res = get("/api/v1/stats", &api_key).await.unwrap();
+            if res.status() == StatusCode::TOO_MANY_REQUESTS {
+                assert!(res.headers().contains_key(header::RETRY_AFTER));
+            }
+            statuses.push(res.status());
+        }
+        assert_eq!(statuses[0], StatusCode::OK);
+        assert!(statuses.contains(&StatusCode::TOO_MANY_REQUESTS));
+
+        let api_keys = ApiKey::get_all(&pool).await.unwrap();
+        assert_eq!(api_keys[0].request_count + api_keys[0].throttled_count, 3);
+        assert!(api_keys[0].throttled_count >= 1);
+
+        let res = client
+            .post(format!(
+                "http://{}/admin/api-keys/{}/revoke",
+                addr, api_keys[0].id
+            ))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            get("/api/v1/stats", &api_key).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 2)
+            .await
+            .unwrap();
+        assert_eq!(audit_log[0].action, "revoke_api_key");
+        assert_eq!(audit_log[1].action, "create_api_key");
+    }
+
+    #[sqlx::test]
+    async fn can_recover_account_with_play_key(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new()
+            .username("recovered")
+            .password("forgotten")
+            .insert(&pool)
+            .await;
+        let (addr, client, _) = start_app(pool.clone(), Config::default()).await;
+        let form = |play_key: &str| {
+            vec![
+                ("uid", user.uid.to_string()),
+                ("play_key", play_key.to_string()),
+                ("password", "remembered".to_string()),
+            ]
+        };
+
+        let res = client
+            .post(format!("http://{}/recover", addr))
+            .form(&form("0123456789abcdef"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res
+            .text()
+            .await
+            .unwrap()
+            .contains("User ID or play key is incorrect."));
+
+        let res = client
+            .post(format!("http://{}/recover", addr))
+            .form(&form(user.play_key.as_str()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.url().path(), "/login");
+        assert!(User::get_user_from_credentials(
+            &pool,
+            openmelee::tenants::DEFAULT_TENANT.to_string(),
+            "recovered".to_string(),
+            SecretString::new("remembered".to_string()),
+        )
+        .await
+        .is_some());
+
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 1)
+            .await
+            .unwrap();
+        assert_eq!(audit_log[0].action, "recover_account");
+    }
+
+    #[sqlx::test]
+    async fn pages_show_times_in_the_user_timezone(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&user, Utc::now().timestamp() + 3600));
+        Login::record(
+            &pool,
+            user.uid.clone(),
+            "203.0.113.7".parse().unwrap(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let res = client
+            .post(format!("http://{}/profile/timezone", addr))
+            .header(header::COOKIE, cookie.clone())
+            .form(&[("timezone", "Not/A_Zone")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let profile = client
+            .post(format!("http://{}/profile/timezone", addr))
+            .header(header::COOKIE, cookie)
+            .form(&[("timezone", "Asia/Tokyo")])
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(
+            User::get_timezone(&pool, user.uid.clone()).await.unwrap(),
+            chrono_tz::Tz::Asia__Tokyo
+        );
+        assert!(profile.contains("<option selected>Asia/Tokyo</option>"));
+
+        let logged_in_at = Login::get_recent(&pool, user.uid, 1).await.unwrap()[0].created_at;
+        let local_time = chrono::TimeZone::timestamp_opt(&Utc, logged_in_at, 0)
+            .unwrap()
+            .with_timezone(&chrono_tz::Tz::Asia__Tokyo)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        assert!(profile.contains(&format!("<td>{}</td>", local_time)));
+    }
+
+    #[sqlx::test]
+    async fn admins_can_browse_the_schema(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new().admin().insert(&pool).await;
+        let (addr, client, key) = start_app(pool, Config::default()).await;
+
+        let page = client
+            .get(format!("http://{}/admin/schema", addr))
+            .header(
+                header::COOKIE,
+                session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600)),
+            )
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(page.contains("<a href=\"#table-users\">users</a> (1 row)"));
+        assert!(page.contains("<samp>users_username_normalized</samp> on tenant_id, unique"));
+    }
+
+    #[cfg(feature = "sql-console")]
+    #[sqlx::test]
+    async fn admins_run_read_only_queries(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new().admin().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+        let run = |query: &str| {
+            client
+                .post(format!("http://{}/admin/console", addr))
+                .header(header::COOKIE, cookie.clone())
+                .form(&[("query", query)])
+                .send()
+        };
+
+        let res = run("select connect_code from users").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res
+            .text()
+            .await
+            .unwrap()
+            .contains(&format!("<td><samp>{}</samp></td>", admin.connect_code)));
+
+        let res = run("update users set is_admin = false").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res
+            .text()
+            .await
+            .unwrap()
+            .contains("Only a single SELECT statement can be run"));
+        assert!(User::is_admin(&pool, admin.uid.clone()).await);
+
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            audit_log
+                .iter()
+                .map(|entry| (entry.action.as_str(), entry.details.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("run_sql_query", Some("update users set is_admin = false")),
+                ("run_sql_query", Some("select connect_code from users")),
+            ]
+        );
+    }
+
+    #[cfg(feature = "graphql")]
+    #[sqlx::test]
+    async fn graphql_resolves_private_fields_only_for_the_user(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().display_name("FOX").insert(&pool).await;
+        let other = UserBuilder::new()
+            .tenant("other")
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let query = |query: String, cookie: Option<String>| {
+            let mut request = client
+                .post(format!("http://{}/api/v1/graphql", addr))
+                .json(&json!({ "query": query }));
+            if let Some(cookie) = cookie {
+                request = request.header(header::COOKIE, cookie);
+            }
+            request.send()
+        };
+
+        let res = query(
+            "{ users { total items { displayName connectCode } } }".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.json::<serde_json::Value>().await.unwrap()["data"],
+            json!({ "users": { "total": 1, "items": [{ "displayName": "FOX", "connectCode": "TEST#001" }] } })
+        );
+
+        let res = query(
+            format!("{{ user(uid: \"{}\") {{ uid }} }}", other.uid),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res.json::<serde_json::Value>().await.unwrap()["data"],
+            json!({ "user": null })
+        );
+
+        let preferences = format!(
+            "{{ user(uid: \"{}\") {{ preferences {{ strict }} }} }}",
+            user.uid
+        );
+        let res = query(preferences.clone(), None).await.unwrap();
+        let body = res.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["data"], json!({ "user": null }));
+        assert_eq!(
+            body["errors"][0]["message"],
+            "Only visible to the user themselves"
+        );
+
+        let cookie = session_cookie(&key, &session_token(&user, Utc::now().timestamp() + 3600));
+        let res = query(preferences, Some(cookie)).await.unwrap();
+        assert_eq!(
+            res.json::<serde_json::Value>().await.unwrap()["data"],
+            json!({ "user": { "preferences": { "strict": false } } })
+        );
+    }
+
+    #[sqlx::test]
+    async fn admins_import_users_from_csv(pool: Pool<Sqlite>) {
+        let admin = UserBuilder::new()
+            .admin()
+            .display_name("ADMIN")
+            .connect_code("ADMN#1")
+            .insert(&pool)
+            .await;
+        let player = UserBuilder::new()
+            .display_name("PLAYER")
+            .connect_code("PLYR#1")
+            .insert(&pool)
+            .await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+
+        let res = client
+            .get(format!("http://{}/admin/users/export", addr))
+            .header(header::COOKIE, cookie.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "text/csv; charset=utf-8"
+        );
+        assert_eq!(
+            res.text().await.unwrap(),
+            format!(
+                "uid,connect_code,display_name,banned\n{},ADMN#1,ADMIN,false\n{},PLYR#1,PLAYER,false\n",
+                admin.uid, player.uid
+            )
+        );
+
+        let import = |csv: &str, dry_run: bool| {
+            let mut body = format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"users.csv\"\r\nContent-Type: text/csv\r\n\r\n{}\r\n",
+                csv
+            );
+            if dry_run {
+                body.push_str(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"dry_run\"\r\n\r\non\r\n",
+                );
+            }
+            body.push_str("--boundary--\r\n");
+
+            client
+                .post(format!("http://{}/admin/users/import", addr))
+                .header(header::COOKIE, cookie.clone())
+                .header(
+                    header::CONTENT_TYPE,
+                    "multipart/form-data; boundary=boundary",
+                )
+                .body(body)
+                .send()
+        };
+
+        let csv = "connect_code,display_name,banned\nPLYR#1,PLAYER 2,\nADMN#1,ADMIN*,\n";
+        let res = import(csv, false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.text().await.unwrap().contains("nothing was changed"));
+
+        let csv = "connect_code,display_name,banned\nPLYR#1,PLAYER 2,true\n";
+        let res = import(csv, true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.text().await.unwrap().contains("Dry run"));
+        assert_eq!(User::get(&pool, player.uid.clone()).await.unwrap(), player);
+
+        let res = import(csv, false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.text().await.unwrap().contains("Updated 1 user."));
+        assert_eq!(
+            User::get(&pool, player.uid.clone())
+                .await
+                .unwrap()
+                .display_name,
+            "PLAYER 2"
+        );
+        assert!(User::is_banned(&pool, player.uid.clone()).await);
+    }
+
+    #[sqlx::test]
+    async fn admins_shadow_queue_suspected_cheaters(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let admin = UserBuilder::new()
+            .connect_code("ADMN#001")
+            .admin()
+            .insert(&pool)
+            .await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&admin, Utc::now().timestamp() + 3600));
+
+        let page = client
+            .post(format!(
+                "http://{}/admin/users/{}/shadow-queue",
+                addr, user.uid
+            ))
+            .header(header::COOKIE, cookie.clone())
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(page.contains(", shadow queued"));
+        assert!(User::is_shadow_queued(&pool, user.uid.clone()).await);
+
+        client
+            .post(format!(
+                "http://{}/admin/users/{}/shadow-queue/remove",
+                addr, user.uid
+            ))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap();
+        assert!(!User::is_shadow_queued(&pool, user.uid.clone()).await);
+
+        let audit_log = openmelee::audit::AuditLogEntry::get_recent(&pool, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            audit_log
+                .iter()
+                .map(|entry| (entry.action.as_str(), entry.subject_uid.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("remove_from_shadow_queue", Some(user.uid.as_str())),
+                ("add_to_shadow_queue", Some(user.uid.as_str())),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn players_can_rate_their_last_match(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let opponent = UserBuilder::new()
+            .connect_code("TEST#002")
+            .insert(&pool)
+            .await;
+        MatchBuilder::new("mode.unranked-1")
+            .players(&[user.clone(), opponent.clone()])
+            .insert(&pool)
+            .await;
+        let config = Config {
+            match_survey: true,
+            ..Config::default()
+        };
+        let (addr, client, key) = start_app(pool.clone(), config).await;
+        let cookie = session_cookie(&key, &session_token(&user, Utc::now().timestamp() + 3600));
+
+        let rate = |rating: &str| {
+            client
+                .post(format!("http://{}/profile/match-rating", addr))
+                .header(header::COOKIE, cookie.clone())
+                .form(&[("match_id", "mode.unranked-1"), ("rating", rating)])
+                .send()
+        };
+
+        let res = client
+            .get(format!("http://{}/profile", addr))
+            .header(header::COOKIE, cookie.clone())
+            .send()
+            .await
+            .unwrap();
+        assert!(res
+            .text()
+            .await
+            .unwrap()
+            .contains("How was your unranked match"));
+
+        assert_eq!(rate("6").await.unwrap().status(), StatusCode::BAD_REQUEST);
+        // Redirected to the profile, which stops asking
+        let res = rate("4").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.text().await.unwrap().contains("How was your"));
+        assert_eq!(rate("5").await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        MatchFormation {
+            match_id: "mode.unranked-1".parse().unwrap(),
+            tenant_id: DEFAULT_TENANT.to_string(),
+            mode: "unranked".to_string(),
+            algorithm: "first_fit".to_string(),
+            algorithm_version: 1,
+            stages: vec![],
+            players: vec![],
+            created_at: Utc::now().timestamp(),
+        }
+        .save(&pool)
+        .await
+        .unwrap();
+        MatchQuality::score_formed_before(&pool, Utc::now().timestamp() + 1)
+            .await
+            .unwrap();
+
+        let stats = client
+            .get(format!("http://{}/stats", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(stats.contains("Match quality"));
+        assert!(stats.contains("4 (1 rating)"));
+    }
+
+    #[sqlx::test]
+    async fn launcher_links_bootstrap_user_json_once(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let (addr, client, key) = start_app(pool.clone(), Config::default()).await;
+        let cookie = session_cookie(&key, &session_token(&user, Utc::now().timestamp() + 3600));
+
+        let res = client
+            .post(format!("http://{}/profile/launcher-link", addr))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let content = res.text().await.unwrap();
+        assert!(content.contains("<svg "));
+        let token = content
+            .split("token=")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let bootstrap = |token: String| {
+            client
+                .post(format!("http://{}/api/v1/launcher/bootstrap", addr))
+                .json(&json!({ "token": token }))
+                .send()
+        };
+        let res = bootstrap(token.clone()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let user_json = res.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(user_json["playKey"], user.play_key.as_str());
+
+        let res = bootstrap(token).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn downloaded_user_json_can_be_verified(pool: Pool<Sqlite>) {
+        let user = UserBuilder::new().insert(&pool).await;
+        let secret_path =
+            std::env::temp_dir().join(format!("openmelee-user-json-{}.key", user.uid));
+        std::fs::write(&secret_path, "secret\n").unwrap();
+        let config = Config {
+            user_json_secret_path: Some(secret_path.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+        let (addr, client, key) = start_app(pool.clone(), config).await;
+        let cookie = session_cookie(&key, &session_token(&user, Utc::now().timestamp() + 3600));
+
+        let mut user_json = client
+            .get(format!("http://{}/openmelee-user.json", addr))
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(user_json["playKey"], user.play_key.as_str());
+
+        let verify = |user_json: serde_json::Value| {
+            client
+                .post(format!("http://{}/api/v1/user-json/verify", addr))
+                .json(&user_json)
+                .send()
+        };
+        let verification = verify(user_json.clone())
+            .await
+            .unwrap()
+            .json::<UserJsonVerification>()
+            .await
+            .unwrap();
+        assert_eq!(
+            verification,
+            UserJsonVerification {
+                signed: true,
+                valid: true
+            }
+        );
+
+        user_json["connectCode"] = json!("EDIT#001");
+        let verification = verify(user_json)
+            .await
+            .unwrap()
+            .json::<UserJsonVerification>()
+            .await
+            .unwrap();
+        assert!(!verification.valid);
+
+        std::fs::remove_file(secret_path).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn can_get_version(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool).await;
+
+        let version = client
+            .get(format!("http://{}/api/v1/version", addr))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(version["latestClientVersion"], LATEST_SLIPPI_CLIENT_VERSION);
+        assert!(version["gitHash"].is_string());
+        assert!(version["uptimeSeconds"].as_i64().unwrap() >= 0);
+    }
+
+    #[sqlx::test]
+    async fn can_list_stages_and_characters(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool).await;
+        let get = |path: &str| client.get(format!("http://{}{}", addr, path)).send();
+
+        let stages = get("/api/v1/stages")
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(stages["meta"]["total"], 6);
+        assert_eq!(
+            stages["data"][4],
+            json!({
+                "id": 31,
+                "slug": "battlefield",
+                "name": "Battlefield",
+                "icon": "static/stages/battlefield.svg",
+            })
+        );
+
+        let characters = get("/api/v1/characters")
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(characters["meta"]["total"], 26);
+        assert_eq!(
+            characters["data"][2],
+            json!({ "id": 2, "slug": "fox", "name": "Fox" })
+        );
+    }
+
+    #[sqlx::test]
+    async fn serves_prometheus_metrics_when_enabled(pool: Pool<Sqlite>) {
+        let (addr, client, _) = start_app(pool.clone(), Config::default()).await;
+        let res = client
+            .get(format!("http://{}/metrics", addr))
+            .send()
+            .await
+            .unwrap();
+        assert!(!res
+            .text()
+            .await
+            .unwrap()
+            .contains("openmelee_ticket_match_seconds"));
+
+        let config = Config {
+            prometheus_metrics: true,
+            ..Config::default()
+        };
+        let (addr, client, _) = start_app(pool, config).await;
+        let res = client
+            .get(format!("http://{}/metrics", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let metrics = res.text().await.unwrap();
+        assert!(metrics.contains("# TYPE openmelee_ticket_match_seconds histogram"));
+        assert!(metrics.contains("openmelee_service_loop_seconds_bucket{le=\"+Inf\"}"));
+        assert!(metrics.contains("openmelee_packet_parse_seconds_count"));
+    }
+
+    #[sqlx::test]
+    async fn readiness_follows_the_matchmaking_heartbeat(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool).await;
+
+        watchdog::MATCHMAKING_HEARTBEAT.tick(Utc::now().timestamp());
+        let response = client
+            .get(format!("http://{}/readyz", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The watchdog tests never tick it, so this is the only test
+        // which can see it stalled
+        watchdog::MATCHMAKING_HEARTBEAT.tick(Utc::now().timestamp() - 60);
+        let response = client
+            .get(format!("http://{}/readyz", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .starts_with("matchmaking server hasn't ticked for"));
+    }
+
+    #[sqlx::test]
+    async fn can_look_up_user_by_connect_code(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+        let user = UserBuilder::new().insert(&pool).await;
+
+        let look_up = |connect_code: &'static str| {
+            client
+                .get(format!(
+                    "http://{}/api/v1/connect-code/{}",
+                    addr, connect_code
+                ))
+                .send()
+        };
+
+        for connect_code in ["TEST%23001", "test-001"] {
+            let public_user = look_up(connect_code)
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap();
+            assert_eq!(public_user["uid"], user.uid.as_str());
+            assert_eq!(public_user["connectCode"], "TEST#001");
+            assert!(public_user.get("playKey").is_none());
+        }
+
+        for connect_code in ["MISS-001", "invalid"] {
+            let missing = look_up(connect_code)
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap();
+            assert!(missing.get("uid").is_none());
+        }
+    }
+
+    #[sqlx::test]
+    async fn can_look_up_rank_in_slippi_format(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        UserBuilder::new().insert(&pool).await;
+
+        let rank_query = |connect_code: &str| {
+            client
+                .post(format!("http://{}/graphql", addr))
+                .json(&json!({
+                    "operationName": "AccountManagementPageQuery",
+                    "variables": { "cc": connect_code, "uid": connect_code },
+                    "query": "query AccountManagementPageQuery($cc: String!) { ... }",
+                }))
+                .send()
+        };
+
+        let rank = rank_query("TEST#001")
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        let user = &rank["data"]["getConnectCode"]["user"];
+
+        assert_eq!(user["connectCode"]["code"], "TEST#001");
+        assert_eq!(user["rankedNetplayProfile"]["wins"], 0);
+        assert_eq!(user["rankedNetplayProfile"]["losses"], 0);
+
+        let missing = rank_query("MISS#001")
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+
+        assert!(missing["data"]["getConnectCode"].is_null());
+    }
+
+    #[sqlx::test]
+    async fn oversized_request_body_is_rejected(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool).await;
+
+        let register_response = client
+            .post(format!("http://{}/register", addr))
+            .form(&PublicUserForm {
+                username: "a".repeat(Config::default().webserver_max_body_bytes),
+                display_name: "test".to_string(),
+                connect_code: "TEST#001".to_string(),
+            })
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            register_response.status(),
+            reqwest::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert!(register_response
+            .text()
+            .await
+            .unwrap()
+            .contains("The submitted data is too large."));
+    }
+
+    #[sqlx::test]
+    async fn cannot_register_with_errors(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool).await;
+
+        let register_response = client
+            .post(format!("http://{}/register", addr))
+            .form(&PublicUserForm {
+                username: "test".to_string(),
+                display_name: "".to_string(),
+                connect_code: "TEST#".to_string(),
+            })
+            .send()
+            .await;
+
+        let res: serde_json::Value =
+            serde_json::from_str(&register_response.unwrap().text().await.unwrap()).unwrap();
+
+        assert_eq!(
+            extract_errors(&res.clone(), "connect_code"),
+            vec!["discriminant_length"]
+        );
+
+        assert_eq!(extract_errors(&res.clone(), "display_name"), vec!["length"]);
+    }
+
+    #[sqlx::test]
+    async fn cannot_register_with_existing_connect_code_or_username(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool).await;
+
+        client
+            .post(format!("http://{}/register", addr))
+            .form(&PublicUserForm {
+                username: "test".to_string(),
+                display_name: "test".to_string(),
+                connect_code: "TEST#001".to_string(),
+            })
+            .send()
+            .await
+            .expect("First registration attempt failed");
+
+        let register_response = client
+            .post(format!("http://{}/register", addr))
+            .form(&PublicUserForm {
+                username: "test".to_string(),
+                display_name: "test".to_string(),
+                connect_code: "TEST#001".to_string(),
+            })
+            .send()
+            .await;
+
+        let res: serde_json::Value =
+            serde_json::from_str(&register_response.unwrap().text().await.unwrap()).unwrap();
+
+        assert_eq!(
+            extract_errors(&res.clone(), "connect_code"),
+            vec!["duplicated"]
+        );
+
+        assert_eq!(extract_errors(&res.clone(), "username"), vec!["duplicated"]);
+    }
+
+    #[sqlx::test]
+    async fn passwords_are_never_echoed(pool: Pool<Sqlite>) {
+        const PASSWORD: &str = "echo-this-and-fail";
+        let user = UserBuilder::new().insert(&pool).await;
+        let (addr, client, _) = start_app(pool, Config::default()).await;
+
+        let response = client
+            .post(format!("http://{}/register", addr))
+            .form(&[
+                ("username", "new"),
+                ("password", PASSWORD),
+                ("display_name", "new"),
+                ("connect_code", user.connect_code.as_str()),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("Connect code is already in use"));
+        assert!(!body.contains(PASSWORD));
+
+        let response = client
+            .post(format!("http://{}/login", addr))
+            .form(&[
+                ("username", user.connect_code.as_str()),
+                ("password", PASSWORD),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!response.text().await.unwrap().contains(PASSWORD));
+
+        let user_form = UserForm {
+            username: "new".to_string(),
+            password: SecretString::from_str(PASSWORD).unwrap(),
+            display_name: "new".to_string(),
+            connect_code: "NEW#001".to_string(),
+        };
+        let auth_payload = AuthPayload {
+            username: "new".to_string(),
+            password: SecretString::from_str(PASSWORD).unwrap(),
+        };
+        assert!(format!("{:?}", user_form).contains("NEW#001"));
+        assert!(!format!("{:?}", user_form).contains(PASSWORD));
+        assert!(!format!("{:?}", auth_payload).contains(PASSWORD));
+    }
+
+    #[sqlx::test]
+    async fn logo_falls_back_to_bundled_logo(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        let response = client
+            .get(format!("http://{}/logo", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/svg+xml"
+        );
+
+        InstanceLogo::save(&pool, "image/png", b"\x89PNG\r\n\x1a\n".to_vec())
+            .await
+            .unwrap();
+
+        let response = client
+            .get(format!("http://{}/logo", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+        assert_eq!(
+            response.bytes().await.unwrap().as_ref(),
+            b"\x89PNG\r\n\x1a\n"
+        );
+    }
+
+    #[sqlx::test]
+    async fn pages_are_served_at_their_slug(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        openmelee::pages::Page {
+            slug: "rules".to_string(),
+            title: "Rules".to_string(),
+            content: "No *wobbling*.".to_string(),
+            show_in_navbar: false,
+            updated_at: 0,
+        }
+        .save(&pool)
+        .await
+        .unwrap();
+
+        let body = client
+            .get(format!("http://{}/rules", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(body.contains("<p>No <em>wobbling</em>.</p>"));
+
+        let body = client
+            .get(format!("http://{}/about", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(!body.contains("wobbling"));
+    }
+
+    #[sqlx::test]
+    async fn sitemap_lists_pages(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        openmelee::pages::Page {
+            slug: "rules".to_string(),
+            title: "Rules".to_string(),
+            content: String::new(),
+            show_in_navbar: false,
+            updated_at: 0,
+        }
+        .save(&pool)
+        .await
+        .unwrap();
+
+        let sitemap = client
+            .get(format!("http://{}/sitemap.xml", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(sitemap.contains("<loc>http://127.0.0.1:5000/</loc>"));
+        assert!(sitemap.contains("<loc>http://127.0.0.1:5000/rules</loc>"));
+
+        let robots = client
+            .get(format!("http://{}/robots.txt", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(robots.contains("Sitemap: http://127.0.0.1:5000/sitemap.xml"));
+    }
+
+    #[sqlx::test]
+    async fn pages_and_api_are_compressed_as_configured(pool: Pool<Sqlite>) {
+        let config = Config {
+            compression_json_gzip: false,
+            ..Config::default()
+        };
+        let (addr, client, _) = start_app(pool, config).await;
+
+        for (path, compressed) in [("/", true), ("/api/v1/stages", false)] {
+            let res = client
+                .get(format!("http://{}{}", addr, path))
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(header::CONTENT_ENCODING).is_some(),
+                compressed,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn feed_lists_announcements(pool: Pool<Sqlite>) {
+        let (addr, client) = start_test_server(pool.clone()).await;
+
+        openmelee::announcements::Announcement::create(
+            &pool,
+            &openmelee::announcements::AnnouncementForm {
+                title: "Netplay night & more".to_string(),
+                content: "Join us *tonight*.".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+        let response = client
+            .get(format!("http://{}/feed.xml", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/atom+xml"
+        );
+
+        let feed = response.text().await.unwrap();
+        assert!(feed.contains("<title>Netplay night &amp; more</title>"));
+        assert!(feed.contains("&lt;p&gt;Join us &lt;em&gt;tonight&lt;/em&gt;.&lt;/p&gt;"));
+
+        let index = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(index.contains("<p>Join us <em>tonight</em>.</p>"));
+    }
+
+    #[test]
+    fn register_form_keeps_input_and_marks_invalid_fields() {
+        let mut context = Context::new();
+        context.insert("base_path", "");
+        context.insert("error", &true);
+        context.insert(
+            "field_errors",
+            &json!({ "connect_code": ["Connect code is already in use"] }),
+        );
+        context.insert(
+            "field_values",
+            &PublicUserForm {
+                username: "test".to_string(),
+                display_name: "test".to_string(),
+                connect_code: "TEST#001".to_string(),
+            },
+        );
+
+        let content = openmelee::TEMPLATES
+            .render("register.html.tera", &context)
+            .unwrap();
+
+        assert!(content.contains(
+            "name=\"connect_code\" value=\"TEST#001\" required aria-required=\"true\" aria-invalid=\"true\" aria-describedby=\"connect_code_error\""
+        ));
+        assert!(content.contains("<strong class=\"error\">Connect code is already in use</strong>"));
+        assert!(
+            content.contains("name=\"username\" value=\"test\" required aria-required=\"true\">")
+        );
+    }
+
+    #[test]
+    fn can_render_index() {
+        let mut context = Context::new();
+        context.insert("base_path", "");
+        assert!(openmelee::TEMPLATES
+            .render("index.html.tera", &context)
+            .is_ok());
+    }
+
+    #[test]
+    fn can_render_capacity_patterns() {
+        let slot = |slot| openmelee::capacity::CapacitySlot {
+            mode: "unranked".to_string(),
+            slot,
+            mean_waiting: 2.5,
+            peak_waiting: 4,
+            matches_formed: 6.0,
+        };
+        let daily = vec![slot(14)];
+        let weekly = vec![slot(4)];
+
+        let mut context = Context::new();
+        context.insert("base_path", "");
+        context.insert("daily", &daily);
+        context.insert("weekly", &weekly);
+        context.insert("quietest_hours", &daily);
+        context.insert("quietest_days", &weekly);
+        context.insert("retention_days", &56);
+        context.insert(
+            "weekdays",
+            &["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday"],
+        );
+        let content = openmelee::TEMPLATES
+            .render("admin-capacity.html.tera", &context)
+            .unwrap();
+
+        assert!(content.contains("14:00 in unranked"));
+        assert!(content.contains("Thursday in unranked"));
+    }
+
+    #[test]
+    fn can_render_admin_with_operator_audit_log_entries() {
+        let mut context = Context::new();
+        context.insert("base_path", "");
+        context.insert("timezone", "UTC");
+        context.insert("users", &Vec::<User>::new());
+        context.insert(
+            "disputes",
+            &json!([{
+                "match": {
+                    "matchId": "mode.unranked-2022-10-12T12:00:00+00:00",
+                    "mode": "unranked",
+                    "createdAt": 0,
+                    "status": "disputed",
+                    "disputeReason": "replay_mismatch",
+                },
+                "players": [],
+                "results": [{
+                    "matchId": "mode.unranked-2022-10-12T12:00:00+00:00",
+                    "reporterUid": "1234",
+                    "winnerUid": "1234",
+                    "createdAt": 0,
+                    "summary": [{ "uid": "1234", "stocks": 1, "percent": 0, "lras": true }],
+                }],
+            }]),
+        );
+        context.insert(
+            "queues",
+            &json!([
+                { "mode": "unranked", "pause": null },
+                {
+                    "mode": "ranked",
+                    "pause": { "mode": "ranked", "reason": "", "pausedBy": null, "pausedAt": 0 },
+                },
+            ]),
+        );
+        context.insert(
+            "audit_log",
+            &vec![openmelee::audit::AuditLogEntry {
+                id: 1,
+                actor_uid: None,
+                action: "impersonate".to_string(),
+                subject_uid: None,
+                details: None,
+                created_at: 0,
+                ip_address: Some("203.0.113.7".to_string()),
+            }],
+        );
         assert!(openmelee::TEMPLATES
-            .render("index.html.tera", &Context::new())
+            .render("admin.html.tera", &context)
             .is_ok());
     }
 }
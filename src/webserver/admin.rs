@@ -0,0 +1,1219 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Form, Multipart, Path},
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
+use axum_sqlx_tx::Tx;
+use chrono::Utc;
+use cookie::time::Duration;
+use serde::{Deserialize, Serialize};
+use sqlx::Sqlite;
+use tera::Context;
+use validator::Validate;
+
+use openmelee::{
+    announcements::{Announcement, AnnouncementForm},
+    api_keys::{ApiKey, ApiKeyForm},
+    audit::*,
+    auth::*,
+    broadcasts::{Broadcast, BroadcastForm},
+    capacity::{CapacitySlot, Pattern, SNAPSHOT_RETENTION_DAYS},
+    claims::{ClaimStatus, ConnectCodeClaim},
+    client_ip::ClientIp,
+    events::{self, Event},
+    game::OnlinePlayMode,
+    ids::{MatchId, Uid},
+    matches::*,
+    models::*,
+    notes::{UserNote, UserNoteForm},
+    pages::Page,
+    queue_pauses::{QueuePause, QueuePauseForm},
+    ranking,
+    rotations::{StageRotation, StageRotationForm},
+    schema,
+    tenants::{CurrentTenant, Tenant},
+    theme::{InstanceLogo, MAX_LOGO_BYTES},
+    user_csv, Config, ReadPool,
+};
+
+use super::{build_removal_cookie, build_token_cookie, get_tenant_user, render::Renderer};
+
+const AUDIT_LOG_PAGE_SIZE: i64 = 50;
+const ANNOUNCEMENTS_PAGE_SIZE: i64 = 20;
+const BROADCASTS_PAGE_SIZE: i64 = 10;
+
+#[derive(Serialize)]
+struct Dispute {
+    #[serde(rename = "match")]
+    disputed_match: Match,
+    players: Vec<User>,
+    results: Vec<ReportedResult>,
+}
+
+#[derive(Serialize)]
+struct QueueStatus {
+    mode: String,
+    pause: Option<QueuePause>,
+}
+
+#[derive(Serialize)]
+struct ReportedResult {
+    #[serde(flatten)]
+    result: MatchResult,
+    summary: Vec<PlayerSummary>,
+}
+
+#[derive(Serialize)]
+struct PendingClaim {
+    claim: ConnectCodeClaim,
+    claimant: User,
+    // The user who has the claimed code on this server, if anyone
+    holder: Option<User>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveForm {
+    pub winner_uid: Uid,
+}
+
+// Sessions created through impersonation are never allowed to act as admin,
+// even if the impersonated user is one.
+pub async fn require_admin(tx: &mut Tx<Sqlite>, claims: &Claims) -> Result<(), AuthError> {
+    if claims.is_impersonation() || !User::is_admin(tx, claims.uid.clone()).await {
+        return Err(AuthError::Forbidden);
+    }
+
+    Ok(())
+}
+
+pub async fn index(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    Ok(render_index(&mut tx, &renderer, Context::new()).await)
+}
+
+// Forms failing validation add their errors to the context
+async fn render_index(tx: &mut Tx<Sqlite>, renderer: &Renderer, mut context: Context) -> Response {
+    context.insert(
+        "users",
+        &User::get_all(&mut *tx, renderer.tenant_id()).await.unwrap(),
+    );
+    context.insert("disputes", &get_disputes(tx, renderer.tenant_id()).await);
+    context.insert(
+        "connect_code_claims",
+        &get_pending_claims(tx, renderer.tenant_id()).await,
+    );
+    context.insert("pages", &Page::get_all(&mut *tx).await.unwrap());
+    context.insert(
+        "announcements",
+        &Announcement::get_recent(&mut *tx, ANNOUNCEMENTS_PAGE_SIZE)
+            .await
+            .unwrap(),
+    );
+    context.insert(
+        "rotations",
+        &StageRotation::get_unfinished(&mut *tx, Utc::now().timestamp())
+            .await
+            .unwrap(),
+    );
+    context.insert("queues", &get_queues(tx).await);
+    context.insert(
+        "broadcasts",
+        &Broadcast::get_recent(&mut *tx, BROADCASTS_PAGE_SIZE)
+            .await
+            .unwrap(),
+    );
+    context.insert("api_keys", &ApiKey::get_all(&mut *tx).await.unwrap());
+    context.insert("sql_console", &cfg!(feature = "sql-console"));
+    context.insert(
+        "audit_log",
+        &AuditLogEntry::get_recent(&mut *tx, AUDIT_LOG_PAGE_SIZE)
+            .await
+            .unwrap(),
+    );
+
+    renderer.render("admin.html.tera", context)
+}
+
+async fn get_queues(tx: &mut Tx<Sqlite>) -> Vec<QueueStatus> {
+    let pauses = QueuePause::get_all(&mut *tx).await.unwrap();
+
+    [
+        OnlinePlayMode::Unranked,
+        OnlinePlayMode::Ranked,
+        OnlinePlayMode::Direct,
+        OnlinePlayMode::Teams,
+    ]
+    .into_iter()
+    .map(|mode| QueueStatus {
+        mode: mode.to_string(),
+        pause: pauses
+            .iter()
+            .find(|pause| pause.mode == mode.to_string())
+            .cloned(),
+    })
+    .collect()
+}
+
+async fn get_disputes(tx: &mut Tx<Sqlite>, tenant_id: String) -> Vec<Dispute> {
+    let mut disputes = vec![];
+
+    for disputed_match in Match::get_by_status(&mut *tx, tenant_id, MatchStatus::Disputed)
+        .await
+        .unwrap()
+    {
+        let mut players = vec![];
+        for uid in Match::get_player_uids(&mut *tx, disputed_match.match_id.clone())
+            .await
+            .unwrap()
+        {
+            players.push(User::get(&mut *tx, uid).await.unwrap());
+        }
+        let mut summaries =
+            PlayerSummary::get_all_for_match(&mut *tx, disputed_match.match_id.clone())
+                .await
+                .unwrap();
+        let results = MatchResult::get_all_for_match(&mut *tx, disputed_match.match_id.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|result| {
+                let (summary, others) = summaries
+                    .drain(..)
+                    .partition(|summary| summary.result_id == result.id);
+                summaries = others;
+                ReportedResult { result, summary }
+            })
+            .collect();
+
+        disputes.push(Dispute {
+            disputed_match,
+            players,
+            results,
+        });
+    }
+
+    disputes
+}
+
+async fn get_pending_claims(tx: &mut Tx<Sqlite>, tenant_id: String) -> Vec<PendingClaim> {
+    let mut pending_claims = vec![];
+
+    for claim in ConnectCodeClaim::get_pending(&mut *tx, tenant_id.clone())
+        .await
+        .unwrap()
+    {
+        let claimant = User::get(&mut *tx, claim.uid.clone()).await.unwrap();
+        let holder =
+            User::get_by_connect_code(&mut *tx, tenant_id.clone(), claim.connect_code.clone())
+                .await
+                .ok();
+
+        pending_claims.push(PendingClaim {
+            claim,
+            claimant,
+            holder,
+        });
+    }
+
+    pending_claims
+}
+
+// Pending claims of the users of the admin's own community
+async fn get_reviewable_claim(
+    tx: &mut Tx<Sqlite>,
+    tenant_id: String,
+    id: i64,
+) -> Option<ConnectCodeClaim> {
+    let claim = ConnectCodeClaim::get(&mut *tx, id).await.ok()?;
+    if claim.status != ClaimStatus::Pending.to_string()
+        || Tenant::get_for_user(&mut *tx, claim.uid.clone()).await.ok() != Some(tenant_id)
+    {
+        return None;
+    }
+
+    Some(claim)
+}
+
+// Whoever has the claimed code on this server is given a free one with the
+// same prefix first, so codes stay unique
+pub async fn approve_connect_code_claim(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    tenant: CurrentTenant,
+    Path(id): Path<i64>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let claim = match get_reviewable_claim(&mut tx, tenant.id(), id).await {
+        Some(claim) => claim,
+        None => return Ok(Redirect::to("/admin")),
+    };
+
+    if let Ok(holder) =
+        User::get_by_connect_code(&mut tx, tenant.id(), claim.connect_code.clone()).await
+    {
+        let prefix = claim
+            .connect_code
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let connect_code = User::find_free_connect_code(&mut tx, tenant.id(), &prefix)
+            .await
+            .unwrap();
+        User::set_connect_code(&mut tx, holder.uid.clone(), connect_code.clone())
+            .await
+            .unwrap();
+
+        AuditLogEntry::record(
+            &mut tx,
+            Some(claims.uid.clone()),
+            Some(ip),
+            AuditAction::SetConnectCode,
+            Some(holder.uid),
+            Some(connect_code.to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    User::set_connect_code(&mut tx, claim.uid.clone(), claim.connect_code.clone())
+        .await
+        .unwrap();
+    ConnectCodeClaim::review(&mut tx, claim.id, ClaimStatus::Approved, claims.uid.clone())
+        .await
+        .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::ApproveConnectCodeClaim,
+        Some(claim.uid),
+        Some(claim.connect_code.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+pub async fn reject_connect_code_claim(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    tenant: CurrentTenant,
+    Path(id): Path<i64>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let claim = match get_reviewable_claim(&mut tx, tenant.id(), id).await {
+        Some(claim) => claim,
+        None => return Ok(Redirect::to("/admin")),
+    };
+
+    ConnectCodeClaim::review(&mut tx, claim.id, ClaimStatus::Rejected, claims.uid.clone())
+        .await
+        .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::RejectConnectCodeClaim,
+        Some(claim.uid),
+        Some(claim.connect_code.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+pub async fn resolve_dispute(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(match_id): Path<MatchId>,
+    Form(form): Form<ResolveForm>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let disputed_match = match Match::get(&mut tx, match_id).await {
+        Ok(disputed_match) if disputed_match.status == MatchStatus::Disputed.to_string() => {
+            disputed_match
+        }
+        _ => return Ok(Redirect::to("/admin")),
+    };
+    let players = Match::get_player_teams(&mut tx, &disputed_match)
+        .await
+        .unwrap();
+    let player_uids = players
+        .iter()
+        .map(|(uid, _)| uid.clone())
+        .collect::<Vec<Uid>>();
+
+    // Matches of other communities are treated as unknown, the winner being
+    // one of the players tells which community the match belongs to
+    if !player_uids.contains(&form.winner_uid)
+        || get_tenant_user(&mut tx, claims.tenant_id.clone(), form.winner_uid.clone())
+            .await
+            .is_none()
+    {
+        return Ok(Redirect::to("/admin"));
+    }
+
+    Match::set_status(
+        &mut tx,
+        disputed_match.match_id.clone(),
+        MatchStatus::Resolved,
+        Some(form.winner_uid.clone()),
+    )
+    .await
+    .unwrap();
+    ranking::rate_match(
+        &mut tx,
+        &disputed_match,
+        &player_uids,
+        &form.winner_uid,
+        Utc::now().timestamp(),
+    )
+    .await
+    .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::ResolveDispute,
+        Some(form.winner_uid.clone()),
+        Some(disputed_match.match_id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    events::publish(Event::MatchConfirmed {
+        match_id: disputed_match.match_id,
+        winner_uids: get_winner_uids(&players, &form.winner_uid),
+        player_uids,
+    });
+
+    Ok(Redirect::to("/admin"))
+}
+
+pub async fn upload_logo(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    mut multipart: Multipart,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let mut data = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("logo") {
+            data = field.bytes().await.ok();
+        }
+    }
+
+    let data = match data {
+        Some(data) if !data.is_empty() => data,
+        _ => return Ok(logo_error(&mut tx, &renderer, "No image was uploaded").await),
+    };
+    if data.len() > MAX_LOGO_BYTES {
+        return Ok(logo_error(&mut tx, &renderer, "The image is too large").await);
+    }
+    let content_type = match InstanceLogo::detect_content_type(&data) {
+        Some(content_type) => content_type,
+        None => {
+            return Ok(logo_error(
+                &mut tx,
+                &renderer,
+                "Only PNG, JPEG, GIF and WebP images are allowed",
+            )
+            .await)
+        }
+    };
+
+    InstanceLogo::save(&mut tx, content_type, data.to_vec())
+        .await
+        .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::UpdateLogo,
+        None,
+        Some(content_type.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+async fn logo_error(tx: &mut Tx<Sqlite>, renderer: &Renderer, message: &str) -> Response {
+    let mut context = Context::new();
+    context.insert("logo_error", message);
+
+    (
+        StatusCode::BAD_REQUEST,
+        render_index(tx, renderer, context).await,
+    )
+        .into_response()
+}
+
+pub async fn remove_logo(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    InstanceLogo::delete(&mut tx).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::RemoveLogo,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+pub async fn publish_announcement(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Form(form): Form<AnnouncementForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    if let Err(errors) = form.validate() {
+        let mut context = Context::new();
+        context.insert("announcement_errors", &error_messages(&errors));
+        context.insert("announcement_values", &form);
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_index(&mut tx, &renderer, context).await,
+        )
+            .into_response());
+    }
+
+    let id = Announcement::create(&mut tx, &form).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::PublishAnnouncement,
+        None,
+        Some(id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+pub async fn delete_announcement(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    Announcement::delete(&mut tx, id).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::DeleteAnnouncement,
+        None,
+        Some(id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+pub async fn create_stage_rotation(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Form(form): Form<StageRotationForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    if let Err(errors) = form.check() {
+        let mut context = Context::new();
+        context.insert("rotation_errors", &error_messages(&errors));
+        context.insert("rotation_values", &form);
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_index(&mut tx, &renderer, context).await,
+        )
+            .into_response());
+    }
+
+    let id = StageRotation::create(&mut tx, &form).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::CreateStageRotation,
+        None,
+        Some(id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+pub async fn delete_stage_rotation(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    StageRotation::delete(&mut tx, id).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::DeleteStageRotation,
+        None,
+        Some(id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+// Takes effect for new tickets right away, while players already searching
+// stop being paired within a minute
+pub async fn pause_queue(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Path(mode): Path<String>,
+    Form(form): Form<QueuePauseForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let mode: OnlinePlayMode = match mode.parse() {
+        Ok(mode) => mode,
+        Err(()) => return Ok(Redirect::to("/admin").into_response()),
+    };
+
+    if let Err(errors) = form.validate() {
+        let mut context = Context::new();
+        context.insert("queue_errors", &error_messages(&errors));
+        context.insert("queue_values", &form);
+        context.insert("queue_error_mode", &mode.to_string());
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_index(&mut tx, &renderer, context).await,
+        )
+            .into_response());
+    }
+
+    let paused = QueuePause::pause(
+        &mut tx,
+        mode,
+        &form.reason,
+        Some(claims.uid.clone()),
+        Utc::now().timestamp(),
+    )
+    .await
+    .unwrap();
+
+    if paused {
+        AuditLogEntry::record(
+            &mut tx,
+            Some(claims.uid),
+            Some(ip),
+            AuditAction::PauseQueue,
+            None,
+            Some(mode.to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+pub async fn resume_queue(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(mode): Path<String>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let mode: OnlinePlayMode = match mode.parse() {
+        Ok(mode) => mode,
+        Err(()) => return Ok(Redirect::to("/admin")),
+    };
+
+    if QueuePause::resume(&mut tx, mode).await.unwrap() {
+        AuditLogEntry::record(
+            &mut tx,
+            Some(claims.uid),
+            Some(ip),
+            AuditAction::ResumeQueue,
+            None,
+            Some(mode.to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+// Delivered by the matchmaking server within a second, the page shows to how
+// many peers once it's reloaded
+pub async fn send_broadcast(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Form(form): Form<BroadcastForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    if let Err(errors) = form.validate() {
+        let mut context = Context::new();
+        context.insert("broadcast_errors", &error_messages(&errors));
+        context.insert("broadcast_values", &form);
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_index(&mut tx, &renderer, context).await,
+        )
+            .into_response());
+    }
+
+    let id = Broadcast::create(
+        &mut tx,
+        &form,
+        Some(claims.uid.clone()),
+        Utc::now().timestamp(),
+    )
+    .await
+    .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::SendBroadcast,
+        None,
+        Some(id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    events::publish(Event::BroadcastCreated { id });
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+// The new key is only ever shown on the page answering this request
+pub async fn create_api_key(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Form(form): Form<ApiKeyForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    if let Err(errors) = form.check() {
+        let mut context = Context::new();
+        context.insert("api_key_errors", &error_messages(&errors));
+        context.insert("api_key_values", &form);
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_index(&mut tx, &renderer, context).await,
+        )
+            .into_response());
+    }
+
+    let key = ApiKey::create(&mut tx, &form).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::CreateApiKey,
+        None,
+        Some(form.name.trim().to_string()),
+    )
+    .await
+    .unwrap();
+
+    let mut context = Context::new();
+    context.insert("created_api_key", &key);
+    let content = render_index(&mut tx, &renderer, context).await;
+    tx.commit().await.unwrap();
+
+    Ok(content)
+}
+
+pub async fn revoke_api_key(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    if ApiKey::revoke(&mut tx, id).await.unwrap() {
+        AuditLogEntry::record(
+            &mut tx,
+            Some(claims.uid),
+            Some(ip),
+            AuditAction::RevokeApiKey,
+            None,
+            Some(id.to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
+
+// Queue activity per hour and weekday, so operators can pick maintenance
+// windows and prepare for peaks
+pub async fn capacity(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    tenant: CurrentTenant,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let since = Utc::now().timestamp() - SNAPSHOT_RETENTION_DAYS * 24 * 60 * 60;
+    let daily = CapacitySlot::get_pattern(&mut tx, tenant.id(), since, Pattern::Daily)
+        .await
+        .unwrap();
+    let weekly = CapacitySlot::get_pattern(&mut tx, tenant.id(), since, Pattern::Weekly)
+        .await
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("quietest_hours", &CapacitySlot::get_quietest(&daily));
+    context.insert("quietest_days", &CapacitySlot::get_quietest(&weekly));
+    context.insert("daily", &daily);
+    context.insert("weekly", &weekly);
+    context.insert("retention_days", &SNAPSHOT_RETENTION_DAYS);
+    context.insert(
+        "weekdays",
+        &[
+            "Sunday",
+            "Monday",
+            "Tuesday",
+            "Wednesday",
+            "Thursday",
+            "Friday",
+            "Saturday",
+        ],
+    );
+
+    Ok(renderer.render("admin-capacity.html.tera", context))
+}
+
+// Read from the replica when there is one, since counting rows scans every
+// table
+pub async fn schema(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let mut context = Context::new();
+    context.insert("tables", &schema::describe_tables(&pool).await.unwrap());
+
+    Ok(renderer.render("admin-schema.html.tera", context))
+}
+
+pub async fn export_users(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    tenant: CurrentTenant,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let data = user_csv::export(&mut tx, tenant.id()).await.unwrap();
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"openmelee-users.csv\"",
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+// Every row is checked first, changes are only applied when none is invalid
+// and it isn't a dry run
+pub async fn import_users(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    tenant: CurrentTenant,
+    mut multipart: Multipart,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let mut data = None;
+    let mut dry_run = false;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        match field.name() {
+            Some("file") => data = field.bytes().await.ok(),
+            Some("dry_run") => dry_run = true,
+            _ => (),
+        }
+    }
+
+    let mut context = Context::new();
+    let data = match data {
+        Some(data) if !data.is_empty() => data,
+        _ => {
+            context.insert("import_error", "No file was uploaded");
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                render_index(&mut tx, &renderer, context).await,
+            )
+                .into_response());
+        }
+    };
+
+    let plan = user_csv::plan(&mut tx, tenant.id(), &data).await.unwrap();
+    let applied = !dry_run && plan.errors.is_empty();
+    if applied {
+        user_csv::apply(&mut tx, Some(claims.uid), Some(ip), &plan.changes)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    context.insert("plan", &plan);
+    context.insert("dry_run", &dry_run);
+    context.insert("applied", &applied);
+    let status = match plan.errors.is_empty() {
+        true => StatusCode::OK,
+        false => StatusCode::BAD_REQUEST,
+    };
+
+    Ok((
+        status,
+        renderer.render("admin-user-import.html.tera", context),
+    )
+        .into_response())
+}
+
+pub async fn show_user(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    tenant: CurrentTenant,
+    Path(uid): Path<Uid>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let user = match get_tenant_user(&mut tx, tenant.id(), uid).await {
+        Some(user) => user,
+        None => return Ok(Redirect::to("/admin").into_response()),
+    };
+
+    Ok(render_user(&mut tx, &renderer, user, Context::new()).await)
+}
+
+// Forms failing validation add their errors to the context
+async fn render_user(
+    tx: &mut Tx<Sqlite>,
+    renderer: &Renderer,
+    user: User,
+    mut context: Context,
+) -> Response {
+    context.insert(
+        "notes",
+        &UserNote::get_for_user(&mut *tx, user.uid.clone())
+            .await
+            .unwrap(),
+    );
+    context.insert(
+        "is_banned",
+        &User::is_banned(&mut *tx, user.uid.clone()).await,
+    );
+    context.insert(
+        "is_shadow_queued",
+        &User::is_shadow_queued(&mut *tx, user.uid.clone()).await,
+    );
+    context.insert("user", &user);
+
+    renderer.render("admin-user.html.tera", context)
+}
+
+pub async fn add_user_note(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    tenant: CurrentTenant,
+    Path(uid): Path<Uid>,
+    Form(form): Form<UserNoteForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let user = match get_tenant_user(&mut tx, tenant.id(), uid).await {
+        Some(user) => user,
+        None => return Ok(Redirect::to("/admin").into_response()),
+    };
+
+    if let Err(errors) = form.validate() {
+        let mut context = Context::new();
+        context.insert("note_errors", &error_messages(&errors));
+        context.insert("note_values", &form);
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_user(&mut tx, &renderer, user, context).await,
+        )
+            .into_response());
+    }
+
+    let id = UserNote::create(&mut tx, user.uid.clone(), claims.uid.clone(), &form)
+        .await
+        .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::AddUserNote,
+        Some(user.uid.clone()),
+        Some(id.to_string()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to(&format!("/admin/users/{}", user.uid)).into_response())
+}
+
+pub async fn add_to_shadow_queue(
+    tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    tenant: CurrentTenant,
+    Path(uid): Path<Uid>,
+) -> Result<Redirect, AuthError> {
+    set_shadow_queued(tx, claims, ip, tenant, uid, true).await
+}
+
+pub async fn remove_from_shadow_queue(
+    tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    tenant: CurrentTenant,
+    Path(uid): Path<Uid>,
+) -> Result<Redirect, AuthError> {
+    set_shadow_queued(tx, claims, ip, tenant, uid, false).await
+}
+
+async fn set_shadow_queued(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ip: IpAddr,
+    tenant: CurrentTenant,
+    uid: Uid,
+    shadow_queued: bool,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let user = match get_tenant_user(&mut tx, tenant.id(), uid).await {
+        Some(user) => user,
+        None => return Ok(Redirect::to("/admin")),
+    };
+
+    User::set_shadow_queued(&mut tx, user.uid.clone(), shadow_queued)
+        .await
+        .unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        if shadow_queued {
+            AuditAction::AddToShadowQueue
+        } else {
+            AuditAction::RemoveFromShadowQueue
+        },
+        Some(user.uid.clone()),
+        None,
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to(&format!("/admin/users/{}", user.uid)))
+}
+
+pub async fn impersonate(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(uid): Path<Uid>,
+    tenant: CurrentTenant,
+    jar: PrivateCookieJar,
+    Extension(config): Extension<Config>,
+) -> Result<(PrivateCookieJar, Redirect), Response> {
+    require_admin(&mut tx, &claims)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let user = User::get(&mut tx, uid)
+        .await
+        .map_err(|_| Redirect::to("/admin").into_response())?;
+
+    // Admins only look after the users of their own community
+    if Tenant::get_for_user(&mut tx, user.uid.clone()).await.ok() != Some(tenant.id()) {
+        return Err(Redirect::to("/admin").into_response());
+    }
+
+    let token = create_impersonation_token(
+        claims.uid.clone(),
+        user.uid.clone(),
+        claims.tenant_id.clone(),
+    )
+    .map_err(IntoResponse::into_response)?;
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::Impersonate,
+        Some(user.uid),
+        None,
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    // Keep the admin's own token around, so the session can be restored
+    // once they're done.
+    let admin_token = jar.get(JWT_COOKIE_NAME).unwrap().value().to_string();
+
+    Ok((
+        jar.add(build_token_cookie(
+            IMPERSONATOR_COOKIE_NAME,
+            admin_token,
+            &config,
+            Duration::hours(JWT_COOKIE_DURATION_HOURS),
+        ))
+        .add(build_token_cookie(
+            JWT_COOKIE_NAME,
+            token,
+            &config,
+            Duration::minutes(IMPERSONATION_DURATION_MINUTES),
+        )),
+        Redirect::to("/profile"),
+    ))
+}
+
+// Only the admin token kept when impersonation started is restored, so
+// sessions that aren't impersonating anyone are refused like on every other
+// admin route
+pub async fn stop_impersonating(
+    jar: PrivateCookieJar,
+    Extension(config): Extension<Config>,
+) -> Result<(PrivateCookieJar, Redirect), AuthError> {
+    match jar.get(IMPERSONATOR_COOKIE_NAME) {
+        Some(admin_token) => Ok((
+            jar.remove(build_removal_cookie(IMPERSONATOR_COOKIE_NAME, &config))
+                .add(build_token_cookie(
+                    JWT_COOKIE_NAME,
+                    admin_token.value().to_string(),
+                    &config,
+                    Duration::hours(JWT_COOKIE_DURATION_HOURS),
+                )),
+            Redirect::to("/admin"),
+        )),
+        None => Err(AuthError::Forbidden),
+    }
+}
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{Form, Path},
+    http::StatusCode,
+    response::{Redirect, Response},
+    Extension,
+};
+use axum_sqlx_tx::Tx;
+use serde::Deserialize;
+use sqlx::Sqlite;
+
+use openmelee::{
+    api_tokens::{ApiScope, ApiToken, MAX_API_TOKENS_PER_USER, MAX_API_TOKEN_NAME_CHARS},
+    auth::*,
+    Config,
+};
+
+use super::{render::Renderer, render_profile};
+
+// Submitted from the profile page, unchecked scopes are omitted
+#[derive(Debug, Deserialize)]
+pub struct ApiTokenForm {
+    pub name: String,
+    #[serde(default, rename = "read-profile")]
+    pub read_profile: Option<String>,
+    #[serde(default, rename = "read-history")]
+    pub read_history: Option<String>,
+    #[serde(default)]
+    pub overlay: Option<String>,
+    #[serde(default)]
+    pub widget: Option<String>,
+}
+
+impl ApiTokenForm {
+    fn get_scopes(&self) -> Vec<ApiScope> {
+        [
+            (ApiScope::ReadProfile, &self.read_profile),
+            (ApiScope::ReadHistory, &self.read_history),
+            (ApiScope::Overlay, &self.overlay),
+            (ApiScope::Widget, &self.widget),
+        ]
+        .into_iter()
+        .filter(|(_, checked)| checked.is_some())
+        .map(|(scope, _)| scope)
+        .collect()
+    }
+}
+
+// The new token is only ever shown on the page answering this request
+pub async fn create_api_token(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    Extension(config): Extension<Config>,
+    Form(form): Form<ApiTokenForm>,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    let name = form.name.trim();
+    let scopes = form.get_scopes();
+    let problem = if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_CHARS {
+        Some(format!(
+            "Token names must have between 1 and {} characters.",
+            MAX_API_TOKEN_NAME_CHARS
+        ))
+    } else if scopes.is_empty() {
+        Some("Tokens must allow reading something.".to_string())
+    } else if scopes.contains(&ApiScope::Overlay) && scopes.len() > 1 {
+        // Anyone seeing the overlay's address could use the token
+        Some("Overlay tokens can't allow anything else.".to_string())
+    } else if scopes.contains(&ApiScope::Widget) && scopes.len() > 1 {
+        // Widgets are embedded on public pages, their tokens are in plain sight
+        Some("Widget tokens can't allow anything else.".to_string())
+    } else if ApiToken::get_for_user(&mut tx, claims.uid.clone())
+        .await
+        .unwrap()
+        .len()
+        >= MAX_API_TOKENS_PER_USER
+    {
+        Some(format!(
+            "You can't have more than {} tokens, revoke one first.",
+            MAX_API_TOKENS_PER_USER
+        ))
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Ok(renderer.render_error(StatusCode::BAD_REQUEST, "Invalid API token", &problem));
+    }
+
+    let token = ApiToken::create(&mut tx, claims.uid.clone(), name.to_string(), &scopes)
+        .await
+        .unwrap();
+    let content = render_profile(
+        &mut tx,
+        &claims,
+        &renderer,
+        &config,
+        None,
+        Some(&token),
+        None,
+    )
+    .await;
+    tx.commit().await.unwrap();
+
+    Ok(content)
+}
+
+pub async fn revoke_api_token(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    Path(id): Path<i64>,
+) -> Result<Redirect, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    ApiToken::revoke(&mut tx, claims.uid, id).await.unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/profile"))
+}
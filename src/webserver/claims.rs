@@ -0,0 +1,63 @@
+use axum::{
+    extract::Form,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_sqlx_tx::Tx;
+use serde::Deserialize;
+use sqlx::Sqlite;
+
+use openmelee::{
+    auth::*,
+    claims::{ClaimStatus, ConnectCodeClaim, OfficialUser},
+    models::User,
+};
+
+use super::render::Renderer;
+
+#[derive(Debug, Deserialize)]
+pub struct ClaimForm {
+    pub user_json: String,
+}
+
+// Users paste the user.json file of their official Slippi account, admins
+// then decide whether to give them its connect code
+pub async fn submit_claim(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    Form(form): Form<ClaimForm>,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    let user = User::get(&mut tx, claims.uid.clone()).await.unwrap();
+    let is_waiting = matches!(
+        ConnectCodeClaim::get_latest_for_user(&mut tx, claims.uid.clone())
+            .await
+            .unwrap(),
+        Some(claim) if claim.status == ClaimStatus::Pending.to_string()
+    );
+
+    let official_user = match OfficialUser::parse(&form.user_json) {
+        _ if is_waiting => Err("Your previous claim is still waiting for review.".to_string()),
+        Ok(official_user) if official_user.connect_code == user.connect_code => {
+            Err("You already have this connect code.".to_string())
+        }
+        result => result,
+    };
+    let official_user = match official_user {
+        Ok(official_user) => official_user,
+        Err(problem) => {
+            return Ok(renderer.render_error(StatusCode::BAD_REQUEST, "Invalid claim", &problem))
+        }
+    };
+
+    ConnectCodeClaim::create(&mut tx, claims.uid, &official_user)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/profile").into_response())
+}
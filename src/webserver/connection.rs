@@ -0,0 +1,52 @@
+use axum::{response::Response, Json};
+use axum_sqlx_tx::Tx;
+use chrono::Utc;
+use sqlx::Sqlite;
+use tera::Context;
+
+use openmelee::{
+    api::ApiError,
+    api_tokens::ApiScope,
+    auth::{ApiCredentials, Claims},
+    connection_health::{ConnectionHealth, FAILURE_WINDOW_SECONDS, REPORT_GRACE_SECONDS},
+    ids::Uid,
+    matches::Match,
+    nat::NatReport,
+};
+
+use super::render::Renderer;
+
+async fn get_connection_health(tx: &mut Tx<Sqlite>, uid: Uid) -> ConnectionHealth {
+    let now = Utc::now().timestamp();
+    let nat_report = NatReport::get_for_user(&mut *tx, uid.clone())
+        .await
+        .unwrap();
+    // Recent matches may still be reported
+    let (recent_matches, failed_matches) = Match::count_unreported_for_user(
+        &mut *tx,
+        uid,
+        now - FAILURE_WINDOW_SECONDS,
+        now - REPORT_GRACE_SECONDS,
+    )
+    .await
+    .unwrap();
+
+    ConnectionHealth::new(nat_report, recent_matches, failed_matches)
+}
+
+pub async fn get_connection_health_json(
+    mut tx: Tx<Sqlite>,
+    credentials: ApiCredentials,
+) -> Result<Json<ConnectionHealth>, ApiError> {
+    let uid = credentials
+        .authorize(&mut tx, ApiScope::ReadProfile)
+        .await?;
+
+    Ok(Json(get_connection_health(&mut tx, uid).await))
+}
+
+pub async fn connection_health(mut tx: Tx<Sqlite>, claims: Claims, renderer: Renderer) -> Response {
+    let mut context = Context::new();
+    context.insert("health", &get_connection_health(&mut tx, claims.uid).await);
+    renderer.render("connection.html.tera", context)
+}
@@ -0,0 +1,92 @@
+use axum::{
+    extract::Form,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_sqlx_tx::Tx;
+use sqlx::Sqlite;
+use tera::Context;
+use validator::Validate;
+
+use openmelee::{
+    audit::*,
+    auth::*,
+    client_ip::ClientIp,
+    console::{self, ConsoleForm, MAX_ROWS, TIMEOUT},
+    models::error_messages,
+    ReadPool,
+};
+
+use super::{admin::require_admin, render::Renderer};
+
+fn get_context() -> Context {
+    let mut context = Context::new();
+    context.insert("max_rows", &MAX_ROWS);
+    context.insert("timeout_seconds", &TIMEOUT.as_secs());
+    context
+}
+
+pub async fn show(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    Ok(renderer.render("admin-console.html.tera", get_context()))
+}
+
+// Queries are recorded before they run, so those which fail or time out are
+// in the audit log too
+pub async fn run(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    Form(form): Form<ConsoleForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let mut context = get_context();
+    context.insert("console_values", &form);
+
+    if let Err(errors) = form.validate() {
+        context.insert("console_errors", &error_messages(&errors));
+
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            renderer.render("admin-console.html.tera", context),
+        )
+            .into_response());
+    }
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::RunSqlQuery,
+        None,
+        Some(form.query.clone()),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    match console::run(&pool, &form.query).await {
+        Ok(result) => {
+            context.insert("result", &result);
+            Ok(renderer.render("admin-console.html.tera", context))
+        }
+        Err(err) => {
+            context.insert("query_error", &err.to_string());
+            Ok((
+                StatusCode::BAD_REQUEST,
+                renderer.render("admin-console.html.tera", context),
+            )
+                .into_response())
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_sqlx_tx::Tx;
+use chrono::{TimeZone, Utc};
+use sqlx::Sqlite;
+
+use openmelee::{announcements::Announcement, pages::render_markdown, Config};
+
+use super::sitemap::escape_xml;
+
+const FEED_ENTRIES: i64 = 20;
+
+fn format_timestamp(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0).unwrap().to_rfc3339()
+}
+
+fn build_feed(base_url: &str, server_name: &str, announcements: &[Announcement]) -> String {
+    // An empty feed is as old as the server's start, rather than changing
+    // on every request
+    let updated = announcements
+        .first()
+        .map(|announcement| announcement.created_at)
+        .unwrap_or_else(|| openmelee::build_info::STARTED_AT.timestamp());
+
+    let base_url = escape_xml(base_url);
+    let mut feed = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+    );
+    feed.push_str(&format!("  <id>{}</id>\n", base_url));
+    feed.push_str(&format!("  <title>{}</title>\n", escape_xml(server_name)));
+    feed.push_str(&format!(
+        "  <updated>{}</updated>\n",
+        format_timestamp(updated)
+    ));
+    feed.push_str(&format!("  <link href=\"{}\"/>\n", base_url));
+    feed.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}feed.xml\"/>\n",
+        base_url
+    ));
+
+    for announcement in announcements {
+        let link = format!("{}#announcement-{}", base_url, announcement.id);
+
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!("    <id>{}</id>\n", link));
+        feed.push_str(&format!(
+            "    <title>{}</title>\n",
+            escape_xml(&announcement.title)
+        ));
+        feed.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            format_timestamp(announcement.created_at)
+        ));
+        feed.push_str(&format!(
+            "    <author><name>{}</name></author>\n",
+            escape_xml(server_name)
+        ));
+        feed.push_str(&format!("    <link href=\"{}\"/>\n", link));
+        feed.push_str(&format!(
+            "    <content type=\"html\">{}</content>\n",
+            escape_xml(&render_markdown(&announcement.content))
+        ));
+        feed.push_str("  </entry>\n");
+    }
+
+    feed.push_str("</feed>\n");
+    feed
+}
+
+pub async fn get_feed(mut tx: Tx<Sqlite>, Extension(config): Extension<Config>) -> Response {
+    let announcements = Announcement::get_recent(&mut tx, FEED_ENTRIES)
+        .await
+        .unwrap();
+
+    (
+        [(header::CONTENT_TYPE, "application/atom+xml")],
+        build_feed(
+            &config.clone().format_public_url(),
+            &config.server_name,
+            &announcements,
+        ),
+    )
+        .into_response()
+}
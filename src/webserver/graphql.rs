@@ -0,0 +1,39 @@
+use axum::{Extension, Json};
+use axum_sqlx_tx::Tx;
+use sqlx::Sqlite;
+
+use openmelee::{
+    api::ApiError,
+    api_tokens::ApiScope,
+    auth::ApiCredentials,
+    graphql::{self, RequestContext},
+    tenants::CurrentTenant,
+    ReadPool,
+};
+
+// Anyone may query public fields. Private ones are resolved for the user
+// logged in, or the owner of a token with the read-profile scope.
+pub async fn execute(
+    mut tx: Tx<Sqlite>,
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    credentials: Option<ApiCredentials>,
+    Json(request): Json<async_graphql::Request>,
+) -> Result<Json<async_graphql::Response>, ApiError> {
+    let viewer_uid = match credentials {
+        Some(credentials) => Some(
+            credentials
+                .authorize(&mut tx, ApiScope::ReadProfile)
+                .await?,
+        ),
+        None => None,
+    };
+    tx.commit().await.unwrap();
+
+    let context = RequestContext {
+        pool,
+        tenant_id: tenant.id(),
+        viewer_uid,
+    };
+    Ok(Json(graphql::execute(request, context).await))
+}
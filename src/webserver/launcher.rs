@@ -0,0 +1,83 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use axum_sqlx_tx::Tx;
+use serde::Deserialize;
+use sqlx::Sqlite;
+
+use openmelee::{
+    api::ApiError,
+    auth::*,
+    launcher::{self, LauncherLink},
+    models::User,
+    tenants::CurrentTenant,
+    Config,
+};
+
+use super::{read_user_json_key, render::Renderer, render_profile};
+
+#[derive(Debug, Deserialize)]
+pub struct BootstrapRequest {
+    pub token: String,
+}
+
+// The link bootstraps the play key, like user.json it isn't handed out to
+// admins viewing the site as this user
+pub async fn create_launcher_link(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    Extension(config): Extension<Config>,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    let (token, expires_at) = launcher::create_token(&mut tx, claims.uid.clone())
+        .await
+        .unwrap();
+    let link = LauncherLink::new(&config.clone().format_public_url(), &token, expires_at);
+    let content = render_profile(
+        &mut tx,
+        &claims,
+        &renderer,
+        &config,
+        None,
+        None,
+        Some(&link),
+    )
+    .await;
+    tx.commit().await.unwrap();
+
+    Ok(content)
+}
+
+// Exchanges a token from a launcher link for the user.json file of its
+// owner, signed when signing is set up
+pub async fn bootstrap(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    Extension(config): Extension<Config>,
+    Json(request): Json<BootstrapRequest>,
+) -> Result<Response, ApiError> {
+    let uid = launcher::redeem_token(&mut tx, &request.token, tenant.id())
+        .await
+        .unwrap()
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "The launcher link is invalid or expired",
+            )
+        })?;
+
+    let user = User::get(&mut tx, uid).await.unwrap();
+    let mut user_json = User::get_user_json(user, config.clone());
+    if let Some(key) = read_user_json_key(&config) {
+        user_json = user_json.sign(key.as_bytes());
+    }
+    tx.commit().await.unwrap();
+
+    Ok(Json(user_json).into_response())
+}
@@ -0,0 +1,291 @@
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use axum_sqlx_tx::Tx;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::Sqlite;
+
+use openmelee::{
+    api::{ApiError, Envelope, PageQuery, Paginated, WithUtcTime},
+    api_tokens::ApiScope,
+    auth::ApiCredentials,
+    events::{self, Event},
+    game::{OnlinePlayMode, Stage},
+    ids::{MatchId, PlayKey, Uid},
+    matches::*,
+    models::User,
+    ranking,
+    tenants::CurrentTenant,
+    ReadPool,
+};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// The most Melee allows
+const MAX_STOCKS: u8 = 99;
+const MAX_PERCENT: u16 = 999;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchReport {
+    pub uid: Uid,
+    pub play_key: PlayKey,
+    pub match_id: MatchId,
+    pub winner_uid: Uid,
+    // May also be sent as an Idempotency-Key header, which takes precedence
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    #[serde(default)]
+    pub client_version: Option<String>,
+    // SHA-256 digest of the .slp file, compared with the other players'
+    #[serde(default)]
+    pub replay_hash: Option<String>,
+    // In-game ID of the stage the game was played on
+    #[serde(default)]
+    pub stage: Option<Stage>,
+    // How the game ended for each player, only kept for ranked matches
+    #[serde(default)]
+    pub summary: Option<Vec<PlayerSummary>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReportError {
+    InvalidPlayKey,
+    MatchNotFound,
+    NotAParticipant,
+    InvalidWinner,
+    InvalidReplayHash,
+    InvalidSummary,
+    AlreadyReported,
+    IdempotencyKeyReused,
+}
+
+impl IntoResponse for ReportError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            ReportError::InvalidPlayKey => (StatusCode::UNAUTHORIZED, "Invalid play key"),
+            ReportError::MatchNotFound => (StatusCode::NOT_FOUND, "Match not found"),
+            ReportError::NotAParticipant => {
+                (StatusCode::FORBIDDEN, "Not a participant of this match")
+            }
+            ReportError::InvalidWinner => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Winner is not a participant of this match",
+            ),
+            ReportError::InvalidReplayHash => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Replay hash must be a hex encoded SHA-256 digest",
+            ),
+            ReportError::InvalidSummary => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Summary must list players of this match at most once, with at most 99 stocks and 999%",
+            ),
+            ReportError::AlreadyReported => (
+                StatusCode::CONFLICT,
+                "A result for this match was already reported",
+            ),
+            ReportError::IdempotencyKeyReused => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Idempotency key was already used for a different report",
+            ),
+        };
+        let body = Json(json!({
+            "error": error_message,
+        }));
+        (status, body).into_response()
+    }
+}
+
+fn is_valid_replay_hash(replay_hash: &str) -> bool {
+    replay_hash.len() == 64 && replay_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_valid_summary(summary: &[PlayerSummary], player_uids: &[Uid]) -> bool {
+    summary.iter().enumerate().all(|(i, player)| {
+        player_uids.contains(&player.uid)
+            && !summary[..i].iter().any(|other| other.uid == player.uid)
+            && player.stocks <= MAX_STOCKS
+            && player.percent <= MAX_PERCENT
+    })
+}
+
+pub async fn list_matches(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    Query(query): Query<PageQuery>,
+    Query(filter): Query<MatchFilter>,
+) -> Result<Envelope<Vec<WithUtcTime<Match>>>, ApiError> {
+    let after = match query.after()? {
+        Some(key) => Some(
+            Match::parse_cursor_key(&key)
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid cursor"))?,
+        ),
+        None => None,
+    };
+
+    let matches = Match::get_page(&pool, tenant.id(), &filter, after, query.limit() + 1)
+        .await
+        .unwrap();
+    let total = Match::count(&pool, tenant.id(), &filter).await.unwrap();
+
+    Ok(
+        Paginated::new(matches, query.limit(), total, Match::get_cursor_key)
+            .map(WithUtcTime::from)
+            .into(),
+    )
+}
+
+// The matches of the user a token was created by, for tools that don't
+// know their UID
+pub async fn list_own_matches(
+    mut tx: Tx<Sqlite>,
+    credentials: ApiCredentials,
+    tenant: CurrentTenant,
+    Query(query): Query<PageQuery>,
+) -> Result<Envelope<Vec<WithUtcTime<Match>>>, ApiError> {
+    let uid = credentials
+        .authorize(&mut tx, ApiScope::ReadHistory)
+        .await?;
+    let after = match query.after()? {
+        Some(key) => Some(
+            Match::parse_cursor_key(&key)
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid cursor"))?,
+        ),
+        None => None,
+    };
+    let filter = MatchFilter {
+        uid: Some(uid),
+        status: None,
+    };
+
+    let matches = Match::get_page(&mut tx, tenant.id(), &filter, after, query.limit() + 1)
+        .await
+        .unwrap();
+    let total = Match::count(&mut tx, tenant.id(), &filter).await.unwrap();
+
+    Ok(
+        Paginated::new(matches, query.limit(), total, Match::get_cursor_key)
+            .map(WithUtcTime::from)
+            .into(),
+    )
+}
+
+pub async fn report(
+    mut tx: Tx<Sqlite>,
+    headers: HeaderMap,
+    Json(report): Json<MatchReport>,
+) -> Result<(StatusCode, Json<WithUtcTime<MatchResult>>), ReportError> {
+    if !User::check_play_key(&mut tx, report.uid.clone(), report.play_key.clone()).await {
+        return Err(ReportError::InvalidPlayKey);
+    }
+
+    let replay_hash = match report.replay_hash {
+        Some(replay_hash) if is_valid_replay_hash(&replay_hash) => Some(replay_hash.to_lowercase()),
+        Some(_) => return Err(ReportError::InvalidReplayHash),
+        None => None,
+    };
+
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or(report.idempotency_key);
+
+    // A retry of an earlier report is answered with the stored result, and
+    // doesn't count the match a second time
+    if let Some(key) = &idempotency_key {
+        if let Ok(result) =
+            MatchResult::get_by_idempotency_key(&mut tx, report.uid.clone(), key.clone()).await
+        {
+            if result.match_id != report.match_id || result.winner_uid != report.winner_uid {
+                return Err(ReportError::IdempotencyKeyReused);
+            }
+
+            return Ok((StatusCode::OK, Json(result.into())));
+        }
+    }
+
+    let reported_match = Match::get(&mut tx, report.match_id.clone())
+        .await
+        .map_err(|_| ReportError::MatchNotFound)?;
+    let players = Match::get_player_teams(&mut tx, &reported_match)
+        .await
+        .unwrap();
+    let player_uids = players
+        .iter()
+        .map(|(uid, _)| uid.clone())
+        .collect::<Vec<Uid>>();
+
+    if !player_uids.contains(&report.uid) {
+        return Err(ReportError::NotAParticipant);
+    }
+    if !player_uids.contains(&report.winner_uid) {
+        return Err(ReportError::InvalidWinner);
+    }
+    if let Some(summary) = &report.summary {
+        if !is_valid_summary(summary, &player_uids) {
+            return Err(ReportError::InvalidSummary);
+        }
+    }
+
+    let result = MatchResult::create(
+        &mut tx,
+        report.match_id,
+        report.uid,
+        report.winner_uid,
+        idempotency_key,
+        report.client_version,
+        replay_hash,
+    )
+    .await
+    .map_err(|_| ReportError::AlreadyReported)?;
+
+    if let Some(summary) = report.summary {
+        if reported_match.mode == OnlinePlayMode::Ranked.to_string() {
+            PlayerSummary::create_all(&mut tx, result.id, &summary)
+                .await
+                .unwrap();
+        }
+    }
+
+    if let Some(stage) = report.stage {
+        Match::set_stage(&mut tx, reported_match.match_id.clone(), stage)
+            .await
+            .unwrap();
+    }
+
+    let results = MatchResult::get_all_for_match(&mut tx, reported_match.match_id.clone())
+        .await
+        .unwrap();
+    let verdict = Verdict::from_results(&players, &results);
+    Match::apply_verdict(&mut tx, reported_match.match_id.clone(), verdict.clone())
+        .await
+        .unwrap();
+    if let Verdict::Confirmed(winner_uid) = &verdict {
+        ranking::rate_match(
+            &mut tx,
+            &reported_match,
+            &player_uids,
+            winner_uid,
+            Utc::now().timestamp(),
+        )
+        .await
+        .unwrap();
+    }
+
+    tx.commit().await.unwrap();
+
+    if let Verdict::Confirmed(winner_uid) = verdict {
+        events::publish(Event::MatchConfirmed {
+            match_id: reported_match.match_id,
+            winner_uids: get_winner_uids(&players, &winner_uid),
+            player_uids,
+        });
+    }
+
+    Ok((StatusCode::CREATED, Json(result.into())))
+}
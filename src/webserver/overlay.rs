@@ -0,0 +1,150 @@
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response},
+    Json,
+};
+use axum_sqlx_tx::Tx;
+use serde::Serialize;
+use sqlx::Sqlite;
+use tera::Context;
+
+use openmelee::{
+    api::{format_timestamp, ApiError},
+    api_tokens::{ApiScope, ApiToken},
+    ids::ConnectCode,
+    matches::{get_winner_uids, Match, MatchResult},
+    models::User,
+    ranking::Rating,
+    tenants::CurrentTenant,
+};
+
+use super::render::Renderer;
+
+// Browser sources reload the overlay this often, so it catches up with
+// matches shortly after they are confirmed
+const OVERLAY_REFRESH_SECONDS: u32 = 15;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastResult {
+    pub won: bool,
+    pub opponent_display_name: Option<String>,
+    pub played_at: i64,
+    pub played_at_utc: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Overlay {
+    pub display_name: String,
+    pub connect_code: ConnectCode,
+    // Only set once the player played ranked
+    pub rating: Option<f64>,
+    pub wins: i64,
+    pub losses: i64,
+    pub last_result: Option<LastResult>,
+}
+
+// The token is part of the overlay's address, so it must not leak to other
+// sites or linger in caches
+const PRIVATE_HEADERS: [(header::HeaderName, &str); 2] = [
+    (header::REFERRER_POLICY, "no-referrer"),
+    (header::CACHE_CONTROL, "no-store"),
+];
+
+// None if the token doesn't exist or doesn't allow showing an overlay
+async fn get_overlay(tx: &mut Tx<Sqlite>, token: &str, tenant_id: String) -> Option<Overlay> {
+    let token = ApiToken::authenticate(&mut *tx, token, tenant_id)
+        .await
+        .unwrap()
+        .filter(|token| token.has_scope(ApiScope::Overlay))?;
+    let user = User::get(&mut *tx, token.uid.clone()).await.unwrap();
+
+    let last_result = match Match::get_last_counted_for_user(&mut *tx, user.uid.clone())
+        .await
+        .unwrap()
+    {
+        Some(last_match) => {
+            let players = Match::get_player_teams(&mut *tx, &last_match)
+                .await
+                .unwrap();
+            let user_team = players
+                .iter()
+                .find(|(uid, _)| uid == &user.uid)
+                .and_then(|(_, team)| *team);
+            // In teams matches, one of the players of the other team
+            let opponent_uid = players
+                .iter()
+                .find(|(uid, team)| uid != &user.uid && (team.is_none() || *team != user_team))
+                .map(|(uid, _)| uid.clone());
+            let won = match &last_match.winner_uid {
+                Some(winner_uid) => get_winner_uids(&players, winner_uid).contains(&user.uid),
+                None => false,
+            };
+            let opponent = match opponent_uid {
+                Some(uid) => User::get(&mut *tx, uid).await.ok(),
+                None => None,
+            };
+            Some(LastResult {
+                won,
+                opponent_display_name: opponent.map(|opponent| opponent.display_name),
+                played_at: last_match.created_at,
+                played_at_utc: format_timestamp(last_match.created_at),
+            })
+        }
+        None => None,
+    };
+
+    let rating = Rating::get(&mut *tx, user.uid.clone()).await.unwrap();
+    Some(Overlay {
+        display_name: user.display_name.clone(),
+        connect_code: user.connect_code.clone(),
+        rating: (rating.games > 0).then_some(rating.rating),
+        wins: MatchResult::count_wins(&mut *tx, user.uid.clone())
+            .await
+            .unwrap(),
+        losses: MatchResult::count_losses(&mut *tx, user.uid.clone())
+            .await
+            .unwrap(),
+        last_result,
+    })
+}
+
+pub async fn show_overlay(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    renderer: Renderer,
+    Path(token): Path<String>,
+) -> Response {
+    let overlay = match get_overlay(&mut tx, &token, tenant.id()).await {
+        Some(overlay) => overlay,
+        None => {
+            return renderer.render_error(
+                StatusCode::NOT_FOUND,
+                "Overlay not found",
+                "This overlay doesn't exist or its token was revoked.",
+            )
+        }
+    };
+
+    let mut context = Context::new();
+    context.insert("overlay", &overlay);
+    context.insert("refresh_seconds", &OVERLAY_REFRESH_SECONDS);
+    (
+        AppendHeaders(PRIVATE_HEADERS),
+        renderer.render("overlay.html.tera", context),
+    )
+        .into_response()
+}
+
+pub async fn get_overlay_json(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    Path(token): Path<String>,
+) -> Result<Response, ApiError> {
+    match get_overlay(&mut tx, &token, tenant.id()).await {
+        Some(overlay) => Ok((AppendHeaders(PRIVATE_HEADERS), Json(overlay)).into_response()),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, "Overlay not found")),
+    }
+}
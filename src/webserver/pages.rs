@@ -0,0 +1,177 @@
+use axum::{
+    extract::{Form, Path},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_sqlx_tx::Tx;
+use serde::{Deserialize, Serialize};
+use sqlx::Sqlite;
+use tera::Context;
+use validator::{Validate, ValidationErrors};
+
+use openmelee::{audit::*, auth::*, client_ip::ClientIp, models::error_messages, pages::*};
+
+use super::{admin::require_admin, not_found, render::Renderer};
+
+// Submitted from the admin page editor, where original_slug is empty for
+// new pages and differs from slug when a page is moved
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PageForm {
+    #[serde(default)]
+    pub original_slug: String,
+    pub slug: String,
+    pub title: String,
+    pub content: String,
+    #[serde(default)]
+    pub show_in_navbar: Option<String>,
+}
+
+impl From<&Page> for PageForm {
+    fn from(page: &Page) -> PageForm {
+        PageForm {
+            original_slug: page.slug.clone(),
+            slug: page.slug.clone(),
+            title: page.title.clone(),
+            content: page.content.clone(),
+            show_in_navbar: page.show_in_navbar.then(|| "on".to_string()),
+        }
+    }
+}
+
+// Serves pages at the top level, wherever no other route matched
+pub async fn show(mut tx: Tx<Sqlite>, uri: Uri, renderer: Renderer) -> Response {
+    let slug = uri.path().trim_start_matches('/').to_string();
+
+    match Page::get(&mut tx, slug).await {
+        Ok(page) => {
+            let mut context = Context::new();
+            context.insert("title", &page.title);
+            context.insert("content", &page.render_content());
+            renderer.render("page.html.tera", context)
+        }
+        Err(_) => not_found(renderer).await,
+    }
+}
+
+fn render_editor(
+    renderer: &Renderer,
+    form: &PageForm,
+    errors: Option<ValidationErrors>,
+) -> Response {
+    let mut context = Context::new();
+    context.insert("field_values", form);
+    match errors {
+        Some(errors) => context.insert("field_errors", &error_messages(&errors)),
+        None => context.insert("field_errors", &false),
+    }
+
+    renderer.render("admin-page.html.tera", context)
+}
+
+pub async fn new(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let form = PageForm {
+        original_slug: String::new(),
+        slug: String::new(),
+        title: String::new(),
+        content: String::new(),
+        show_in_navbar: None,
+    };
+
+    Ok(render_editor(&renderer, &form, None))
+}
+
+pub async fn edit(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    Path(slug): Path<String>,
+    renderer: Renderer,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    match Page::get(&mut tx, slug).await {
+        Ok(page) => Ok(render_editor(&renderer, &PageForm::from(&page), None)),
+        Err(_) => Ok(Redirect::to("/admin").into_response()),
+    }
+}
+
+pub async fn save(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    renderer: Renderer,
+    Form(form): Form<PageForm>,
+) -> Result<Response, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    let page = Page {
+        slug: form.slug.trim().to_string(),
+        title: form.title.trim().to_string(),
+        content: form.content.clone(),
+        show_in_navbar: form.show_in_navbar.is_some(),
+        updated_at: 0,
+    };
+
+    if let Err(errors) = page.validate() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            render_editor(&renderer, &form, Some(errors)),
+        )
+            .into_response());
+    }
+
+    if !form.original_slug.is_empty() && form.original_slug != page.slug {
+        Page::delete(&mut tx, form.original_slug.clone())
+            .await
+            .unwrap();
+    }
+    page.save(&mut tx).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::SavePage,
+        None,
+        Some(page.slug),
+    )
+    .await
+    .unwrap();
+
+    refresh_navbar_links(&mut tx).await.unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+pub async fn delete(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    ClientIp(ip): ClientIp,
+    Path(slug): Path<String>,
+) -> Result<Redirect, AuthError> {
+    require_admin(&mut tx, &claims).await?;
+
+    Page::delete(&mut tx, slug.clone()).await.unwrap();
+
+    AuditLogEntry::record(
+        &mut tx,
+        Some(claims.uid),
+        Some(ip),
+        AuditAction::DeletePage,
+        None,
+        Some(slug),
+    )
+    .await
+    .unwrap();
+
+    refresh_navbar_links(&mut tx).await.unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/admin"))
+}
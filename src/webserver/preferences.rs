@@ -0,0 +1,153 @@
+use axum::{
+    extract::Form,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+use axum_sqlx_tx::Tx;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::Sqlite;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use openmelee::{api::ApiError, api_tokens::ApiScope, auth::*, models::*, Config};
+
+use super::{render::Renderer, render_profile};
+
+// Submitted from the profile page, where an empty field means no limit and
+// an unchecked checkbox is omitted entirely
+#[derive(Debug, Deserialize)]
+pub struct TimezoneForm {
+    pub timezone: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PreferencesForm {
+    pub max_ping_ms: String,
+    #[serde(default)]
+    pub strict: Option<String>,
+}
+
+impl TryFrom<&PreferencesForm> for MatchmakingPreferences {
+    type Error = ValidationErrors;
+
+    fn try_from(form: &PreferencesForm) -> Result<MatchmakingPreferences, ValidationErrors> {
+        let max_ping_ms = match form.max_ping_ms.trim() {
+            "" => None,
+            max_ping_ms => match max_ping_ms.parse::<u32>() {
+                Ok(max_ping_ms) => Some(max_ping_ms),
+                Err(_) => {
+                    let mut errors = ValidationErrors::new();
+                    let mut error = ValidationError::new("number");
+                    error.message = Some(std::borrow::Cow::Borrowed("Must be a number"));
+                    errors.add("max_ping_ms", error);
+                    return Err(errors);
+                }
+            },
+        };
+
+        let preferences = MatchmakingPreferences {
+            max_ping_ms,
+            strict: form.strict.is_some(),
+        };
+
+        preferences.validate().map(|_| preferences)
+    }
+}
+
+pub async fn get_preferences(
+    mut tx: Tx<Sqlite>,
+    credentials: ApiCredentials,
+) -> Result<Json<MatchmakingPreferences>, ApiError> {
+    let uid = credentials
+        .authorize(&mut tx, ApiScope::ReadProfile)
+        .await?;
+
+    Ok(Json(
+        MatchmakingPreferences::get(&mut tx, uid).await.unwrap(),
+    ))
+}
+
+pub async fn update_preferences(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    Json(preferences): Json<MatchmakingPreferences>,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    if let Err(errors) = preferences.validate() {
+        let body = json!({ "errors": errors });
+        return Ok((StatusCode::BAD_REQUEST, Json(body)).into_response());
+    }
+
+    preferences.save(&mut tx, claims.uid).await.unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Json(preferences).into_response())
+}
+
+pub async fn preferences_form(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    Form(form): Form<PreferencesForm>,
+    renderer: Renderer,
+    Extension(config): Extension<Config>,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    match MatchmakingPreferences::try_from(&form) {
+        Ok(preferences) => {
+            preferences.save(&mut tx, claims.uid).await.unwrap();
+            tx.commit().await.unwrap();
+            Ok(Redirect::to("/profile").into_response())
+        }
+        Err(errors) => {
+            let content = render_profile(
+                &mut tx,
+                &claims,
+                &renderer,
+                &config,
+                Some((&form, errors)),
+                None,
+                None,
+            )
+            .await;
+            Ok((StatusCode::BAD_REQUEST, content).into_response())
+        }
+    }
+}
+
+pub async fn timezone_form(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    Form(form): Form<TimezoneForm>,
+    renderer: Renderer,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    // Only tampered forms send a time zone which isn't one of the options
+    let timezone = match form.timezone.parse::<Tz>() {
+        Ok(timezone) => timezone,
+        Err(_) => {
+            return Ok(renderer.render_error(
+                StatusCode::BAD_REQUEST,
+                "Unknown time zone",
+                "This time zone doesn't exist, please pick one from the list.",
+            ))
+        }
+    };
+
+    User::set_timezone(&mut tx, claims.uid, timezone)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/profile").into_response())
+}
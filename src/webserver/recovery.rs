@@ -0,0 +1,130 @@
+use axum::{
+    extract::Form,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_sqlx_tx::Tx;
+use chrono::Utc;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use sqlx::Sqlite;
+use tera::Context;
+
+use openmelee::{
+    audit::{AuditAction, AuditLogEntry},
+    client_ip::ClientIp,
+    ids::{PlayKey, Uid},
+    models::User,
+    recovery,
+    tenants::Tenant,
+};
+
+use super::render::Renderer;
+
+#[derive(Debug, Deserialize)]
+pub struct RecoveryForm {
+    pub uid: String,
+    pub play_key: String,
+    pub password: SecretString,
+}
+
+// Only the user ID is filled in again, the play key is as much a secret as
+// the password
+#[derive(Debug, Serialize)]
+struct RecoveryFieldValues {
+    uid: String,
+}
+
+fn render_form(renderer: &Renderer, error: Option<&str>, uid: &str) -> Response {
+    let mut context = Context::new();
+    context.insert("error", &error);
+    context.insert(
+        "field_values",
+        &RecoveryFieldValues {
+            uid: uid.to_string(),
+        },
+    );
+    let status = match error {
+        Some(_) => StatusCode::BAD_REQUEST,
+        None => StatusCode::OK,
+    };
+
+    (status, renderer.render("recover.html.tera", context)).into_response()
+}
+
+pub async fn show(renderer: Renderer) -> Result<Response, Redirect> {
+    if renderer.is_logged_in() {
+        return Err(Redirect::to("/profile"));
+    }
+
+    Ok(render_form(&renderer, None, ""))
+}
+
+// Players who lost their password but still have their user.json prove
+// who they are with the user ID and play key in it
+pub async fn recover(
+    mut tx: Tx<Sqlite>,
+    renderer: Renderer,
+    ClientIp(ip): ClientIp,
+    Form(form): Form<RecoveryForm>,
+) -> Response {
+    let now = Utc::now().timestamp();
+    let uid = form.uid.trim().parse::<Uid>().ok();
+
+    if recovery::is_rate_limited(&mut tx, ip, uid.clone(), now)
+        .await
+        .unwrap()
+    {
+        return renderer.render_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many attempts",
+            "Too many failed attempts to recover an account, please try again later.",
+        );
+    }
+    if form.password.expose_secret().is_empty() {
+        return render_form(&renderer, Some("Password cannot be empty."), &form.uid);
+    }
+
+    let play_key = form.play_key.trim().parse::<PlayKey>().ok();
+    let is_verified = match (&uid, play_key) {
+        (Some(uid), Some(play_key)) => {
+            User::check_play_key(&mut tx, uid.clone(), play_key).await
+                && Tenant::get_for_user(&mut tx, uid.clone()).await.ok()
+                    == Some(renderer.tenant_id())
+        }
+        _ => false,
+    };
+    let uid = match uid {
+        Some(uid) if is_verified => uid,
+        uid => {
+            println!("Failed account recovery for {:?} from {}", form.uid, ip);
+            recovery::record_failed_attempt(&mut tx, ip, uid, now)
+                .await
+                .unwrap();
+            tx.commit().await.unwrap();
+
+            return render_form(
+                &renderer,
+                Some("User ID or play key is incorrect."),
+                &form.uid,
+            );
+        }
+    };
+
+    User::set_password(&mut tx, uid.clone(), form.password)
+        .await
+        .unwrap();
+    AuditLogEntry::record(
+        &mut tx,
+        Some(uid.clone()),
+        Some(ip),
+        AuditAction::RecoverAccount,
+        Some(uid),
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+
+    Redirect::to("/login").into_response()
+}
@@ -0,0 +1,131 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequest, RequestParts},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Extension,
+};
+use chrono_tz::Tz;
+use tera::{Context, Tera};
+
+use openmelee::{
+    announcements::Announcement, auth::Claims, models::User, tenants::CurrentTenant, Config,
+    ReadPool,
+};
+
+const ANNOUNCEMENTS: i64 = 5;
+
+// Renders templates with the context every page relies on, so handlers only
+// insert what is specific to them
+pub struct Renderer {
+    tera: Tera,
+    logged_in: bool,
+    impersonating: bool,
+    server_name: String,
+    tenant_id: String,
+    base_path: String,
+    timezone: Tz,
+    announcements: Vec<Announcement>,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Renderer
+where
+    B: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(tera) = Extension::<Tera>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(config) = Extension::<Config>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let tenant = CurrentTenant::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(ReadPool(pool)) = Extension::<ReadPool>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let claims = Claims::from_request(req).await.ok();
+        let timezone = match &claims {
+            Some(claims) => User::get_timezone(&pool, claims.uid.clone())
+                .await
+                .unwrap_or(Tz::UTC),
+            None => Tz::UTC,
+        };
+        let announcements = Announcement::get_recent(&pool, ANNOUNCEMENTS)
+            .await
+            .unwrap_or_else(|err| {
+                println!("Failed to load announcements: {}", err);
+                vec![]
+            });
+
+        Ok(Renderer {
+            tera,
+            logged_in: claims.is_some(),
+            impersonating: matches!(&claims, Some(claims) if claims.is_impersonation()),
+            tenant_id: tenant.id(),
+            base_path: config.webserver_base_path.clone(),
+            timezone,
+            announcements,
+            // Tenants go by their own name
+            server_name: match tenant.0 {
+                Some(tenant) => tenant.name,
+                None => config.server_name,
+            },
+        })
+    }
+}
+
+impl Renderer {
+    pub fn is_logged_in(&self) -> bool {
+        self.logged_in
+    }
+
+    pub fn tenant_id(&self) -> String {
+        self.tenant_id.clone()
+    }
+
+    pub fn render(&self, template: &str, mut context: Context) -> Response {
+        context.insert("logged_in", &self.logged_in);
+        context.insert("impersonating", &self.impersonating);
+        context.insert("server_name", &self.server_name);
+        context.insert("base_path", &self.base_path);
+        // For the date filter, which takes time zones by name
+        context.insert("timezone", self.timezone.name());
+        // Pages listing announcements their own way insert them themselves
+        if !context.contains_key("announcements") {
+            context.insert("announcements", &self.announcements);
+        }
+
+        match self.tera.render(template, &context) {
+            Ok(content) => Html(content).into_response(),
+            Err(err) => {
+                println!("Failed to render {}: {:?}", template, err);
+                self.render_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal Server Error",
+                    "Something went wrong while showing this page.",
+                )
+            }
+        }
+    }
+
+    // Falls back to a plain response if the error page itself can't be
+    // rendered
+    pub fn render_error(&self, status: StatusCode, title: &str, message: &str) -> Response {
+        let mut context = Context::new();
+        context.insert("logged_in", &self.logged_in);
+        context.insert("server_name", &self.server_name);
+        context.insert("base_path", &self.base_path);
+        context.insert("title", title);
+        context.insert("message", message);
+
+        match self.tera.render("error.html.tera", &context) {
+            Ok(content) => (status, Html(content)).into_response(),
+            Err(_) => (status, message.to_string()).into_response(),
+        }
+    }
+}
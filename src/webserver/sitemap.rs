@@ -0,0 +1,89 @@
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_sqlx_tx::Tx;
+use chrono::{TimeZone, Utc};
+use sqlx::Sqlite;
+
+use openmelee::{pages::Page, Config};
+
+// Public pages which don't change with the database
+const STATIC_PATHS: &[&str] = &["", "register", "login", "stats"];
+
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn build_sitemap(base_url: &str, pages: &[Page]) -> String {
+    let mut sitemap = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+
+    for path in STATIC_PATHS {
+        sitemap.push_str(&format!(
+            "  <url><loc>{}</loc></url>\n",
+            escape_xml(&format!("{}{}", base_url, path))
+        ));
+    }
+
+    for page in pages {
+        sitemap.push_str(&format!(
+            "  <url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+            escape_xml(&format!("{}{}", base_url, page.slug)),
+            Utc.timestamp_opt(page.updated_at, 0)
+                .unwrap()
+                .format("%Y-%m-%d")
+        ));
+    }
+
+    sitemap.push_str("</urlset>\n");
+    sitemap
+}
+
+pub async fn get_sitemap(mut tx: Tx<Sqlite>, Extension(config): Extension<Config>) -> Response {
+    let pages = Page::get_all(&mut tx).await.unwrap();
+
+    (
+        [(header::CONTENT_TYPE, "application/xml")],
+        build_sitemap(&config.format_public_url(), &pages),
+    )
+        .into_response()
+}
+
+// Crawlers only read robots.txt at the root of a domain, under a base path
+// it's meant to be copied there
+fn default_robots(config: Config) -> String {
+    format!(
+        "User-agent: *\n\
+         Disallow: {base_path}/admin\n\
+         Disallow: {base_path}/api/\n\
+         Disallow: {base_path}/profile\n\
+         Sitemap: {}sitemap.xml\n",
+        config.clone().format_public_url(),
+        base_path = config.webserver_base_path
+    )
+}
+
+// Operators can replace the defaults with their own file, which is read on
+// every request so it can be changed without a restart
+pub async fn get_robots(Extension(config): Extension<Config>) -> Response {
+    let robots = match &config.robots_txt_path {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|err| {
+            println!(
+                "Unable to read {}, serving the default robots.txt: {}",
+                path, err
+            );
+            default_robots(config.clone())
+        }),
+        None => default_robots(config.clone()),
+    };
+
+    ([(header::CONTENT_TYPE, "text/plain")], robots).into_response()
+}
@@ -0,0 +1,88 @@
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use openmelee::{
+    ids::{ConnectCode, Uid},
+    matches::MatchResult,
+    models::User,
+    ranking::Rating,
+    tenants::CurrentTenant,
+    ReadPool,
+};
+
+// Launchers look up ranks by posting a GraphQL query to the official
+// server. The query itself is ignored, only the connect code variable is
+// used to build a response of the same shape.
+#[derive(Debug, Deserialize)]
+pub struct RankQuery {
+    pub variables: RankQueryVariables,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RankQueryVariables {
+    #[serde(alias = "connectCode")]
+    pub cc: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RankedNetplayProfile {
+    id: Uid,
+    // Only set once the player played ranked
+    rating_ordinal: Option<f64>,
+    rating_update_count: i64,
+    wins: i64,
+    losses: i64,
+    daily_global_placement: Option<i64>,
+    daily_regional_placement: Option<i64>,
+    continent: Option<String>,
+    characters: Vec<serde_json::Value>,
+}
+
+// Ranks are served from the read replica, if any, as launchers look them up
+// far more often than matches are played
+pub async fn get_rank(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    Json(query): Json<RankQuery>,
+) -> Json<serde_json::Value> {
+    let user = match query.variables.cc.parse::<ConnectCode>() {
+        Ok(connect_code) => User::get_by_connect_code(&pool, tenant.id(), connect_code).await,
+        Err(_) => Err(sqlx::Error::RowNotFound),
+    };
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => return Json(json!({ "data": { "getConnectCode": null } })),
+    };
+
+    let rating = Rating::get(&pool, user.uid.clone()).await.unwrap();
+    let profile = RankedNetplayProfile {
+        id: user.uid.clone(),
+        rating_ordinal: (rating.games > 0).then_some(rating.rating),
+        rating_update_count: rating.games,
+        wins: MatchResult::count_wins(&pool, user.uid.clone())
+            .await
+            .unwrap(),
+        losses: MatchResult::count_losses(&pool, user.uid.clone())
+            .await
+            .unwrap(),
+        daily_global_placement: None,
+        daily_regional_placement: None,
+        continent: None,
+        characters: vec![],
+    };
+
+    Json(json!({
+        "data": {
+            "getConnectCode": {
+                "user": {
+                    "displayName": user.display_name,
+                    "connectCode": { "code": user.connect_code },
+                    "status": "ACTIVE",
+                    "rankedNetplayProfile": profile,
+                }
+            }
+        }
+    }))
+}
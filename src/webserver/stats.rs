@@ -0,0 +1,100 @@
+use axum::{extract::TypedHeader, headers::IfNoneMatch, response::Response, Extension};
+use chrono::Utc;
+use tera::Context;
+
+use openmelee::{
+    api::Envelope,
+    capacity::{QueueSnapshot, CURRENT_SNAPSHOT_SECONDS},
+    formations::QueueWait,
+    quality::QualitySummary,
+    stats::{get_first_day, get_start_timestamp, Chart, DailyMatchCount},
+    tenants::CurrentTenant,
+    ReadPool,
+};
+
+use super::{check_not_modified, json_with_etag, render::Renderer, version_etag};
+
+pub async fn get_stats(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    renderer: Renderer,
+) -> Response {
+    let today = Utc::now().date_naive();
+    let first_day = get_first_day(today);
+    let counts = DailyMatchCount::get_since(&pool, tenant.id(), first_day)
+        .await
+        .unwrap();
+
+    let queue_waits = QueueWait::get_since(&pool, tenant.id(), get_start_timestamp(first_day))
+        .await
+        .unwrap();
+    let quality = QualitySummary::get_since(&pool, tenant.id(), get_start_timestamp(first_day))
+        .await
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("charts", &Chart::build(&counts, first_day, today));
+    context.insert("queue_waits", &queue_waits);
+    context.insert("quality", &quality);
+    context.insert("first_day", &first_day.to_string());
+    context.insert("today", &today.to_string());
+    renderer.render("stats.html.tera", context)
+}
+
+pub async fn get_stats_json(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+) -> Envelope<Vec<DailyMatchCount>> {
+    let first_day = get_first_day(Utc::now().date_naive());
+
+    DailyMatchCount::get_since(&pool, tenant.id(), first_day)
+        .await
+        .unwrap()
+        .into()
+}
+
+pub async fn get_queue_stats_json(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+) -> Envelope<Vec<QueueWait>> {
+    let first_day = get_first_day(Utc::now().date_naive());
+
+    QueueWait::get_since(&pool, tenant.id(), get_start_timestamp(first_day))
+        .await
+        .unwrap()
+        .into()
+}
+
+// Polled by launchers showing how busy the queues are, so unchanged
+// snapshots are answered from their ID alone
+pub async fn get_queue_status_json(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Response {
+    let since = Utc::now().timestamp() - CURRENT_SNAPSHOT_SECONDS;
+    let latest_id = QueueSnapshot::get_latest_id(&pool, tenant.id(), since)
+        .await
+        .unwrap();
+    let etag = version_etag(format!("queues-{}", latest_id.unwrap_or(0)));
+    if let Some(response) = check_not_modified(&if_none_match, &etag) {
+        return response;
+    }
+
+    let snapshots = QueueSnapshot::get_latest(&pool, tenant.id(), since)
+        .await
+        .unwrap();
+    json_with_etag(etag, &Envelope::from(snapshots))
+}
+
+pub async fn get_quality_stats_json(
+    Extension(ReadPool(pool)): Extension<ReadPool>,
+    tenant: CurrentTenant,
+) -> Envelope<Vec<QualitySummary>> {
+    let first_day = get_first_day(Utc::now().date_naive());
+
+    QualitySummary::get_since(&pool, tenant.id(), get_start_timestamp(first_day))
+        .await
+        .unwrap()
+        .into()
+}
@@ -0,0 +1,66 @@
+use axum::{
+    extract::Form,
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_sqlx_tx::Tx;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::Sqlite;
+
+use openmelee::{
+    auth::*,
+    ids::MatchId,
+    quality::{MatchRating, MAX_RATING, MIN_RATING, SURVEY_WINDOW_SECONDS},
+};
+
+use super::render::Renderer;
+
+#[derive(Debug, Deserialize)]
+pub struct RatingForm {
+    pub match_id: MatchId,
+    pub rating: i64,
+}
+
+// Only the last match a player played can be rated, and only once, as
+// that's the one shown on their profile
+pub async fn rate_match(
+    mut tx: Tx<Sqlite>,
+    claims: Claims,
+    renderer: Renderer,
+    Form(form): Form<RatingForm>,
+) -> Result<Response, AuthError> {
+    if claims.is_impersonation() {
+        return Err(AuthError::Forbidden);
+    }
+
+    let unrated_match = MatchRating::get_unrated_match(
+        &mut tx,
+        claims.uid.clone(),
+        Utc::now().timestamp() - SURVEY_WINDOW_SECONDS,
+    )
+    .await
+    .unwrap();
+    let problem = if !(MIN_RATING..=MAX_RATING).contains(&form.rating) {
+        Some(format!(
+            "Ratings must be between {} and {}.",
+            MIN_RATING, MAX_RATING
+        ))
+    } else if unrated_match.map(|unrated_match| unrated_match.match_id)
+        != Some(form.match_id.clone())
+    {
+        Some("Only your last match can be rated, once.".to_string())
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Ok(renderer.render_error(StatusCode::BAD_REQUEST, "Invalid rating", &problem));
+    }
+
+    MatchRating::create(&mut tx, form.match_id, claims.uid, form.rating)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    Ok(Redirect::to("/profile").into_response())
+}
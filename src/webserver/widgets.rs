@@ -0,0 +1,115 @@
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response},
+    Extension,
+};
+use axum_sqlx_tx::Tx;
+use chrono::Utc;
+use sqlx::Sqlite;
+use tera::Context;
+
+use openmelee::{
+    api_tokens::{ApiScope, ApiToken},
+    capacity::{QueueSnapshot, CURRENT_SNAPSHOT_SECONDS},
+    matches::LeaderboardEntry,
+    tenants::CurrentTenant,
+    Config,
+};
+
+use super::render::Renderer;
+
+// Widgets only change as often as matches are confirmed and queues are
+// snapshotted, there's no need to reload them more often
+const WIDGET_REFRESH_SECONDS: u32 = 60;
+const LEADERBOARD_SIZE: i64 = 10;
+
+// The configured policy, except that any site may embed the widget. Since
+// the token is part of the address, it must not leak through the referrer
+// or linger in caches either.
+fn get_widget_headers(config: &Config) -> [(header::HeaderName, String); 3] {
+    let mut directives: Vec<&str> = config
+        .webserver_content_security_policy
+        .split(';')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty() && !directive.starts_with("frame-ancestors"))
+        .collect();
+    directives.push("frame-ancestors *");
+
+    [
+        (header::CONTENT_SECURITY_POLICY, directives.join("; ")),
+        (header::REFERRER_POLICY, "no-referrer".to_string()),
+        (header::CACHE_CONTROL, "no-store".to_string()),
+    ]
+}
+
+async fn is_widget_token(tx: &mut Tx<Sqlite>, token: &str, tenant_id: String) -> bool {
+    ApiToken::authenticate(&mut *tx, token, tenant_id)
+        .await
+        .unwrap()
+        .filter(|token| token.has_scope(ApiScope::Widget))
+        .is_some()
+}
+
+fn render_widget(
+    renderer: &Renderer,
+    config: &Config,
+    template: &str,
+    mut context: Context,
+) -> Response {
+    context.insert("refresh_seconds", &WIDGET_REFRESH_SECONDS);
+    (
+        AppendHeaders(get_widget_headers(config)),
+        renderer.render(template, context),
+    )
+        .into_response()
+}
+
+fn render_not_found(renderer: &Renderer) -> Response {
+    renderer.render_error(
+        StatusCode::NOT_FOUND,
+        "Widget not found",
+        "This widget doesn't exist or its token was revoked.",
+    )
+}
+
+pub async fn show_leaderboard(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    renderer: Renderer,
+    Extension(config): Extension<Config>,
+    Path(token): Path<String>,
+) -> Response {
+    if !is_widget_token(&mut tx, &token, tenant.id()).await {
+        return render_not_found(&renderer);
+    }
+
+    let entries = LeaderboardEntry::get_top(&mut tx, tenant.id(), LEADERBOARD_SIZE)
+        .await
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("entries", &entries);
+    render_widget(&renderer, &config, "widget-leaderboard.html.tera", context)
+}
+
+pub async fn show_queue_status(
+    mut tx: Tx<Sqlite>,
+    tenant: CurrentTenant,
+    renderer: Renderer,
+    Extension(config): Extension<Config>,
+    Path(token): Path<String>,
+) -> Response {
+    if !is_widget_token(&mut tx, &token, tenant.id()).await {
+        return render_not_found(&renderer);
+    }
+
+    let since = Utc::now().timestamp() - CURRENT_SNAPSHOT_SECONDS;
+    let snapshots = QueueSnapshot::get_latest(&mut tx, tenant.id(), since)
+        .await
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("snapshots", &snapshots);
+    render_widget(&renderer, &config, "widget-queue-status.html.tera", context)
+}